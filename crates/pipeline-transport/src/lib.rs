@@ -0,0 +1,199 @@
+//! Carries audio chunks and stream events between the pipeline's services
+//! over a message broker. NATS and Valkey Streams are interchangeable
+//! behind [`Transport`], chosen by [`TransportConfig`].
+
+mod nats;
+mod valkey;
+
+use async_trait::async_trait;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use shared_types::{AudioChunk, StreamEvent, StreamId};
+
+pub use nats::NatsTransport;
+pub use valkey::ValkeyTransport;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("NATS: {0}")]
+    Nats(String),
+    #[error("Valkey: {0}")]
+    Valkey(#[from] redis::RedisError),
+    #[error("failed to encode or decode message: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("malformed message: {0}")]
+    Malformed(String),
+}
+
+/// Which broker carries the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Core NATS: low latency, but nothing is kept for consumers that
+    /// were not listening.
+    Nats,
+    /// Valkey (or Redis) Streams: messages are kept, trimmed to a maximum
+    /// length, and consumer groups resume where they left off.
+    Valkey,
+}
+
+impl Backend {
+    pub fn default_url(self) -> &'static str {
+        match self {
+            Self::Nats => "nats://127.0.0.1:4222",
+            Self::Valkey => "redis://127.0.0.1:6379",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "nats" => Ok(Self::Nats),
+            "valkey" | "redis" => Ok(Self::Valkey),
+            _ => Err(format!("expected nats or valkey, got `{backend}`")),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Nats => "nats",
+            Self::Valkey => "valkey",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    pub backend: Backend,
+    pub url: String,
+    /// Namespaces the subjects or stream keys, so several pipelines can
+    /// share a broker.
+    pub prefix: String,
+    /// Entries a Valkey stream is trimmed to, approximately.
+    pub max_len: usize,
+}
+
+impl TransportConfig {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            url: backend.default_url().to_string(),
+            prefix: "navitel".to_string(),
+            max_len: 100_000,
+        }
+    }
+}
+
+/// Where a subscriber starts reading when its consumer group is new. An
+/// existing group carries on from its own position regardless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartFrom {
+    /// Only messages published from now on.
+    Latest,
+    /// Everything the broker still holds.
+    Beginning,
+    /// Messages after this broker position, e.g. a Valkey entry ID.
+    After(String),
+}
+
+/// A message on the pipeline.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Chunk(AudioChunk),
+    Event(Box<StreamEvent>),
+}
+
+impl Message {
+    pub fn stream_id(&self) -> StreamId {
+        match self {
+            Self::Chunk(chunk) => chunk.metadata.stream_id,
+            Self::Event(event) => event.stream_id(),
+        }
+    }
+}
+
+/// A message handed to a subscriber, to be acknowledged once processed.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub message: Message,
+    /// Broker position of the message, where the backend has one.
+    pub id: Option<String>,
+    /// Stream key or subject it came from.
+    pub source: String,
+}
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn publish_chunk(&self, chunk: &AudioChunk) -> Result<(), TransportError>;
+
+    async fn publish_event(&self, event: &StreamEvent) -> Result<(), TransportError>;
+
+    /// Receives chunks and events as `consumer` within `group`. Members of
+    /// a group share the messages between them.
+    async fn subscribe(
+        &self,
+        group: &str,
+        consumer: &str,
+        start: StartFrom,
+    ) -> Result<Box<dyn Subscription>, TransportError>;
+
+    /// Waits until everything published so far has reached the broker.
+    async fn flush(&self) -> Result<(), TransportError>;
+}
+
+#[async_trait]
+pub trait Subscription: Send {
+    /// Waits for the next message.
+    async fn next(&mut self) -> Result<Delivery, TransportError>;
+
+    /// Marks a message processed. Unacknowledged messages are delivered
+    /// again when the consumer resubscribes, on backends that keep them.
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), TransportError>;
+}
+
+/// Connects to the configured backend.
+pub async fn connect(config: &TransportConfig) -> Result<Arc<dyn Transport>, TransportError> {
+    Ok(match config.backend {
+        Backend::Nats => Arc::new(NatsTransport::connect(config).await?),
+        Backend::Valkey => Arc::new(ValkeyTransport::connect(config).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_backend_names() {
+        assert_eq!("nats".parse(), Ok(Backend::Nats));
+        assert_eq!("valkey".parse(), Ok(Backend::Valkey));
+        assert_eq!("redis".parse(), Ok(Backend::Valkey));
+        assert_eq!(
+            "kafka".parse::<Backend>(),
+            Err("expected nats or valkey, got `kafka`".to_string())
+        );
+        assert!("NATS".parse::<Backend>().is_err());
+
+        for backend in [Backend::Nats, Backend::Valkey] {
+            assert_eq!(backend.to_string().parse(), Ok(backend));
+        }
+    }
+
+    #[test]
+    fn configures_each_backend_with_its_default_url() {
+        let config = TransportConfig::new(Backend::Nats);
+        assert_eq!(config.url, "nats://127.0.0.1:4222");
+        assert_eq!(config.prefix, "navitel");
+        assert_eq!(config.max_len, 100_000);
+
+        let config = TransportConfig::new(Backend::Valkey);
+        assert_eq!(config.backend, Backend::Valkey);
+        assert_eq!(config.url, "redis://127.0.0.1:6379");
+    }
+}
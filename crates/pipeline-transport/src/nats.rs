@@ -0,0 +1,126 @@
+use async_nats::{Client, ConnectOptions, Subscriber};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::stream::Select;
+use tracing::{info, warn};
+
+use shared_types::{AudioChunk, StreamEvent};
+
+use crate::{
+    Delivery, Message, StartFrom, Subscription, Transport, TransportConfig, TransportError,
+};
+
+/// Core NATS. Chunks go to `<prefix>.audio.<stream id>` and events to
+/// `<prefix>.events`, as JSON. Nothing is stored, so subscribers only see
+/// what is published while they are connected and acknowledging is a no-op.
+pub struct NatsTransport {
+    client: Client,
+    prefix: String,
+}
+
+impl NatsTransport {
+    pub async fn connect(config: &TransportConfig) -> Result<Self, TransportError> {
+        let client = ConnectOptions::new()
+            .name("navitel")
+            .connect(config.url.as_str())
+            .await
+            .map_err(|e| TransportError::Nats(e.to_string()))?;
+        info!(
+            "Connected to NATS at {} under {}",
+            config.url, config.prefix
+        );
+        Ok(Self {
+            client,
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    fn events_subject(&self) -> String {
+        format!("{}.events", self.prefix)
+    }
+
+    async fn publish(&self, subject: String, payload: Vec<u8>) -> Result<(), TransportError> {
+        self.client
+            .publish(subject, Bytes::from(payload))
+            .await
+            .map_err(|e| TransportError::Nats(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Transport for NatsTransport {
+    async fn publish_chunk(&self, chunk: &AudioChunk) -> Result<(), TransportError> {
+        let subject = format!("{}.audio.{}", self.prefix, chunk.metadata.stream_id);
+        self.publish(subject, serde_json::to_vec(chunk)?).await
+    }
+
+    async fn publish_event(&self, event: &StreamEvent) -> Result<(), TransportError> {
+        self.publish(self.events_subject(), serde_json::to_vec(event)?)
+            .await
+    }
+
+    async fn subscribe(
+        &self,
+        group: &str,
+        consumer: &str,
+        start: StartFrom,
+    ) -> Result<Box<dyn Subscription>, TransportError> {
+        if start != StartFrom::Latest {
+            warn!(
+                "NATS keeps no history, {} starts from the latest message",
+                consumer
+            );
+        }
+        let subscribe = |subject: String| self.client.queue_subscribe(subject, group.to_string());
+        let audio = subscribe(format!("{}.audio.*", self.prefix))
+            .await
+            .map_err(|e| TransportError::Nats(e.to_string()))?;
+        let events = subscribe(self.events_subject())
+            .await
+            .map_err(|e| TransportError::Nats(e.to_string()))?;
+        info!("{} subscribed to NATS in queue group {}", consumer, group);
+        Ok(Box::new(NatsSubscription {
+            messages: futures::stream::select(audio, events),
+            events_subject: self.events_subject(),
+        }))
+    }
+
+    async fn flush(&self) -> Result<(), TransportError> {
+        self.client
+            .flush()
+            .await
+            .map_err(|e| TransportError::Nats(e.to_string()))
+    }
+}
+
+struct NatsSubscription {
+    messages: Select<Subscriber, Subscriber>,
+    events_subject: String,
+}
+
+#[async_trait]
+impl Subscription for NatsSubscription {
+    async fn next(&mut self) -> Result<Delivery, TransportError> {
+        let message = self
+            .messages
+            .next()
+            .await
+            .ok_or_else(|| TransportError::Nats("subscription closed".to_string()))?;
+        let source = message.subject.to_string();
+        let decoded = if source == self.events_subject {
+            Message::Event(Box::new(serde_json::from_slice(&message.payload)?))
+        } else {
+            Message::Chunk(serde_json::from_slice(&message.payload)?)
+        };
+        Ok(Delivery {
+            message: decoded,
+            id: None,
+            source,
+        })
+    }
+
+    async fn ack(&mut self, _delivery: &Delivery) -> Result<(), TransportError> {
+        Ok(())
+    }
+}
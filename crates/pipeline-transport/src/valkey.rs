@@ -0,0 +1,375 @@
+use async_trait::async_trait;
+use redis::aio::{ConnectionManager, MultiplexedConnection};
+use redis::streams::{StreamId, StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, Client};
+use std::collections::VecDeque;
+use tracing::{debug, info, warn};
+
+use shared_types::{AudioChunk, StreamEvent};
+
+use crate::{
+    Delivery, Message, StartFrom, Subscription, Transport, TransportConfig, TransportError,
+};
+
+/// Entries fetched per XREADGROUP.
+const READ_COUNT: usize = 64;
+/// How long one XREADGROUP waits for new entries before asking again.
+const READ_BLOCK_MS: usize = 1000;
+/// Ask for entries never delivered to any consumer in the group.
+const NEW_ENTRIES: &str = ">";
+
+/// Valkey (or Redis) Streams. Chunks are appended to `<prefix>:audio` and
+/// events to `<prefix>:events`, each trimmed to roughly `max_len` entries.
+/// Every entry carries the `stream_id` it belongs to and the JSON `payload`.
+///
+/// Subscribers read through consumer groups. A consumer that crashes picks
+/// up its delivered but unacknowledged entries first when it comes back,
+/// then carries on from the group's position, so nothing still held in the
+/// stream is lost.
+pub struct ValkeyTransport {
+    client: Client,
+    connection: ConnectionManager,
+    audio_key: String,
+    events_key: String,
+    max_len: usize,
+}
+
+impl ValkeyTransport {
+    pub async fn connect(config: &TransportConfig) -> Result<Self, TransportError> {
+        let client = Client::open(config.url.as_str())?;
+        let connection = client.get_connection_manager().await?;
+        info!(
+            "Connected to Valkey at {}, streams {}:audio and {}:events trimmed to ~{} entries",
+            config.url, config.prefix, config.prefix, config.max_len
+        );
+        Ok(Self {
+            client,
+            connection,
+            audio_key: format!("{}:audio", config.prefix),
+            events_key: format!("{}:events", config.prefix),
+            max_len: config.max_len,
+        })
+    }
+
+    async fn append(
+        &self,
+        key: &str,
+        stream_id: String,
+        payload: Vec<u8>,
+    ) -> Result<(), TransportError> {
+        let mut connection = self.connection.clone();
+        let _: String = connection
+            .xadd_maxlen(
+                key,
+                StreamMaxlen::Approx(self.max_len),
+                "*",
+                &[("stream_id", stream_id.into_bytes()), ("payload", payload)],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Creates the group on `key` unless it exists, in which case it keeps
+    /// its position.
+    async fn create_group(
+        &self,
+        key: &str,
+        group: &str,
+        start: &StartFrom,
+    ) -> Result<(), TransportError> {
+        let id = match start {
+            StartFrom::Latest => "$",
+            StartFrom::Beginning => "0",
+            StartFrom::After(id) => id.as_str(),
+        };
+        let mut connection = self.connection.clone();
+        match connection
+            .xgroup_create_mkstream::<_, _, _, ()>(key, group, id)
+            .await
+        {
+            Ok(()) => {
+                info!("Created consumer group {} on {} from {}", group, key, id);
+                Ok(())
+            }
+            Err(e) if e.code() == Some("BUSYGROUP") => {
+                debug!("Consumer group {} on {} already exists", group, key);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ValkeyTransport {
+    async fn publish_chunk(&self, chunk: &AudioChunk) -> Result<(), TransportError> {
+        self.append(
+            &self.audio_key,
+            chunk.metadata.stream_id.to_string(),
+            serde_json::to_vec(chunk)?,
+        )
+        .await
+    }
+
+    async fn publish_event(&self, event: &StreamEvent) -> Result<(), TransportError> {
+        self.append(
+            &self.events_key,
+            event.stream_id().to_string(),
+            serde_json::to_vec(event)?,
+        )
+        .await
+    }
+
+    async fn subscribe(
+        &self,
+        group: &str,
+        consumer: &str,
+        start: StartFrom,
+    ) -> Result<Box<dyn Subscription>, TransportError> {
+        self.create_group(&self.audio_key, group, &start).await?;
+        self.create_group(&self.events_key, group, &start).await?;
+        // Blocking reads hold up everything behind them on a connection,
+        // so each subscription gets its own.
+        let connection = self.client.get_multiplexed_async_connection().await?;
+        info!("{} subscribed to Valkey in group {}", consumer, group);
+        Ok(Box::new(ValkeySubscription {
+            connection,
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            keys: [self.audio_key.clone(), self.events_key.clone()],
+            // "0" reads back this consumer's pending entries from before.
+            cursors: ["0".to_string(), "0".to_string()],
+            ready: VecDeque::new(),
+        }))
+    }
+
+    async fn flush(&self) -> Result<(), TransportError> {
+        // Every XADD has been answered by the time it returns.
+        Ok(())
+    }
+}
+
+struct ValkeySubscription {
+    connection: MultiplexedConnection,
+    group: String,
+    consumer: String,
+    keys: [String; 2],
+    /// Per key, the last pending entry read back, or `>` once there are no
+    /// more and new entries are wanted.
+    cursors: [String; 2],
+    ready: VecDeque<Delivery>,
+}
+
+impl ValkeySubscription {
+    async fn read(&mut self) -> Result<(), TransportError> {
+        let mut options = StreamReadOptions::default()
+            .group(&self.group, &self.consumer)
+            .count(READ_COUNT);
+        if self.cursors.iter().all(|cursor| cursor == NEW_ENTRIES) {
+            options = options.block(READ_BLOCK_MS);
+        }
+        let reply: Option<StreamReadReply> = self
+            .connection
+            .xread_options(&self.keys, &self.cursors, &options)
+            .await?;
+        let reply = reply.unwrap_or_default();
+        for key in advance_cursors(&self.keys, &mut self.cursors, &reply) {
+            debug!("{} has no pending entries left on {}", self.consumer, key);
+        }
+
+        for stream in reply.keys {
+            let events = stream.key == self.keys[1];
+            for entry in stream.ids {
+                match decode(&entry, events) {
+                    Ok(message) => self.ready.push_back(Delivery {
+                        message,
+                        id: Some(entry.id),
+                        source: stream.key.clone(),
+                    }),
+                    Err(e) => {
+                        // Trimmed away while pending, or not ours: nothing
+                        // to deliver, so stop it coming back.
+                        warn!("Skipping entry {} on {}: {}", entry.id, stream.key, e);
+                        let _: i64 = self
+                            .connection
+                            .xack(&stream.key, &self.group, &[&entry.id])
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscription for ValkeySubscription {
+    async fn next(&mut self) -> Result<Delivery, TransportError> {
+        loop {
+            if let Some(delivery) = self.ready.pop_front() {
+                return Ok(delivery);
+            }
+            self.read().await?;
+        }
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), TransportError> {
+        let Some(id) = &delivery.id else {
+            return Ok(());
+        };
+        let _: i64 = self
+            .connection
+            .xack(&delivery.source, &self.group, &[id])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Moves each cursor still reading back pending entries past those `reply`
+/// returned for its key, or on to new entries when it returned none.
+/// Returns the keys whose pending entries ran out.
+fn advance_cursors<'k>(
+    keys: &'k [String; 2],
+    cursors: &mut [String; 2],
+    reply: &StreamReadReply,
+) -> Vec<&'k str> {
+    let mut exhausted = Vec::new();
+    for (key, cursor) in keys.iter().zip(cursors) {
+        if cursor == NEW_ENTRIES {
+            continue;
+        }
+        let entries = reply
+            .keys
+            .iter()
+            .find(|stream| &stream.key == key)
+            .map(|stream| stream.ids.as_slice())
+            .unwrap_or_default();
+        if let Some(last) = entries.last() {
+            cursor.clone_from(&last.id);
+        } else {
+            *cursor = NEW_ENTRIES.to_string();
+            exhausted.push(key.as_str());
+        }
+    }
+    exhausted
+}
+
+fn decode(entry: &StreamId, event: bool) -> Result<Message, TransportError> {
+    let payload: Vec<u8> = entry
+        .get("payload")
+        .ok_or_else(|| TransportError::Malformed("no payload field".to_string()))?;
+    Ok(if event {
+        Message::Event(Box::new(serde_json::from_slice(&payload)?))
+    } else {
+        Message::Chunk(serde_json::from_slice(&payload)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+    use redis::streams::StreamKey;
+    use shared_types::{AudioFormat, ChunkKind, HeaderExtensions, LatencyMetadata, StreamMetadata};
+    use std::collections::HashMap;
+
+    fn keys() -> [String; 2] {
+        ["navitel:audio".to_string(), "navitel:events".to_string()]
+    }
+
+    fn entry(id: &str, fields: &[(&str, &[u8])]) -> StreamId {
+        StreamId {
+            id: id.to_string(),
+            map: fields
+                .iter()
+                .map(|&(name, value)| (name.to_string(), Value::BulkString(value.to_vec())))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    fn reply(streams: &[(&str, &[&str])]) -> StreamReadReply {
+        StreamReadReply {
+            keys: streams
+                .iter()
+                .map(|&(key, ids)| StreamKey {
+                    key: key.to_string(),
+                    ids: ids.iter().map(|id| entry(id, &[])).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn reads_pending_entries_before_new_ones() {
+        let keys = keys();
+        let mut cursors = ["0".to_string(), "0".to_string()];
+
+        let exhausted = advance_cursors(
+            &keys,
+            &mut cursors,
+            &reply(&[("navitel:audio", &["5-0", "7-1"])]),
+        );
+        assert_eq!(exhausted, ["navitel:events"]);
+        assert_eq!(cursors, ["7-1", ">"]);
+
+        // New entries on the other key do not move a pending cursor.
+        let exhausted = advance_cursors(
+            &keys,
+            &mut cursors,
+            &reply(&[("navitel:audio", &["9-0"]), ("navitel:events", &["8-0"])]),
+        );
+        assert_eq!(exhausted.len(), 0);
+        assert_eq!(cursors, ["9-0", ">"]);
+
+        let exhausted = advance_cursors(&keys, &mut cursors, &StreamReadReply::default());
+        assert_eq!(exhausted, ["navitel:audio"]);
+        assert_eq!(cursors, [">", ">"]);
+
+        let exhausted =
+            advance_cursors(&keys, &mut cursors, &reply(&[("navitel:audio", &["10-0"])]));
+        assert_eq!(exhausted.len(), 0);
+        assert_eq!(cursors, [">", ">"]);
+    }
+
+    #[test]
+    fn decodes_chunks_and_events() {
+        let metadata = StreamMetadata::new("192.0.2.10:5004".parse().unwrap());
+        let chunk = AudioChunk {
+            data: bytes::Bytes::from_static(&[0xff; 160]),
+            format: AudioFormat::g711_ulaw_mono(),
+            sequence_number: 7,
+            timestamp: 1120,
+            kind: ChunkKind::Audio,
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata: LatencyMetadata::new(metadata.id),
+        };
+        let payload = serde_json::to_vec(&chunk).unwrap();
+        let Message::Chunk(decoded) =
+            decode(&entry("1-0", &[("payload", &payload)]), false).unwrap()
+        else {
+            panic!("decoded a chunk as an event");
+        };
+        assert_eq!(decoded.sequence_number, 7);
+        assert_eq!(decoded.metadata.stream_id, metadata.id);
+
+        let event = StreamEvent::Created { metadata };
+        let payload = serde_json::to_vec(&event).unwrap();
+        let decoded = decode(&entry("2-0", &[("payload", &payload)]), true).unwrap();
+        assert!(matches!(decoded, Message::Event(_)));
+        assert_eq!(decoded.stream_id(), event.stream_id());
+    }
+
+    #[test]
+    fn refuses_entries_it_cannot_decode() {
+        // A pending entry trimmed from the stream comes back without fields.
+        assert!(matches!(
+            decode(&entry("1-0", &[]), false),
+            Err(TransportError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode(&entry("1-0", &[("payload", b"{}")]), true),
+            Err(TransportError::Encoding(_))
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{Result, bail};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::capture::{CaptureError, CaptureFilter, PacketCapture};
+use shared_types::StreamId;
+
+const CAPTURES_PATH: &str = "/captures";
+
+/// HTTP API for operators, for now to start and stop packet captures:
+///
+/// - `GET /captures` lists running captures.
+/// - `POST /captures` starts one; see [`StartCapture`].
+/// - `DELETE /captures/{id}` stops one early.
+///
+/// There is no authentication, so it only binds to loopback unless told
+/// that operators alone can reach the address.
+pub struct AdminServer {
+    listener: TcpListener,
+    capture: PacketCapture,
+}
+
+/// Body of `POST /captures`. With neither `source` nor `stream_id`,
+/// everything received is captured.
+#[derive(Debug, Deserialize)]
+struct StartCapture {
+    /// A host (`IP`) or a single sender (`IP:PORT`).
+    source: Option<String>,
+    stream_id: Option<StreamId>,
+    /// Defaults to, and is capped at, the configured maximum.
+    duration_secs: Option<u64>,
+}
+
+/// Why a request was turned down, as an HTTP response.
+struct Rejection(StatusCode, String);
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl AdminServer {
+    /// Binds `bind`, refusing anything but loopback unless `allow_remote`.
+    pub async fn bind(
+        bind: SocketAddr,
+        allow_remote: bool,
+        capture: PacketCapture,
+    ) -> Result<Self> {
+        let remote = !bind.ip().is_loopback();
+        if remote && !allow_remote {
+            bail!("the admin API is unauthenticated, so it needs a loopback address, not {bind}");
+        }
+        let listener = TcpListener::bind(bind).await?;
+        info!("Admin API listening on http://{}", bind);
+        if remote {
+            warn!("Admin API is unauthenticated and not bound to loopback");
+        }
+        Ok(Self { listener, capture })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let router = Router::new()
+            .route(CAPTURES_PATH, get(list_captures).post(start_capture))
+            .route(&format!("{CAPTURES_PATH}/{{id}}"), delete(stop_capture))
+            .with_state(self.capture);
+        axum::serve(self.listener, router).await?;
+        Ok(())
+    }
+}
+
+async fn list_captures(State(capture): State<PacketCapture>) -> Response {
+    Json(capture.list()).into_response()
+}
+
+async fn start_capture(
+    State(capture): State<PacketCapture>,
+    Json(request): Json<StartCapture>,
+) -> Result<Response, Rejection> {
+    let filter = match (request.source.as_deref(), request.stream_id) {
+        (None, None) => CaptureFilter::All,
+        (None, Some(stream_id)) => CaptureFilter::Stream(stream_id),
+        (Some(source), None) => parse_source(source)?,
+        (Some(_), Some(_)) => {
+            return Err(Rejection(
+                StatusCode::BAD_REQUEST,
+                "give source or stream_id, not both".to_string(),
+            ));
+        }
+    };
+    let duration = request.duration_secs.map(Duration::from_secs);
+    match capture.start(filter, duration) {
+        Ok(info) => Ok((StatusCode::CREATED, Json(info)).into_response()),
+        Err(e @ CaptureError::UnknownStream(_)) => {
+            Err(Rejection(StatusCode::NOT_FOUND, e.to_string()))
+        }
+        Err(e @ CaptureError::Io(_)) => {
+            Err(Rejection(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+    }
+}
+
+async fn stop_capture(
+    State(capture): State<PacketCapture>,
+    Path(id): Path<String>,
+) -> Result<Response, Rejection> {
+    capture
+        .stop(&id)
+        .map(|info| Json(info).into_response())
+        .ok_or_else(|| Rejection(StatusCode::NOT_FOUND, format!("no capture {id}")))
+}
+
+fn parse_source(source: &str) -> Result<CaptureFilter, Rejection> {
+    if let Ok(addr) = source.parse::<SocketAddr>() {
+        return Ok(CaptureFilter::Source(addr));
+    }
+    source
+        .parse::<IpAddr>()
+        .map(CaptureFilter::Host)
+        .map_err(|_| {
+            Rejection(
+                StatusCode::BAD_REQUEST,
+                format!("source `{source}` is neither IP nor IP:PORT"),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureConfig;
+    use tokio::sync::broadcast;
+
+    fn parsed(source: &str) -> Result<CaptureFilter, StatusCode> {
+        parse_source(source).map_err(|rejection| rejection.0)
+    }
+
+    #[test]
+    fn parses_hosts_and_senders() {
+        assert_eq!(
+            parsed("192.0.2.10"),
+            Ok(CaptureFilter::Host("192.0.2.10".parse().unwrap()))
+        );
+        assert_eq!(
+            parsed("192.0.2.10:5004"),
+            Ok(CaptureFilter::Source("192.0.2.10:5004".parse().unwrap()))
+        );
+        assert_eq!(
+            parsed("2001:db8::1"),
+            Ok(CaptureFilter::Host("2001:db8::1".parse().unwrap()))
+        );
+        assert_eq!(
+            parsed("[2001:db8::1]:5004"),
+            Ok(CaptureFilter::Source("[2001:db8::1]:5004".parse().unwrap()))
+        );
+        for source in ["sbc.example.com", "192.0.2.10:", "192.0.2.10:99999", ""] {
+            assert_eq!(parsed(source), Err(StatusCode::BAD_REQUEST), "{source}");
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_beyond_loopback_only_when_allowed() {
+        let capture = PacketCapture::new(
+            CaptureConfig {
+                dir: std::env::temp_dir(),
+                file_bytes: 1024,
+                file_duration: Duration::from_secs(1),
+                max_files: 1,
+                max_duration: Duration::from_secs(1),
+            },
+            broadcast::channel(1).1,
+        );
+        let unspecified: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let refused = AdminServer::bind(unspecified, false, capture.clone()).await;
+        assert!(refused.is_err());
+
+        assert!(
+            AdminServer::bind("127.0.0.1:0".parse().unwrap(), false, capture.clone())
+                .await
+                .is_ok()
+        );
+        assert!(AdminServer::bind(unspecified, true, capture).await.is_ok());
+    }
+}
@@ -0,0 +1,788 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info, warn};
+
+use shared_types::{StreamEvent, StreamId};
+
+/// Classic pcap with nanosecond timestamps.
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+/// Records start at the IP header, which suits datagrams read off a socket.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65_535;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const PROTOCOL_UDP: u8 = 17;
+/// Datagrams held for a capture's writer before further ones are dropped.
+const CAPTURE_QUEUE: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("no stream {0} is being received")]
+    UnknownStream(StreamId),
+    #[error("failed to prepare capture directory: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Where captures go and the limits that keep them from filling the disk.
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// A capture moves on to a new file once one reaches this size...
+    pub file_bytes: u64,
+    /// ...or has been open this long.
+    pub file_duration: Duration,
+    /// Files kept per capture; the oldest is deleted to make room.
+    pub max_files: usize,
+    /// Longest a capture may run, and how long it runs when not told.
+    pub max_duration: Duration,
+}
+
+/// Which received datagrams a capture takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureFilter {
+    All,
+    /// Everything from one host.
+    Host(IpAddr),
+    /// Everything from one address and port.
+    Source(SocketAddr),
+    /// One stream's RTP and RTCP, wherever it is currently latched.
+    Stream(StreamId),
+}
+
+/// A capture as the admin API reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureInfo {
+    pub id: String,
+    pub filter: CaptureFilter,
+    pub started_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Datagrams lost because the writer fell behind or failed.
+    pub dropped: u64,
+    /// Files still on disk, oldest first.
+    pub files: Vec<PathBuf>,
+}
+
+/// Writes received RTP and RTCP datagrams to rotating pcap files, for
+/// evidence when someone reports bad audio. Captures are started and
+/// stopped at runtime; while none is running, receiving pays for one
+/// atomic load per batch. Clones share the same captures.
+///
+/// Each datagram is written with its arrival time as a UDP packet from
+/// its sender to the socket that read it. A socket bound to a wildcard
+/// address shows up as that address. TCP-framed media is not captured.
+#[derive(Clone)]
+pub struct PacketCapture {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: CaptureConfig,
+    /// Whether any capture is running, checked before anything else.
+    armed: AtomicBool,
+    captures: RwLock<HashMap<String, ActiveCapture>>,
+    /// Where each stream is latched, for stream filters.
+    streams: RwLock<HashMap<StreamId, SocketAddr>>,
+}
+
+struct ActiveCapture {
+    filter: CaptureFilter,
+    started_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+    records: mpsc::Sender<Record>,
+    stats: Arc<CaptureStats>,
+    expiry: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct CaptureStats {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+    files: Mutex<VecDeque<PathBuf>>,
+}
+
+struct Record {
+    time: SystemTime,
+    source: SocketAddr,
+    destination: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PacketCapture {
+    /// Follows `events` to know where streams are, for stream filters.
+    pub fn new(config: CaptureConfig, events: broadcast::Receiver<StreamEvent>) -> Self {
+        let capture = Self {
+            inner: Arc::new(Inner {
+                config,
+                armed: AtomicBool::new(false),
+                captures: RwLock::new(HashMap::new()),
+                streams: RwLock::new(HashMap::new()),
+            }),
+        };
+        tokio::spawn(capture.clone().track_streams(events));
+        capture
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.inner.armed.load(Ordering::Relaxed)
+    }
+
+    /// Hands a datagram received at `time` to every capture whose filter
+    /// takes it. Never waits: a capture whose writer is behind loses the
+    /// datagram.
+    pub fn record(
+        &self,
+        data: &[u8],
+        source: SocketAddr,
+        destination: SocketAddr,
+        time: SystemTime,
+    ) {
+        let captures = self
+            .inner
+            .captures
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        for capture in captures.values() {
+            if !self.matches(capture.filter, source) {
+                continue;
+            }
+            let record = Record {
+                time,
+                source,
+                destination,
+                data: data.to_vec(),
+            };
+            if capture.records.try_send(record).is_err() {
+                capture.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Starts a capture that runs for `duration`, or the configured
+    /// maximum if that is shorter or none is given.
+    pub fn start(
+        &self,
+        filter: CaptureFilter,
+        duration: Option<Duration>,
+    ) -> Result<CaptureInfo, CaptureError> {
+        if let CaptureFilter::Stream(stream_id) = filter
+            && !self.stream_known(stream_id)
+        {
+            return Err(CaptureError::UnknownStream(stream_id));
+        }
+        let config = &self.inner.config;
+        fs::create_dir_all(&config.dir)?;
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let duration = duration.map_or(config.max_duration, |d| d.min(config.max_duration));
+        let started_at = Utc::now();
+        let ends_at = started_at + chrono::Duration::from_std(duration).unwrap_or_default();
+        let (records, queued) = mpsc::channel(CAPTURE_QUEUE);
+        let stats = Arc::new(CaptureStats::default());
+
+        let writer = PcapWriter {
+            config: config.clone(),
+            id: id.clone(),
+            stats: Arc::clone(&stats),
+            file: None,
+            sequence: 0,
+        };
+        tokio::task::spawn_blocking(move || writer.run(queued));
+        let expiry = tokio::spawn({
+            let capture = self.clone();
+            let id = id.clone();
+            async move {
+                time::sleep(duration).await;
+                capture.stop(&id);
+            }
+        });
+
+        let capture = ActiveCapture {
+            filter,
+            started_at,
+            ends_at,
+            records,
+            stats,
+            expiry,
+        };
+        let info = capture.info(&id);
+        info!(
+            "Capturing {:?} to {} for {:?} as {}",
+            filter,
+            config.dir.display(),
+            duration,
+            id
+        );
+        let mut captures = self
+            .inner
+            .captures
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        captures.insert(id, capture);
+        self.inner.armed.store(true, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// Stops a capture. Its writer finishes what is queued and closes the
+    /// file.
+    pub fn stop(&self, id: &str) -> Option<CaptureInfo> {
+        let mut captures = self
+            .inner
+            .captures
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let capture = captures.remove(id)?;
+        self.inner
+            .armed
+            .store(!captures.is_empty(), Ordering::Relaxed);
+        drop(captures);
+
+        capture.expiry.abort();
+        let info = capture.info(id);
+        info!(
+            "Capture {} stopped: {} packet(s), {} byte(s), {} dropped",
+            id, info.packets, info.bytes, info.dropped
+        );
+        Some(info)
+    }
+
+    pub fn list(&self) -> Vec<CaptureInfo> {
+        self.inner
+            .captures
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(id, capture)| capture.info(id))
+            .collect()
+    }
+
+    fn matches(&self, filter: CaptureFilter, source: SocketAddr) -> bool {
+        match filter {
+            CaptureFilter::All => true,
+            CaptureFilter::Host(ip) => source.ip() == ip,
+            CaptureFilter::Source(addr) => source == addr,
+            CaptureFilter::Stream(stream_id) => {
+                let streams = self
+                    .inner
+                    .streams
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                // Its RTCP comes from the same port or the next one up.
+                streams.get(&stream_id).is_some_and(|latched| {
+                    latched.ip() == source.ip()
+                        && (latched.port() == source.port()
+                            || latched.port().checked_add(1) == Some(source.port()))
+                })
+            }
+        }
+    }
+
+    fn stream_known(&self, stream_id: StreamId) -> bool {
+        self.inner
+            .streams
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(&stream_id)
+    }
+
+    /// Keeps the stream directory current, and ends stream captures with
+    /// their stream.
+    async fn track_streams(self, mut events: broadcast::Receiver<StreamEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Capture missed {} stream event(s)", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let mut streams = self
+                .inner
+                .streams
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            match event {
+                StreamEvent::Created { metadata } => {
+                    streams.insert(metadata.id, metadata.source_addr);
+                }
+                StreamEvent::SourceChanged {
+                    stream_id,
+                    source_addr,
+                    ..
+                } => {
+                    streams.insert(stream_id, source_addr);
+                }
+                StreamEvent::Ended { metadata, .. } => {
+                    streams.remove(&metadata.id);
+                    drop(streams);
+                    for id in self.captures_of(metadata.id) {
+                        self.stop(&id);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn captures_of(&self, stream_id: StreamId) -> Vec<String> {
+        self.inner
+            .captures
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, capture)| capture.filter == CaptureFilter::Stream(stream_id))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+impl ActiveCapture {
+    fn info(&self, id: &str) -> CaptureInfo {
+        CaptureInfo {
+            id: id.to_string(),
+            filter: self.filter,
+            started_at: self.started_at,
+            ends_at: self.ends_at,
+            packets: self.stats.packets.load(Ordering::Relaxed),
+            bytes: self.stats.bytes.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            files: self
+                .stats
+                .files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// One capture's files, written on a blocking thread of their own.
+struct PcapWriter {
+    config: CaptureConfig,
+    id: String,
+    stats: Arc<CaptureStats>,
+    file: Option<PcapFile>,
+    sequence: u32,
+}
+
+struct PcapFile {
+    writer: BufWriter<File>,
+    bytes: u64,
+    opened_at: Instant,
+}
+
+impl PcapWriter {
+    fn run(mut self, mut records: mpsc::Receiver<Record>) {
+        while let Some(record) = records.blocking_recv() {
+            // Flushing whenever the queue runs dry keeps the current file
+            // readable while the capture runs.
+            let mut result = self.write(&record);
+            if result.is_ok() && records.is_empty() {
+                result = self.flush();
+            }
+            if let Err(e) = result {
+                error!("Capture {} failed to write: {}", self.id, e);
+                // Whatever is still queued or arrives is counted as lost.
+                records.close();
+                let lost = 1 + std::iter::from_fn(|| records.blocking_recv()).count();
+                self.stats.dropped.fetch_add(lost as u64, Ordering::Relaxed);
+                break;
+            }
+        }
+        if let Err(e) = self.flush() {
+            error!("Capture {} failed to flush: {}", self.id, e);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let Some(packet) = udp_packet(record) else {
+            return Ok(());
+        };
+        let rotate = self.file.as_ref().is_none_or(|file| {
+            file.bytes >= self.config.file_bytes
+                || file.opened_at.elapsed() >= self.config.file_duration
+        });
+        if rotate {
+            self.rotate()?;
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+
+        let since_epoch = record.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = u32::try_from(since_epoch.as_secs()).unwrap_or(u32::MAX);
+        let length = u32::try_from(packet.len()).unwrap_or(u32::MAX);
+        file.writer.write_all(&seconds.to_le_bytes())?;
+        file.writer
+            .write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        file.writer.write_all(&length.to_le_bytes())?;
+        file.writer.write_all(&length.to_le_bytes())?;
+        file.writer.write_all(&packet)?;
+
+        file.bytes += 16 + packet.len() as u64;
+        self.stats.packets.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes
+            .fetch_add(record.data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Closes the current file and opens the next, deleting the oldest one
+    /// past the file limit.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.writer.flush()?;
+        }
+        let path = self
+            .config
+            .dir
+            .join(format!("{}-{:04}.pcap", self.id, self.sequence));
+        self.sequence += 1;
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(&PCAP_MAGIC_NANOS.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        info!("Capture {} writing {}", self.id, path.display());
+
+        let mut files = self
+            .stats
+            .files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        files.push_back(path);
+        while files.len() > self.config.max_files.max(1) {
+            if let Some(oldest) = files.pop_front()
+                && let Err(e) = fs::remove_file(&oldest)
+            {
+                warn!("Failed to remove {}: {}", oldest.display(), e);
+            }
+        }
+        self.file = Some(PcapFile {
+            writer,
+            bytes: 24,
+            opened_at: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+/// The datagram wrapped in the IP and UDP headers it arrived with, as far
+/// as the socket tells us. None if it could not have fit in one.
+fn udp_packet(record: &Record) -> Option<Vec<u8>> {
+    let udp_len = u16::try_from(UDP_HEADER_LEN + record.data.len()).ok()?;
+    let mut udp = Vec::with_capacity(usize::from(udp_len));
+    udp.extend_from_slice(&record.source.port().to_be_bytes());
+    udp.extend_from_slice(&record.destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(&record.data);
+
+    let mut packet = match (record.source.ip(), record.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total_len = u16::try_from(IPV4_HEADER_LEN + udp.len()).ok()?;
+            let mut header = Vec::with_capacity(IPV4_HEADER_LEN + udp.len());
+            header.extend_from_slice(&[0x45, 0]);
+            header.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, don't fragment, TTL 64.
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTOCOL_UDP, 0, 0]);
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+            let checksum = internet_checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let pseudo = [
+                &source.octets()[..],
+                &destination.octets(),
+                &[0, PROTOCOL_UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            let checksum = internet_checksum(&[&pseudo, &udp]);
+            udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (source, destination) => {
+            let source = to_ipv6(source);
+            let destination = to_ipv6(destination);
+            let mut header = Vec::with_capacity(IPV6_HEADER_LEN + udp.len());
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&[PROTOCOL_UDP, 64]);
+            header.extend_from_slice(&source);
+            header.extend_from_slice(&destination);
+
+            let pseudo = [
+                &source[..],
+                &destination,
+                &u32::from(udp_len).to_be_bytes(),
+                &[0, 0, 0, PROTOCOL_UDP],
+            ]
+            .concat();
+            let checksum = internet_checksum(&[&pseudo, &udp]);
+            udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+    };
+    packet.extend_from_slice(&udp);
+    Some(packet)
+}
+
+/// A dual-stack socket can hear IPv4 senders as mapped IPv6 addresses.
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// RFC 1071 ones' complement sum over `parts`, each of even length but
+/// the last.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let (words, remainder) = part.as_chunks::<2>();
+        for &word in words {
+            sum += u32::from(u16::from_be_bytes(word));
+        }
+        if let [last] = remainder {
+            sum += u32::from(*last) << 8;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    // Zero means "no checksum" for UDP, so all ones stands in for it.
+    #[allow(clippy::cast_possible_truncation)]
+    match !(sum as u16) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// A fresh directory under the system temporary one, removed on drop.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("navitel-capture-{}", Uuid::new_v4().simple())))
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn config(dir: &ScratchDir) -> CaptureConfig {
+        CaptureConfig {
+            dir: dir.0.clone(),
+            file_bytes: 1024 * 1024,
+            file_duration: Duration::from_secs(3600),
+            max_files: 2,
+            max_duration: Duration::from_secs(3600),
+        }
+    }
+
+    fn writer(config: CaptureConfig) -> PcapWriter {
+        fs::create_dir_all(&config.dir).unwrap();
+        PcapWriter {
+            config,
+            id: "test".to_string(),
+            stats: Arc::default(),
+            file: None,
+            sequence: 0,
+        }
+    }
+
+    /// A 100-byte datagram, 128 bytes once wrapped in IPv4 and UDP.
+    fn record() -> Record {
+        Record {
+            time: UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+            source: "192.0.2.10:5004".parse().unwrap(),
+            destination: "198.51.100.1:10000".parse().unwrap(),
+            data: vec![0x80; 100],
+        }
+    }
+
+    fn files(writer: &PcapWriter) -> Vec<String> {
+        writer
+            .stats
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn capture(config: CaptureConfig) -> PacketCapture {
+        PacketCapture::new(config, broadcast::channel(1).1)
+    }
+
+    #[test]
+    fn writes_pcap_records_with_arrival_times() {
+        let dir = ScratchDir::new();
+        let mut writer = writer(config(&dir));
+        writer.write(&record()).unwrap();
+        writer.flush().unwrap();
+
+        let written = fs::read(dir.0.join("test-0000.pcap")).unwrap();
+        assert_eq!(written.len(), 24 + 16 + 128);
+        assert_eq!(written[..4], PCAP_MAGIC_NANOS.to_le_bytes());
+        assert_eq!(written[20..24], LINKTYPE_RAW.to_le_bytes());
+        assert_eq!(written[24..28], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(written[28..32], 5u32.to_le_bytes());
+        assert_eq!(written[32..36], 128u32.to_le_bytes());
+        // The UDP header carries the sender's and our ports.
+        assert_eq!(written[60..64], [0x13, 0x8c, 0x27, 0x10]);
+        assert_eq!(writer.stats.packets.load(Ordering::Relaxed), 1);
+        assert_eq!(writer.stats.bytes.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn rotates_files_by_size_and_keeps_the_newest() {
+        let dir = ScratchDir::new();
+        let mut writer = writer(CaptureConfig {
+            // Room for exactly one record.
+            file_bytes: 24 + 16 + 128,
+            ..config(&dir)
+        });
+        for _ in 0..3 {
+            writer.write(&record()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(files(&writer), ["test-0001.pcap", "test-0002.pcap"]);
+        assert!(!dir.0.join("test-0000.pcap").exists());
+        let newest = fs::metadata(dir.0.join("test-0002.pcap")).unwrap();
+        assert_eq!(newest.len(), 24 + 16 + 128);
+        assert_eq!(writer.stats.packets.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn rotates_files_by_age() {
+        let dir = ScratchDir::new();
+        let mut writer = writer(config(&dir));
+        writer.write(&record()).unwrap();
+        writer.write(&record()).unwrap();
+        assert_eq!(files(&writer), ["test-0000.pcap"]);
+
+        writer.config.file_duration = Duration::ZERO;
+        writer.write(&record()).unwrap();
+        assert_eq!(files(&writer), ["test-0000.pcap", "test-0001.pcap"]);
+    }
+
+    #[tokio::test]
+    async fn caps_how_long_a_capture_runs() {
+        let dir = ScratchDir::new();
+        let capture = capture(config(&dir));
+        for (asked, secs) in [(None, 3600), (Some(7200), 3600), (Some(10), 10)] {
+            let info = capture
+                .start(CaptureFilter::All, asked.map(Duration::from_secs))
+                .unwrap();
+            assert_eq!((info.ends_at - info.started_at).num_seconds(), secs);
+            assert!(capture.stop(&info.id).is_some());
+        }
+        assert!(!capture.is_armed());
+    }
+
+    #[tokio::test]
+    async fn stops_captures_when_their_time_is_up() {
+        let dir = ScratchDir::new();
+        let capture = capture(CaptureConfig {
+            max_duration: Duration::from_millis(20),
+            ..config(&dir)
+        });
+        let info = capture.start(CaptureFilter::All, None).unwrap();
+        assert!(capture.is_armed());
+        assert_eq!(capture.list().len(), 1);
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(capture.list().len(), 0);
+        assert!(!capture.is_armed());
+        assert!(capture.stop(&info.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn matches_datagrams_by_filter() {
+        let dir = ScratchDir::new();
+        let capture = capture(config(&dir));
+        let sender: SocketAddr = "192.0.2.10:5004".parse().unwrap();
+        let rtcp: SocketAddr = "192.0.2.10:5005".parse().unwrap();
+        let neighbour: SocketAddr = "192.0.2.10:6000".parse().unwrap();
+        let stranger: SocketAddr = "192.0.2.99:5004".parse().unwrap();
+
+        assert!(capture.matches(CaptureFilter::All, stranger));
+
+        let host = CaptureFilter::Host(sender.ip());
+        assert!(capture.matches(host, neighbour));
+        assert!(!capture.matches(host, stranger));
+
+        let source = CaptureFilter::Source(sender);
+        assert!(capture.matches(source, sender));
+        assert!(!capture.matches(source, rtcp));
+
+        let (stream_id, top) = (Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut streams = capture.inner.streams.write().unwrap();
+            streams.insert(stream_id, sender);
+            streams.insert(top, "192.0.2.10:65535".parse().unwrap());
+        }
+        let stream = CaptureFilter::Stream(stream_id);
+        assert!(capture.matches(stream, sender));
+        assert!(capture.matches(stream, rtcp));
+        assert!(!capture.matches(stream, neighbour));
+        assert!(!capture.matches(stream, "192.0.2.99:5005".parse().unwrap()));
+        // Nothing sits above the top port for its RTCP to come from.
+        assert!(!capture.matches(CaptureFilter::Stream(top), "192.0.2.10:0".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_capture_an_unknown_stream() {
+        let dir = ScratchDir::new();
+        let capture = capture(config(&dir));
+        let stream_id = Uuid::new_v4();
+        assert!(matches!(
+            capture.start(CaptureFilter::Stream(stream_id), None),
+            Err(CaptureError::UnknownStream(id)) if id == stream_id
+        ));
+        assert!(!capture.is_armed());
+    }
+}
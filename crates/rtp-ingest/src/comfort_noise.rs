@@ -0,0 +1,523 @@
+use bytes::Bytes;
+use rand::Rng;
+use tracing::debug;
+
+use shared_types::{
+    AudioChunk, AudioCodec, AudioFormat, ChunkKind, HeaderExtensions, LatencyMetadata, StreamId,
+};
+
+const COMFORT_NOISE_STAGE: &str = "comfort_noise";
+/// Timestamp jumps longer than this are not treated as DTX silence.
+const MAX_SILENCE_SECS: u32 = 10;
+/// RFC 3389 levels run to 127 -dBov; use the quietest when no CN was sent.
+const SILENT_NOISE_LEVEL: u8 = 127;
+
+/// Noise level in -dBov from an RFC 3389 section 3 comfort noise payload.
+pub fn noise_level(payload: &[u8]) -> Option<u8> {
+    payload.first().map(|level| level & 0x7f)
+}
+
+/// Tells DTX silence apart from loss as packets arrive. The sender stops
+/// sending during silence, optionally sending CN packets, and resumes with
+/// a talkspurt whose first packet has the marker bit set. Sequence numbers
+/// carry on unbroken, so the jump in timestamp is silence rather than loss.
+#[derive(Debug, Default)]
+pub struct DtxTracker {
+    last: Option<LastPacket>,
+    /// Timestamp units per audio packet, the smallest step seen between
+    /// consecutive packets.
+    frame_samples: Option<u32>,
+    in_silence: bool,
+    pub comfort_noise_packets: u64,
+    /// Silent periods, each ended by a talkspurt.
+    pub periods: u64,
+    /// Total silence in timestamp units.
+    pub silence_samples: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LastPacket {
+    seq: u32,
+    timestamp: u32,
+    comfort_noise: bool,
+}
+
+impl DtxTracker {
+    /// Records a packet by extended sequence number.
+    pub fn on_packet(
+        &mut self,
+        seq: u32,
+        timestamp: u32,
+        marker: bool,
+        comfort_noise: bool,
+        clock_rate: u32,
+    ) {
+        let silent_before = self.in_silence;
+        if comfort_noise {
+            self.comfort_noise_packets += 1;
+            self.in_silence = true;
+        }
+
+        if let Some(last) = self.last
+            && seq == last.seq.wrapping_add(1)
+        {
+            let step = timestamp.wrapping_sub(last.timestamp);
+            let frame = self.frame_samples.unwrap_or(0);
+            if step > 0 && step <= clock_rate.saturating_mul(MAX_SILENCE_SECS) {
+                if last.comfort_noise {
+                    self.silence_samples += u64::from(step);
+                } else if comfort_noise {
+                    self.silence_samples += u64::from(step.saturating_sub(frame));
+                } else if marker && frame > 0 && step > frame {
+                    // Silence with no CN packets at all.
+                    self.silence_samples += u64::from(step - frame);
+                    self.in_silence = true;
+                } else if !marker {
+                    self.frame_samples = Some(self.frame_samples.map_or(step, |f| f.min(step)));
+                }
+            }
+        }
+
+        if self.in_silence && !silent_before {
+            self.periods += 1;
+        }
+        if !comfort_noise {
+            self.in_silence = false;
+        }
+        if self.last.is_none_or(|last| seq > last.seq) {
+            self.last = Some(LastPacket {
+                seq,
+                timestamp,
+                comfort_noise,
+            });
+        }
+    }
+
+    pub fn silence_ms(&self, clock_rate: u32) -> u64 {
+        self.silence_samples * 1000 / u64::from(clock_rate.max(1))
+    }
+}
+
+/// Sits after the jitter buffer and fills DTX silence in the released audio,
+/// either with a [`ChunkKind::Silence`] marker or, when enabled and the codec
+/// is one we can encode, with generated comfort noise frames.
+#[derive(Debug)]
+pub struct NoiseFiller {
+    synthesize: bool,
+    last: Option<LastPacket>,
+    frame_samples: Option<u32>,
+    noise_level: u8,
+}
+
+impl NoiseFiller {
+    pub fn new(synthesize: bool) -> Self {
+        Self {
+            synthesize,
+            last: None,
+            frame_samples: None,
+            noise_level: SILENT_NOISE_LEVEL,
+        }
+    }
+
+    /// Returns what to forward before `next` to cover any silence since the
+    /// previous chunk.
+    pub fn fill(&mut self, stream_id: StreamId, next: &AudioChunk) -> Vec<AudioChunk> {
+        let comfort_noise = match next.kind {
+            ChunkKind::Audio | ChunkKind::GeneratedNoise => None,
+            ChunkKind::ComfortNoise { noise_level } => Some(noise_level),
+            ChunkKind::Gap { .. } | ChunkKind::Discontinuity | ChunkKind::Silence { .. } => {
+                self.last = None;
+                return Vec::new();
+            }
+        };
+
+        let previous = self.last.replace(LastPacket {
+            seq: next.sequence_number,
+            timestamp: next.timestamp,
+            comfort_noise: comfort_noise.is_some(),
+        });
+        // Silence after a CN packet sounds like it; silence straight after
+        // speech had no CN to describe it.
+        let level = if previous.is_some_and(|previous| previous.comfort_noise) {
+            self.noise_level
+        } else {
+            SILENT_NOISE_LEVEL
+        };
+        if let Some(noise_level) = comfort_noise {
+            self.noise_level = noise_level;
+        }
+
+        let Some(previous) = previous else {
+            return Vec::new();
+        };
+        let step = next.timestamp.wrapping_sub(previous.timestamp);
+        if next.sequence_number != previous.seq.wrapping_add(1)
+            || step == 0
+            || step
+                > next
+                    .format
+                    .rtp_clock_rate()
+                    .saturating_mul(MAX_SILENCE_SECS)
+        {
+            return Vec::new();
+        }
+        if !previous.comfort_noise && comfort_noise.is_none() {
+            if self.frame_samples.is_none_or(|frame| step < frame) {
+                self.frame_samples = Some(step);
+            }
+            if self.frame_samples == Some(step) {
+                return Vec::new();
+            }
+        }
+        let Some(frame) = self.frame_samples else {
+            return Vec::new();
+        };
+
+        // Audio and CN packets span a frame; a CN packet only marks when
+        // silence started.
+        let start = if previous.comfort_noise {
+            previous.timestamp
+        } else {
+            previous.timestamp.wrapping_add(frame)
+        };
+        let silence = next.timestamp.wrapping_sub(start);
+        if silence == 0 || silence > step {
+            return Vec::new();
+        }
+        self.silence(stream_id, previous.seq, start, silence, level, next.format)
+    }
+
+    fn silence(
+        &self,
+        stream_id: StreamId,
+        seq: u32,
+        start: u32,
+        samples: u32,
+        level: u8,
+        format: AudioFormat,
+    ) -> Vec<AudioChunk> {
+        let chunk = |data: Bytes, timestamp: u32, kind: ChunkKind| {
+            let mut metadata = LatencyMetadata::new(stream_id);
+            metadata.start_stage(COMFORT_NOISE_STAGE, "rtp-ingest");
+            metadata.end_stage();
+            AudioChunk {
+                data,
+                format,
+                // Filler sits between two packets and takes no sequence
+                // number of its own.
+                sequence_number: seq,
+                timestamp,
+                kind,
+                capture_time: None,
+                extensions: HeaderExtensions::default(),
+                metadata,
+            }
+        };
+
+        let frame = self.frame_samples.unwrap_or(samples).max(1);
+        if !self.synthesize || !can_synthesize(format) {
+            debug!(
+                "DTX silence of {} samples at {} (stream: {})",
+                samples, start, stream_id
+            );
+            return vec![chunk(Bytes::new(), start, ChunkKind::Silence { samples })];
+        }
+
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset < samples {
+            let length = frame.min(samples - offset);
+            let data = synthesize(format, length, level);
+            frames.push(chunk(
+                data,
+                start.wrapping_add(offset),
+                ChunkKind::GeneratedNoise,
+            ));
+            offset += length;
+        }
+        debug!(
+            "Filled {} samples of DTX silence with {} noise frame(s) at -{} dBov (stream: {})",
+            samples,
+            frames.len(),
+            level,
+            stream_id
+        );
+        frames
+    }
+}
+
+const fn can_synthesize(format: AudioFormat) -> bool {
+    matches!(
+        format.codec,
+        AudioCodec::G711Ulaw | AudioCodec::G711Alaw | AudioCodec::Pcm
+    )
+}
+
+/// White noise at `level` -dBov, encoded in the stream's own codec.
+#[allow(clippy::cast_possible_truncation)]
+fn synthesize(format: AudioFormat, samples: u32, level: u8) -> Bytes {
+    let rms = f64::from(i16::MAX) * 10f64.powf(-f64::from(level) / 20.0);
+    // Uniform noise on [-a, a] has an RMS of a / sqrt(3).
+    let amplitude = (rms * 3f64.sqrt()).min(f64::from(i16::MAX));
+    let count = samples as usize * usize::from(format.channels.max(1));
+
+    let mut rng = rand::thread_rng();
+    let mut next_sample = || (rng.gen_range(-1.0..=1.0) * amplitude) as i16;
+    let data: Vec<u8> = match format.codec {
+        AudioCodec::G711Ulaw => (0..count).map(|_| linear_to_ulaw(next_sample())).collect(),
+        AudioCodec::G711Alaw => (0..count).map(|_| linear_to_alaw(next_sample())).collect(),
+        _ => (0..count)
+            .flat_map(|_| next_sample().to_be_bytes())
+            .collect(),
+    };
+    Bytes::from(data)
+}
+
+/// G.711 µ-law encoding of a 16-bit sample, rounding as the Sun reference
+/// `linear2ulaw` does.
+#[allow(clippy::cast_possible_truncation)]
+fn linear_to_ulaw(sample: i16) -> u8 {
+    const BIAS: u32 = 0x21;
+    const MAX: u32 = 0x1fff;
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = ((i32::from(sample) >> 2).unsigned_abs() + BIAS).min(MAX);
+    let exponent = magnitude.ilog2() - 5;
+    let mantissa = (magnitude >> (exponent + 1)) & 0x0f;
+    !((sign | (exponent << 4) | mantissa) as u8)
+}
+
+/// G.711 A-law encoding of a 16-bit sample, rounding as the Sun reference
+/// `linear2alaw` does.
+#[allow(clippy::cast_possible_truncation)]
+fn linear_to_alaw(sample: i16) -> u8 {
+    let sign = if sample >= 0 { 0x80 } else { 0 };
+    let shifted = i32::from(sample) >> 3;
+    // Negative samples are one below their magnitude, so -8 joins 0.
+    let magnitude = (if shifted < 0 { !shifted } else { shifted }).unsigned_abs();
+    let magnitude = magnitude.min(0x0fff);
+    let (exponent, mantissa) = if magnitude < 32 {
+        (0, magnitude >> 1)
+    } else {
+        let exponent = magnitude.ilog2() - 4;
+        (exponent, (magnitude >> exponent) & 0x0f)
+    };
+    ((sign | (exponent << 4) | mantissa) as u8) ^ 0x55
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn chunk(seq: u32, timestamp: u32, kind: ChunkKind) -> AudioChunk {
+        AudioChunk {
+            data: Bytes::from_static(&[0xff; 160]),
+            format: AudioFormat::g711_ulaw_mono(),
+            sequence_number: seq,
+            timestamp,
+            kind,
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata: LatencyMetadata::new(Uuid::nil()),
+        }
+    }
+
+    /// Timestamps and kinds of what the filler adds before each chunk.
+    fn filled(filler: &mut NoiseFiller, chunks: &[AudioChunk]) -> Vec<(u32, ChunkKind)> {
+        chunks
+            .iter()
+            .flat_map(|chunk| filler.fill(Uuid::nil(), chunk))
+            .map(|chunk| (chunk.timestamp, chunk.kind))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_noise_level() {
+        assert_eq!(noise_level(&[0x28, 0x01, 0x02]), Some(40));
+        assert_eq!(noise_level(&[0xff]), Some(127));
+        assert_eq!(noise_level(&[]), None);
+    }
+
+    #[test]
+    fn counts_silence_covered_by_comfort_noise() {
+        let mut dtx = DtxTracker::default();
+        // (seq, timestamp, marker, comfort noise)
+        for (seq, timestamp, marker, comfort_noise) in [
+            (0, 0, true, false),
+            (1, 160, false, false),
+            (2, 320, false, false),
+            (3, 480, false, true),
+            (4, 2080, false, true),
+            (5, 4000, true, false),
+            (6, 4160, false, false),
+        ] {
+            dtx.on_packet(seq, timestamp, marker, comfort_noise, 8000);
+        }
+        assert_eq!(dtx.comfort_noise_packets, 2);
+        assert_eq!(dtx.periods, 1);
+        assert_eq!(dtx.silence_samples, 3520);
+        assert_eq!(dtx.silence_ms(8000), 440);
+    }
+
+    #[test]
+    fn counts_silence_a_talkspurt_ends_without_comfort_noise() {
+        let mut dtx = DtxTracker::default();
+        for (seq, timestamp, marker) in [(0, 0, true), (1, 160, false), (2, 1760, true)] {
+            dtx.on_packet(seq, timestamp, marker, false, 8000);
+        }
+        assert_eq!(dtx.periods, 1);
+        assert_eq!(dtx.silence_samples, 1440);
+    }
+
+    #[test]
+    fn does_not_mistake_loss_for_silence() {
+        let mut dtx = DtxTracker::default();
+        for (seq, timestamp, marker) in [
+            (0, 0, false),
+            (1, 160, false),
+            // Lost packets, with and without a talkspurt behind them.
+            (3, 480, false),
+            (6, 1600, true),
+            // A jump without a marker.
+            (7, 3200, false),
+            // Too long to be silence.
+            (8, 3200 + 8000 * 11, true),
+        ] {
+            dtx.on_packet(seq, timestamp, marker, false, 8000);
+        }
+        assert_eq!(dtx.periods, 0);
+        assert_eq!(dtx.silence_samples, 0);
+    }
+
+    #[test]
+    fn marks_silence_between_talkspurts() {
+        let mut filler = NoiseFiller::new(false);
+        let added = filled(
+            &mut filler,
+            &[
+                chunk(0, 0, ChunkKind::Audio),
+                chunk(1, 160, ChunkKind::Audio),
+                chunk(2, 1760, ChunkKind::Audio),
+                chunk(3, 1920, ChunkKind::Audio),
+            ],
+        );
+        assert_eq!(added, [(320, ChunkKind::Silence { samples: 1440 })]);
+    }
+
+    #[test]
+    fn fills_silence_after_comfort_noise_with_noise_frames() {
+        let mut filler = NoiseFiller::new(true);
+        let added = filled(
+            &mut filler,
+            &[
+                chunk(0, 0, ChunkKind::Audio),
+                chunk(1, 160, ChunkKind::Audio),
+                chunk(2, 320, ChunkKind::ComfortNoise { noise_level: 40 }),
+                chunk(3, 720, ChunkKind::Audio),
+            ],
+        );
+        assert_eq!(
+            added,
+            [
+                (320, ChunkKind::GeneratedNoise),
+                (480, ChunkKind::GeneratedNoise),
+                (640, ChunkKind::GeneratedNoise),
+            ]
+        );
+    }
+
+    #[test]
+    fn generates_frames_in_the_stream_codec() {
+        let mut filler = NoiseFiller::new(true);
+        let frames: Vec<_> = [
+            chunk(0, 0, ChunkKind::Audio),
+            chunk(1, 160, ChunkKind::Audio),
+            chunk(2, 1000, ChunkKind::Audio),
+        ]
+        .iter()
+        .flat_map(|chunk| filler.fill(Uuid::nil(), chunk))
+        .collect();
+        let lengths: Vec<_> = frames.iter().map(|frame| frame.data.len()).collect();
+        assert_eq!(lengths, [160, 160, 160, 160, 40]);
+        // With no CN to give a level the noise is at -127 dBov, which
+        // encodes as µ-law zero.
+        assert!(
+            frames
+                .iter()
+                .all(|frame| frame.data.iter().all(|&b| b == 0xff))
+        );
+        assert!(frames.iter().all(|frame| frame.sequence_number == 1));
+    }
+
+    #[test]
+    fn marks_silence_it_cannot_encode() {
+        let mut filler = NoiseFiller::new(true);
+        let opus = |seq, timestamp| AudioChunk {
+            format: AudioFormat::opus_mono_48khz(),
+            ..chunk(seq, timestamp, ChunkKind::Audio)
+        };
+        let added = filled(&mut filler, &[opus(0, 0), opus(1, 960), opus(2, 9600)]);
+        assert_eq!(added, [(1920, ChunkKind::Silence { samples: 7680 })]);
+    }
+
+    #[test]
+    fn leaves_loss_and_discontinuities_alone() {
+        let mut filler = NoiseFiller::new(false);
+        let added = filled(
+            &mut filler,
+            &[
+                chunk(0, 0, ChunkKind::Audio),
+                chunk(1, 160, ChunkKind::Audio),
+                chunk(3, 1760, ChunkKind::Audio),
+                chunk(4, 1920, ChunkKind::Discontinuity),
+                chunk(5, 4000, ChunkKind::Audio),
+                chunk(6, 4160, ChunkKind::Audio),
+            ],
+        );
+        assert_eq!(added, []);
+    }
+
+    #[test]
+    fn encodes_mu_law_like_the_reference() {
+        // Values from the Sun g711.c `linear2ulaw` reference.
+        for (sample, expected) in [
+            (0, 0xff),
+            (1, 0xff),
+            (4, 0xfe),
+            (-1, 0x7e),
+            (-5, 0x7e),
+            (100, 0xf2),
+            (-100, 0x72),
+            (1000, 0xce),
+            (-1000, 0x4e),
+            (8031, 0xa0),
+            (-8032, 0x20),
+            (-31611, 0x00),
+            (32767, 0x80),
+            (-32768, 0x00),
+        ] {
+            assert_eq!(linear_to_ulaw(sample), expected, "{sample}");
+        }
+    }
+
+    #[test]
+    fn encodes_a_law_like_the_reference() {
+        // Values from the Sun g711.c `linear2alaw` reference.
+        for (sample, expected) in [
+            (0, 0xd5),
+            (-1, 0x55),
+            (-8, 0x55),
+            (-16, 0x55),
+            (100, 0xd3),
+            (-100, 0x53),
+            (1000, 0xfa),
+            (-1000, 0x7a),
+            (-8032, 0x0a),
+            (-31744, 0x2b),
+            (32767, 0xaa),
+            (-32768, 0x2a),
+        ] {
+            assert_eq!(linear_to_alaw(sample), expected, "{sample}");
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, Ssl, SslContext, SslMethod, SslOptions,
+    SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{X509, X509NameBuilder};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::srtp::{SrtpError, SrtpKeys, SrtpProfile};
+
+/// Protection profiles offered in the `use_srtp` extension, preferred first.
+const SRTP_PROFILES: &str = "SRTP_AEAD_AES_128_GCM:SRTP_AES128_CM_SHA1_80:SRTP_AES128_CM_SHA1_32";
+/// RFC 5764 section 4.2.
+const SRTP_EXPORTER_LABEL: &str = "EXTRACTOR-dtls_srtp";
+/// Keeps handshake flights inside a datagram on any path worth using.
+const DTLS_MTU: u32 = 1200;
+const CERTIFICATE_DAYS: u32 = 30;
+
+#[derive(Debug, Error)]
+pub enum DtlsError {
+    #[error("OpenSSL: {0}")]
+    OpenSsl(#[from] ErrorStack),
+    #[error("DTLS handshake failed: {0}")]
+    Handshake(String),
+    #[error("peer certificate does not match the fingerprint in its offer")]
+    FingerprintMismatch,
+    #[error("unsupported certificate fingerprint hash `{0}`")]
+    UnsupportedHash(String),
+    #[error("peer negotiated no SRTP protection profile we support")]
+    NoSrtpProfile,
+    #[error(transparent)]
+    Srtp(#[from] SrtpError),
+}
+
+/// Our self-signed certificate and the DTLS context built around it. Peers
+/// authenticate it by the fingerprint in our SDP rather than a CA.
+pub struct DtlsCertificate {
+    context: SslContext,
+    fingerprint: String,
+}
+
+impl DtlsCertificate {
+    /// Generates a fresh ECDSA P-256 certificate.
+    pub fn generate() -> Result<Self, DtlsError> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "navitel")?;
+        let name = name.build();
+        let mut serial = BigNum::new()?;
+        serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&*Asn1Time::days_from_now(CERTIFICATE_DAYS)?)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        let certificate = builder.build();
+
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_min_proto_version(Some(SslVersion::DTLS1_2))?;
+        context.set_certificate(&certificate)?;
+        context.set_private_key(&key)?;
+        context.check_private_key()?;
+        context.set_tlsext_use_srtp(SRTP_PROFILES)?;
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        // WebRTC peers present self-signed certificates too; they are
+        // checked against the offer's fingerprint once the handshake is done.
+        context.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            |_, _| true,
+        );
+
+        Ok(Self {
+            context: context.build(),
+            fingerprint: format!(
+                "sha-256 {}",
+                hex_fingerprint(&certificate.digest(MessageDigest::sha256())?)
+            ),
+        })
+    }
+
+    /// The value of our `a=fingerprint` attribute.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Starts the server side of a handshake with a peer whose certificate
+    /// must match `remote_fingerprint`, as `<hash> <hex>` from its SDP.
+    pub fn accept(&self, remote_fingerprint: &str) -> Result<DtlsServer, DtlsError> {
+        let (hash, expected) = remote_fingerprint
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| DtlsError::UnsupportedHash(remote_fingerprint.to_string()))?;
+        let digest = MessageDigest::from_name(&hash.replace('-', ""))
+            .ok_or_else(|| DtlsError::UnsupportedHash(hash.to_string()))?;
+
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_mtu(DTLS_MTU)?;
+        let state = match ssl.accept(Datagrams::default()) {
+            Err(HandshakeError::WouldBlock(handshake)) => State::Handshaking(handshake),
+            Err(HandshakeError::SetupFailure(e)) => return Err(e.into()),
+            Err(HandshakeError::Failure(handshake)) => {
+                return Err(DtlsError::Handshake(handshake.error().to_string()));
+            }
+            Ok(stream) => State::Established(stream),
+        };
+        Ok(DtlsServer {
+            state,
+            digest,
+            expected: expected.trim().to_ascii_uppercase(),
+        })
+    }
+}
+
+/// What a datagram did to a DTLS session.
+#[derive(Debug)]
+pub enum DtlsEvent {
+    /// The handshake finished and agreed these SRTP keys.
+    Connected(SrtpKeys),
+    /// The peer closed the session.
+    Closed,
+}
+
+enum State {
+    Handshaking(MidHandshakeSslStream<Datagrams>),
+    Established(SslStream<Datagrams>),
+    Closed,
+}
+
+/// The server side of one peer's DTLS-SRTP handshake (RFC 5763), fed the
+/// DTLS datagrams that arrive on the media port. Records to send back are
+/// collected for the caller to put on the socket.
+pub struct DtlsServer {
+    state: State,
+    digest: MessageDigest,
+    /// Fingerprint from the peer's offer, as upper-case hex pairs.
+    expected: String,
+}
+
+impl DtlsServer {
+    pub fn handle(&mut self, datagram: &[u8]) -> Result<Option<DtlsEvent>, DtlsError> {
+        match std::mem::replace(&mut self.state, State::Closed) {
+            State::Handshaking(mut handshake) => {
+                handshake.get_mut().incoming.push_back(datagram.to_vec());
+                match handshake.handshake() {
+                    Ok(stream) => {
+                        // A peer that fails the checks stays closed.
+                        let keys = self.srtp_keys(stream.ssl())?;
+                        self.state = State::Established(stream);
+                        Ok(Some(DtlsEvent::Connected(keys)))
+                    }
+                    Err(HandshakeError::WouldBlock(handshake)) => {
+                        self.state = State::Handshaking(handshake);
+                        Ok(None)
+                    }
+                    Err(HandshakeError::Failure(handshake)) => {
+                        Err(DtlsError::Handshake(handshake.error().to_string()))
+                    }
+                    Err(HandshakeError::SetupFailure(e)) => Err(e.into()),
+                }
+            }
+            State::Established(mut stream) => {
+                stream.get_mut().incoming.push_back(datagram.to_vec());
+                let mut buf = [0u8; 1500];
+                loop {
+                    match stream.ssl_read(&mut buf) {
+                        // Audio-only sessions carry no data channel, so there
+                        // is nothing to do with application data.
+                        Ok(len) => debug!("Ignoring {} bytes of DTLS application data", len),
+                        Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                            return Ok(Some(DtlsEvent::Closed));
+                        }
+                        Err(e) if e.code() == ErrorCode::WANT_READ => break,
+                        Err(e) => {
+                            debug!("DTLS read failed: {}", e);
+                            break;
+                        }
+                    }
+                }
+                self.state = State::Established(stream);
+                Ok(None)
+            }
+            State::Closed => Ok(None),
+        }
+    }
+
+    /// Records waiting to go to the peer.
+    pub fn take_outgoing(&mut self) -> Vec<Vec<u8>> {
+        let datagrams = match &mut self.state {
+            State::Handshaking(handshake) => handshake.get_mut(),
+            State::Established(stream) => stream.get_mut(),
+            State::Closed => return Vec::new(),
+        };
+        std::mem::take(&mut datagrams.outgoing)
+    }
+
+    /// Checks the peer is who its offer said, then derives the SRTP keys.
+    /// We are the DTLS server, so the client's keys protect what we receive.
+    fn srtp_keys(&self, ssl: &openssl::ssl::SslRef) -> Result<SrtpKeys, DtlsError> {
+        let certificate = ssl
+            .peer_certificate()
+            .ok_or(DtlsError::FingerprintMismatch)?;
+        if hex_fingerprint(&certificate.digest(self.digest)?) != self.expected {
+            return Err(DtlsError::FingerprintMismatch);
+        }
+
+        let profile = ssl
+            .selected_srtp_profile()
+            .and_then(|profile| SrtpProfile::from_dtls_profile(profile.name()))
+            .ok_or(DtlsError::NoSrtpProfile)?;
+        let mut material = vec![0u8; profile.dtls_keying_material_len()];
+        ssl.export_keying_material(&mut material, SRTP_EXPORTER_LABEL, None)?;
+        Ok(SrtpKeys::from_dtls(profile, &material, true)?)
+    }
+}
+
+/// Stands in for the socket under OpenSSL: each read takes one whole
+/// datagram, as DTLS expects, and each write is one datagram out.
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether a datagram on a port shared with STUN and RTP is DTLS
+/// (RFC 7983 section 7): a first byte of 20 to 63.
+pub fn is_dtls(data: &[u8]) -> bool {
+    matches!(data.first(), Some(20..=63))
+}
+
+fn hex_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
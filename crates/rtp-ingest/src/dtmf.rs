@@ -0,0 +1,276 @@
+use std::time::Duration;
+use tracing::debug;
+
+use shared_types::DtmfEvent;
+
+/// One RFC 4733 section 2.3 telephone-event payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelephoneEvent {
+    pub event: u8,
+    pub end: bool,
+    /// Power level in -dBm0.
+    pub volume: u8,
+    /// Duration so far, in RTP timestamp units.
+    pub duration: u16,
+}
+
+impl TelephoneEvent {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let &[event, flags, duration_hi, duration_lo, ..] = payload else {
+            return None;
+        };
+        Some(Self {
+            event,
+            end: flags & 0x80 != 0,
+            volume: flags & 0x3f,
+            duration: u16::from_be_bytes([duration_hi, duration_lo]),
+        })
+    }
+}
+
+/// An event still being held down.
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    /// When the key was pressed.
+    rtp_timestamp: u32,
+    /// Timestamp of the packets now arriving. A press too long for the
+    /// 16-bit duration goes on in segments, each starting where the last
+    /// one's maximum duration ends (RFC 4733 section 2.5.1.3).
+    segment: u32,
+    event: u8,
+    /// Duration so far within the current segment.
+    duration: u16,
+}
+
+impl Pending {
+    fn duration(&self) -> u64 {
+        u64::from(self.segment.wrapping_sub(self.rtp_timestamp)) + u64::from(self.duration)
+    }
+}
+
+/// Turns a source's telephone-event packets into one [`DtmfEvent`] per key
+/// press. Every packet of an event shares its RTP timestamp, and the end
+/// packet is sent three times (RFC 4733 section 2.5.1.4), so events are
+/// keyed on that timestamp and reported once.
+#[derive(Debug, Default)]
+pub struct DtmfDetector {
+    pending: Option<Pending>,
+    /// Timestamp of the last event reported, to drop its redundant end packets.
+    last_reported: Option<u32>,
+}
+
+impl DtmfDetector {
+    /// Feeds one packet, returning the key presses it completes. A new event
+    /// arriving before the previous one ended means its end packets were all
+    /// lost, so the previous one is reported with the duration seen so far.
+    pub fn push(
+        &mut self,
+        rtp_timestamp: u32,
+        packet: TelephoneEvent,
+        clock_rate: u32,
+    ) -> Vec<DtmfEvent> {
+        if self.last_reported == Some(rtp_timestamp) {
+            return Vec::new();
+        }
+
+        let mut completed = Vec::new();
+        let current = match self.pending {
+            Some(pending) if pending.segment == rtp_timestamp => Pending {
+                duration: pending.duration.max(packet.duration),
+                ..pending
+            },
+            Some(pending)
+                if pending.event == packet.event
+                    && rtp_timestamp == pending.segment.wrapping_add(u32::from(u16::MAX)) =>
+            {
+                Pending {
+                    segment: rtp_timestamp,
+                    duration: packet.duration,
+                    ..pending
+                }
+            }
+            previous => {
+                if let Some(pending) = previous {
+                    debug!(
+                        "Telephone-event at {} ended without an end packet",
+                        pending.rtp_timestamp
+                    );
+                    completed.extend(self.report(pending, clock_rate));
+                }
+                Pending {
+                    rtp_timestamp,
+                    segment: rtp_timestamp,
+                    event: packet.event,
+                    duration: packet.duration,
+                }
+            }
+        };
+        if packet.end {
+            self.pending = None;
+            completed.extend(self.report(current, clock_rate));
+        } else {
+            self.pending = Some(current);
+        }
+        completed
+    }
+
+    /// Reports an event still held when the stream ends.
+    pub fn flush(&mut self, clock_rate: u32) -> Option<DtmfEvent> {
+        let pending = self.pending.take()?;
+        self.report(pending, clock_rate)
+    }
+
+    fn report(&mut self, pending: Pending, clock_rate: u32) -> Option<DtmfEvent> {
+        self.last_reported = Some(pending.segment);
+        let Some(digit) = DtmfEvent::digit_for_code(pending.event) else {
+            debug!("Ignoring non-DTMF telephone-event {}", pending.event);
+            return None;
+        };
+        Some(DtmfEvent {
+            digit,
+            duration: Duration::from_micros(
+                pending.duration() * 1_000_000 / u64::from(clock_rate.max(1)),
+            ),
+            rtp_timestamp: pending.rtp_timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(event: u8, end: bool, duration: u16) -> TelephoneEvent {
+        TelephoneEvent {
+            event,
+            end,
+            volume: 10,
+            duration,
+        }
+    }
+
+    fn press(digit: char, millis: u64, rtp_timestamp: u32) -> DtmfEvent {
+        DtmfEvent {
+            digit,
+            duration: Duration::from_millis(millis),
+            rtp_timestamp,
+        }
+    }
+
+    /// Feeds packets at their timestamps, collecting every press reported.
+    fn feed(detector: &mut DtmfDetector, packets: &[(u32, TelephoneEvent)]) -> Vec<DtmfEvent> {
+        packets
+            .iter()
+            .flat_map(|&(rtp_timestamp, packet)| detector.push(rtp_timestamp, packet, 8000))
+            .collect()
+    }
+
+    #[test]
+    fn parses_telephone_event_payloads() {
+        assert_eq!(
+            TelephoneEvent::parse(&[11, 0xca, 0x03, 0x20]),
+            Some(TelephoneEvent {
+                event: 11,
+                end: true,
+                volume: 10,
+                duration: 800,
+            })
+        );
+        assert_eq!(TelephoneEvent::parse(&[11, 0x0a, 0x03]), None);
+    }
+
+    #[test]
+    fn reports_a_press_once_despite_retransmitted_ends() {
+        let mut detector = DtmfDetector::default();
+        let presses = feed(
+            &mut detector,
+            &[
+                (1000, packet(5, false, 160)),
+                (1000, packet(5, false, 480)),
+                // Reordered behind a later update.
+                (1000, packet(5, false, 320)),
+                (1000, packet(5, true, 800)),
+                (1000, packet(5, true, 800)),
+                (1000, packet(5, true, 800)),
+            ],
+        );
+        assert_eq!(presses, [press('5', 100, 1000)]);
+        assert_eq!(detector.flush(8000), None);
+    }
+
+    #[test]
+    fn reports_a_press_whose_end_was_lost_when_the_next_starts() {
+        let mut detector = DtmfDetector::default();
+        let presses = feed(
+            &mut detector,
+            &[(1000, packet(1, false, 400)), (3000, packet(1, false, 160))],
+        );
+        assert_eq!(presses, [press('1', 50, 1000)]);
+
+        let presses = feed(&mut detector, &[(3000, packet(1, true, 640))]);
+        assert_eq!(presses, [press('1', 80, 3000)]);
+    }
+
+    #[test]
+    fn joins_the_segments_of_a_long_press() {
+        // Started just before the RTP timestamp wraps.
+        let start = u32::MAX - 100;
+        let second = start.wrapping_add(65535);
+        let third = second.wrapping_add(65535);
+        let mut detector = DtmfDetector::default();
+        let presses = feed(
+            &mut detector,
+            &[
+                (start, packet(11, false, 32000)),
+                (start, packet(11, false, 65535)),
+                (second, packet(11, false, 400)),
+                // The end of the second segment was lost.
+                (third, packet(11, false, 8000)),
+                (third, packet(11, true, 8400)),
+                (third, packet(11, true, 8400)),
+            ],
+        );
+        let held = DtmfEvent {
+            digit: '#',
+            duration: Duration::from_micros(17_433_750),
+            rtp_timestamp: start,
+        };
+        assert_eq!(presses, [held]);
+
+        // A different key where a segment would go is a new press.
+        let next = third.wrapping_add(16000);
+        let presses = feed(
+            &mut detector,
+            &[
+                (next, packet(2, false, 65535)),
+                (next.wrapping_add(65535), packet(3, true, 800)),
+            ],
+        );
+        assert_eq!(presses[0].digit, '2');
+        assert_eq!(presses[0].rtp_timestamp, next);
+        assert_eq!(presses[1..], [press('3', 100, next.wrapping_add(65535))]);
+    }
+
+    #[test]
+    fn reports_a_held_key_when_the_stream_ends() {
+        let mut detector = DtmfDetector::default();
+        assert_eq!(feed(&mut detector, &[(0, packet(12, false, 1600))]), []);
+        assert_eq!(detector.flush(8000), Some(press('A', 200, 0)));
+        assert_eq!(detector.flush(8000), None);
+    }
+
+    #[test]
+    fn ignores_events_that_are_not_keys() {
+        let mut detector = DtmfDetector::default();
+        // Event 16 is a hook flash.
+        let presses = feed(
+            &mut detector,
+            &[
+                (0, packet(16, true, 800)),
+                (0, packet(16, true, 800)),
+                (800, packet(0, true, 800)),
+            ],
+        );
+        assert_eq!(presses, [press('0', 100, 800)]);
+    }
+}
@@ -0,0 +1,178 @@
+use rtp::header::{EXTENSION_PROFILE_ONE_BYTE, EXTENSION_PROFILE_TWO_BYTE, Header};
+use tracing::debug;
+
+use shared_types::{AudioChunk, HeaderExtensionKind, HeaderExtensionMap, OriginStamp};
+
+/// Two-byte headers may carry four application bits in the low nibble of
+/// the profile (RFC 8285 section 4.3).
+const TWO_BYTE_PROFILE_MASK: u16 = 0xFFF0;
+/// Two-byte element IDs of zero are padding.
+const PADDING_ID: u8 = 0;
+
+/// Fills in a chunk's extension values from the header of the packet it
+/// came from, reading only the IDs the stream negotiated. Elements that
+/// are not the size their extension defines are skipped.
+pub fn apply(map: &HeaderExtensionMap, header: &Header, chunk: &mut AudioChunk) {
+    if !header.extension || map.is_empty() {
+        return;
+    }
+    for (id, payload) in elements(header) {
+        let Some(kind) = map.get(id) else {
+            continue;
+        };
+        let valid = match kind {
+            HeaderExtensionKind::Origin => OriginStamp::decode(payload)
+                .map(|origin| chunk.metadata.origin = Some(origin))
+                .is_some(),
+            _ => chunk.extensions.record(kind, payload),
+        };
+        if !valid {
+            debug!(
+                "Ignoring malformed {} extension ({} bytes) at seq {}",
+                kind,
+                payload.len(),
+                header.sequence_number
+            );
+        }
+    }
+}
+
+/// The header's extension elements as ID and payload. The rtp crate splits
+/// up one-byte and plain two-byte headers itself; two-byte headers with
+/// application bits set reach us as a single raw element.
+fn elements(header: &Header) -> Vec<(u8, &[u8])> {
+    match header.extension_profile {
+        EXTENSION_PROFILE_ONE_BYTE | EXTENSION_PROFILE_TWO_BYTE => header
+            .extensions
+            .iter()
+            .map(|extension| (extension.id, extension.payload.as_ref()))
+            .collect(),
+        profile if profile & TWO_BYTE_PROFILE_MASK == EXTENSION_PROFILE_TWO_BYTE => header
+            .extensions
+            .first()
+            .map(|raw| two_byte_elements(&raw.payload))
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+fn two_byte_elements(mut raw: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut elements = Vec::new();
+    while let [id, rest @ ..] = raw {
+        if *id == PADDING_ID {
+            raw = rest;
+            continue;
+        }
+        let Some((&len, rest)) = rest.split_first() else {
+            break;
+        };
+        let Some((payload, rest)) = rest.split_at_checked(usize::from(len)) else {
+            break;
+        };
+        elements.push((*id, payload));
+        raw = rest;
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use shared_types::{AudioFormat, AudioLevel, ChunkKind, HeaderExtensions, LatencyMetadata};
+    use uuid::Uuid;
+    use webrtc_util::marshal::Unmarshal;
+
+    /// An RTP header with the X bit set, followed by `extension`.
+    fn header(extension: &[u8]) -> Header {
+        let mut packet = vec![0x90, 0, 0, 1, 0, 0, 0, 160, 0x11, 0x22, 0x33, 0x44];
+        packet.extend_from_slice(extension);
+        Header::unmarshal(&mut Bytes::from(packet)).unwrap()
+    }
+
+    fn chunk() -> AudioChunk {
+        AudioChunk {
+            data: Bytes::new(),
+            format: AudioFormat::g711_ulaw_mono(),
+            sequence_number: 1,
+            timestamp: 160,
+            kind: ChunkKind::Audio,
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata: LatencyMetadata::new(Uuid::new_v4()),
+        }
+    }
+
+    fn map() -> HeaderExtensionMap {
+        let mut map = HeaderExtensionMap::default();
+        map.insert(1, HeaderExtensionKind::AudioLevel);
+        map.insert(2, HeaderExtensionKind::AbsSendTime);
+        map
+    }
+
+    #[test]
+    fn splits_two_byte_elements() {
+        // RFC 8285 section 4.3: an empty element, padding, then a two byte one.
+        let raw = [1, 0, 0, 2, 2, 0xAB, 0xCD];
+        assert_eq!(
+            two_byte_elements(&raw),
+            [(1, &[][..]), (2, &[0xAB, 0xCD][..])]
+        );
+    }
+
+    #[test]
+    fn stops_at_a_truncated_two_byte_element() {
+        assert_eq!(two_byte_elements(&[3, 5, 1, 2]), []);
+        assert_eq!(two_byte_elements(&[2, 1, 0x85, 3]), [(2, &[0x85][..])]);
+    }
+
+    #[test]
+    fn reads_one_byte_elements_the_stream_negotiated() {
+        let header = header(&[
+            0xBE, 0xDE, 0x00, 0x02, // one-byte profile, two words
+            0x10, 0x85, // ID 1, audio level 5 with voice
+            0x22, 0x01, 0x02, 0x03, // ID 2, abs-send-time
+            0x30, 0x07, // ID 3, not negotiated
+        ]);
+        let mut chunk = chunk();
+        apply(&map(), &header, &mut chunk);
+        assert_eq!(
+            chunk.extensions.audio_level,
+            Some(AudioLevel {
+                level: 5,
+                voice_activity: true
+            })
+        );
+        assert_eq!(chunk.extensions.abs_send_time, Some(0x0001_0203));
+        assert_eq!(chunk.extensions.transport_sequence, None);
+    }
+
+    #[test]
+    fn reads_two_byte_elements_with_application_bits() {
+        let header = header(&[
+            0x10, 0x07, 0x00, 0x01, // two-byte profile with app bits, one word
+            0x01, 0x01, 0x7F, 0x00, // ID 1, audio level 127, padding
+        ]);
+        let mut chunk = chunk();
+        apply(&map(), &header, &mut chunk);
+        assert_eq!(
+            chunk.extensions.audio_level,
+            Some(AudioLevel {
+                level: 127,
+                voice_activity: false
+            })
+        );
+    }
+
+    #[test]
+    fn skips_elements_of_the_wrong_size() {
+        let header = header(&[
+            0xBE, 0xDE, 0x00, 0x01, // one-byte profile, one word
+            0x11, 0x85, 0x00, // ID 1 with two bytes
+            0x00,
+        ]);
+        let mut chunk = chunk();
+        apply(&map(), &header, &mut chunk);
+        assert_eq!(chunk.extensions, HeaderExtensions::default());
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use bytes::Bytes;
+use shared_types::{
+    AudioChunk, AudioFormat, ChunkKind, HeaderExtensions, LatencyMetadata, StreamId,
+};
+
+const JITTER_BUFFER_STAGE: &str = "jitter_buffer";
+
+#[derive(Debug, Clone, Copy)]
+pub struct JitterBufferConfig {
+    /// Lower bound for the playout delay, applied even on a perfect network.
+    pub min_delay: Duration,
+    /// Upper bound for the playout delay, regardless of measured jitter.
+    pub max_delay: Duration,
+    /// How many multiples of the interarrival jitter estimate to hold packets for.
+    pub jitter_multiplier: f64,
+    /// Packets held beyond this are released early to bound memory.
+    pub max_packets: usize,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(200),
+            jitter_multiplier: 3.0,
+            max_packets: 500,
+        }
+    }
+}
+
+struct BufferedPacket {
+    chunk: AudioChunk,
+    arrival: Instant,
+}
+
+/// Per-stream reordering buffer.
+///
+/// Packets are keyed by extended sequence number and each one is held for
+/// the current playout delay after it arrives. Packets are released strictly
+/// in sequence order; when the head of the buffer is missing and a later
+/// packet's hold time has expired, the missing run is declared lost and a
+/// [`ChunkKind::Gap`] marker is emitted in its place. Sequence numbers taken
+/// by packets that carry no audio, such as FEC, are skipped over rather than
+/// reported as gaps.
+pub struct JitterBuffer {
+    config: JitterBufferConfig,
+    stream_id: StreamId,
+    packets: BTreeMap<u32, BufferedPacket>,
+    skipped: BTreeSet<u32>,
+    next_seq: Option<u32>,
+    last_released: Option<(u32, u32)>,
+    playout_delay: Duration,
+    late_packets: u64,
+}
+
+impl JitterBuffer {
+    pub fn new(stream_id: StreamId, config: JitterBufferConfig) -> Self {
+        Self {
+            config,
+            stream_id,
+            packets: BTreeMap::new(),
+            skipped: BTreeSet::new(),
+            next_seq: None,
+            last_released: None,
+            playout_delay: config.min_delay,
+            late_packets: 0,
+        }
+    }
+
+    /// Buffers a chunk whose `sequence_number` is already extended. The
+    /// stream's current interarrival jitter sets the playout delay.
+    pub fn push(&mut self, mut chunk: AudioChunk, arrival: Instant, jitter: Duration) {
+        self.playout_delay = jitter
+            .mul_f64(self.config.jitter_multiplier)
+            .clamp(self.config.min_delay, self.config.max_delay);
+
+        let seq = chunk.sequence_number;
+        if self.next_seq.is_some_and(|next| seq < next) {
+            self.late_packets += 1;
+            debug!(
+                "Dropping late packet: seq={}, stream={}",
+                seq, self.stream_id
+            );
+            return;
+        }
+        chunk
+            .metadata
+            .start_stage(JITTER_BUFFER_STAGE, "rtp-ingest");
+        self.packets.insert(seq, BufferedPacket { chunk, arrival });
+    }
+
+    /// Whether a chunk with this extended sequence number would still be
+    /// played out rather than dropped as late.
+    pub fn accepts(&self, seq: u32) -> bool {
+        self.next_seq.is_none_or(|next| seq >= next)
+    }
+
+    /// Marks a sequence number as used by a packet without audio, so its
+    /// absence from the buffer is not a loss.
+    pub fn skip(&mut self, seq: u32) {
+        if self.accepts(seq) {
+            self.skipped.insert(seq);
+        }
+    }
+
+    /// Releases every chunk whose playout time has passed, in sequence order,
+    /// with gap markers for packets declared lost.
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<AudioChunk> {
+        self.pop_ready_until(Some(now))
+    }
+
+    fn pop_ready_until(&mut self, now: Option<Instant>) -> Vec<AudioChunk> {
+        let mut ready = Vec::new();
+
+        while let Some((&seq, packet)) = self.packets.first_key_value() {
+            let overflowing = self.packets.len() > self.config.max_packets;
+            let due = now.is_none_or(|now| {
+                now.saturating_duration_since(packet.arrival) >= self.playout_delay
+            });
+            if !due && !overflowing {
+                break;
+            }
+
+            if let Some(next) = self.next_seq
+                && seq > next
+            {
+                let skipped = self.skipped.range(next..seq).count();
+                let first_lost = (next..seq).find(|seq| !self.skipped.contains(seq));
+                if let Some(first_lost) = first_lost {
+                    let (timestamp, format) = (packet.chunk.timestamp, packet.chunk.format);
+                    let missing = seq - next - u32::try_from(skipped).unwrap_or(u32::MAX);
+                    ready.push(self.gap_marker(first_lost, missing, seq, timestamp, format));
+                }
+            }
+            self.skipped = self.skipped.split_off(&seq);
+
+            if let Some((_, packet)) = self.packets.pop_first() {
+                let mut chunk = packet.chunk;
+                chunk.metadata.end_stage();
+                self.last_released = Some((seq, chunk.timestamp));
+                self.next_seq = Some(seq.wrapping_add(1));
+                ready.push(chunk);
+            }
+        }
+
+        ready
+    }
+
+    /// Releases everything still held, regardless of playout time, and forgets
+    /// the sequence position so a restarted sender can begin afresh.
+    pub fn reset(&mut self) -> Vec<AudioChunk> {
+        let ready = self.pop_ready_until(None);
+        self.next_seq = None;
+        self.last_released = None;
+        self.skipped.clear();
+        ready
+    }
+
+    pub fn playout_delay(&self) -> Duration {
+        self.playout_delay
+    }
+
+    pub fn late_packets(&self) -> u64 {
+        self.late_packets
+    }
+
+    fn gap_marker(
+        &self,
+        first_lost: u32,
+        missing: u32,
+        next_present: u32,
+        next_timestamp: u32,
+        format: AudioFormat,
+    ) -> AudioChunk {
+        let timestamp = match self.last_released {
+            Some((seq, ts)) if next_present > seq => {
+                let step = next_timestamp.wrapping_sub(ts) / (next_present - seq);
+                ts.wrapping_add(step.wrapping_mul(first_lost - seq))
+            }
+            _ => next_timestamp,
+        };
+
+        debug!(
+            "Declaring {} packet(s) lost from seq {} (stream: {})",
+            missing, first_lost, self.stream_id
+        );
+
+        let mut metadata = LatencyMetadata::new(self.stream_id);
+        metadata.start_stage(JITTER_BUFFER_STAGE, "rtp-ingest");
+        metadata.end_stage();
+
+        AudioChunk {
+            data: Bytes::new(),
+            format,
+            sequence_number: first_lost,
+            timestamp,
+            kind: ChunkKind::Gap { packets: missing },
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const DELAY: Duration = Duration::from_millis(20);
+
+    fn chunk(seq: u32) -> AudioChunk {
+        AudioChunk {
+            data: Bytes::from_static(&[0xff; 160]),
+            format: AudioFormat::g711_ulaw_mono(),
+            sequence_number: seq,
+            timestamp: 1000 + seq * 160,
+            kind: ChunkKind::Audio,
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata: LatencyMetadata::new(Uuid::nil()),
+        }
+    }
+
+    fn buffer() -> JitterBuffer {
+        JitterBuffer::new(Uuid::nil(), JitterBufferConfig::default())
+    }
+
+    /// Sequence numbers and kinds of released chunks.
+    fn released(chunks: &[AudioChunk]) -> Vec<(u32, ChunkKind)> {
+        chunks
+            .iter()
+            .map(|chunk| (chunk.sequence_number, chunk.kind))
+            .collect()
+    }
+
+    #[test]
+    fn reorders_within_the_playout_delay() {
+        let mut buffer = buffer();
+        let start = Instant::now();
+        for seq in [1, 3, 2] {
+            buffer.push(chunk(seq), start, Duration::ZERO);
+        }
+        assert_eq!(buffer.pop_ready(start + DELAY / 2).len(), 0);
+
+        let ready = buffer.pop_ready(start + DELAY);
+        assert_eq!(
+            released(&ready),
+            [
+                (1, ChunkKind::Audio),
+                (2, ChunkKind::Audio),
+                (3, ChunkKind::Audio)
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_lost_packets_with_interpolated_timestamps() {
+        let mut buffer = buffer();
+        let start = Instant::now();
+        buffer.push(chunk(10), start, Duration::ZERO);
+        assert_eq!(buffer.pop_ready(start + DELAY).len(), 1);
+
+        buffer.push(chunk(14), start + DELAY, Duration::ZERO);
+        let ready = buffer.pop_ready(start + 2 * DELAY);
+        assert_eq!(
+            released(&ready),
+            [(11, ChunkKind::Gap { packets: 3 }), (14, ChunkKind::Audio)]
+        );
+        assert_eq!(ready[0].timestamp, chunk(11).timestamp);
+        assert_eq!(ready[0].data.len(), 0);
+    }
+
+    #[test]
+    fn skipped_sequence_numbers_are_not_losses() {
+        let mut buffer = buffer();
+        let start = Instant::now();
+        buffer.push(chunk(1), start, Duration::ZERO);
+        buffer.pop_ready(start + DELAY);
+
+        // 2 carried FEC, 3 was lost.
+        buffer.skip(2);
+        buffer.push(chunk(4), start, Duration::ZERO);
+        let ready = buffer.pop_ready(start + DELAY);
+        assert_eq!(
+            released(&ready),
+            [(3, ChunkKind::Gap { packets: 1 }), (4, ChunkKind::Audio)]
+        );
+
+        buffer.skip(5);
+        buffer.push(chunk(6), start, Duration::ZERO);
+        assert_eq!(
+            released(&buffer.pop_ready(start + DELAY)),
+            [(6, ChunkKind::Audio)]
+        );
+    }
+
+    #[test]
+    fn drops_packets_that_arrive_after_their_turn() {
+        let mut buffer = buffer();
+        let start = Instant::now();
+        buffer.push(chunk(1), start, Duration::ZERO);
+        buffer.push(chunk(3), start, Duration::ZERO);
+        buffer.pop_ready(start + DELAY);
+
+        assert!(!buffer.accepts(2));
+        buffer.push(chunk(2), start + DELAY, Duration::ZERO);
+        assert_eq!(buffer.late_packets(), 1);
+        assert_eq!(buffer.pop_ready(start + 10 * DELAY).len(), 0);
+        assert!(buffer.accepts(4));
+    }
+
+    #[test]
+    fn holds_for_a_multiple_of_the_jitter_within_bounds() {
+        let config = JitterBufferConfig::default();
+        let mut buffer = buffer();
+        let now = Instant::now();
+        for (jitter, delay) in [
+            (Duration::ZERO, config.min_delay),
+            (Duration::from_millis(10), Duration::from_millis(30)),
+            (Duration::from_secs(1), config.max_delay),
+        ] {
+            buffer.push(chunk(1), now, jitter);
+            assert_eq!(buffer.playout_delay(), delay);
+        }
+    }
+
+    #[test]
+    fn releases_early_when_too_many_packets_are_held() {
+        let config = JitterBufferConfig {
+            max_packets: 4,
+            ..JitterBufferConfig::default()
+        };
+        let mut buffer = JitterBuffer::new(Uuid::nil(), config);
+        let now = Instant::now();
+        for seq in 0..6 {
+            buffer.push(chunk(seq), now, Duration::ZERO);
+        }
+        assert_eq!(
+            released(&buffer.pop_ready(now)),
+            [(0, ChunkKind::Audio), (1, ChunkKind::Audio)]
+        );
+    }
+
+    #[test]
+    fn reset_releases_everything_and_starts_afresh() {
+        let mut buffer = buffer();
+        let now = Instant::now();
+        buffer.push(chunk(7), now, Duration::ZERO);
+        buffer.push(chunk(9), now, Duration::ZERO);
+        assert_eq!(
+            released(&buffer.reset()),
+            [
+                (7, ChunkKind::Audio),
+                (8, ChunkKind::Gap { packets: 1 }),
+                (9, ChunkKind::Audio)
+            ]
+        );
+        // A restarted sender may go back to lower sequence numbers.
+        assert!(buffer.accepts(1));
+    }
+}
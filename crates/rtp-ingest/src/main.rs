@@ -1,26 +1,487 @@
+mod admin;
+mod capture;
+mod comfort_noise;
+mod dtls;
+mod dtmf;
+mod header_extensions;
+mod jitter_buffer;
+mod media_ports;
+mod probation;
+mod publisher;
+mod red;
+mod replay;
+mod rtcp_parser;
+mod rtcp_sender;
 mod rtp_receiver;
+mod sip_message;
+mod sip_server;
+mod siprec;
+mod source_state;
+mod srtp;
 mod stream_manager;
+mod stun;
+mod udp_batch;
+mod ulpfec;
+mod webrtc_transport;
+mod whip;
 
+use admin::AdminServer;
 use anyhow::Result;
-use std::net::SocketAddr;
-use tracing::{Level, info};
+use capture::{CaptureConfig, PacketCapture};
+use clap::{ArgGroup, Parser};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{Level, error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
+use pipeline_transport::{Backend, TransportConfig};
+use probation::ProbationConfig;
+use publisher::{ChunkPublisher, OverflowPolicy};
+use rtp_receiver::{ReceiveConfig, RtpReceiver};
+use shared_types::sdp::CryptoAttribute;
+use shared_types::{
+    HeaderExtensionKind, HeaderExtensionMap, PayloadMapping, PayloadTypeMap, StreamEvent,
+};
+use sip_server::{SipConfig, SipServer};
+use srtp::{SrtpKey, SrtpKeys};
+use stream_manager::{
+    LifecycleConfig, RelatchPolicy, SsrcChangePolicy, StreamManager, StreamManagerConfig,
+};
+use whip::{WhipConfig, WhipServer};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(group = ArgGroup::new("signalling").args(["sip_bind", "whip_bind"]).multiple(true))]
+#[allow(clippy::struct_excessive_bools)]
+struct Args {
+    /// Address to receive RTP on, with an even port (RTCP uses the next port up)
+    #[arg(short, long, default_value = "0.0.0.0:5004")]
+    bind: SocketAddr,
+
+    /// Receive media only on the ports allocated to SIP calls and WHIP
+    /// sessions, not on --bind
+    #[arg(long, requires = "signalling")]
+    no_fixed_port: bool,
+
+    /// Seconds without media before a stream is marked paused
+    #[arg(long, default_value = "5")]
+    pause_after: u64,
+
+    /// Seconds without media before a stream is disconnected and freed
+    #[arg(long, default_value = "30")]
+    disconnect_after: u64,
+
+    /// Link a new SSRC from the same source address to its existing stream
+    #[arg(long)]
+    link_ssrc_changes: bool,
+
+    /// When a stream's SSRC moves to a new source address: new-stream,
+    /// allow (follow it) or reject, both of which need one worker; call
+    /// ports treat new-stream as allow
+    #[arg(long, value_parser = parse_relatch, default_value = "new-stream")]
+    relatch: RelatchPolicy,
+
+    /// In-sequence packets required before a new source becomes a stream
+    #[arg(long, default_value = "2")]
+    min_sequential: u16,
+
+    /// Dynamic payload type mapping as PT=NAME/CLOCK[/CHANNELS]; repeatable
+    #[arg(long = "payload-type", value_parser = parse_payload_type, default_values = ["111=opus/48000/2", "101=telephone-event/8000"])]
+    payload_types: Vec<(u8, PayloadMapping)>,
+
+    /// RFC 8285 header extension read on --bind as ID=URI; repeatable.
+    /// Calls use the IDs their SDP negotiates
+    #[arg(long = "header-extension", value_parser = parse_header_extension, default_values = [
+        "1=urn:ietf:params:rtp-hdrext:ssrc-audio-level",
+        "2=http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time",
+        "3=http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01",
+        "4=urn:navitel:rtp-hdrext:origin",
+    ])]
+    header_extensions: Vec<(u8, HeaderExtensionKind)>,
+
+    /// Address to accept SIP calls on over UDP and TCP (disabled if unset)
+    #[arg(long)]
+    sip_bind: Option<SocketAddr>,
+
+    /// Trunk address allowed to place calls; repeatable, any if unset
+    #[arg(long = "sip-trunk")]
+    sip_trunks: Vec<IpAddr>,
+
+    /// Address advertised for media in SDP answers (defaults to the SIP or
+    /// WHIP bind address)
+    #[arg(long)]
+    media_ip: Option<IpAddr>,
+
+    /// Port range for per-call media, as FIRST-LAST
+    #[arg(long, value_parser = parse_port_range, default_value = "20000-20999")]
+    media_ports: RangeInclusive<u16>,
+
+    /// Address to accept WebRTC audio on over WHIP, at /whip (disabled if unset)
+    #[arg(long)]
+    whip_bind: Option<SocketAddr>,
+
+    /// Port range for WHIP sessions' media, as FIRST-LAST
+    #[arg(long, value_parser = parse_port_range, default_value = "40000-40999")]
+    whip_media_ports: RangeInclusive<u16>,
+
+    /// Bearer token WHIP clients must present
+    #[arg(long, requires = "whip_bind")]
+    whip_token: Option<String>,
+
+    /// Sockets sharing the RTP port via `SO_REUSEPORT`, each with its own
+    /// worker and share of the streams
+    #[arg(long, default_value = "1")]
+    workers: usize,
+
+    /// Datagrams read per system call (recvmmsg); 1 reads one at a time
+    #[arg(long, default_value = "32")]
+    recv_batch: usize,
+
+    /// Also accept RTP and RTCP over TCP (RFC 4571) on the bind address
+    #[arg(long)]
+    tcp: bool,
+
+    /// Fill DTX silence with generated comfort noise (G.711 and L16 only)
+    #[arg(long)]
+    synthesize_comfort_noise: bool,
+
+    /// Broker to publish audio chunks and stream events to: nats or valkey
+    #[arg(long)]
+    transport: Option<Backend>,
+
+    /// Broker URL [default: `nats://127.0.0.1:4222` or `redis://127.0.0.1:6379`]
+    #[arg(long, requires = "transport")]
+    transport_url: Option<String>,
+
+    /// Namespace on the broker: NATS subjects `PREFIX.audio.STREAM_ID` and
+    /// `PREFIX.events`, Valkey streams `PREFIX:audio` and `PREFIX:events`
+    #[arg(long, default_value = "navitel")]
+    transport_prefix: String,
+
+    /// Entries each Valkey stream is trimmed to, approximately
+    #[arg(long, default_value = "100000")]
+    stream_max_len: usize,
+
+    /// Chunks buffered for the broker before the overflow policy applies
+    #[arg(long, default_value = "1024")]
+    publish_buffer: usize,
+
+    /// When the publish buffer is full: drop (the chunk) or block (receiving)
+    #[arg(long, value_parser = parse_overflow, default_value = "drop")]
+    publish_overflow: OverflowPolicy,
+
+    /// Static SRTP key for the main receiver, as `SUITE inline:KEY||SALT`
+    /// in SDES form; also protects the RTCP we send
+    #[arg(long, value_parser = parse_srtp_key)]
+    srtp_key: Option<SrtpKey>,
+
+    /// Address to serve the admin API on, which starts and stops packet
+    /// captures (disabled if unset; unauthenticated)
+    #[arg(long)]
+    admin_bind: Option<SocketAddr>,
+
+    /// Let the admin API bind to an address other than loopback, for when
+    /// only operators can reach it
+    #[arg(long, requires = "admin_bind")]
+    admin_allow_remote: bool,
+
+    /// Directory captures are written to
+    #[arg(long, default_value = "captures")]
+    capture_dir: PathBuf,
+
+    /// Megabytes a capture file reaches before the next is started
+    #[arg(long, default_value = "64")]
+    capture_file_mb: u64,
+
+    /// Seconds a capture file is written before the next is started
+    #[arg(long, default_value = "300")]
+    capture_file_secs: u64,
+
+    /// Files kept per capture, the oldest deleted first
+    #[arg(long, default_value = "10")]
+    capture_max_files: usize,
+
+    /// Longest a capture runs, and how long it runs if not told
+    #[arg(long, default_value = "3600")]
+    capture_max_secs: u64,
+
+    /// Instead of listening, feed the RTP and RTCP in this pcap or pcapng
+    /// file through the pipeline, then exit
+    #[arg(long, conflicts_with_all = ["bind", "signalling", "tcp", "admin_bind"])]
+    replay: Option<PathBuf>,
+
+    /// How many times faster than captured to replay
+    #[arg(long, value_parser = parse_replay_speed, default_value = "1", requires = "replay")]
+    replay_speed: f64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing();
 
+    let args = Args::parse();
+
     info!("Starting RTP Ingest Service");
 
-    let bind_addr: SocketAddr = "0.0.0.0:5004".parse()?;
-    info!("Binding to {}", bind_addr);
+    let mut payload_types = PayloadTypeMap::rfc3551();
+    for (payload_type, mapping) in &args.payload_types {
+        info!("Payload type {} mapped to {}", payload_type, mapping);
+        payload_types.insert(*payload_type, mapping.clone());
+    }
+    let mut header_extensions = HeaderExtensionMap::default();
+    for &(id, kind) in &args.header_extensions {
+        header_extensions.insert(id, kind);
+    }
+
+    let config = StreamManagerConfig {
+        lifecycle: LifecycleConfig {
+            pause_after: Duration::from_secs(args.pause_after),
+            disconnect_after: Duration::from_secs(args.disconnect_after),
+        },
+        ssrc_change: if args.link_ssrc_changes {
+            SsrcChangePolicy::LinkToExisting
+        } else {
+            SsrcChangePolicy::NewStream
+        },
+        relatch: args.relatch,
+        probation: ProbationConfig {
+            min_sequential: args.min_sequential,
+            ..ProbationConfig::default()
+        },
+        payload_types,
+        header_extensions,
+        synthesize_comfort_noise: args.synthesize_comfort_noise,
+        srtp: args.srtp_key.clone().map(|key| SrtpKeys {
+            remote: key.clone(),
+            local: key,
+        }),
+        ..StreamManagerConfig::default()
+    };
+
+    let events = StreamManager::event_channel();
+    tokio::spawn(log_stream_events(events.subscribe()));
+
+    let publisher = match args.transport {
+        Some(backend) => {
+            let mut transport = TransportConfig::new(backend);
+            if let Some(url) = &args.transport_url {
+                transport.url.clone_from(url);
+            }
+            transport.prefix.clone_from(&args.transport_prefix);
+            transport.max_len = args.stream_max_len;
+            Some(ChunkPublisher::start(
+                pipeline_transport::connect(&transport).await?,
+                args.publish_buffer,
+                args.publish_overflow,
+                events.subscribe(),
+            ))
+        }
+        None => None,
+    };
+
+    if let Some(path) = &args.replay {
+        return replay::run(path, args.replay_speed, config, events, publisher).await;
+    }
 
-    let receiver = rtp_receiver::RtpReceiver::new(bind_addr).await?;
-    receiver.run().await?;
+    let capture = start_admin(&args, &events).await?;
+
+    let receiver = if args.no_fixed_port {
+        None
+    } else {
+        info!("Binding to {}", args.bind);
+        let receive = ReceiveConfig {
+            workers: args.workers,
+            batch_size: args.recv_batch,
+        };
+        let mut receiver = RtpReceiver::bind(args.bind, config.clone(), events.clone(), receive)
+            .await?
+            .with_publisher(publisher.as_ref())
+            .with_capture(capture.as_ref());
+        if args.tcp {
+            receiver = receiver.listen_tcp(args.bind).await?;
+        }
+        Some(receiver)
+    };
+
+    let servers = start_signalling(&args, config, events, publisher, capture).await?;
+    if let Some(receiver) = receiver {
+        receiver.run().await?;
+    } else {
+        for server in servers {
+            server.await?;
+        }
+    }
 
     Ok(())
 }
 
+/// Starts the SIP and WHIP servers that are enabled, each on its own task.
+async fn start_signalling(
+    args: &Args,
+    config: StreamManagerConfig,
+    events: broadcast::Sender<StreamEvent>,
+    publisher: Option<ChunkPublisher>,
+    capture: Option<PacketCapture>,
+) -> Result<Vec<JoinHandle<()>>> {
+    let media_ip = |bind: SocketAddr| {
+        args.media_ip.unwrap_or_else(|| {
+            if bind.ip().is_unspecified() {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            } else {
+                bind.ip()
+            }
+        })
+    };
+
+    let mut servers = Vec::new();
+    if let Some(sip_bind) = args.sip_bind {
+        let sip_config = SipConfig {
+            bind: sip_bind,
+            trunks: args.sip_trunks.clone(),
+            media_ip: media_ip(sip_bind),
+            media_ports: args.media_ports.clone(),
+        };
+        let server = SipServer::bind(
+            sip_config,
+            config.clone(),
+            events.clone(),
+            publisher.clone(),
+            capture.clone(),
+        )
+        .await?;
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = server.run().await {
+                error!("SIP server stopped: {}", e);
+            }
+        }));
+    }
+    if let Some(whip_bind) = args.whip_bind {
+        let whip_config = WhipConfig {
+            bind: whip_bind,
+            media_ip: media_ip(whip_bind),
+            media_ports: args.whip_media_ports.clone(),
+            token: args.whip_token.clone(),
+        };
+        let server = WhipServer::bind(whip_config, config, events, publisher, capture).await?;
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = server.run().await {
+                error!("WHIP server stopped: {}", e);
+            }
+        }));
+    }
+    Ok(servers)
+}
+
+/// Starts the admin API if enabled, with the packet capture it controls.
+async fn start_admin(
+    args: &Args,
+    events: &broadcast::Sender<StreamEvent>,
+) -> Result<Option<PacketCapture>> {
+    let Some(admin_bind) = args.admin_bind else {
+        return Ok(None);
+    };
+    let capture = PacketCapture::new(
+        CaptureConfig {
+            dir: args.capture_dir.clone(),
+            file_bytes: args.capture_file_mb.saturating_mul(1024 * 1024),
+            file_duration: Duration::from_secs(args.capture_file_secs),
+            max_files: args.capture_max_files,
+            max_duration: Duration::from_secs(args.capture_max_secs),
+        },
+        events.subscribe(),
+    );
+    let server = AdminServer::bind(admin_bind, args.admin_allow_remote, capture.clone()).await?;
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            error!("Admin API stopped: {}", e);
+        }
+    });
+    Ok(Some(capture))
+}
+
+fn parse_payload_type(entry: &str) -> Result<(u8, PayloadMapping), String> {
+    PayloadTypeMap::parse_entry(entry).map_err(|e| e.to_string())
+}
+
+fn parse_header_extension(entry: &str) -> Result<(u8, HeaderExtensionKind), String> {
+    HeaderExtensionMap::parse_entry(entry).map_err(|e| e.to_string())
+}
+
+fn parse_replay_speed(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed > 0.0)
+        .ok_or_else(|| format!("expected a positive speed, got `{value}`"))
+}
+
+fn parse_srtp_key(value: &str) -> Result<SrtpKey, String> {
+    let (suite, key_params) = value
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| format!("expected SUITE inline:KEY, got `{value}`"))?;
+    SrtpKey::from_crypto(&CryptoAttribute {
+        tag: 1,
+        suite: suite.to_string(),
+        key_params: key_params.trim().to_string(),
+        session_params: None,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn parse_relatch(policy: &str) -> Result<RelatchPolicy, String> {
+    match policy {
+        "new-stream" => Ok(RelatchPolicy::NewStream),
+        "allow" => Ok(RelatchPolicy::Allow),
+        "reject" => Ok(RelatchPolicy::Reject),
+        _ => Err(format!(
+            "expected new-stream, allow or reject, got `{policy}`"
+        )),
+    }
+}
+
+fn parse_overflow(policy: &str) -> Result<OverflowPolicy, String> {
+    match policy {
+        "drop" => Ok(OverflowPolicy::Drop),
+        "block" => Ok(OverflowPolicy::Block),
+        _ => Err(format!("expected drop or block, got `{policy}`")),
+    }
+}
+
+fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = range
+        .split_once('-')
+        .ok_or_else(|| format!("expected FIRST-LAST, got `{range}`"))?;
+    let first: u16 = first
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad first port: {e}"))?;
+    let last: u16 = last
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad last port: {e}"))?;
+    if first > last {
+        return Err(format!("empty port range `{range}`"));
+    }
+    Ok(first..=last)
+}
+
+async fn log_stream_events(mut events: broadcast::Receiver<StreamEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => info!("Stream event: {:?}", event),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Stream event log lagged, skipped {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
 fn init_tracing() {
     tracing_subscriber::registry()
         .with(fmt::layer())
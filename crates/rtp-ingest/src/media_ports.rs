@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use shared_types::StreamId;
+
+/// The RTP port (even) and the RTCP port above it that one call media
+/// stream is received on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortPair {
+    pub rtp: u16,
+    pub rtcp: u16,
+}
+
+/// Who holds a port pair: the call it was handed to and the streams that
+/// have since turned up on it.
+#[derive(Debug)]
+struct Allocation {
+    call_id: String,
+    streams: Vec<StreamId>,
+}
+
+/// Hands out even/odd RTP/RTCP port pairs for signalled calls and keeps
+/// track of the streams received on each, so that they can be ended when
+/// the call is.
+pub struct MediaPortPool {
+    first: u16,
+    last: u16,
+    next: u16,
+    in_use: HashMap<u16, Allocation>,
+}
+
+impl MediaPortPool {
+    /// Uses the pairs that fit in `range`; an odd first port or an even
+    /// last port is left out.
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        let first = range.start().saturating_add(range.start() % 2);
+        Self {
+            first,
+            last: *range.end(),
+            next: first,
+            in_use: HashMap::new(),
+        }
+    }
+
+    /// Returns a free pair for `call_id`, cycling through the range so a
+    /// just-released pair is not reused while stray packets for the old
+    /// call may still arrive.
+    pub fn allocate(&mut self, call_id: &str) -> Option<PortPair> {
+        if self.first >= self.last {
+            return None;
+        }
+        let slots = (self.last - self.first).div_ceil(2);
+
+        for _ in 0..slots {
+            let rtp = self.next;
+            self.next = match rtp.checked_add(2) {
+                Some(next) if next < self.last => next,
+                _ => self.first,
+            };
+            if self.in_use.contains_key(&rtp) {
+                continue;
+            }
+            self.in_use.insert(
+                rtp,
+                Allocation {
+                    call_id: call_id.to_string(),
+                    streams: Vec::new(),
+                },
+            );
+            return Some(PortPair { rtp, rtcp: rtp + 1 });
+        }
+        None
+    }
+
+    /// Records a stream that started on an allocated pair, returning the
+    /// call that holds it.
+    pub fn assign(&mut self, rtp: u16, stream_id: StreamId) -> Option<&str> {
+        let allocation = self.in_use.get_mut(&rtp)?;
+        if !allocation.streams.contains(&stream_id) {
+            allocation.streams.push(stream_id);
+        }
+        Some(&allocation.call_id)
+    }
+
+    /// Forgets a stream that has ended, returning the call that holds its
+    /// pair.
+    pub fn finish(&mut self, rtp: u16, stream_id: StreamId) -> Option<String> {
+        let allocation = self.in_use.get_mut(&rtp)?;
+        allocation.streams.retain(|&stream| stream != stream_id);
+        Some(allocation.call_id.clone())
+    }
+
+    /// Frees a pair, returning the streams that were received on it.
+    pub fn release(&mut self, rtp: u16) -> Vec<StreamId> {
+        self.in_use
+            .remove(&rtp)
+            .map(|allocation| allocation.streams)
+            .unwrap_or_default()
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn rtp_ports(pool: &mut MediaPortPool, calls: usize) -> Vec<Option<u16>> {
+        (0..calls)
+            .map(|call| pool.allocate(&format!("call-{call}")).map(|pair| pair.rtp))
+            .collect()
+    }
+
+    #[test]
+    fn hands_out_even_odd_pairs_within_the_range() {
+        let mut pool = MediaPortPool::new(10000..=10005);
+        assert_eq!(
+            pool.allocate("a"),
+            Some(PortPair {
+                rtp: 10000,
+                rtcp: 10001,
+            })
+        );
+        assert_eq!(rtp_ports(&mut pool, 3), [Some(10002), Some(10004), None]);
+
+        // An odd first port and an even last port have no partner.
+        let mut pool = MediaPortPool::new(10001..=10006);
+        assert_eq!(rtp_ports(&mut pool, 3), [Some(10002), Some(10004), None]);
+
+        let mut pool = MediaPortPool::new(65530..=65535);
+        assert_eq!(
+            rtp_ports(&mut pool, 4),
+            [Some(65530), Some(65532), Some(65534), None]
+        );
+    }
+
+    #[test]
+    fn has_nothing_to_give_from_a_range_without_a_pair() {
+        for range in [10000..=10000, 10001..=10002, 65535..=65535] {
+            assert_eq!(MediaPortPool::new(range).allocate("a"), None);
+        }
+    }
+
+    #[test]
+    fn reuses_released_pairs_after_the_rest() {
+        let mut pool = MediaPortPool::new(10000..=10007);
+        assert_eq!(
+            rtp_ports(&mut pool, 4),
+            [Some(10000), Some(10002), Some(10004), Some(10006)]
+        );
+        assert_eq!(pool.allocate("full"), None);
+
+        pool.release(10002);
+        pool.release(10006);
+        assert_eq!(pool.in_use(), 2);
+        assert_eq!(rtp_ports(&mut pool, 3), [Some(10002), Some(10006), None]);
+
+        // With room to spare, a freed pair waits for the others first.
+        let mut pool = MediaPortPool::new(10000..=10005);
+        assert_eq!(rtp_ports(&mut pool, 1), [Some(10000)]);
+        pool.release(10000);
+        assert_eq!(
+            rtp_ports(&mut pool, 3),
+            [Some(10002), Some(10004), Some(10000)]
+        );
+    }
+
+    #[test]
+    fn tracks_the_streams_on_each_pair() {
+        let mut pool = MediaPortPool::new(10000..=10003);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let pair = pool.allocate("call").unwrap();
+
+        assert_eq!(pool.assign(pair.rtp, first), Some("call"));
+        assert_eq!(pool.assign(pair.rtp, first), Some("call"));
+        assert_eq!(pool.assign(pair.rtp, second), Some("call"));
+        assert_eq!(pool.assign(10002, first), None);
+
+        assert_eq!(pool.finish(pair.rtp, first).as_deref(), Some("call"));
+        assert_eq!(pool.release(pair.rtp), [second]);
+        assert_eq!(pool.in_use(), 0);
+        assert_eq!(pool.finish(pair.rtp, second), None);
+        assert_eq!(pool.release(pair.rtp).len(), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::stream_manager::StreamKey;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProbationConfig {
+    /// In-sequence packets needed before a source becomes a stream
+    /// (RFC 3550 `MIN_SEQUENTIAL`). 1 or less disables probation.
+    pub min_sequential: u16,
+    /// Upper bound on sources held on probation at once.
+    pub max_sources: usize,
+    /// Probationary sources that go quiet for this long are dropped.
+    pub timeout: Duration,
+}
+
+impl Default for ProbationConfig {
+    fn default() -> Self {
+        Self {
+            min_sequential: 2,
+            max_sources: 4096,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+struct Probation {
+    max_seq: u16,
+    remaining: u16,
+    last_seen: Instant,
+}
+
+/// Sources that have sent something RTP-shaped but not yet enough
+/// in-sequence packets to be believed, per RFC 3550 Appendix A.1.
+pub struct ProbationTable {
+    config: ProbationConfig,
+    sources: HashMap<StreamKey, Probation>,
+    discarded: u64,
+    evicted: u64,
+}
+
+impl ProbationTable {
+    pub fn new(config: ProbationConfig) -> Self {
+        Self {
+            config,
+            sources: HashMap::new(),
+            discarded: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Returns true once `key` has completed probation with this packet.
+    pub fn admit(&mut self, key: StreamKey, seq: u16, now: Instant) -> bool {
+        if self.config.min_sequential <= 1 {
+            return true;
+        }
+
+        if let Some(probation) = self.sources.get_mut(&key) {
+            probation.last_seen = now;
+            if seq == probation.max_seq.wrapping_add(1) {
+                probation.remaining -= 1;
+                probation.max_seq = seq;
+                if probation.remaining == 0 {
+                    self.sources.remove(&key);
+                    return true;
+                }
+            } else {
+                probation.remaining = self.config.min_sequential - 1;
+                probation.max_seq = seq;
+            }
+            self.discarded += 1;
+            return false;
+        }
+
+        if self.sources.len() >= self.config.max_sources {
+            self.evict_oldest();
+        }
+        debug!(
+            "Source on probation: SSRC={}, Source={}",
+            key.ssrc, key.source_addr
+        );
+        self.sources.insert(
+            key,
+            Probation {
+                max_seq: seq,
+                remaining: self.config.min_sequential - 1,
+                last_seen: now,
+            },
+        );
+        self.discarded += 1;
+        false
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.config.timeout;
+        self.sources
+            .retain(|_, probation| now.saturating_duration_since(probation.last_seen) < timeout);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Packets dropped because their source had not finished probation.
+    pub fn discarded(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Sources pushed out of a full table before finishing probation.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(oldest) = self
+            .sources
+            .iter()
+            .min_by_key(|(_, probation)| probation.last_seen)
+            .map(|(&key, _)| key)
+        {
+            self.sources.remove(&oldest);
+            self.evicted += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn key(ssrc: u32) -> StreamKey {
+        StreamKey {
+            source_addr: SocketAddr::from(([192, 0, 2, 10], 5004)),
+            ssrc,
+        }
+    }
+
+    fn table(min_sequential: u16, max_sources: usize) -> ProbationTable {
+        ProbationTable::new(ProbationConfig {
+            min_sequential,
+            max_sources,
+            ..ProbationConfig::default()
+        })
+    }
+
+    #[test]
+    fn admits_after_min_sequential_packets() {
+        let mut table = table(3, 10);
+        let now = Instant::now();
+        let admitted: Vec<_> = [65534, 65535, 0]
+            .into_iter()
+            .map(|seq| table.admit(key(1), seq, now))
+            .collect();
+        assert_eq!(admitted, [false, false, true]);
+        assert_eq!(table.len(), 0);
+        assert_eq!(table.discarded(), 2);
+    }
+
+    #[test]
+    fn starts_again_when_the_sequence_breaks() {
+        let mut table = table(2, 10);
+        let now = Instant::now();
+        for seq in [10, 12, 11, 20] {
+            assert!(!table.admit(key(1), seq, now), "{seq}");
+        }
+        assert!(table.admit(key(1), 21, now));
+        assert_eq!(table.discarded(), 4);
+    }
+
+    #[test]
+    fn admits_at_once_without_probation() {
+        for min_sequential in [0, 1] {
+            let mut table = table(min_sequential, 10);
+            assert!(table.admit(key(1), 7, Instant::now()));
+            assert_eq!(table.len(), 0);
+        }
+    }
+
+    #[test]
+    fn forgets_sources_that_go_quiet() {
+        let mut table = table(2, 10);
+        let start = Instant::now();
+        table.admit(key(1), 0, start);
+        table.admit(key(2), 0, start + Duration::from_secs(1));
+
+        table.expire(start + Duration::from_millis(1999));
+        assert_eq!(table.len(), 2);
+        table.expire(start + Duration::from_secs(2));
+        assert_eq!(table.len(), 1);
+
+        // Key 1 has to start over.
+        assert!(!table.admit(key(1), 1, start + Duration::from_secs(2)));
+        assert!(table.admit(key(2), 1, start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn evicts_the_quietest_source_when_full() {
+        let mut table = table(2, 2);
+        let start = Instant::now();
+        table.admit(key(1), 0, start + Duration::from_millis(10));
+        table.admit(key(2), 0, start);
+        table.admit(key(3), 0, start + Duration::from_millis(20));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.evicted(), 1);
+
+        assert!(!table.admit(key(2), 1, start + Duration::from_millis(30)));
+        assert!(table.admit(key(3), 1, start + Duration::from_millis(30)));
+    }
+}
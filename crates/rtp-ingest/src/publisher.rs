@@ -0,0 +1,332 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use pipeline_transport::Transport;
+use shared_types::{AudioChunk, StreamEvent};
+
+const PUBLISH_STAGE: &str = "publish";
+
+/// What to do with a chunk when the publish buffer is full because the
+/// broker is not keeping up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the chunk and count it, so receiving is never held up.
+    #[default]
+    Drop,
+    /// Wait for room. Receiving stalls and packets are lost in the socket
+    /// buffer instead, so every chunk that is taken gets published.
+    Block,
+}
+
+/// Where receivers hand the chunks their jitter buffers release. Clones
+/// share one buffer.
+#[derive(Clone)]
+pub struct ChunkPublisher {
+    chunks: mpsc::Sender<AudioChunk>,
+    overflow: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl ChunkPublisher {
+    /// Starts publishing chunks and every event on `events` over
+    /// `transport`, holding up to `buffer` chunks while it catches up.
+    /// Chunks and events are not ordered with respect to each other, so a
+    /// stream's last chunks may follow its `Ended` event.
+    pub fn start(
+        transport: Arc<dyn Transport>,
+        buffer: usize,
+        overflow: OverflowPolicy,
+        events: broadcast::Receiver<StreamEvent>,
+    ) -> Self {
+        info!(
+            "Publishing chunks and stream events ({} chunk buffer, {:?} when full)",
+            buffer, overflow
+        );
+        let (chunks, queued) = mpsc::channel(buffer.max(1));
+        let task = tokio::spawn(publish_loop(transport, queued, events));
+        Self {
+            chunks,
+            overflow,
+            dropped: Arc::default(),
+            task: Arc::new(Mutex::new(Some(task))),
+        }
+    }
+
+    /// Publishes the chunks still queued and the stream events already
+    /// sent, then stops. Only returns once every clone has been dropped.
+    pub async fn close(self) {
+        let Self { chunks, task, .. } = self;
+        drop(chunks);
+        let task = task.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(task) = task
+            && let Err(e) = task.await
+        {
+            error!("Publisher failed: {}", e);
+        }
+    }
+
+    /// Queues chunks for publishing, applying the overflow policy.
+    pub async fn send(&self, chunks: Vec<AudioChunk>) {
+        for mut chunk in chunks {
+            chunk.metadata.start_stage(PUBLISH_STAGE, "rtp-ingest");
+            let queued = match self.overflow {
+                OverflowPolicy::Block => self.chunks.send(chunk).await.is_ok(),
+                OverflowPolicy::Drop => match self.chunks.try_send(chunk) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(chunk)) => {
+                        self.count_drop(&chunk);
+                        true
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                },
+            };
+            if !queued {
+                error!("Publisher has stopped, discarding chunks");
+                return;
+            }
+        }
+    }
+
+    fn count_drop(&self, chunk: &AudioChunk) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            warn!(
+                "Publish buffer full, dropped chunk seq {} of stream {} ({} dropped in total)",
+                chunk.sequence_number, chunk.metadata.stream_id, dropped
+            );
+        } else {
+            debug!(
+                "Dropped chunk seq {} of stream {}",
+                chunk.sequence_number, chunk.metadata.stream_id
+            );
+        }
+    }
+}
+
+async fn publish_loop(
+    transport: Arc<dyn Transport>,
+    mut chunks: mpsc::Receiver<AudioChunk>,
+    mut events: broadcast::Receiver<StreamEvent>,
+) {
+    let mut events_open = true;
+    loop {
+        tokio::select! {
+            chunk = chunks.recv() => {
+                let Some(mut chunk) = chunk else {
+                    publish_pending_events(transport.as_ref(), &mut events).await;
+                    break;
+                };
+                chunk.metadata.end_stage();
+                if let Err(e) = transport.publish_chunk(&chunk).await {
+                    warn!("Failed to publish chunk of stream {}: {}", chunk.metadata.stream_id, e);
+                }
+            }
+            event = events.recv(), if events_open => match event {
+                Ok(event) => {
+                    if let Err(e) = transport.publish_event(&event).await {
+                        warn!("Failed to publish stream event: {}", e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Publisher missed {} stream events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => events_open = false,
+            },
+        }
+    }
+    if let Err(e) = transport.flush().await {
+        warn!("Failed to flush transport: {}", e);
+    }
+}
+
+async fn publish_pending_events(
+    transport: &dyn Transport,
+    events: &mut broadcast::Receiver<StreamEvent>,
+) {
+    loop {
+        match events.try_recv() {
+            Ok(event) => {
+                if let Err(e) = transport.publish_event(&event).await {
+                    warn!("Failed to publish stream event: {}", e);
+                }
+            }
+            Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                warn!("Publisher missed {} stream events", skipped);
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use chrono::Utc;
+    use pipeline_transport::{StartFrom, Subscription, TransportError};
+    use shared_types::{AudioFormat, ChunkKind, HeaderExtensions, LatencyMetadata};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+    use uuid::Uuid;
+
+    /// Records what it is asked to publish. Publishing a chunk waits for a
+    /// permit, so a test can stall it like a broker that is not keeping up.
+    struct RecordingTransport {
+        gate: Semaphore,
+        attempts: AtomicUsize,
+        chunks: Mutex<Vec<u32>>,
+        events: Mutex<Vec<StreamEvent>>,
+        flushes: AtomicUsize,
+    }
+
+    impl RecordingTransport {
+        fn new(permits: usize) -> Arc<Self> {
+            Arc::new(Self {
+                gate: Semaphore::new(permits),
+                attempts: AtomicUsize::new(0),
+                chunks: Mutex::new(Vec::new()),
+                events: Mutex::new(Vec::new()),
+                flushes: AtomicUsize::new(0),
+            })
+        }
+
+        fn open() -> Arc<Self> {
+            Self::new(Semaphore::MAX_PERMITS)
+        }
+
+        fn release(&self) {
+            self.gate
+                .add_permits(Semaphore::MAX_PERMITS - self.gate.available_permits());
+        }
+
+        /// Waits until `count` chunks have reached `publish_chunk`.
+        async fn wait_for_attempts(&self, count: usize) {
+            while self.attempts.load(Ordering::SeqCst) < count {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        fn published(&self) -> Vec<u32> {
+            self.chunks.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Transport for RecordingTransport {
+        async fn publish_chunk(&self, chunk: &AudioChunk) -> Result<(), TransportError> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            self.gate.acquire().await.unwrap().forget();
+            self.chunks.lock().unwrap().push(chunk.sequence_number);
+            Ok(())
+        }
+
+        async fn publish_event(&self, event: &StreamEvent) -> Result<(), TransportError> {
+            self.events.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+
+        async fn subscribe(
+            &self,
+            _group: &str,
+            _consumer: &str,
+            _start: StartFrom,
+        ) -> Result<Box<dyn Subscription>, TransportError> {
+            Err(TransportError::Malformed("not supported".to_string()))
+        }
+
+        async fn flush(&self) -> Result<(), TransportError> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn chunks(sequences: std::ops::Range<u32>) -> Vec<AudioChunk> {
+        let stream_id = Uuid::new_v4();
+        sequences
+            .map(|sequence_number| AudioChunk {
+                data: Bytes::from_static(&[0xff; 160]),
+                format: AudioFormat::g711_ulaw_mono(),
+                sequence_number,
+                timestamp: sequence_number * 160,
+                kind: ChunkKind::Audio,
+                capture_time: None,
+                extensions: HeaderExtensions::default(),
+                metadata: LatencyMetadata::new(stream_id),
+            })
+            .collect()
+    }
+
+    fn ssrc_changed(ssrc: u32) -> StreamEvent {
+        StreamEvent::SsrcChanged {
+            stream_id: Uuid::new_v4(),
+            previous_ssrc: ssrc - 1,
+            ssrc,
+            at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_policy_counts_chunks_that_do_not_fit() {
+        let transport = RecordingTransport::new(0);
+        let (_events, receiver) = broadcast::channel(16);
+        let publisher = ChunkPublisher::start(transport.clone(), 2, OverflowPolicy::Drop, receiver);
+
+        publisher.send(chunks(0..1)).await;
+        transport.wait_for_attempts(1).await;
+        // Seq 0 is stuck in the transport, 1 and 2 fill the buffer.
+        publisher.send(chunks(1..5)).await;
+        assert_eq!(publisher.dropped.load(Ordering::Relaxed), 2);
+
+        transport.release();
+        publisher.close().await;
+        assert_eq!(transport.published(), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn block_policy_waits_for_room() {
+        let transport = RecordingTransport::new(0);
+        let (_events, receiver) = broadcast::channel(16);
+        let publisher =
+            ChunkPublisher::start(transport.clone(), 1, OverflowPolicy::Block, receiver);
+
+        let sender = publisher.clone();
+        let sending = tokio::spawn(async move { sender.send(chunks(0..4)).await });
+        transport.wait_for_attempts(1).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!sending.is_finished());
+
+        transport.release();
+        sending.await.unwrap();
+        assert_eq!(publisher.dropped.load(Ordering::Relaxed), 0);
+        publisher.close().await;
+        assert_eq!(transport.published(), [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn close_publishes_queued_chunks_and_pending_events() {
+        let transport = RecordingTransport::open();
+        let (events, receiver) = broadcast::channel(16);
+        let publisher = ChunkPublisher::start(transport.clone(), 8, OverflowPolicy::Drop, receiver);
+
+        publisher.send(chunks(0..5)).await;
+        events.send(ssrc_changed(1)).unwrap();
+        events.send(ssrc_changed(2)).unwrap();
+        publisher.close().await;
+
+        assert_eq!(transport.published(), [0, 1, 2, 3, 4]);
+        let published_events = transport.events.lock().unwrap();
+        assert_eq!(published_events.len(), 2);
+        assert!(matches!(
+            published_events[1],
+            StreamEvent::SsrcChanged { ssrc: 2, .. }
+        ));
+        assert_eq!(transport.flushes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,148 @@
+use bytes::Bytes;
+
+/// One block of an RFC 2198 redundant audio payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedBlock {
+    pub payload_type: u8,
+    /// How far this block's timestamp lies behind the packet's.
+    pub timestamp_offset: u32,
+    pub data: Bytes,
+}
+
+/// An RFC 2198 payload: redundant copies of earlier frames, oldest first,
+/// followed by the primary encoding of this packet's own frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedPayload {
+    pub redundant: Vec<RedBlock>,
+    pub primary: RedBlock,
+}
+
+impl RedPayload {
+    /// Splits a RED payload into its blocks. Every header but the last is
+    /// four bytes, `F | PT(7) | timestamp offset(14) | length(10)`; the last
+    /// is the single byte `0 | PT(7)` and its block runs to the end.
+    pub fn parse(payload: &Bytes) -> Option<Self> {
+        let mut headers = Vec::new();
+        let mut offset = 0;
+        loop {
+            let &first = payload.get(offset)?;
+            if first & 0x80 == 0 {
+                offset += 1;
+                break;
+            }
+            let header = payload.get(offset..offset + 4)?;
+            let word = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+            headers.push((first & 0x7f, word >> 10, (word & 0x3ff) as usize));
+            offset += 4;
+        }
+        let primary_type = payload[offset - 1] & 0x7f;
+
+        let mut redundant = Vec::with_capacity(headers.len());
+        for (payload_type, timestamp_offset, length) in headers {
+            let data = payload.get(offset..offset + length)?;
+            redundant.push(RedBlock {
+                payload_type,
+                timestamp_offset,
+                data: payload.slice_ref(data),
+            });
+            offset += length;
+        }
+
+        Some(Self {
+            redundant,
+            primary: RedBlock {
+                payload_type: primary_type,
+                timestamp_offset: 0,
+                data: payload.slice(offset..),
+            },
+        })
+    }
+
+    /// Sequence number of each redundant block. RED does not carry them, so
+    /// like other receivers we take the blocks as copies of the packets
+    /// immediately before this one.
+    pub fn redundant_sequence_numbers(&self, primary_seq: u16) -> impl Iterator<Item = u16> + '_ {
+        let count = self.redundant.len();
+        (0..count).map(move |index| {
+            #[allow(clippy::cast_possible_truncation)]
+            primary_seq.wrapping_sub((count - index) as u16)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_redundant_and_primary_blocks() {
+        // RFC 2198 section 3: one redundant block of PT 5, 160 timestamp
+        // units old and 4 bytes long, then a PT 0 primary.
+        let payload = Bytes::from_static(&[
+            0x85, 0x02, 0x80, 0x04, // F=1, PT 5, offset 160, length 4
+            0x00, // F=0, PT 0
+            1, 2, 3, 4, // redundant
+            9, 8, 7, // primary
+        ]);
+        let red = RedPayload::parse(&payload).unwrap();
+        assert_eq!(
+            red.redundant,
+            [RedBlock {
+                payload_type: 5,
+                timestamp_offset: 160,
+                data: Bytes::from_static(&[1, 2, 3, 4]),
+            }]
+        );
+        assert_eq!(
+            red.primary,
+            RedBlock {
+                payload_type: 0,
+                timestamp_offset: 0,
+                data: Bytes::from_static(&[9, 8, 7]),
+            }
+        );
+    }
+
+    #[test]
+    fn reads_the_largest_offset_and_length() {
+        let payload =
+            Bytes::from([&[0xE1, 0xFF, 0xFF, 0xFF, 0x61][..], &[0xAA; 1023], &[0xBB]].concat());
+        let red = RedPayload::parse(&payload).unwrap();
+        assert_eq!(red.redundant[0].payload_type, 97);
+        assert_eq!(red.redundant[0].timestamp_offset, 0x3FFF);
+        assert_eq!(red.redundant[0].data.len(), 1023);
+        assert_eq!(red.primary.data, [0xBB][..]);
+    }
+
+    #[test]
+    fn primary_alone_may_be_empty() {
+        let red = RedPayload::parse(&Bytes::from_static(&[0x00])).unwrap();
+        assert_eq!(red.redundant.len(), 0);
+        assert_eq!(red.primary.payload_type, 0);
+        assert_eq!(red.primary.data.len(), 0);
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        for payload in [
+            &[][..],
+            // Only redundant headers.
+            &[0x85, 0x02, 0x80, 0x04],
+            // A header cut short.
+            &[0x85, 0x02],
+            // A block longer than what follows.
+            &[0x85, 0x02, 0x80, 0x04, 0x00, 1, 2, 3],
+        ] {
+            assert_eq!(RedPayload::parse(&Bytes::copy_from_slice(payload)), None);
+        }
+    }
+
+    #[test]
+    fn numbers_redundant_blocks_before_the_primary() {
+        let payload = Bytes::from_static(&[0x80, 0x01, 0x40, 0x00, 0x80, 0x00, 0xA0, 0x00, 0x00]);
+        let red = RedPayload::parse(&payload).unwrap();
+        assert_eq!(red.redundant.len(), 2);
+        let numbers: Vec<_> = red.redundant_sequence_numbers(1).collect();
+        assert_eq!(numbers, [65535, 0]);
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+use crate::publisher::ChunkPublisher;
+use crate::rtp_receiver::{handle_packet, handle_rtcp};
+use crate::stream_manager::{StreamManager, StreamManagerConfig};
+use shared_types::pcap::{self, UdpDatagram};
+use shared_types::{EndReason, StreamEvent};
+
+const JITTER_DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+const LIFECYCLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Feeds the RTP and RTCP in a pcap or pcapng capture straight into a
+/// stream manager, as if each datagram had just arrived from its original
+/// sender. Datagrams are spaced as they were captured, divided by `speed`.
+/// Streams still open when the capture runs out end with
+/// [`EndReason::ReplayEnded`]. Nothing is sent back to the senders.
+pub async fn run(
+    path: &Path,
+    speed: f64,
+    config: StreamManagerConfig,
+    events: broadcast::Sender<StreamEvent>,
+    publisher: Option<ChunkPublisher>,
+) -> Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let datagrams: Vec<UdpDatagram> = pcap::read_udp(&data)?
+        .into_iter()
+        .filter(|datagram| datagram.rtp_ssrc().is_some() || datagram.is_rtcp())
+        .collect();
+    for flow in pcap::rtp_flows(&datagrams) {
+        info!(
+            "Replaying SSRC {} from {} to {}: {} packet(s) from seq {}",
+            flow.ssrc, flow.source, flow.destination, flow.packets, flow.first_sequence
+        );
+    }
+    // RTCP sent to the port its RTP went to was multiplexed with it.
+    let rtp_destinations: HashSet<_> = datagrams
+        .iter()
+        .filter(|datagram| datagram.rtp_ssrc().is_some())
+        .map(|datagram| datagram.destination)
+        .collect();
+
+    let mut manager = StreamManager::with_events(config, events);
+    let mut drain_interval = time::interval(JITTER_DRAIN_INTERVAL);
+    drain_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    let mut lifecycle_interval = time::interval(LIFECYCLE_CHECK_INTERVAL);
+    lifecycle_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+    let start = Instant::now();
+    let first = datagrams.first().map(|datagram| datagram.time);
+    for datagram in &datagrams {
+        let offset = first
+            .and_then(|first| (datagram.time - first).to_std().ok())
+            .unwrap_or_default();
+        let due = start + offset.div_f64(speed);
+        loop {
+            tokio::select! {
+                () = time::sleep_until(due) => break,
+                _ = drain_interval.tick() => {
+                    manager.drain_jitter_buffers(std::time::Instant::now());
+                }
+                _ = lifecycle_interval.tick() => {
+                    manager.expire_idle_streams(std::time::Instant::now());
+                }
+            }
+            publish(&mut manager, publisher.as_ref()).await;
+        }
+
+        let result = if datagram.is_rtcp() {
+            let rtcp_mux = rtp_destinations.contains(&datagram.destination);
+            handle_rtcp(&mut manager, &datagram.payload, datagram.source, rtcp_mux)
+        } else {
+            handle_packet(&mut manager, &datagram.payload, datagram.source, false)
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to handle replayed packet from {}: {}",
+                datagram.source, e
+            );
+        }
+        publish(&mut manager, publisher.as_ref()).await;
+    }
+
+    manager.end_all_streams(EndReason::ReplayEnded);
+    publish(&mut manager, publisher.as_ref()).await;
+    info!(
+        "Replayed {} datagram(s) from {} in {:?}",
+        datagrams.len(),
+        path.display(),
+        start.elapsed()
+    );
+    if let Some(publisher) = publisher {
+        publisher.close().await;
+    }
+    Ok(())
+}
+
+async fn publish(manager: &mut StreamManager, publisher: Option<&ChunkPublisher>) {
+    let released = manager.take_released();
+    if let Some(publisher) = publisher
+        && !released.is_empty()
+    {
+        publisher.send(released).await;
+    }
+}
@@ -0,0 +1,302 @@
+use anyhow::{Result, bail};
+use bytes::{Buf, Bytes};
+use chrono::{DateTime, TimeDelta, Utc};
+use rtcp::goodbye::Goodbye;
+use rtcp::header::PacketType;
+use rtcp::raw_packet::RawPacket;
+use rtcp::receiver_report::ReceiverReport;
+use rtcp::sender_report::SenderReport;
+use rtcp::source_description::{SdesType, SourceDescription};
+use std::time::Instant;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// A parsed RTCP packet from a compound datagram.
+#[derive(Debug, Clone)]
+pub enum RtcpPacket {
+    SenderReport(SenderReport),
+    ReceiverReport(ReceiverReport),
+    SourceDescription(SourceDescription),
+    Goodbye(Goodbye),
+    App(AppPacket),
+    Other(PacketType),
+}
+
+/// RFC 3550 section 6.7 application-defined packet. The rtcp crate leaves
+/// these as raw bytes, so they are decoded here.
+#[derive(Debug, Clone)]
+pub struct AppPacket {
+    pub subtype: u8,
+    pub ssrc: u32,
+    pub name: [u8; 4],
+    pub data: Bytes,
+}
+
+/// RFC 5761 section 4: RTP and RTCP sharing a port are told apart by the
+/// second octet, which for RTCP is a packet type in the 192..=223 range.
+pub fn is_rtcp(data: &[u8]) -> bool {
+    data.len() >= 2 && (192..=223).contains(&data[1])
+}
+
+pub fn parse_compound(data: &[u8]) -> Result<Vec<RtcpPacket>> {
+    let mut raw = Bytes::copy_from_slice(data);
+    let packets = rtcp::packet::unmarshal(&mut raw)?;
+
+    packets
+        .iter()
+        .map(|packet| {
+            let any = packet.as_any();
+            if let Some(sr) = any.downcast_ref::<SenderReport>() {
+                Ok(RtcpPacket::SenderReport(sr.clone()))
+            } else if let Some(rr) = any.downcast_ref::<ReceiverReport>() {
+                Ok(RtcpPacket::ReceiverReport(rr.clone()))
+            } else if let Some(sdes) = any.downcast_ref::<SourceDescription>() {
+                Ok(RtcpPacket::SourceDescription(sdes.clone()))
+            } else if let Some(bye) = any.downcast_ref::<Goodbye>() {
+                Ok(RtcpPacket::Goodbye(bye.clone()))
+            } else if let Some(raw) = any.downcast_ref::<RawPacket>()
+                && packet.header().packet_type == PacketType::ApplicationDefined
+            {
+                parse_app(&raw.0).map(RtcpPacket::App)
+            } else {
+                Ok(RtcpPacket::Other(packet.header().packet_type))
+            }
+        })
+        .collect()
+}
+
+fn parse_app(raw: &[u8]) -> Result<AppPacket> {
+    if raw.len() < 12 {
+        bail!("APP packet too short: {} bytes", raw.len());
+    }
+    let mut buf = raw;
+    let subtype = buf.get_u8() & 0x1f;
+    buf.advance(3);
+    let ssrc = buf.get_u32();
+    let mut name = [0u8; 4];
+    buf.copy_to_slice(&mut name);
+
+    Ok(AppPacket {
+        subtype,
+        ssrc,
+        name,
+        data: Bytes::copy_from_slice(buf),
+    })
+}
+
+/// Returns the CNAME carried for `ssrc` in an SDES packet, if any.
+pub fn sdes_cname(sdes: &SourceDescription, ssrc: u32) -> Option<String> {
+    sdes.chunks
+        .iter()
+        .filter(|chunk| chunk.source == ssrc)
+        .flat_map(|chunk| &chunk.items)
+        .find(|item| item.sdes_type == SdesType::SdesCname)
+        .map(|item| String::from_utf8_lossy(&item.text).into_owned())
+}
+
+/// Converts a 64-bit NTP timestamp to wall-clock time.
+pub fn ntp_to_datetime(ntp_time: u64) -> Option<DateTime<Utc>> {
+    let seconds = (ntp_time >> 32).checked_sub(NTP_UNIX_OFFSET)?;
+    let nanos = ((ntp_time & 0xffff_ffff) * 1_000_000_000) >> 32;
+    DateTime::from_timestamp(i64::try_from(seconds).ok()?, u32::try_from(nanos).ok()?)
+}
+
+/// The NTP/RTP timestamp pair from the most recent sender report, which maps
+/// the sender's media clock onto wall-clock time.
+#[derive(Debug, Clone, Copy)]
+pub struct SenderClock {
+    pub ntp_time: u64,
+    pub rtp_time: u32,
+    /// When the report arrived, for the DLSR field of our receiver reports.
+    pub received_at: Instant,
+}
+
+impl SenderClock {
+    pub fn wallclock(&self, rtp_timestamp: u32, clock_rate: u32) -> Option<DateTime<Utc>> {
+        if clock_rate == 0 {
+            return None;
+        }
+        #[allow(clippy::cast_possible_wrap)]
+        let offset = i64::from(rtp_timestamp.wrapping_sub(self.rtp_time) as i32);
+        let micros = offset * 1_000_000 / i64::from(clock_rate);
+        ntp_to_datetime(self.ntp_time)
+            .and_then(|base| base.checked_add_signed(TimeDelta::microseconds(micros)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One RTCP packet with its common header, `body` being everything
+    /// after the length word.
+    fn packet(count: u8, packet_type: u8, body: &[u8]) -> Vec<u8> {
+        assert!(body.len().is_multiple_of(4));
+        let words = u16::try_from(body.len() / 4).unwrap();
+        let mut packet = vec![0x80 | count, packet_type];
+        packet.extend_from_slice(&words.to_be_bytes());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn sender_report() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0x1122_3344u32.to_be_bytes());
+        body.extend_from_slice(&0xe8a4_c1a0_8000_0000u64.to_be_bytes());
+        body.extend_from_slice(&160_000u32.to_be_bytes());
+        body.extend_from_slice(&1000u32.to_be_bytes());
+        body.extend_from_slice(&160_000u32.to_be_bytes());
+        // One report block about 0x55667788.
+        body.extend_from_slice(&0x5566_7788u32.to_be_bytes());
+        body.extend_from_slice(&[64, 0, 0, 12]);
+        body.extend_from_slice(&0x0001_0010u32.to_be_bytes());
+        body.extend_from_slice(&42u32.to_be_bytes());
+        body.extend_from_slice(&0xc1a0_8000u32.to_be_bytes());
+        body.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        packet(1, 200, &body)
+    }
+
+    fn sdes(ssrc: u32, cname: &[u8]) -> Vec<u8> {
+        let mut body = ssrc.to_be_bytes().to_vec();
+        body.push(1);
+        body.push(u8::try_from(cname.len()).unwrap());
+        body.extend_from_slice(cname);
+        // The item list ends with a null octet, padded to a word.
+        body.push(0);
+        while !body.len().is_multiple_of(4) {
+            body.push(0);
+        }
+        packet(1, 202, &body)
+    }
+
+    fn bye(ssrc: u32, reason: &[u8]) -> Vec<u8> {
+        let mut body = ssrc.to_be_bytes().to_vec();
+        body.push(u8::try_from(reason.len()).unwrap());
+        body.extend_from_slice(reason);
+        while !body.len().is_multiple_of(4) {
+            body.push(0);
+        }
+        packet(1, 203, &body)
+    }
+
+    #[test]
+    fn walks_a_compound_packet() {
+        let mut data = sender_report();
+        data.extend(sdes(0x1122_3344, b"alice@example.com"));
+        data.extend(bye(0x1122_3344, b"done"));
+        assert!(is_rtcp(&data));
+
+        let packets = parse_compound(&data).unwrap();
+        let [
+            RtcpPacket::SenderReport(sr),
+            RtcpPacket::SourceDescription(sdes),
+            RtcpPacket::Goodbye(bye),
+        ] = &packets[..]
+        else {
+            panic!("unexpected packets {packets:?}");
+        };
+
+        assert_eq!(sr.ssrc, 0x1122_3344);
+        assert_eq!(sr.ntp_time, 0xe8a4_c1a0_8000_0000);
+        assert_eq!(sr.rtp_time, 160_000);
+        assert_eq!(sr.packet_count, 1000);
+        let report = &sr.reports[0];
+        assert_eq!(report.ssrc, 0x5566_7788);
+        assert_eq!(report.fraction_lost, 64);
+        assert_eq!(report.total_lost, 12);
+        assert_eq!(report.last_sequence_number, 0x0001_0010);
+        assert_eq!(report.jitter, 42);
+        assert_eq!(report.last_sender_report, 0xc1a0_8000);
+        assert_eq!(report.delay, 0x0001_0000);
+
+        assert_eq!(
+            sdes_cname(sdes, 0x1122_3344).as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(sdes_cname(sdes, 0x5566_7788), None);
+        assert_eq!(bye.sources, [0x1122_3344]);
+        assert_eq!(&bye.reason[..], b"done");
+    }
+
+    #[test]
+    fn reads_receiver_reports_and_app_packets() {
+        let mut data = packet(0, 201, &0x0a0b_0c0du32.to_be_bytes());
+        let mut app = 0x0a0b_0c0du32.to_be_bytes().to_vec();
+        app.extend_from_slice(b"NAVI");
+        app.extend_from_slice(&[1, 2, 3, 4]);
+        data.extend(packet(5, 204, &app));
+
+        let packets = parse_compound(&data).unwrap();
+        let [RtcpPacket::ReceiverReport(rr), RtcpPacket::App(app)] = &packets[..] else {
+            panic!("unexpected packets {packets:?}");
+        };
+        assert_eq!(rr.ssrc, 0x0a0b_0c0d);
+        assert_eq!(rr.reports.len(), 0);
+        assert_eq!(app.subtype, 5);
+        assert_eq!(app.ssrc, 0x0a0b_0c0d);
+        assert_eq!(&app.name, b"NAVI");
+        assert_eq!(&app.data[..], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn accepts_padding_on_the_last_packet() {
+        let mut data = packet(0, 201, &0x0a0b_0c0du32.to_be_bytes());
+        let mut padded = bye(0x0a0b_0c0d, b"");
+        padded[0] |= 0x20;
+        padded[3] += 1;
+        padded.extend_from_slice(&[0, 0, 0, 4]);
+        data.extend(padded);
+
+        let packets = parse_compound(&data).unwrap();
+        let [RtcpPacket::ReceiverReport(_), RtcpPacket::Goodbye(bye)] = &packets[..] else {
+            panic!("unexpected packets {packets:?}");
+        };
+        assert_eq!(bye.sources, [0x0a0b_0c0d]);
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let mut data = sender_report();
+        data.extend(sdes(0x1122_3344, b"alice@example.com"));
+        for length in [0, 3, 7, 24, data.len() - 1] {
+            assert!(parse_compound(&data[..length]).is_err(), "{length} bytes");
+        }
+        // Too short for the APP header it claims.
+        assert!(parse_compound(&packet(0, 204, &[0; 4])).is_err());
+        assert!(parse_app(&[0x80, 204, 0, 1, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn tells_rtcp_from_rtp_on_a_shared_port() {
+        assert!(is_rtcp(&[0x80, 200]));
+        assert!(is_rtcp(&[0x80, 223]));
+        // RTP with the marker bit set and payload types 0 and 96.
+        assert!(!is_rtcp(&[0x80, 0x80]));
+        assert!(!is_rtcp(&[0x80, 0xe0]));
+        assert!(!is_rtcp(&[0x80]));
+    }
+
+    #[test]
+    fn maps_sender_clocks_onto_wall_clock_time() {
+        // 2024-01-01T00:00:00Z and a half.
+        let ntp_time = ((1_704_067_200 + NTP_UNIX_OFFSET) << 32) | 0x8000_0000;
+        assert_eq!(
+            ntp_to_datetime(ntp_time).unwrap().to_rfc3339(),
+            "2024-01-01T00:00:00.500+00:00"
+        );
+        assert_eq!(ntp_to_datetime(0), None);
+
+        let clock = SenderClock {
+            ntp_time,
+            rtp_time: u32::MAX - 799,
+            received_at: Instant::now(),
+        };
+        // 160 ms on, across the RTP timestamp wrapping, and 100 ms before.
+        let later = clock.wallclock(480, 8000).unwrap();
+        assert_eq!(later.to_rfc3339(), "2024-01-01T00:00:00.660+00:00");
+        let earlier = clock.wallclock(u32::MAX - 1599, 8000).unwrap();
+        assert_eq!(earlier.to_rfc3339(), "2024-01-01T00:00:00.400+00:00");
+        assert_eq!(clock.wallclock(480, 0), None);
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::Result;
+use bytes::Bytes;
+use rand::Rng;
+use rtcp::goodbye::Goodbye;
+use rtcp::packet::Packet;
+use rtcp::receiver_report::ReceiverReport;
+use rtcp::reception_report::ReceptionReport;
+use rtcp::source_description::{
+    SdesType, SourceDescription, SourceDescriptionChunk, SourceDescriptionItem,
+};
+use std::time::{Duration, Instant};
+
+use shared_types::ReceptionStats;
+
+/// Minimum report interval from RFC 3550 section 6.2.
+const RTCP_MIN_TIME: Duration = Duration::from_secs(5);
+/// Fraction of the session bandwidth given over to RTCP.
+const RTCP_BANDWIDTH_FRACTION: f64 = 0.05;
+/// Share of the RTCP bandwidth reserved for senders when they are a minority.
+const RTCP_SENDER_BANDWIDTH_FRACTION: f64 = 0.25;
+/// Compensates for the timer reconsideration algorithm converging below the
+/// intended average (RFC 3550 section 6.3.1).
+const COMPENSATION: f64 = std::f64::consts::E - 1.5;
+/// IPv4 + UDP header overhead counted into the average RTCP packet size.
+const UDP_IP_OVERHEAD: usize = 28;
+
+#[derive(Debug, Clone)]
+pub struct RtcpReportConfig {
+    /// Session bandwidth in bits per second that the RTCP share is taken from.
+    pub session_bandwidth: u32,
+    /// SDES CNAME sent with every report.
+    pub cname: String,
+}
+
+impl Default for RtcpReportConfig {
+    fn default() -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+        Self {
+            // G.711 at 50 packets per second including IP/UDP/RTP headers.
+            session_bandwidth: 80_000,
+            cname: format!("navitel-rtp-ingest@{host}"),
+        }
+    }
+}
+
+/// Per-session RTCP transmission timer following RFC 3550 Appendix A.7.
+pub struct RtcpTimer {
+    avg_rtcp_size: f64,
+    initial: bool,
+    next_report: Instant,
+}
+
+impl RtcpTimer {
+    pub fn new(now: Instant, session_bandwidth: u32) -> Self {
+        let mut timer = Self {
+            avg_rtcp_size: 128.0,
+            initial: true,
+            next_report: now,
+        };
+        timer.schedule(now, session_bandwidth);
+        timer
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_report
+    }
+
+    /// Folds a sent or received compound packet into the average size.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn on_packet(&mut self, size: usize) {
+        let size = (size + UDP_IP_OVERHEAD) as f64;
+        self.avg_rtcp_size += (size - self.avg_rtcp_size) / 16.0;
+    }
+
+    pub fn schedule(&mut self, now: Instant, session_bandwidth: u32) {
+        // One remote sender and us as the only receiver.
+        self.next_report = now
+            + rtcp_interval(
+                2,
+                1,
+                f64::from(session_bandwidth) * RTCP_BANDWIDTH_FRACTION,
+                false,
+                self.avg_rtcp_size,
+                self.initial,
+            );
+        self.initial = false;
+    }
+}
+
+/// RFC 3550 Appendix A.7 `rtcp_interval`, returning a randomized interval.
+#[allow(clippy::cast_precision_loss)]
+pub fn rtcp_interval(
+    members: u32,
+    senders: u32,
+    rtcp_bw: f64,
+    we_sent: bool,
+    avg_rtcp_size: f64,
+    initial: bool,
+) -> Duration {
+    let mut min_time = RTCP_MIN_TIME.as_secs_f64();
+    if initial {
+        min_time /= 2.0;
+    }
+
+    let mut n = f64::from(members);
+    let mut rtcp_bw = rtcp_bw / 8.0;
+    if f64::from(senders) <= f64::from(members) * RTCP_SENDER_BANDWIDTH_FRACTION {
+        if we_sent {
+            rtcp_bw *= RTCP_SENDER_BANDWIDTH_FRACTION;
+            n = f64::from(senders);
+        } else {
+            rtcp_bw *= 1.0 - RTCP_SENDER_BANDWIDTH_FRACTION;
+            n -= f64::from(senders);
+        }
+    }
+
+    let t = if rtcp_bw > 0.0 {
+        (avg_rtcp_size * n / rtcp_bw).max(min_time)
+    } else {
+        min_time
+    };
+
+    let randomized = t * rand::thread_rng().gen_range(0.5..1.5);
+    Duration::from_secs_f64(randomized / COMPENSATION)
+}
+
+/// The middle 32 bits of an NTP timestamp, as carried in the LSR field.
+#[allow(clippy::cast_possible_truncation)]
+pub fn compact_ntp(ntp_time: u64) -> u32 {
+    (ntp_time >> 16) as u32
+}
+
+/// DLSR in units of 1/65536 seconds.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn delay_since(received_at: Instant, now: Instant) -> u32 {
+    let delay = now.saturating_duration_since(received_at).as_secs_f64() * 65536.0;
+    delay.min(f64::from(u32::MAX)) as u32
+}
+
+pub fn reception_report(stats: &ReceptionStats, lsr: u32, dlsr: u32) -> ReceptionReport {
+    // Cumulative loss is a signed 24-bit field.
+    let total_lost = stats.cumulative_lost.clamp(-0x80_0000, 0x7f_ffff);
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let total_lost = (total_lost as u32) & 0xff_ffff;
+
+    ReceptionReport {
+        ssrc: stats.ssrc,
+        fraction_lost: stats.fraction_lost,
+        total_lost,
+        last_sequence_number: stats.extended_highest_seq,
+        jitter: stats.jitter,
+        last_sender_report: lsr,
+        delay: dlsr,
+    }
+}
+
+/// Builds a compound RR + SDES CNAME packet.
+pub fn build_receiver_report(
+    reporter_ssrc: u32,
+    cname: &str,
+    reports: Vec<ReceptionReport>,
+) -> Result<Bytes> {
+    let packets: Vec<Box<dyn Packet + Send + Sync>> = vec![
+        Box::new(ReceiverReport {
+            ssrc: reporter_ssrc,
+            reports,
+            profile_extensions: Bytes::new(),
+        }),
+        Box::new(SourceDescription {
+            chunks: vec![SourceDescriptionChunk {
+                source: reporter_ssrc,
+                items: vec![SourceDescriptionItem {
+                    sdes_type: SdesType::SdesCname,
+                    text: Bytes::copy_from_slice(cname.as_bytes()),
+                }],
+            }],
+        }),
+    ];
+    Ok(rtcp::packet::marshal(&packets)?)
+}
+
+/// Builds a compound empty RR + BYE retiring one of our own SSRCs.
+pub fn build_bye(ssrc: u32, reason: &str) -> Result<Bytes> {
+    let packets: Vec<Box<dyn Packet + Send + Sync>> = vec![
+        Box::new(ReceiverReport {
+            ssrc,
+            reports: vec![],
+            profile_extensions: Bytes::new(),
+        }),
+        Box::new(Goodbye {
+            sources: vec![ssrc],
+            reason: Bytes::copy_from_slice(reason.as_bytes()),
+        }),
+    ];
+    Ok(rtcp::packet::marshal(&packets)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtcp_parser::{RtcpPacket, parse_compound, sdes_cname};
+    use crate::source_state::SourceState;
+
+    fn stats() -> ReceptionStats {
+        let mut source = SourceState::new(0x5566_7788, 8000, 65534);
+        for seq in [65534, 65535, 1] {
+            source.update_sequence(seq);
+        }
+        source.snapshot()
+    }
+
+    /// Samples of the interval for the same inputs, in seconds.
+    fn intervals(members: u32, senders: u32, we_sent: bool, initial: bool) -> (f64, f64) {
+        (0..500)
+            .map(|_| rtcp_interval(members, senders, 4000.0, we_sent, 128.0, initial).as_secs_f64())
+            .fold((f64::MAX, 0.0), |(min, max), t| (min.min(t), max.max(t)))
+    }
+
+    fn assert_spread(members: u32, senders: u32, we_sent: bool, initial: bool, t: f64) {
+        let (min, max) = intervals(members, senders, we_sent, initial);
+        let (low, high) = (t * 0.5 / COMPENSATION, t * 1.5 / COMPENSATION);
+        assert!(
+            min >= low && max <= high,
+            "{min}..{max} outside {low}..{high}"
+        );
+        // Randomised across the range rather than fixed.
+        assert!(max - min > (high - low) / 2.0, "{min}..{max} barely varies");
+    }
+
+    #[test]
+    fn reports_reception_statistics() {
+        let report = reception_report(&stats(), 0xc1a0_8000, 98304);
+        assert_eq!(report.ssrc, 0x5566_7788);
+        // One lost of four expected, across the wrap.
+        assert_eq!(report.fraction_lost, 64);
+        assert_eq!(report.total_lost, 1);
+        assert_eq!(report.last_sequence_number, 0x0001_0001);
+        assert_eq!(report.last_sender_report, 0xc1a0_8000);
+        assert_eq!(report.delay, 98304);
+    }
+
+    #[test]
+    fn clamps_cumulative_loss_to_24_bits() {
+        let mut stats = stats();
+        for (lost, field) in [
+            (0x7f_ffff, 0x7f_ffff),
+            (0x100_0000, 0x7f_ffff),
+            (-1, 0xff_ffff),
+            (-0x80_0000, 0x80_0000),
+            (-0x100_0000, 0x80_0000),
+        ] {
+            stats.cumulative_lost = lost;
+            assert_eq!(reception_report(&stats, 0, 0).total_lost, field, "{lost}");
+        }
+    }
+
+    #[test]
+    fn converts_sender_report_times() {
+        assert_eq!(compact_ntp(0xe8a4_c1a0_8000_1234), 0xc1a0_8000);
+
+        let received_at = Instant::now();
+        let later = received_at + Duration::from_millis(1500);
+        assert_eq!(delay_since(received_at, later), 98304);
+        assert_eq!(delay_since(later, received_at), 0);
+    }
+
+    #[test]
+    fn randomises_the_report_interval_above_the_minimum() {
+        // Two members leave the 5 s minimum in charge, halved at the start.
+        assert_spread(2, 1, false, false, 5.0);
+        assert_spread(2, 1, false, true, 2.5);
+        // Receivers share 75% of 500 B/s among the 999 of them that are
+        // not senders; a sender gets 25% to itself.
+        assert_spread(1000, 1, false, false, 128.0 * 999.0 / 375.0);
+        assert_spread(1000, 1, true, false, 5.0);
+    }
+
+    #[test]
+    fn falls_back_to_the_minimum_without_bandwidth() {
+        for _ in 0..100 {
+            let t = rtcp_interval(2, 1, 0.0, false, 128.0, false).as_secs_f64();
+            assert!((2.5 / COMPENSATION..=7.5 / COMPENSATION).contains(&t));
+        }
+    }
+
+    #[test]
+    fn schedules_the_first_report_early() {
+        let now = Instant::now();
+        let mut timer = RtcpTimer::new(now, 80_000);
+        assert!(!timer.is_due(now));
+        assert!(timer.is_due(now + Duration::from_secs_f64(1.5 * 2.5 / COMPENSATION)));
+
+        timer.on_packet(100);
+        assert!((timer.avg_rtcp_size - 128.0).abs() < f64::EPSILON);
+        timer.schedule(now, 80_000);
+        assert!(!timer.is_due(now + Duration::from_secs_f64(0.5 * 5.0 / COMPENSATION)));
+    }
+
+    #[test]
+    fn builds_packets_that_parse_back() {
+        let report = reception_report(&stats(), 0, 0);
+        let packet =
+            build_receiver_report(0x0a0b_0c0d, "navitel@test", vec![report.clone()]).unwrap();
+        let packets = parse_compound(&packet).unwrap();
+        let [
+            RtcpPacket::ReceiverReport(rr),
+            RtcpPacket::SourceDescription(sdes),
+        ] = &packets[..]
+        else {
+            panic!("unexpected packets {packets:?}");
+        };
+        assert_eq!(rr.ssrc, 0x0a0b_0c0d);
+        assert_eq!(rr.reports, [report]);
+        assert_eq!(
+            sdes_cname(sdes, 0x0a0b_0c0d).as_deref(),
+            Some("navitel@test")
+        );
+
+        let packet = build_bye(0x0a0b_0c0d, "SSRC collision").unwrap();
+        let packets = parse_compound(&packet).unwrap();
+        let [RtcpPacket::ReceiverReport(rr), RtcpPacket::Goodbye(bye)] = &packets[..] else {
+            panic!("unexpected packets {packets:?}");
+        };
+        assert_eq!(rr.reports.len(), 0);
+        assert_eq!(bye.sources, [0x0a0b_0c0d]);
+        assert_eq!(&bye.reason[..], b"SSRC collision");
+    }
+}
@@ -1,99 +1,883 @@
-use anyhow::Result;
-use bytes::BytesMut;
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
 use rtp::packet::Packet;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, RwLock, broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio::time;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tracing::{debug, error, info, warn};
 use webrtc_util::marshal::Unmarshal;
 
-use crate::stream_manager::StreamManager;
-use shared_types::{AudioChunk, AudioFormat, LatencyMetadata};
+use crate::capture::PacketCapture;
+use crate::comfort_noise;
+use crate::dtls::DtlsEvent;
+use crate::header_extensions;
+use crate::publisher::ChunkPublisher;
+use crate::red::RedPayload;
+use crate::rtcp_parser;
+use crate::stream_manager::{PayloadKind, RelatchPolicy, StreamManager, StreamManagerConfig};
+use crate::udp_batch::BatchReceiver;
+use crate::webrtc_transport::{Inbound, WebRtcTransport};
+use shared_types::{
+    AudioChunk, AudioFormat, ChunkKind, EndReason, HeaderExtensions, LatencyMetadata, StreamEvent,
+    StreamId,
+};
 
 const MAX_PACKET_SIZE: usize = 1500;
+const JITTER_DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+const RTCP_REPORT_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const LIFECYCLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// RTCP frames queued for one TCP connection before reports are dropped.
+const TCP_SEND_QUEUE: usize = 16;
 
-pub struct RtpReceiver {
+/// Outgoing frame queues of the open RFC 4571 connections, by peer.
+type TcpPeers = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
+/// Held across a synchronous batch, so not the async kind.
+pub type SharedWebRtc = Arc<std::sync::Mutex<WebRtcTransport>>;
+
+/// How the RTP port is read.
+#[derive(Debug, Clone, Copy)]
+pub struct ReceiveConfig {
+    /// Sockets bound to the RTP port with `SO_REUSEPORT`, each read by its
+    /// own task into its own share of the stream state.
+    pub workers: usize,
+    /// Datagrams taken per system call; 1 reads one packet at a time.
+    pub batch_size: usize,
+}
+
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            batch_size: 32,
+        }
+    }
+}
+
+/// One worker's socket and the streams that arrive on it. The kernel picks
+/// a `SO_REUSEPORT` socket by hashing the sender's address, so a sender's
+/// packets always reach the same shard and workers never share a lock.
+#[derive(Clone)]
+struct Shard {
     socket: Arc<UdpSocket>,
     stream_manager: Arc<RwLock<StreamManager>>,
+    publisher: Option<ChunkPublisher>,
+    webrtc: Option<SharedWebRtc>,
+    capture: Option<PacketCapture>,
+}
+
+impl Shard {
+    /// Runs `f` on the shard's streams, then forwards the chunks it
+    /// released once the lock is dropped.
+    async fn update<R>(&self, f: impl FnOnce(&mut StreamManager) -> R) -> R {
+        let (result, released) = {
+            let mut manager = self.stream_manager.write().await;
+            let result = f(&mut manager);
+            (result, manager.take_released())
+        };
+        if let Some(publisher) = &self.publisher
+            && !released.is_empty()
+        {
+            publisher.send(released).await;
+        }
+        result
+    }
+}
+
+pub struct RtpReceiver {
+    shards: Vec<Shard>,
+    rtcp_socket: Arc<UdpSocket>,
+    tcp_listener: Option<TcpListener>,
+    tcp_peers: TcpPeers,
+    batch_size: usize,
+    capture: Option<PacketCapture>,
 }
 
 impl RtpReceiver {
-    pub async fn new(bind_addr: SocketAddr) -> Result<Self> {
-        let socket = UdpSocket::bind(bind_addr).await?;
-        info!("RTP receiver listening on {}", bind_addr);
+    /// Binds RTP on `bind_addr` and RTCP on the next port up, publishing
+    /// stream events onto `events`. RTCP arriving on the RTP port
+    /// (rtcp-mux) is demultiplexed as well. An odd RTP port is refused.
+    pub async fn bind(
+        bind_addr: SocketAddr,
+        config: StreamManagerConfig,
+        events: broadcast::Sender<StreamEvent>,
+        receive: ReceiveConfig,
+    ) -> Result<Self> {
+        let workers = worker_count(receive.workers);
+        // A rebound sender usually hashes to another worker, whose streams
+        // are its own, so the stream could not follow it there.
+        if workers > 1 && config.relatch != RelatchPolicy::NewStream {
+            bail!("re-latching streams needs a single worker, not {workers}");
+        }
+        // RTCP goes on the port above, so RTP takes the even one (RFC 3550).
+        if !bind_addr.port().is_multiple_of(2) {
+            bail!("RTP needs an even port, not {}", bind_addr.port());
+        }
+        // Only share the port when sharding, so that a port already in use
+        // still fails to bind.
+        let first = bind_udp(bind_addr, workers > 1)?;
+        let bind_addr = first.local_addr()?;
+        let mut sockets = vec![first];
+        for _ in 1..workers {
+            sockets.push(bind_udp(bind_addr, true)?);
+        }
+        info!(
+            "RTP receiver listening on {} with {} worker(s), {} datagram(s) per read",
+            bind_addr, workers, receive.batch_size
+        );
+
+        let Some(rtcp_port) = bind_addr.port().checked_add(1) else {
+            bail!("no port above {bind_addr} for RTCP");
+        };
+        let mut rtcp_addr = bind_addr;
+        rtcp_addr.set_port(rtcp_port);
+        let rtcp_socket = UdpSocket::bind(rtcp_addr).await?;
+        info!("RTCP receiver listening on {}", rtcp_addr);
+
+        let shards = sockets
+            .into_iter()
+            .map(|socket| Shard {
+                socket: Arc::new(socket),
+                stream_manager: Arc::new(RwLock::new(StreamManager::with_events(
+                    config.clone(),
+                    events.clone(),
+                ))),
+                publisher: None,
+                webrtc: None,
+                capture: None,
+            })
+            .collect();
 
         Ok(Self {
-            socket: Arc::new(socket),
-            stream_manager: Arc::new(RwLock::new(StreamManager::new())),
+            shards,
+            rtcp_socket: Arc::new(rtcp_socket),
+            tcp_listener: None,
+            tcp_peers: Arc::default(),
+            batch_size: receive.batch_size,
+            capture: None,
         })
     }
 
+    /// Also accepts RTP and RTCP framed over TCP per RFC 4571 on `bind_addr`,
+    /// for senders whose firewalls will not pass UDP.
+    pub async fn listen_tcp(mut self, bind_addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        info!("RTP over TCP (RFC 4571) listening on {}", bind_addr);
+        self.tcp_listener = Some(listener);
+        Ok(self)
+    }
+
+    /// Forwards the audio of every stream to `publisher`.
+    #[must_use]
+    pub fn with_publisher(mut self, publisher: Option<&ChunkPublisher>) -> Self {
+        for shard in &mut self.shards {
+            shard.publisher = publisher.cloned();
+        }
+        self
+    }
+
+    /// Offers every UDP datagram received to `capture`, before anything
+    /// else looks at it.
+    #[must_use]
+    pub fn with_capture(mut self, capture: Option<&PacketCapture>) -> Self {
+        for shard in &mut self.shards {
+            shard.capture = capture.cloned();
+        }
+        self.capture = capture.cloned();
+        self
+    }
+
+    /// Makes this a WebRTC peer's media port: ICE checks are answered, DTLS
+    /// is handshaken to key SRTP, and only media from checked addresses is
+    /// taken.
+    #[must_use]
+    pub fn with_webrtc(mut self, transport: &SharedWebRtc) -> Self {
+        for shard in &mut self.shards {
+            shard.webrtc = Some(Arc::clone(transport));
+        }
+        self
+    }
+
+    /// Ends every stream this receiver knows about, e.g. when its call hangs up.
+    pub async fn end_streams(&self, reason: EndReason) {
+        for shard in &self.shards {
+            shard
+                .update(|manager| manager.end_all_streams(reason))
+                .await;
+        }
+    }
+
+    pub async fn end_stream(&self, stream_id: StreamId, reason: EndReason) {
+        for shard in &self.shards {
+            shard
+                .update(|manager| manager.end_stream(stream_id, reason))
+                .await;
+        }
+    }
+
+    /// Runs the workers, and handles RTCP on its own port and TCP
+    /// connections here. Dropping the future stops the workers too.
     pub async fn run(&self) -> Result<()> {
-        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let mut workers = JoinSet::new();
+        for shard in &self.shards {
+            workers.spawn(run_worker(
+                shard.clone(),
+                shard.socket.local_addr()?,
+                Arc::clone(&self.rtcp_socket),
+                Arc::clone(&self.tcp_peers),
+                self.batch_size,
+            ));
+        }
 
+        let rtcp_addr = self.rtcp_socket.local_addr()?;
+        let mut rtcp_buf = vec![0u8; MAX_PACKET_SIZE];
         loop {
-            match self.socket.recv_from(&mut buf).await {
-                Ok((len, source_addr)) => {
-                    let packet_data = &buf[..len];
-                    if let Err(e) = self.handle_packet(packet_data, source_addr).await {
-                        warn!("Failed to handle packet from {}: {}", source_addr, e);
+            tokio::select! {
+                result = self.rtcp_socket.recv_from(&mut rtcp_buf) => match result {
+                    Ok((len, source_addr)) => {
+                        let data = &rtcp_buf[..len];
+                        if let Some(capture) = &self.capture
+                            && capture.is_armed()
+                        {
+                            capture.record(data, source_addr, rtcp_addr, SystemTime::now());
+                        }
+                        let shard = self.shard_for_rtcp(data).await;
+                        let result = shard
+                            .update(|manager| handle_rtcp(manager, data, source_addr, false))
+                            .await;
+                        if let Err(e) = result {
+                            warn!("Failed to handle RTCP packet from {}: {}", source_addr, e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to receive RTCP packet: {}", e);
+                    }
+                },
+                result = accept_tcp(self.tcp_listener.as_ref()) => match result {
+                    Ok((stream, peer)) => {
+                        info!("TCP media connection from {}", peer);
+                        tokio::spawn(serve_tcp(
+                            stream,
+                            peer,
+                            self.shard_for_peer(peer).clone(),
+                            Arc::clone(&self.tcp_peers),
+                        ));
+                    }
+                    Err(e) => {
+                        error!("Failed to accept TCP connection: {}", e);
+                    }
+                },
+                Some(result) = workers.join_next() => {
+                    result?;
+                }
+            }
+        }
+    }
+
+    /// RTCP on its own port comes from a different address than the RTP it
+    /// reports on, so find the shard by the sender's SSRC instead.
+    async fn shard_for_rtcp(&self, data: &[u8]) -> &Shard {
+        if self.shards.len() > 1
+            && let Some(ssrc) = data.get(4..8)
+        {
+            let ssrc = u32::from_be_bytes([ssrc[0], ssrc[1], ssrc[2], ssrc[3]]);
+            for shard in &self.shards {
+                if shard.stream_manager.read().await.has_source(ssrc) {
+                    return shard;
+                }
+            }
+        }
+        &self.shards[0]
+    }
+
+    fn shard_for_peer(&self, peer: SocketAddr) -> &Shard {
+        let index = usize::from(peer.port()) % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+/// Sharding relies on `SO_REUSEPORT`, which only Unix has; elsewhere one
+/// worker reads the port.
+fn worker_count(requested: usize) -> usize {
+    if cfg!(unix) {
+        requested.max(1)
+    } else {
+        if requested > 1 {
+            warn!("SO_REUSEPORT is unavailable, using 1 worker instead of {requested}");
+        }
+        1
+    }
+}
+
+fn bind_udp(bind_addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(bind_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    debug_assert!(!reuse_port, "SO_REUSEPORT needs Unix");
+    socket.set_nonblocking(true)?;
+    socket.bind(&bind_addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Reads one shard's socket in batches, taking the shard's lock once per
+/// batch, and runs the shard's timers.
+async fn run_worker(
+    shard: Shard,
+    local_addr: SocketAddr,
+    rtcp_socket: Arc<UdpSocket>,
+    tcp_peers: TcpPeers,
+    batch_size: usize,
+) {
+    let mut receiver = BatchReceiver::new(&shard.socket, batch_size);
+    let mut drain_interval = time::interval(JITTER_DRAIN_INTERVAL);
+    drain_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    let mut report_interval = time::interval(RTCP_REPORT_CHECK_INTERVAL);
+    report_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    let mut lifecycle_interval = time::interval(LIFECYCLE_CHECK_INTERVAL);
+    lifecycle_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            result = receiver.recv(&shard.socket) => match result {
+                Ok(datagrams) => {
+                    // Taken before waiting for the stream manager, so captures
+                    // show when the batch arrived rather than when it was handled.
+                    let arrived = SystemTime::now();
+                    let mut replies = Vec::new();
+                    let capture = shard.capture.as_ref().filter(|capture| capture.is_armed());
+                    shard
+                        .update(|manager| {
+                            for (data, source_addr) in datagrams {
+                                if let Some(capture) = capture {
+                                    capture.record(data, source_addr, local_addr, arrived);
+                                }
+                                if let Some(webrtc) = &shard.webrtc
+                                    && !demux_webrtc(manager, webrtc, data, source_addr, &mut replies)
+                                {
+                                    continue;
+                                }
+                                let result = if rtcp_parser::is_rtcp(data) {
+                                    handle_rtcp(manager, data, source_addr, true)
+                                } else {
+                                    handle_packet(manager, data, source_addr, false)
+                                };
+                                if let Err(e) = result {
+                                    warn!("Failed to handle packet from {}: {}", source_addr, e);
+                                }
+                            }
+                        })
+                        .await;
+                    for (reply, destination) in replies {
+                        if let Err(e) = shard.socket.send_to(&reply, destination).await {
+                            warn!("Failed to reply to {}: {}", destination, e);
+                        }
                     }
                 }
                 Err(e) => {
                     error!("Failed to receive packet: {}", e);
                 }
+            },
+            _ = drain_interval.tick() => {
+                shard
+                    .update(|manager| manager.drain_jitter_buffers(Instant::now()))
+                    .await;
+            }
+            _ = report_interval.tick() => {
+                send_receiver_reports(&shard, &rtcp_socket, &tcp_peers).await;
+            }
+            _ = lifecycle_interval.tick() => {
+                shard
+                    .update(|manager| manager.expire_idle_streams(Instant::now()))
+                    .await;
             }
         }
     }
+}
 
-    async fn handle_packet(&self, data: &[u8], source_addr: SocketAddr) -> Result<()> {
-        let packet = Packet::unmarshal(&mut BytesMut::from(data))?;
+async fn send_receiver_reports(shard: &Shard, rtcp_socket: &UdpSocket, tcp_peers: &TcpPeers) {
+    let reports = shard
+        .stream_manager
+        .write()
+        .await
+        .due_receiver_reports(Instant::now());
 
-        debug!(
-            "Received RTP packet: SSRC={}, Seq={}, TS={}, PT={}",
-            packet.header.ssrc,
-            packet.header.sequence_number,
-            packet.header.timestamp,
-            packet.header.payload_type
-        );
+    for report in reports {
+        if let Some(connection) = tcp_peers.lock().await.get(&report.destination) {
+            if connection.try_send(report.packet).is_err() {
+                warn!(
+                    "Dropping receiver report to {}: TCP send queue full",
+                    report.destination
+                );
+            }
+            continue;
+        }
+        let socket = if report.rtcp_mux {
+            &shard.socket
+        } else {
+            rtcp_socket
+        };
+        match socket.send_to(&report.packet, report.destination).await {
+            Ok(_) => debug!("Sent receiver report to {}", report.destination),
+            Err(e) => warn!(
+                "Failed to send receiver report to {}: {}",
+                report.destination, e
+            ),
+        }
+    }
+}
+
+/// Waits for the next RFC 4571 connection, or forever when TCP is off.
+async fn accept_tcp(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-        let mut manager = self.stream_manager.write().await;
-        let stream_id = manager.get_or_create_stream(source_addr, packet.header.ssrc);
+/// Reads RFC 4571 framed RTP and RTCP from one connection, which may carry
+/// any number of SSRCs, and writes our RTCP back over it. Every stream from
+/// the connection ends when it closes.
+async fn serve_tcp(stream: TcpStream, peer: SocketAddr, shard: Shard, tcp_peers: TcpPeers) {
+    // Each frame is preceded by its length as a 16-bit big-endian integer.
+    let codec = LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(usize::from(u16::MAX))
+        .new_codec();
+    let (mut frames_out, mut frames_in) = Framed::new(stream, codec).split();
 
-        let mut metadata = LatencyMetadata::new(stream_id);
-        metadata.start_stage("rtp_ingestion", "rtp-ingest");
+    let (sender, mut outgoing) = mpsc::channel::<Bytes>(TCP_SEND_QUEUE);
+    tcp_peers.lock().await.insert(peer, sender);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if let Err(e) = frames_out.send(frame).await {
+                warn!("Failed to send RTCP over TCP to {}: {}", peer, e);
+                break;
+            }
+        }
+    });
 
-        let audio_chunk = AudioChunk {
-            data: packet.payload,
-            format: detect_audio_format(packet.header.payload_type),
-            sequence_number: u32::from(packet.header.sequence_number),
-            timestamp: packet.header.timestamp,
-            metadata: metadata.clone(),
+    while let Some(frame) = frames_in.next().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("TCP media connection from {} failed: {}", peer, e);
+                break;
+            }
         };
+        let result = shard
+            .update(|manager| {
+                if rtcp_parser::is_rtcp(&frame) {
+                    handle_rtcp(manager, &frame, peer, true)
+                } else {
+                    handle_packet(manager, &frame, peer, true)
+                }
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Failed to handle framed packet from {}: {}", peer, e);
+        }
+    }
 
-        manager.process_audio_chunk(stream_id, &audio_chunk);
+    tcp_peers.lock().await.remove(&peer);
+    writer.abort();
+    shard
+        .update(|manager| manager.end_streams_from(peer, EndReason::ConnectionClosed))
+        .await;
+    info!("TCP media connection from {} closed", peer);
+}
 
-        metadata.end_stage();
-        debug!(
-            "Processed packet from {} in {:?}",
-            source_addr,
-            metadata.total_latency()
-        );
+/// Handles the ICE checks and DTLS a WebRTC peer sends alongside its media,
+/// queueing what goes back. Returns whether the datagram is media to go on
+/// to the streams.
+fn demux_webrtc(
+    manager: &mut StreamManager,
+    webrtc: &SharedWebRtc,
+    data: &[u8],
+    source_addr: SocketAddr,
+    replies: &mut Vec<(Vec<u8>, SocketAddr)>,
+) -> bool {
+    let inbound = webrtc
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .receive(data, source_addr);
+    match inbound {
+        Inbound::Media => true,
+        Inbound::Handled {
+            replies: outgoing,
+            event,
+        } => {
+            replies.extend(outgoing.into_iter().map(|reply| (reply, source_addr)));
+            match event {
+                Some(DtlsEvent::Connected(keys)) => manager.set_srtp(&keys),
+                Some(DtlsEvent::Closed) => {
+                    manager.end_streams_from(source_addr, EndReason::ConnectionClosed);
+                }
+                None => {}
+            }
+            false
+        }
+        Inbound::Dropped => false,
+    }
+}
 
-        Ok(())
+pub fn handle_rtcp(
+    manager: &mut StreamManager,
+    data: &[u8],
+    source_addr: SocketAddr,
+    rtcp_mux: bool,
+) -> Result<()> {
+    let Some(data) = manager.unprotect_rtcp(source_addr, data) else {
+        return Ok(());
+    };
+    let packets = rtcp_parser::parse_compound(&data)?;
+    debug!(
+        "Received RTCP compound packet from {} with {} packet(s)",
+        source_addr,
+        packets.len()
+    );
+
+    manager.process_rtcp(source_addr, packets, data.len(), rtcp_mux);
+    Ok(())
+}
+
+/// Handles one RTP packet. `framed` says it came over RFC 4571 TCP, where
+/// RTCP goes back over the same connection.
+pub fn handle_packet(
+    manager: &mut StreamManager,
+    data: &[u8],
+    source_addr: SocketAddr,
+    framed: bool,
+) -> Result<()> {
+    let arrival = Instant::now();
+    let Some(data) = manager.unprotect_rtp(source_addr, data) else {
+        return Ok(());
+    };
+    let raw = Bytes::copy_from_slice(&data);
+    let packet = Packet::unmarshal(&mut raw.clone())?;
+
+    debug!(
+        "Received RTP packet: SSRC={}, Seq={}, TS={}, PT={}",
+        packet.header.ssrc,
+        packet.header.sequence_number,
+        packet.header.timestamp,
+        packet.header.payload_type
+    );
+
+    let Some(stream_id) = manager.get_or_create_stream(
+        source_addr,
+        packet.header.ssrc,
+        packet.header.sequence_number,
+    ) else {
+        return Ok(());
+    };
+    if framed {
+        manager.mux_rtcp(stream_id);
+    }
+
+    let mut rebuilt = dispatch(manager, stream_id, &packet, &raw, false, arrival);
+    while let Some(raw) = rebuilt.pop() {
+        match Packet::unmarshal(&mut raw.clone()) {
+            Ok(packet) => {
+                rebuilt.extend(dispatch(manager, stream_id, &packet, &raw, true, arrival));
+            }
+            Err(e) => debug!("Discarding unparseable FEC recovery: {}", e),
+        }
     }
+
+    debug!(
+        "Processed packet from {} in {:?}",
+        source_addr,
+        arrival.elapsed()
+    );
+    Ok(())
 }
 
-fn detect_audio_format(payload_type: u8) -> AudioFormat {
-    match payload_type {
-        0 => AudioFormat::g711_ulaw_mono(),
-        111 => AudioFormat::opus_mono_48khz(),
-        _ => AudioFormat {
-            codec: shared_types::AudioCodec::Pcm,
-            sample_rate: 8000,
-            channels: 1,
-            bits_per_sample: 16,
-        },
+/// Routes one packet by payload type, unwrapping RED first. `recovered`
+/// says the packet was rebuilt from FEC rather than received. Returns any
+/// packets that FEC carried in this one let us rebuild.
+fn dispatch(
+    manager: &mut StreamManager,
+    stream_id: StreamId,
+    packet: &Packet,
+    raw: &Bytes,
+    recovered: bool,
+    arrival: Instant,
+) -> Vec<Bytes> {
+    let header = &packet.header;
+    if !recovered {
+        manager.note_arrival(stream_id, arrival);
+    }
+    let mut payload = packet.payload.clone();
+    let mut kind = manager.resolve_payload(stream_id, header.payload_type);
+    let mut red = None;
+    if kind == Some(PayloadKind::Red) {
+        let Some(parsed) = RedPayload::parse(&payload) else {
+            debug!("Malformed RED payload on stream {}", stream_id);
+            return Vec::new();
+        };
+        payload = parsed.primary.data.clone();
+        kind = manager.resolve_payload(stream_id, parsed.primary.payload_type);
+        red = Some(parsed);
+    }
+    if kind != Some(PayloadKind::Ulpfec) && !recovered {
+        manager.remember_for_fec(stream_id, header.sequence_number, raw.clone());
+    }
+
+    let (format, chunk_kind) = match kind {
+        Some(PayloadKind::Audio(format)) => (format, ChunkKind::Audio),
+        Some(PayloadKind::ComfortNoise(format)) => {
+            let noise_level = comfort_noise::noise_level(&payload).unwrap_or_default();
+            (format, ChunkKind::ComfortNoise { noise_level })
+        }
+        Some(PayloadKind::TelephoneEvent { clock_rate }) if !recovered => {
+            manager.process_telephone_event(
+                stream_id,
+                header.sequence_number,
+                header.timestamp,
+                &payload,
+                clock_rate,
+                arrival,
+            );
+            return Vec::new();
+        }
+        Some(PayloadKind::Ulpfec) if !recovered => {
+            return manager.process_fec(stream_id, header.sequence_number, &payload, arrival);
+        }
+        _ => return Vec::new(),
+    };
+
+    let mut chunk = audio_chunk(
+        stream_id,
+        payload,
+        format,
+        header.sequence_number,
+        header.timestamp,
+        chunk_kind,
+    );
+    // Redundant RED blocks below are older audio, so the extensions, which
+    // describe this packet, stay with the primary.
+    if let Some(map) = manager.header_extensions(stream_id) {
+        header_extensions::apply(map, header, &mut chunk);
+    }
+    if recovered {
+        manager.recover_audio_chunk(stream_id, header.sequence_number, chunk, arrival);
+    } else {
+        manager.process_audio_chunk(stream_id, chunk, header.marker, arrival);
+    }
+
+    // Redundant copies go in after the primary so that a lost packet just
+    // before it already shows as missing.
+    if let Some(red) = red {
+        let sequence_numbers = red.redundant_sequence_numbers(header.sequence_number);
+        for (block, seq) in red.redundant.iter().zip(sequence_numbers) {
+            let Some(PayloadKind::Audio(format)) =
+                manager.resolve_payload(stream_id, block.payload_type)
+            else {
+                continue;
+            };
+            let timestamp = header.timestamp.wrapping_sub(block.timestamp_offset);
+            let chunk = audio_chunk(
+                stream_id,
+                block.data.clone(),
+                format,
+                seq,
+                timestamp,
+                ChunkKind::Audio,
+            );
+            manager.recover_audio_chunk(stream_id, seq, chunk, arrival);
+        }
+    }
+    Vec::new()
+}
+
+fn audio_chunk(
+    stream_id: StreamId,
+    data: Bytes,
+    format: AudioFormat,
+    seq: u16,
+    timestamp: u32,
+    kind: ChunkKind,
+) -> AudioChunk {
+    let mut metadata = LatencyMetadata::new(stream_id);
+    metadata.start_stage("rtp_ingestion", "rtp-ingest");
+    let mut chunk = AudioChunk {
+        data,
+        format,
+        sequence_number: u32::from(seq),
+        timestamp,
+        kind,
+        capture_time: None,
+        extensions: HeaderExtensions::default(),
+        metadata,
+    };
+    chunk.metadata.end_stage();
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probation::ProbationConfig;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    /// An RFC 4571 frame holding a µ-law packet with sequence number `seq`.
+    fn frame(seq: u16) -> Vec<u8> {
+        let mut packet = vec![0x80, 0];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&(u32::from(seq) * 160).to_be_bytes());
+        packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        packet.extend_from_slice(&[0xff; 160]);
+        let mut frame = u16::try_from(packet.len()).unwrap().to_be_bytes().to_vec();
+        frame.append(&mut packet);
+        frame
+    }
+
+    /// Serves one loopback TCP connection, returning its client end, the
+    /// serving task and a subscription to the stream events.
+    async fn connection() -> (
+        TcpStream,
+        tokio::task::JoinHandle<()>,
+        broadcast::Receiver<StreamEvent>,
+    ) {
+        let events = StreamManager::event_channel();
+        let subscription = events.subscribe();
+        let config = StreamManagerConfig {
+            probation: ProbationConfig {
+                min_sequential: 1,
+                ..ProbationConfig::default()
+            },
+            ..StreamManagerConfig::default()
+        };
+        let shard = Shard {
+            socket: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            stream_manager: Arc::new(RwLock::new(StreamManager::with_events(config, events))),
+            publisher: None,
+            webrtc: None,
+            capture: None,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let server = tokio::spawn(serve_tcp(stream, peer, shard, TcpPeers::default()));
+        (client, server, subscription)
+    }
+
+    /// Streams created and ended, with the packets each ended with.
+    fn lifecycle(subscription: &mut broadcast::Receiver<StreamEvent>) -> Vec<String> {
+        let mut seen = Vec::new();
+        loop {
+            match subscription.try_recv() {
+                Ok(StreamEvent::Created { .. }) => seen.push("created".to_string()),
+                Ok(StreamEvent::Ended { reason, stats, .. }) => seen.push(format!(
+                    "ended {reason:?}, {} received",
+                    stats.map_or(0, |stats| stats.packets_received)
+                )),
+                Ok(_) => {}
+                // The manager goes once the connection is served.
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return seen,
+                Err(e) => panic!("event subscription failed: {e}"),
+            }
+        }
+    }
+
+    /// Writes `data` a few bytes at a time, giving the server a chance to
+    /// read each piece on its own.
+    async fn trickle(client: &mut TcpStream, data: &[u8]) {
+        for piece in data.chunks(7) {
+            client.write_all(piece).await.unwrap();
+            client.flush().await.unwrap();
+            time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_frames_split_across_reads() {
+        let (mut client, server, mut events) = connection().await;
+        let data: Vec<u8> = (0..3).flat_map(frame).collect();
+        trickle(&mut client, &data).await;
+        drop(client);
+        server.await.unwrap();
+        assert_eq!(
+            lifecycle(&mut events),
+            ["created", "ended ConnectionClosed, 3 received"]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_zero_length_frames() {
+        let (mut client, server, mut events) = connection().await;
+        let mut data = vec![0, 0];
+        data.extend(frame(0));
+        data.extend([0, 0, 0, 0]);
+        data.extend(frame(1));
+        client.write_all(&data).await.unwrap();
+        drop(client);
+        server.await.unwrap();
+        assert_eq!(
+            lifecycle(&mut events),
+            ["created", "ended ConnectionClosed, 2 received"]
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_streams_when_closed_mid_frame() {
+        let (mut client, server, mut events) = connection().await;
+        let mut data = frame(0);
+        data.extend(&frame(1)[..50]);
+        trickle(&mut client, &data).await;
+        drop(client);
+        server.await.unwrap();
+        assert_eq!(
+            lifecycle(&mut events),
+            ["created", "ended ConnectionClosed, 1 received"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_to_relatch_with_several_workers() {
+        for relatch in [RelatchPolicy::Allow, RelatchPolicy::Reject] {
+            let config = StreamManagerConfig {
+                relatch,
+                ..StreamManagerConfig::default()
+            };
+            let receive = ReceiveConfig {
+                workers: 2,
+                ..ReceiveConfig::default()
+            };
+            let bound = RtpReceiver::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                config,
+                StreamManager::event_channel(),
+                receive,
+            )
+            .await;
+            let Err(e) = bound else {
+                panic!("bound {relatch:?} with two workers");
+            };
+            assert_eq!(
+                e.to_string(),
+                "re-latching streams needs a single worker, not 2"
+            );
+        }
     }
 }
@@ -0,0 +1,552 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Largest header block accepted before a stream connection is given up on.
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+/// Largest body accepted on a stream transport; SDP and SIPREC metadata
+/// are far smaller.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum SipError {
+    #[error("malformed SIP message: {0}")]
+    Malformed(&'static str),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Invite,
+    Ack,
+    Bye,
+    Cancel,
+    Options,
+    Other(String),
+}
+
+impl Method {
+    fn parse(method: &str) -> Self {
+        match method {
+            "INVITE" => Self::Invite,
+            "ACK" => Self::Ack,
+            "BYE" => Self::Bye,
+            "CANCEL" => Self::Cancel,
+            "OPTIONS" => Self::Options,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Invite => "INVITE",
+            Self::Ack => "ACK",
+            Self::Bye => "BYE",
+            Self::Cancel => "CANCEL",
+            Self::Options => "OPTIONS",
+            Self::Other(other) => other,
+        })
+    }
+}
+
+/// Header fields in order of appearance. Lookups are case-insensitive and
+/// understand the RFC 3261 section 7.3.3 compact forms.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(header, _)| same_header(header, name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(header, _)| same_header(header, name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// Replaces every occurrence of `name` with a single value, keeping the
+    /// position of the first.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        match self
+            .0
+            .iter()
+            .position(|(header, _)| same_header(header, name))
+        {
+            Some(first) => {
+                self.0[first].1 = value.into();
+                let mut index = 0;
+                self.0.retain(|(header, _)| {
+                    index += 1;
+                    index - 1 == first || !same_header(header, name)
+                });
+            }
+            None => self.push(name, value),
+        }
+    }
+
+    fn copy_from(&mut self, other: &Self, name: &str) {
+        for value in other.get_all(name) {
+            self.push(name, value);
+        }
+    }
+}
+
+fn full_header_name(name: &str) -> &str {
+    match name {
+        "i" | "I" => "Call-ID",
+        "m" | "M" => "Contact",
+        "e" | "E" => "Content-Encoding",
+        "l" | "L" => "Content-Length",
+        "c" | "C" => "Content-Type",
+        "f" | "F" => "From",
+        "s" | "S" => "Subject",
+        "k" | "K" => "Supported",
+        "t" | "T" => "To",
+        "v" | "V" => "Via",
+        other => other,
+    }
+}
+
+fn same_header(a: &str, b: &str) -> bool {
+    full_header_name(a).eq_ignore_ascii_case(full_header_name(b))
+}
+
+#[derive(Debug, Clone)]
+pub struct SipRequest {
+    pub method: Method,
+    pub uri: String,
+    pub headers: Headers,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub struct SipResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub enum SipMessage {
+    Request(SipRequest),
+    Response(SipResponse),
+}
+
+impl SipRequest {
+    pub fn call_id(&self) -> Option<&str> {
+        self.headers.get("Call-ID")
+    }
+
+    /// The `CSeq` sequence number and method.
+    pub fn cseq(&self) -> Option<(u32, Method)> {
+        let (number, method) = self.headers.get("CSeq")?.trim().split_once(' ')?;
+        Some((number.parse().ok()?, Method::parse(method.trim())))
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// Starts a response carrying the headers RFC 3261 section 8.2.6.2
+    /// requires to be copied from the request.
+    pub fn response(&self, status: u16, reason: &str) -> SipResponse {
+        let mut headers = Headers::default();
+        for name in ["Via", "From", "To", "Call-ID", "CSeq"] {
+            headers.copy_from(&self.headers, name);
+        }
+        SipResponse {
+            status,
+            reason: reason.to_string(),
+            headers,
+            body: Bytes::new(),
+        }
+    }
+}
+
+impl SipResponse {
+    /// Adds our dialog tag to the To header unless it already has one.
+    #[must_use]
+    pub fn with_to_tag(mut self, tag: &str) -> Self {
+        if let Some(to) = self.headers.get("To")
+            && header_param(to, "tag").is_none()
+        {
+            let to = format!("{to};tag={tag}");
+            self.headers.set("To", to);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn with_body(mut self, content_type: &str, body: impl Into<Bytes>) -> Self {
+        self.headers.set("Content-Type", content_type);
+        self.body = body.into();
+        self
+    }
+}
+
+/// Value of a `;name=value` parameter on a header such as To or Via.
+pub fn header_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(param, _)| param.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+impl SipMessage {
+    /// Parses one complete message, as a UDP datagram carries.
+    pub fn parse(data: &[u8]) -> Result<Self, SipError> {
+        let header_end = find_header_end(data).ok_or(SipError::Malformed("no end of headers"))?;
+        let head = std::str::from_utf8(&data[..header_end])
+            .map_err(|_| SipError::Malformed("headers are not UTF-8"))?;
+        let mut lines = unfold(head).into_iter();
+
+        let start_line = lines.next().ok_or(SipError::Malformed("empty message"))?;
+        let mut headers = Headers::default();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(SipError::Malformed("header without a colon"))?;
+            headers.push(full_header_name(name.trim()), value.trim());
+        }
+
+        let body = &data[header_end + 4..];
+        let body = match headers.get("Content-Length") {
+            Some(length) => {
+                let length: usize = length
+                    .parse()
+                    .map_err(|_| SipError::Malformed("bad Content-Length"))?;
+                body.get(..length)
+                    .ok_or(SipError::Malformed("body shorter than Content-Length"))?
+            }
+            None => body,
+        };
+        let body = Bytes::copy_from_slice(body);
+
+        let mut parts = start_line.splitn(3, ' ');
+        let (first, second, third) = (parts.next(), parts.next(), parts.next());
+        match (first, second, third) {
+            (Some(version), Some(status), Some(reason)) if version.starts_with("SIP/") => {
+                Ok(Self::Response(SipResponse {
+                    status: status
+                        .parse()
+                        .map_err(|_| SipError::Malformed("bad status code"))?,
+                    reason: reason.to_string(),
+                    headers,
+                    body,
+                }))
+            }
+            (Some(method), Some(uri), Some(version)) if version.starts_with("SIP/") => {
+                Ok(Self::Request(SipRequest {
+                    method: Method::parse(method),
+                    uri: uri.to_string(),
+                    headers,
+                    body,
+                }))
+            }
+            _ => Err(SipError::Malformed("bad start line")),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let (start_line, headers, body) = match self {
+            Self::Request(request) => (
+                format!("{} {} SIP/2.0", request.method, request.uri),
+                &request.headers,
+                &request.body,
+            ),
+            Self::Response(response) => (
+                format!("SIP/2.0 {} {}", response.status, response.reason),
+                &response.headers,
+                &response.body,
+            ),
+        };
+
+        let mut out = BytesMut::new();
+        out.put_slice(start_line.as_bytes());
+        out.put_slice(b"\r\n");
+        for (name, value) in &headers.0 {
+            if !same_header(name, "Content-Length") {
+                out.put_slice(format!("{name}: {value}\r\n").as_bytes());
+            }
+        }
+        out.put_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+        out.put_slice(body);
+        out.freeze()
+    }
+}
+
+impl From<SipResponse> for SipMessage {
+    fn from(response: SipResponse) -> Self {
+        Self::Response(response)
+    }
+}
+
+fn find_header_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+/// Splits header lines, joining folded continuation lines onto the previous one.
+fn unfold(head: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in head.split("\r\n") {
+        if line.starts_with([' ', '\t'])
+            && let Some(previous) = lines.last_mut()
+        {
+            previous.push(' ');
+            previous.push_str(line.trim());
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    lines
+}
+
+/// Frames SIP messages on stream transports, where Content-Length is the
+/// only way to find where one message ends (RFC 3261 section 18.3).
+#[derive(Debug, Default)]
+pub struct SipCodec;
+
+impl Decoder for SipCodec {
+    type Item = SipMessage;
+    type Error = SipError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // CRLF keep-alives (RFC 5626) may sit between messages.
+        let leading = src
+            .iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count();
+        let _ = src.split_to(leading);
+
+        let Some(header_end) = find_header_end(src) else {
+            if src.len() > MAX_HEADER_SIZE {
+                return Err(SipError::Malformed("headers too large"));
+            }
+            return Ok(None);
+        };
+
+        let head = String::from_utf8_lossy(&src[..header_end]);
+        let content_length = unfold(&head)
+            .iter()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| same_header(name.trim(), "Content-Length"))
+            .map(|(_, value)| value.trim().parse::<usize>())
+            .transpose()
+            .map_err(|_| SipError::Malformed("bad Content-Length"))?
+            .ok_or(SipError::Malformed("stream message without Content-Length"))?;
+        if content_length > MAX_BODY_SIZE {
+            return Err(SipError::Malformed("body too large"));
+        }
+
+        let total = (header_end + 4)
+            .checked_add(content_length)
+            .ok_or(SipError::Malformed("body too large"))?;
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(total);
+        SipMessage::parse(&frame).map(Some)
+    }
+}
+
+impl Encoder<SipMessage> for SipCodec {
+    type Error = SipError;
+
+    fn encode(&mut self, item: SipMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_slice(&item.to_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 3261 section 24.2, message F1, without the SDP lines.
+    const INVITE: &str = "INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+        Via: SIP/2.0/TCP client.atlanta.example.com:5060;branch=z9hG4bK74bf9\r\n\
+        Max-Forwards: 70\r\n\
+        From: Alice <sip:alice@atlanta.example.com>;tag=9fxced76sl\r\n\
+        To: Bob <sip:bob@biloxi.example.com>\r\n\
+        Call-ID: 3848276298220188511@atlanta.example.com\r\n\
+        CSeq: 1 INVITE\r\n\
+        Contact: <sip:alice@client.atlanta.example.com;transport=tcp>\r\n\
+        Content-Type: application/sdp\r\n\
+        Content-Length: 4\r\n\
+        \r\n\
+        v=0\n";
+
+    fn request(message: SipMessage) -> SipRequest {
+        match message {
+            SipMessage::Request(request) => request,
+            SipMessage::Response(response) => panic!("expected a request, got {response:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let invite = request(SipMessage::parse(INVITE.as_bytes()).unwrap());
+        assert_eq!(invite.method, Method::Invite);
+        assert_eq!(invite.uri, "sip:bob@biloxi.example.com");
+        assert_eq!(
+            invite.call_id(),
+            Some("3848276298220188511@atlanta.example.com")
+        );
+        assert_eq!(invite.cseq(), Some((1, Method::Invite)));
+        assert_eq!(invite.content_type(), Some("application/sdp"));
+        assert_eq!(invite.body, "v=0\n");
+        assert_eq!(
+            header_param(invite.headers.get("from").unwrap(), "TAG"),
+            Some("9fxced76sl")
+        );
+    }
+
+    #[test]
+    fn parses_a_response() {
+        let message = "SIP/2.0 180 Ringing\r\nCSeq: 1 INVITE\r\n\r\n";
+        let SipMessage::Response(response) = SipMessage::parse(message.as_bytes()).unwrap() else {
+            panic!("expected a response");
+        };
+        assert_eq!(response.status, 180);
+        assert_eq!(response.reason, "Ringing");
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn understands_compact_forms_and_folded_lines() {
+        // After the short forms and line folding of RFC 4475 section 3.1.1.1.
+        let message = "OPTIONS sip:ingest@example.com SIP/2.0\r\n\
+            i: folded\r\n  @call\r\n\
+            l: 0\r\n\
+            t: <sip:ingest@example.com>\r\n\
+            Subject:\r\n\tsplit\r\n\
+            \r\n";
+        let options = request(SipMessage::parse(message.as_bytes()).unwrap());
+        assert_eq!(options.method, Method::Options);
+        assert_eq!(options.call_id(), Some("folded @call"));
+        assert_eq!(options.headers.get("Content-Length"), Some("0"));
+        assert_eq!(options.headers.get("TO"), Some("<sip:ingest@example.com>"));
+        assert_eq!(options.headers.get("s"), Some("split"));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        for message in [
+            "INVITE sip:bob@example.com SIP/2.0\r\nCSeq: 1 INVITE\r\n",
+            "INVITE sip:bob@example.com SIP/2.0\r\nNo colon here\r\n\r\n",
+            "INVITE sip:bob@example.com\r\nCSeq: 1 INVITE\r\n\r\n",
+            "SIP/2.0 OK\r\n\r\n",
+            "SIP/2.0 abc Nope\r\n\r\n",
+            "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: 10\r\n\r\nshort",
+            "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: -1\r\n\r\n",
+        ] {
+            assert!(
+                matches!(
+                    SipMessage::parse(message.as_bytes()),
+                    Err(SipError::Malformed(_))
+                ),
+                "{message:?}"
+            );
+        }
+        assert!(SipMessage::parse(b"INVITE \xff SIP/2.0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn builds_responses_from_requests() {
+        let invite = request(SipMessage::parse(INVITE.as_bytes()).unwrap());
+        let response = invite
+            .response(200, "OK")
+            .with_to_tag("ours")
+            .with_to_tag("again")
+            .with_body("application/sdp", "v=0\r\n");
+        assert_eq!(
+            response.headers.get("To"),
+            Some("Bob <sip:bob@biloxi.example.com>;tag=ours")
+        );
+        assert_eq!(response.headers.get("Contact"), None);
+
+        let bytes = SipMessage::from(response).to_bytes();
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.starts_with("SIP/2.0 200 OK\r\nVia: SIP/2.0/TCP"));
+        assert!(text.ends_with("Content-Length: 5\r\n\r\nv=0\r\n"));
+    }
+
+    #[test]
+    fn rewrites_content_length_and_replaces_repeated_headers() {
+        let mut invite = request(SipMessage::parse(INVITE.as_bytes()).unwrap());
+        invite.headers.push("Via", "SIP/2.0/UDP second.example.com");
+        invite.headers.set("v", "SIP/2.0/UDP only.example.com");
+        assert_eq!(
+            invite.headers.get_all("Via").collect::<Vec<_>>(),
+            ["SIP/2.0/UDP only.example.com"]
+        );
+        invite.body = Bytes::from_static(b"longer body");
+
+        let bytes = SipMessage::Request(invite).to_bytes();
+        let reparsed = request(SipMessage::parse(&bytes).unwrap());
+        assert_eq!(reparsed.body, "longer body");
+        assert_eq!(reparsed.headers.get_all("l").count(), 1);
+        assert!(
+            bytes
+                .starts_with(b"INVITE sip:bob@biloxi.example.com SIP/2.0\r\nVia: SIP/2.0/UDP only")
+        );
+    }
+
+    #[test]
+    fn frames_messages_on_a_stream() {
+        let mut codec = SipCodec;
+        let mut src = BytesMut::from(&b"\r\n\r\n"[..]);
+        src.put_slice(INVITE.as_bytes());
+        src.put_slice(&INVITE.as_bytes()[..INVITE.len() - 2]);
+
+        let first = request(codec.decode(&mut src).unwrap().unwrap());
+        assert_eq!(first.body, "v=0\n");
+        // The second is two bytes short of its body.
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.put_slice(b"0\n");
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn refuses_unframeable_stream_messages() {
+        let no_length = "BYE sip:bob@example.com SIP/2.0\r\nCSeq: 2 BYE\r\n\r\n";
+        let too_long = format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let overflowing = format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        let endless_headers = format!(
+            "INVITE sip:bob@example.com SIP/2.0\r\nX: {}",
+            "a".repeat(MAX_HEADER_SIZE)
+        );
+        for message in [no_length, &too_long, &overflowing, &endless_headers] {
+            let mut src = BytesMut::from(message.as_bytes());
+            assert!(
+                matches!(SipCodec.decode(&mut src), Err(SipError::Malformed(_))),
+                "{message:.60}"
+            );
+        }
+    }
+}
@@ -0,0 +1,824 @@
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+
+use crate::capture::PacketCapture;
+use crate::media_ports::{MediaPortPool, PortPair};
+use crate::publisher::ChunkPublisher;
+use crate::rtp_receiver::{ReceiveConfig, RtpReceiver};
+use crate::sip_message::{Method, SipCodec, SipMessage, SipRequest, SipResponse};
+use crate::siprec::{self, RecordingMetadata};
+use crate::srtp::{SrtpKey, SrtpKeys};
+use crate::stream_manager::{CallContext, RelatchPolicy, SsrcChangePolicy, StreamManagerConfig};
+use shared_types::sdp::{AcceptedMedia, CryptoAttribute, MediaDescription, Origin};
+use shared_types::{
+    EndReason, NegotiatedMedia, RecordingLeg, SessionDescription, StreamEvent, StreamId,
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+const ALLOW: &str = "INVITE, ACK, BYE, CANCEL, OPTIONS";
+const SUPPORTED: &str = "siprec";
+/// RFC 3261 timer values for retransmitting 2xx responses over UDP.
+const T1: Duration = Duration::from_millis(500);
+const T2: Duration = Duration::from_secs(4);
+const TIMER_H: Duration = Duration::from_secs(32);
+/// Ports in the pool that may be taken by something else; try a few.
+const MEDIA_BIND_ATTEMPTS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct SipConfig {
+    /// Where to listen for SIP over both UDP and TCP.
+    pub bind: SocketAddr,
+    /// Addresses allowed to send requests. Empty accepts anyone.
+    pub trunks: Vec<IpAddr>,
+    /// Address advertised in SDP answers and Contact headers.
+    pub media_ip: IpAddr,
+    pub media_ports: RangeInclusive<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+        }
+    }
+}
+
+struct Call {
+    local_tag: String,
+    invite_cseq: u32,
+    answer: SipResponse,
+    acked: Arc<AtomicBool>,
+    media: Vec<CallMedia>,
+}
+
+/// One media stream of a call with the receiver running on its ports.
+struct CallMedia {
+    ports: PortPair,
+    recording: Option<RecordingLeg>,
+    /// Our SDES key for the RTCP we send, when the call uses SRTP.
+    crypto: Option<CryptoAttribute>,
+    receiver: Arc<RtpReceiver>,
+    task: JoinHandle<()>,
+}
+
+/// A user agent server that answers inbound calls from SIP trunks and
+/// receives their media on a port of its own per call.
+pub struct SipServer {
+    config: SipConfig,
+    stream_config: StreamManagerConfig,
+    events: broadcast::Sender<StreamEvent>,
+    publisher: Option<ChunkPublisher>,
+    capture: Option<PacketCapture>,
+    udp: UdpSocket,
+    tcp: TcpListener,
+    calls: Mutex<HashMap<String, Call>>,
+    ports: Mutex<MediaPortPool>,
+}
+
+impl SipServer {
+    pub async fn bind(
+        config: SipConfig,
+        stream_config: StreamManagerConfig,
+        events: broadcast::Sender<StreamEvent>,
+        publisher: Option<ChunkPublisher>,
+        capture: Option<PacketCapture>,
+    ) -> Result<Arc<Self>> {
+        let udp = UdpSocket::bind(config.bind).await?;
+        let tcp = TcpListener::bind(config.bind).await?;
+        info!("SIP listening on {} (UDP and TCP)", config.bind);
+        if config.trunks.is_empty() {
+            warn!("No SIP trunks configured, accepting calls from anyone");
+        }
+
+        Ok(Arc::new(Self {
+            ports: Mutex::new(MediaPortPool::new(config.media_ports.clone())),
+            config,
+            stream_config,
+            events,
+            publisher,
+            capture,
+            udp,
+            tcp,
+            calls: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut events = self.events.subscribe();
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(StreamEvent::Created { metadata }) => {
+                        if let Some(port) = metadata.media_port {
+                            self.track_stream(port, metadata.id).await;
+                        }
+                    }
+                    Ok(StreamEvent::Ended { metadata, .. }) => {
+                        if let Some(port) = metadata.media_port {
+                            self.stream_ended(port, metadata.id).await;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("SIP server missed {} stream events", skipped);
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Closed) => {}
+                },
+                result = self.udp.recv_from(&mut buf) => match result {
+                    Ok((len, source_addr)) => {
+                        match SipMessage::parse(&buf[..len]) {
+                            Ok(message) => {
+                                for response in self.handle(message, source_addr, Transport::Udp).await {
+                                    self.send_udp(&response, source_addr).await;
+                                }
+                            }
+                            Err(e) => debug!("Ignoring SIP datagram from {}: {}", source_addr, e),
+                        }
+                    }
+                    Err(e) => error!("Failed to receive SIP datagram: {}", e),
+                },
+                result = self.tcp.accept() => match result {
+                    Ok((stream, peer)) => {
+                        debug!("SIP TCP connection from {}", peer);
+                        tokio::spawn(Arc::clone(&self).serve_tcp(stream, peer));
+                    }
+                    Err(e) => error!("Failed to accept SIP connection: {}", e),
+                },
+            }
+        }
+    }
+
+    /// Ties a stream that started on a call's ports to that allocation.
+    async fn track_stream(&self, port: u16, stream_id: StreamId) {
+        if let Some(call_id) = self.ports.lock().await.assign(port, stream_id) {
+            debug!(
+                "Stream {} belongs to call {} on port {}",
+                stream_id, call_id, port
+            );
+        }
+    }
+
+    /// Forgets a stream that has ended, e.g. by timing out while the call
+    /// is on hold. The call keeps its ports until the trunk ends the
+    /// dialog, so media that resumes on them starts a new stream.
+    async fn stream_ended(&self, port: u16, stream_id: StreamId) {
+        if let Some(call_id) = self.ports.lock().await.finish(port, stream_id) {
+            debug!(
+                "Stream {} of call {} on port {} ended",
+                stream_id, call_id, port
+            );
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+        let (mut sink, mut messages) = Framed::new(stream, SipCodec).split();
+        let (tx, mut rx) = mpsc::channel::<SipMessage>(16);
+        let writer = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Err(e) = sink.send(message).await {
+                    warn!("Failed to write SIP message to {}: {}", peer, e);
+                    break;
+                }
+            }
+        });
+
+        while let Some(result) = messages.next().await {
+            match result {
+                Ok(message) => {
+                    for response in self.handle(message, peer, Transport::Tcp).await {
+                        if tx.send(response.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Closing SIP connection from {}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+        drop(tx);
+        let _ = writer.await;
+        debug!("SIP TCP connection from {} closed", peer);
+    }
+
+    async fn send_udp(&self, response: &SipResponse, destination: SocketAddr) {
+        let message = SipMessage::Response(response.clone());
+        if let Err(e) = self.udp.send_to(&message.to_bytes(), destination).await {
+            warn!("Failed to send SIP response to {}: {}", destination, e);
+        }
+    }
+
+    async fn handle(
+        self: &Arc<Self>,
+        message: SipMessage,
+        source_addr: SocketAddr,
+        transport: Transport,
+    ) -> Vec<SipResponse> {
+        let request = match message {
+            SipMessage::Request(request) => request,
+            SipMessage::Response(response) => {
+                debug!(
+                    "Ignoring SIP response {} {} from {}",
+                    response.status, response.reason, source_addr
+                );
+                return Vec::new();
+            }
+        };
+        debug!(
+            "SIP {} from {} over {}",
+            request.method,
+            source_addr,
+            transport.name()
+        );
+
+        if request.call_id().is_none() || request.cseq().is_none() {
+            return vec![request.response(400, "Bad Request")];
+        }
+
+        if !self.config.trunks.is_empty() && !self.config.trunks.contains(&source_addr.ip()) {
+            warn!(
+                "Rejecting {} {} from unknown peer {}",
+                request.method,
+                request.call_id().unwrap_or_default(),
+                source_addr
+            );
+            // ACKs get no response.
+            if request.method == Method::Ack {
+                return Vec::new();
+            }
+            return vec![request.response(403, "Forbidden")];
+        }
+
+        match request.method {
+            Method::Invite => self.handle_invite(&request, source_addr, transport).await,
+            Method::Ack => {
+                self.handle_ack(&request).await;
+                Vec::new()
+            }
+            Method::Bye => vec![self.handle_bye(&request).await],
+            Method::Cancel => vec![self.handle_cancel(&request).await],
+            Method::Options => {
+                let mut response = request.response(200, "OK");
+                response.headers.push("Allow", ALLOW);
+                response.headers.push("Accept", siprec::SDP);
+                response.headers.push("Supported", SUPPORTED);
+                vec![response]
+            }
+            Method::Other(_) => {
+                let mut response = request.response(405, "Method Not Allowed");
+                response.headers.push("Allow", ALLOW);
+                vec![response]
+            }
+        }
+    }
+
+    async fn handle_invite(
+        self: &Arc<Self>,
+        request: &SipRequest,
+        source_addr: SocketAddr,
+        transport: Transport,
+    ) -> Vec<SipResponse> {
+        let call_id = request.call_id().unwrap_or_default().to_string();
+        let cseq = request.cseq().map_or(0, |(number, _)| number);
+
+        if let Some(call) = self.calls.lock().await.get(&call_id) {
+            if cseq == call.invite_cseq {
+                debug!("Retransmitted INVITE {}, resending answer", call_id);
+                return vec![call.answer.clone()];
+            }
+            // Re-INVITEs (session refresh, hold) keep the media we already set up.
+            info!("Re-INVITE for call {}, keeping existing media", call_id);
+            let response = request
+                .response(200, "OK")
+                .with_to_tag(&call.local_tag)
+                .with_body(siprec::SDP, call.answer.body.clone());
+            let recording = call.media.iter().any(|media| media.recording.is_some());
+            return vec![self.with_contact(response, transport, recording)];
+        }
+
+        let (offer, metadata) = match read_offer(request) {
+            Ok(offer) => offer,
+            Err(response) => {
+                warn!("Rejecting INVITE {}: {}", call_id, response.reason);
+                return vec![*response];
+            }
+        };
+
+        // A recording session carries one m-line per participant; a plain
+        // call is answered on its first usable audio stream.
+        let mut negotiated = offer.negotiate_all_audio();
+        negotiated.retain_mut(|(index, negotiated)| {
+            choose_crypto(&call_id, &offer.media[*index], negotiated)
+        });
+        if metadata.is_none() {
+            negotiated.truncate(1);
+        }
+        if negotiated.is_empty() {
+            warn!("No acceptable audio in INVITE {}", call_id);
+            return vec![request.response(488, "Not Acceptable Here")];
+        }
+
+        let Some(media) = self
+            .start_call_media(&call_id, &offer, metadata.as_ref(), &negotiated)
+            .await
+        else {
+            return vec![request.response(503, "Service Unavailable")];
+        };
+
+        let local_tag = format!("{:08x}", rand::random::<u32>());
+        let answer_sdp = self.answer_sdp(&offer, &negotiated, &media);
+
+        let answer = request
+            .response(200, "OK")
+            .with_to_tag(&local_tag)
+            .with_body(siprec::SDP, answer_sdp.to_string());
+        let mut answer = self.with_contact(answer, transport, metadata.is_some());
+        if metadata.is_some() {
+            answer.headers.push("Require", "siprec");
+        }
+
+        let acked = Arc::new(AtomicBool::new(false));
+        if transport == Transport::Udp {
+            self.retransmit_until_acked(
+                call_id.clone(),
+                answer.clone(),
+                source_addr,
+                Arc::clone(&acked),
+            );
+        }
+
+        info!(
+            "Answered {} {} from {} with {} media stream(s)",
+            if metadata.is_some() {
+                "recording"
+            } else {
+                "call"
+            },
+            call_id,
+            source_addr,
+            media.len()
+        );
+        self.calls.lock().await.insert(
+            call_id,
+            Call {
+                local_tag: local_tag.clone(),
+                invite_cseq: cseq,
+                answer: answer.clone(),
+                acked,
+                media,
+            },
+        );
+
+        vec![
+            request.response(100, "Trying"),
+            request.response(180, "Ringing").with_to_tag(&local_tag),
+            answer,
+        ]
+    }
+
+    /// Our SDP answer, accepting each negotiated m-line on the port its
+    /// receiver was started on.
+    fn answer_sdp(
+        &self,
+        offer: &SessionDescription,
+        negotiated: &[(usize, NegotiatedMedia)],
+        media: &[CallMedia],
+    ) -> SessionDescription {
+        let origin = Origin {
+            username: "navitel".to_string(),
+            session_id: u64::from(rand::random::<u32>()),
+            session_version: 1,
+            address: self.config.media_ip,
+        };
+        let accepted: Vec<_> = negotiated
+            .iter()
+            .zip(media)
+            .map(|((index, negotiated), media)| AcceptedMedia {
+                index: *index,
+                port: media.ports.rtp,
+                negotiated,
+                crypto: media.crypto.clone(),
+            })
+            .collect();
+        offer.answer(origin, self.config.media_ip, &accepted)
+    }
+
+    /// Starts a receiver for each negotiated m-line, tagging recorded ones
+    /// with the participant the metadata says they carry.
+    async fn start_call_media(
+        &self,
+        call_id: &str,
+        offer: &SessionDescription,
+        metadata: Option<&RecordingMetadata>,
+        negotiated: &[(usize, NegotiatedMedia)],
+    ) -> Option<Vec<CallMedia>> {
+        let mut media = Vec::with_capacity(negotiated.len());
+        for (index, negotiated) in negotiated {
+            let recording = metadata.and_then(|metadata| {
+                let label = offer.media[*index].attribute("label")?.value.as_deref()?;
+                let leg = metadata.leg_for_label(label);
+                if leg.is_none() {
+                    warn!(
+                        "Call {} has no metadata for stream label {}",
+                        call_id, label
+                    );
+                }
+                leg
+            });
+            let Some(started) = self.start_media(call_id, negotiated, recording).await else {
+                self.stop_media(media).await;
+                return None;
+            };
+            media.push(started);
+        }
+        Some(media)
+    }
+
+    /// Allocates a port and starts a receiver for one of the call's media
+    /// streams on it.
+    async fn start_media(
+        &self,
+        call_id: &str,
+        negotiated: &NegotiatedMedia,
+        recording: Option<RecordingLeg>,
+    ) -> Option<CallMedia> {
+        let mut config = self.stream_config.clone();
+        let mut call = CallContext {
+            call_id: call_id.to_string(),
+            media: negotiated.clone(),
+            recording: recording.clone(),
+            media_port: 0,
+        };
+        // A call's port only ever carries that call, so a new SSRC on it is
+        // the same party.
+        config.ssrc_change = SsrcChangePolicy::LinkToExisting;
+        // Likewise its SSRC turning up from a new address is the caller's
+        // NAT rebinding, unless re-latching is refused outright.
+        if config.relatch == RelatchPolicy::NewStream {
+            config.relatch = RelatchPolicy::Allow;
+        }
+        // Keys come from this call's signalling, never the static configuration.
+        config.srtp = None;
+        let crypto = match &negotiated.crypto {
+            Some(offered) => {
+                let remote = SrtpKey::from_crypto(offered).ok()?;
+                let local = SrtpKey::generate(remote.profile);
+                let crypto = local.to_crypto(offered.tag);
+                config.srtp = Some(SrtpKeys { remote, local });
+                Some(crypto)
+            }
+            None => None,
+        };
+
+        for _ in 0..MEDIA_BIND_ATTEMPTS {
+            let Some(ports) = self.ports.lock().await.allocate(call_id) else {
+                warn!("Media port pool exhausted, rejecting call {}", call_id);
+                return None;
+            };
+            call.media_port = ports.rtp;
+            config.call = Some(call.clone());
+            let bind_addr = SocketAddr::new(self.config.bind.ip(), ports.rtp);
+            let receiver = RtpReceiver::bind(
+                bind_addr,
+                config.clone(),
+                self.events.clone(),
+                ReceiveConfig::default(),
+            );
+            match receiver.await {
+                Ok(receiver) => {
+                    let receiver = receiver
+                        .with_publisher(self.publisher.as_ref())
+                        .with_capture(self.capture.as_ref());
+                    info!(
+                        "Call {} media on ports {}/{}: {:?}{} ({} pair(s) in use)",
+                        call_id,
+                        ports.rtp,
+                        ports.rtcp,
+                        negotiated.format.codec,
+                        recording
+                            .as_ref()
+                            .map(|leg| format!(
+                                ", from {}",
+                                leg.aor.as_deref().unwrap_or(&leg.participant_id)
+                            ))
+                            .unwrap_or_default(),
+                        self.ports.lock().await.in_use()
+                    );
+                    let receiver = Arc::new(receiver);
+                    let media_receiver = Arc::clone(&receiver);
+                    let task = tokio::spawn(async move {
+                        if let Err(e) = media_receiver.run().await {
+                            error!("Media receiver stopped: {}", e);
+                        }
+                    });
+                    return Some(CallMedia {
+                        ports,
+                        recording,
+                        crypto,
+                        receiver,
+                        task,
+                    });
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not bind media ports {}/{}: {}",
+                        ports.rtp, ports.rtcp, e
+                    );
+                    self.ports.lock().await.release(ports.rtp);
+                }
+            }
+        }
+        None
+    }
+
+    /// Adds our Contact, flagged with `+sip.srs` when answering as a
+    /// recording server (RFC 7866 section 6.1.1).
+    fn with_contact(
+        &self,
+        mut response: SipResponse,
+        transport: Transport,
+        recording: bool,
+    ) -> SipResponse {
+        response.headers.push(
+            "Contact",
+            format!(
+                "<sip:navitel@{};transport={}>{}",
+                SocketAddr::new(self.config.media_ip, self.config.bind.port()),
+                transport.name(),
+                if recording { ";+sip.srs" } else { "" }
+            ),
+        );
+        response.headers.push("Allow", ALLOW);
+        response
+    }
+
+    /// RFC 3261 section 13.3.1.4: over UDP the 2xx is resent, doubling up
+    /// to T2, until the ACK arrives. If timer H fires first the call is
+    /// ended.
+    fn retransmit_until_acked(
+        self: &Arc<Self>,
+        call_id: String,
+        answer: SipResponse,
+        destination: SocketAddr,
+        acked: Arc<AtomicBool>,
+    ) {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = T1;
+            let mut waited = Duration::ZERO;
+            while waited < TIMER_H {
+                time::sleep(interval).await;
+                waited += interval;
+                if acked.load(Ordering::Relaxed) {
+                    return;
+                }
+                server.send_udp(&answer, destination).await;
+                interval = (interval * 2).min(T2);
+            }
+            if server.end_call(&call_id).await {
+                warn!(
+                    "No ACK for call {} from {} after {:?}, ended it",
+                    call_id, destination, TIMER_H
+                );
+            }
+        });
+    }
+
+    async fn handle_ack(&self, request: &SipRequest) {
+        let call_id = request.call_id().unwrap_or_default();
+        if let Some(call) = self.calls.lock().await.get(call_id) {
+            call.acked.store(true, Ordering::Relaxed);
+            debug!("Call {} confirmed", call_id);
+        } else {
+            debug!("ACK for unknown call {}", call_id);
+        }
+    }
+
+    async fn handle_bye(&self, request: &SipRequest) -> SipResponse {
+        let call_id = request.call_id().unwrap_or_default();
+        if !self.end_call(call_id).await {
+            return request.response(481, "Call/Transaction Does Not Exist");
+        }
+        info!("Call {} hung up", call_id);
+        request.response(200, "OK")
+    }
+
+    /// Forgets a call and releases its media. Returns false if there was no
+    /// such call.
+    async fn end_call(&self, call_id: &str) -> bool {
+        let Some(call) = self.calls.lock().await.remove(call_id) else {
+            return false;
+        };
+        call.acked.store(true, Ordering::Relaxed);
+        self.stop_media(call.media).await;
+        true
+    }
+
+    async fn stop_media(&self, media: Vec<CallMedia>) {
+        for media in media {
+            let streams = self.ports.lock().await.release(media.ports.rtp);
+            for &stream_id in &streams {
+                media
+                    .receiver
+                    .end_stream(stream_id, EndReason::Hangup)
+                    .await;
+            }
+            // Anything that started after the last event we saw.
+            media.receiver.end_streams(EndReason::Hangup).await;
+            media.task.abort();
+            info!(
+                "Released media ports {}/{} ({} stream(s))",
+                media.ports.rtp,
+                media.ports.rtcp,
+                streams.len()
+            );
+        }
+    }
+
+    /// Calls are answered as soon as the INVITE arrives, so by the time a
+    /// CANCEL can match one the final response has gone out and it has no
+    /// effect (RFC 3261 section 9.2). The caller ends the call with BYE.
+    async fn handle_cancel(&self, request: &SipRequest) -> SipResponse {
+        let call_id = request.call_id().unwrap_or_default();
+        if self.calls.lock().await.contains_key(call_id) {
+            request.response(200, "OK")
+        } else {
+            request.response(481, "Call/Transaction Does Not Exist")
+        }
+    }
+}
+
+/// Pulls the SDP offer, and for SIPREC the recording metadata, out of an
+/// INVITE body, or says which error response to send instead.
+fn read_offer(
+    request: &SipRequest,
+) -> Result<(SessionDescription, Option<RecordingMetadata>), Box<SipResponse>> {
+    let unsupported: Vec<_> = request
+        .headers
+        .get_all("Require")
+        .flat_map(|require| require.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.eq_ignore_ascii_case(SUPPORTED))
+        .collect();
+    if !unsupported.is_empty() {
+        let mut response = request.response(420, "Bad Extension");
+        response.headers.push("Unsupported", unsupported.join(", "));
+        return Err(Box::new(response));
+    }
+
+    let content_type = request.content_type().unwrap_or_default();
+    let (sdp, metadata) = if siprec::is_media_type(content_type, siprec::MULTIPART_MIXED) {
+        let parts = siprec::parse_multipart(content_type, &request.body)
+            .map_err(|_| Box::new(request.response(400, "Bad Request")))?;
+        let sdp = parts.iter().find(|part| part.is(siprec::SDP));
+        let metadata = parts.iter().find(|part| part.is(siprec::RS_METADATA));
+        (
+            sdp.map(|part| part.body.clone()),
+            metadata.map(|part| part.body.clone()),
+        )
+    } else if siprec::is_media_type(content_type, siprec::SDP) {
+        (Some(request.body.clone()), None)
+    } else {
+        (None, None)
+    };
+
+    let Some(sdp) = sdp.filter(|sdp| !sdp.is_empty()) else {
+        let mut response = request.response(415, "Unsupported Media Type");
+        response.headers.push("Accept", siprec::SDP);
+        return Err(Box::new(response));
+    };
+    let offer = SessionDescription::parse(&String::from_utf8_lossy(&sdp))
+        .map_err(|_| Box::new(request.response(400, "Bad Request")))?;
+    let metadata = metadata
+        .map(|xml| RecordingMetadata::parse(&String::from_utf8_lossy(&xml)))
+        .transpose()
+        .map_err(|_| Box::new(request.response(400, "Bad Request")))?;
+    Ok((offer, metadata))
+}
+
+/// Settles on the first SDES key offered for an SRTP m-line that we can
+/// use. Returns false when the m-line is SRTP but none is usable, so it
+/// gets rejected rather than answered in the clear.
+fn choose_crypto(
+    call_id: &str,
+    offered: &MediaDescription,
+    negotiated: &mut NegotiatedMedia,
+) -> bool {
+    if !offered.is_secure() {
+        return true;
+    }
+    negotiated.crypto = offered
+        .crypto
+        .iter()
+        .find(|crypto| SrtpKey::from_crypto(crypto).is_ok())
+        .cloned();
+    if negotiated.crypto.is_none() {
+        warn!(
+            "Call {} offers {} with no usable SRTP crypto suite",
+            call_id, offered.protocol
+        );
+    }
+    negotiated.crypto.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 1 1 IN IP4 192.0.2.10\r\n\
+        s=-\r\n\
+        c=IN IP4 192.0.2.10\r\n\
+        t=0 0\r\n\
+        m=audio 49170 RTP/AVP 0\r\n";
+
+    fn invite(content_type: &str, body: &str) -> SipRequest {
+        let message = format!(
+            "INVITE sip:navitel@192.0.2.1 SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK776asdhds\r\n\
+             From: <sip:trunk@192.0.2.10>;tag=1928301774\r\n\
+             To: <sip:navitel@192.0.2.1>\r\n\
+             Call-ID: a84b4c76e66710\r\n\
+             CSeq: 314159 INVITE\r\n\
+             Content-Type: {content_type}\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {body}",
+            body.len()
+        );
+        match SipMessage::parse(message.as_bytes()).unwrap() {
+            SipMessage::Request(request) => request,
+            SipMessage::Response(response) => panic!("expected a request, got {response:?}"),
+        }
+    }
+
+    fn status(
+        result: Result<(SessionDescription, Option<RecordingMetadata>), Box<SipResponse>>,
+    ) -> u16 {
+        result.map_or_else(|response| response.status, |_| 200)
+    }
+
+    #[test]
+    fn reads_sdp_whatever_its_content_type_parameters() {
+        for content_type in [
+            "application/sdp",
+            "Application/SDP",
+            "application/sdp; charset=utf-8",
+        ] {
+            let (offer, metadata) = read_offer(&invite(content_type, OFFER)).unwrap();
+            assert_eq!(offer.media.len(), 1);
+            assert!(metadata.is_none());
+        }
+    }
+
+    #[test]
+    fn reads_sdp_and_metadata_from_a_multipart_body() {
+        let body = format!(
+            "--boundary\r\n\
+             Content-Type: application/sdp\r\n\
+             \r\n\
+             {OFFER}\r\n\
+             --boundary\r\n\
+             Content-Type: application/rs-metadata+xml; charset=utf-8\r\n\
+             \r\n\
+             <recording xmlns='urn:ietf:params:xml:ns:recording:1'/>\r\n\
+             --boundary--\r\n"
+        );
+        let content_type = "multipart/mixed; boundary=\"boundary\"";
+        let (offer, metadata) = read_offer(&invite(content_type, &body)).unwrap();
+        assert_eq!(offer.media.len(), 1);
+        assert!(metadata.is_some());
+    }
+
+    #[test]
+    fn rejects_offers_it_cannot_read() {
+        assert_eq!(status(read_offer(&invite("text/plain", OFFER))), 415);
+        assert_eq!(status(read_offer(&invite("application/sdpx", OFFER))), 415);
+        assert_eq!(status(read_offer(&invite("application/sdp", ""))), 415);
+        assert_eq!(status(read_offer(&invite("application/sdp", "x"))), 400);
+        assert_eq!(
+            status(read_offer(&invite("multipart/mixed; boundary=b", OFFER))),
+            400
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use roxmltree::{Document, Node};
+
+use crate::sip_message::header_param;
+use shared_types::RecordingLeg;
+
+pub const SDP: &str = "application/sdp";
+pub const RS_METADATA: &str = "application/rs-metadata+xml";
+pub const MULTIPART_MIXED: &str = "multipart/mixed";
+
+/// Whether a Content-Type value names `media_type`, whatever parameters
+/// follow it.
+pub fn is_media_type(content_type: &str, media_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|name| name.trim().eq_ignore_ascii_case(media_type))
+}
+
+/// One part of a `multipart/mixed` body.
+#[derive(Debug, Clone)]
+pub struct BodyPart {
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+/// Splits a `multipart/mixed` body (RFC 2046 section 5.1.1) into its parts.
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Result<Vec<BodyPart>> {
+    let boundary = header_param(content_type, "boundary")
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .context("multipart body without a boundary")?;
+    let delimiter = format!("--{boundary}");
+    let text = String::from_utf8_lossy(body);
+
+    // Delimiters are whole lines, ended by CRLF or, from lax senders, a
+    // bare LF. The line break before each one belongs to it, not the part.
+    let mut parts = Vec::new();
+    let mut part_start = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let Some(rest) = line.trim_end_matches(['\r', '\n']).strip_prefix(&delimiter) else {
+            continue;
+        };
+        let closing = rest.strip_prefix("--");
+        if !closing.unwrap_or(rest).trim().is_empty() {
+            continue;
+        }
+        if let Some(start) = part_start {
+            let part = &text[start..line_start];
+            let part = part.strip_suffix('\n').unwrap_or(part);
+            parts.push(parse_part(part.strip_suffix('\r').unwrap_or(part)));
+        }
+        if closing.is_some() {
+            return Ok(parts);
+        }
+        part_start = Some(offset);
+    }
+
+    if part_start.is_none() {
+        bail!("multipart boundary not found in body");
+    }
+    bail!("multipart body without a closing boundary")
+}
+
+fn parse_part(part: &str) -> BodyPart {
+    // Headers run up to the first blank line; a part with no headers
+    // starts straight away with it.
+    let mut content_type = None;
+    let mut body = "";
+    let mut offset = 0;
+    for line in part.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            body = &part[offset..];
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("Content-Type")
+        {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+
+    BodyPart {
+        content_type: content_type.unwrap_or_else(|| "text/plain".to_string()),
+        body: Bytes::copy_from_slice(body.as_bytes()),
+    }
+}
+
+impl BodyPart {
+    pub fn is(&self, media_type: &str) -> bool {
+        is_media_type(&self.content_type, media_type)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Participant {
+    id: String,
+    aor: Option<String>,
+    name: Option<String>,
+    sends: Vec<String>,
+    receives: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct MetadataStream {
+    id: String,
+    label: String,
+}
+
+/// The parts of RFC 7865 recording metadata needed to say whose audio each
+/// recorded m-line carries.
+#[derive(Debug, Clone)]
+pub struct RecordingMetadata {
+    session_id: Option<String>,
+    participants: Vec<Participant>,
+    streams: Vec<MetadataStream>,
+}
+
+impl RecordingMetadata {
+    pub fn parse(xml: &str) -> Result<Self> {
+        let document = Document::parse(xml)?;
+        let root = document.root_element();
+        if root.tag_name().name() != "recording" {
+            bail!(
+                "expected a <recording> document, got <{}>",
+                root.tag_name().name()
+            );
+        }
+
+        let session_id = elements(root, "session")
+            .next()
+            .and_then(|session| id_attribute(session, "session_id"));
+
+        let mut participants: Vec<Participant> = elements(root, "participant")
+            .filter_map(|node| {
+                let name_id = elements(node, "nameID").next();
+                Some(Participant {
+                    id: id_attribute(node, "participant_id")?,
+                    aor: name_id.and_then(|name_id| name_id.attribute("aor").map(str::to_string)),
+                    name: name_id
+                        .and_then(|name_id| elements(name_id, "name").next())
+                        .and_then(|name| name.text())
+                        .map(|name| name.trim().to_string()),
+                    // Early SIPREC drafts listed streams on the participant itself.
+                    sends: child_texts(node, "send"),
+                    receives: child_texts(node, "recv"),
+                })
+            })
+            .collect();
+
+        for assoc in elements(root, "participantstreamassoc") {
+            if let Some(id) = id_attribute(assoc, "participant_id")
+                && let Some(participant) = participants.iter_mut().find(|p| p.id == id)
+            {
+                participant.sends.extend(child_texts(assoc, "send"));
+                participant.receives.extend(child_texts(assoc, "recv"));
+            }
+        }
+
+        let streams = elements(root, "stream")
+            .filter_map(|node| {
+                Some(MetadataStream {
+                    id: id_attribute(node, "stream_id")?,
+                    label: elements(node, "label").next()?.text()?.trim().to_string(),
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            session_id,
+            participants,
+            streams,
+        })
+    }
+
+    /// Whose audio the m-line with this `a=label` carries, going by the
+    /// `send` and `recv` associations. None unless exactly one participant
+    /// sends the stream, as a mix of several has no single owner.
+    pub fn leg_for_label(&self, label: &str) -> Option<RecordingLeg> {
+        let stream = self.streams.iter().find(|stream| stream.label == label)?;
+        let mut senders = self
+            .participants
+            .iter()
+            .filter(|participant| participant.sends.contains(&stream.id));
+        let (Some(participant), None) = (senders.next(), senders.next()) else {
+            return None;
+        };
+
+        Some(RecordingLeg {
+            session_id: self.session_id.clone(),
+            participant_id: participant.id.clone(),
+            aor: participant.aor.clone(),
+            name: participant.name.clone(),
+            heard_by: self
+                .participants
+                .iter()
+                .filter(|other| other.id != participant.id && other.receives.contains(&stream.id))
+                .map(|other| other.id.clone())
+                .collect(),
+            label: label.to_string(),
+        })
+    }
+}
+
+/// Descendants with the given local name, whatever their namespace.
+fn elements<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.descendants()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn child_texts(node: Node, name: &str) -> Vec<String> {
+    node.children()
+        .filter(|child| child.is_element() && child.tag_name().name() == name)
+        .filter_map(|child| child.text())
+        .map(|text| text.trim().to_string())
+        .collect()
+}
+
+/// RFC 7865 identifiers use `<element>_id`; early drafts used plain `id`.
+fn id_attribute(node: Node, name: &str) -> Option<String> {
+    node.attribute(name)
+        .or_else(|| node.attribute("id"))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Modelled on the complete metadata example in RFC 7865 section 10,
+    /// with the participant who sends label 96 listed second.
+    const METADATA: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<recording xmlns="urn:ietf:params:xml:ns:recording:1">
+  <datamode>complete</datamode>
+  <group group_id="7+OTCyoxTmqmqyA/1weDAg==">
+    <associate-time>2010-12-16T23:41:07Z</associate-time>
+  </group>
+  <session session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <sipSessionID>ab30317f1a784dc48ff824d0d3715d86;remote=47755a9de7794ba387653f2099600ef2</sipSessionID>
+    <group-ref>7+OTCyoxTmqmqyA/1weDAg==</group-ref>
+  </session>
+  <participant participant_id="srfBElmCRp2QB23b7Mpk0w==">
+    <nameID aor="sip:bob@biloxy.com">
+      <name xml:lang="it">Bob</name>
+    </nameID>
+  </participant>
+  <participant participant_id="zSfPoSvdSDCmU3A3TRDxAw==">
+    <nameID aor="sip:alice@atlanta.com">
+      <name xml:lang="it"> Alice </name>
+    </nameID>
+  </participant>
+  <stream stream_id="UAAMm5GRQKSCMVvLyl4rFw==" session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <label>96</label>
+  </stream>
+  <stream stream_id="i1Pz3to5hGk8fuXl+PbwCw==" session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <label>97</label>
+  </stream>
+  <stream stream_id="8zc6e0lYTlWIINA6GR+3ag==" session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <label>98</label>
+  </stream>
+  <sessionrecordingassoc session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <associate-time>2010-12-16T23:41:07Z</associate-time>
+  </sessionrecordingassoc>
+  <participantsessionassoc participant_id="srfBElmCRp2QB23b7Mpk0w==" session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <associate-time>2010-12-16T23:41:07Z</associate-time>
+  </participantsessionassoc>
+  <participantsessionassoc participant_id="zSfPoSvdSDCmU3A3TRDxAw==" session_id="hVpd7YQgRW2nD22h7q60JQ==">
+    <associate-time>2010-12-16T23:41:07Z</associate-time>
+  </participantsessionassoc>
+  <participantstreamassoc participant_id="srfBElmCRp2QB23b7Mpk0w==">
+    <send>i1Pz3to5hGk8fuXl+PbwCw==</send>
+    <send>8zc6e0lYTlWIINA6GR+3ag==</send>
+    <recv>UAAMm5GRQKSCMVvLyl4rFw==</recv>
+  </participantstreamassoc>
+  <participantstreamassoc participant_id="zSfPoSvdSDCmU3A3TRDxAw==">
+    <send>UAAMm5GRQKSCMVvLyl4rFw==</send>
+    <send>8zc6e0lYTlWIINA6GR+3ag==</send>
+    <recv>i1Pz3to5hGk8fuXl+PbwCw==</recv>
+  </participantstreamassoc>
+</recording>"#;
+
+    fn contents(parts: &[BodyPart]) -> Vec<(&str, &[u8])> {
+        parts
+            .iter()
+            .map(|part| (part.content_type.as_str(), &part.body[..]))
+            .collect()
+    }
+
+    #[test]
+    fn splits_a_multipart_body() {
+        let body = "preamble\r\n\
+            --OSS-unique-boundary-42\r\n\
+            Content-Type: application/sdp\r\n\
+            \r\n\
+            v=0\r\n\
+            \r\n\
+            --OSS-unique-boundary-42\r\n\
+            content-type : application/rs-metadata+xml\r\n\
+            Content-Disposition: recording-session\r\n\
+            \r\n\
+            <recording/>\r\n\
+            --OSS-unique-boundary-42--\r\n\
+            epilogue";
+        for content_type in [
+            "multipart/mixed;boundary=OSS-unique-boundary-42",
+            "multipart/mixed; boundary=\"OSS-unique-boundary-42\"",
+        ] {
+            let parts = parse_multipart(content_type, body.as_bytes()).unwrap();
+            assert_eq!(
+                contents(&parts),
+                [(SDP, &b"v=0\r\n"[..]), (RS_METADATA, &b"<recording/>"[..])]
+            );
+            assert!(parts[1].is(RS_METADATA));
+        }
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds() {
+        let body = "--b\nContent-Type: application/sdp\n\nv=0\n\n--b\n\nplain\n--b--";
+        let parts = parse_multipart("multipart/mixed; boundary=b", body.as_bytes()).unwrap();
+        assert_eq!(
+            contents(&parts),
+            [(SDP, &b"v=0\n"[..]), ("text/plain", &b"plain"[..])]
+        );
+    }
+
+    #[test]
+    fn ignores_lines_that_only_start_with_the_boundary() {
+        let body = "--b \r\n\r\n--bb\r\n--b-- \r\n";
+        let parts = parse_multipart("multipart/mixed; boundary=b", body.as_bytes()).unwrap();
+        assert_eq!(contents(&parts), [("text/plain", &b"--bb"[..])]);
+    }
+
+    #[test]
+    fn rejects_malformed_multipart_bodies() {
+        let body = b"--b\r\n\r\nv=0\r\n--b\r\n\r\nv=0\r\n";
+        for content_type in ["multipart/mixed", "multipart/mixed; boundary=\"\""] {
+            assert!(parse_multipart(content_type, body).is_err());
+        }
+        // No closing boundary, and no boundary at all.
+        assert!(parse_multipart("multipart/mixed; boundary=b", body).is_err());
+        assert!(parse_multipart("multipart/mixed; boundary=c", body).is_err());
+    }
+
+    #[test]
+    fn matches_media_types_without_parameters() {
+        assert!(is_media_type("Application/SDP ; charset=utf-8", SDP));
+        assert!(is_media_type(
+            " multipart/mixed;boundary=x",
+            MULTIPART_MIXED
+        ));
+        assert!(!is_media_type("application/sdp+xml", SDP));
+    }
+
+    #[test]
+    fn ties_streams_to_the_participant_that_sends_them() {
+        let metadata = RecordingMetadata::parse(METADATA).unwrap();
+
+        let alice = metadata.leg_for_label("96").unwrap();
+        assert_eq!(
+            alice,
+            RecordingLeg {
+                session_id: Some("hVpd7YQgRW2nD22h7q60JQ==".to_string()),
+                participant_id: "zSfPoSvdSDCmU3A3TRDxAw==".to_string(),
+                aor: Some("sip:alice@atlanta.com".to_string()),
+                name: Some("Alice".to_string()),
+                heard_by: vec!["srfBElmCRp2QB23b7Mpk0w==".to_string()],
+                label: "96".to_string(),
+            }
+        );
+        let bob = metadata.leg_for_label("97").unwrap();
+        assert_eq!(bob.aor.as_deref(), Some("sip:bob@biloxy.com"));
+        assert_eq!(bob.heard_by, ["zSfPoSvdSDCmU3A3TRDxAw=="]);
+
+        // A mix of both parties, and a label the metadata does not list.
+        assert_eq!(metadata.leg_for_label("98"), None);
+        assert_eq!(metadata.leg_for_label("99"), None);
+    }
+
+    #[test]
+    fn reads_streams_listed_on_participants_by_early_drafts() {
+        let metadata = RecordingMetadata::parse(
+            "<recording xmlns='urn:ietf:params:xml:ns:recording'>\
+               <participant id='p1'><send>s1</send><recv>s2</recv></participant>\
+               <participant id='p2'><send>s2</send><recv>s1</recv></participant>\
+               <stream id='s1'><label>1</label></stream>\
+               <stream id='s2'><label>2</label></stream>\
+             </recording>",
+        )
+        .unwrap();
+        let leg = metadata.leg_for_label("2").unwrap();
+        assert_eq!(leg.participant_id, "p2");
+        assert_eq!(leg.heard_by, ["p1"]);
+        assert_eq!(leg.session_id, None);
+    }
+
+    #[test]
+    fn rejects_documents_that_are_not_recording_metadata() {
+        assert!(RecordingMetadata::parse("<recording>").is_err());
+        assert!(RecordingMetadata::parse("<session/>").is_err());
+    }
+}
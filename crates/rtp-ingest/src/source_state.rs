@@ -0,0 +1,390 @@
+use std::time::{Duration, Instant};
+
+use shared_types::ReceptionStats;
+
+const RTP_SEQ_MOD: u32 = 1 << 16;
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+const DUPLICATE_WINDOW: u32 = 128;
+
+/// How an incoming sequence number fits into a source's sequence space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceOutcome {
+    /// Valid packet, with its extended sequence number.
+    Accepted(u32),
+    /// The sender jumped to a new sequence space and we followed it.
+    Restarted(u32),
+    Duplicate,
+    /// A large jump that has not been confirmed yet, or a packet from before
+    /// the start of the stream.
+    Invalid,
+}
+
+/// Per-source reception state from RFC 3550 Appendix A.1, A.3 and A.8.
+pub struct SourceState {
+    ssrc: u32,
+    clock_rate: u32,
+    epoch: Instant,
+    max_seq: u16,
+    cycles: u32,
+    base_seq: u32,
+    bad_seq: u32,
+    received: u64,
+    expected_prior: u64,
+    received_prior: u64,
+    duplicates: u64,
+    out_of_order: u64,
+    /// Bit `n` is set if `cycles + max_seq - n` has been received.
+    recent: u128,
+    transit: Option<u32>,
+    jitter: f64,
+}
+
+impl SourceState {
+    pub fn new(ssrc: u32, clock_rate: u32, seq: u16) -> Self {
+        let mut state = Self {
+            ssrc,
+            clock_rate: clock_rate.max(1),
+            epoch: Instant::now(),
+            max_seq: 0,
+            cycles: 0,
+            base_seq: 0,
+            bad_seq: RTP_SEQ_MOD + 1,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            duplicates: 0,
+            out_of_order: 0,
+            recent: 0,
+            transit: None,
+            jitter: 0.0,
+        };
+        state.init_seq(seq);
+        state
+    }
+
+    /// Records a packet, returning where it falls in the extended sequence space.
+    pub fn update(&mut self, seq: u16, rtp_timestamp: u32, arrival: Instant) -> SequenceOutcome {
+        let outcome = self.update_seq(seq);
+        if matches!(
+            outcome,
+            SequenceOutcome::Accepted(_) | SequenceOutcome::Restarted(_)
+        ) {
+            self.update_jitter(rtp_timestamp, arrival);
+        }
+        outcome
+    }
+
+    /// Records a packet whose timestamp says nothing about when it was
+    /// sampled, such as a telephone-event, leaving jitter alone.
+    pub fn update_sequence(&mut self, seq: u16) -> SequenceOutcome {
+        self.update_seq(seq)
+    }
+
+    /// The extended sequence number of `seq` if it lies within the recent
+    /// window behind the highest received and has not arrived.
+    pub fn missing(&self, seq: u16) -> Option<u32> {
+        let behind = u32::from(self.max_seq.wrapping_sub(seq));
+        if behind == 0 || behind >= DUPLICATE_WINDOW || self.recent & (1u128 << behind) != 0 {
+            return None;
+        }
+        self.extended_max()
+            .checked_sub(behind)
+            .filter(|&extended| extended >= self.base_seq)
+    }
+
+    /// Whether `seq` could carry on from the highest received: a moderate
+    /// step forward, or a little behind it.
+    pub fn follows(&self, seq: u16) -> bool {
+        let udelta = seq.wrapping_sub(self.max_seq);
+        udelta < MAX_DROPOUT || self.max_seq.wrapping_sub(seq) <= MAX_MISORDER
+    }
+
+    pub fn clock_rate(&self) -> u32 {
+        self.clock_rate
+    }
+
+    pub fn extended_max(&self) -> u32 {
+        self.cycles.wrapping_add(u32::from(self.max_seq))
+    }
+
+    pub fn expected(&self) -> u64 {
+        u64::from(self.extended_max().wrapping_sub(self.base_seq)) + 1
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn cumulative_lost(&self) -> i64 {
+        self.expected() as i64 - self.received as i64
+    }
+
+    /// Fraction lost over the current reporting interval, per A.3.
+    pub fn fraction_lost(&self) -> u8 {
+        let expected_interval = self.expected().saturating_sub(self.expected_prior);
+        let received_interval = self.received.saturating_sub(self.received_prior);
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        if expected_interval == 0 || lost_interval == 0 {
+            return 0;
+        }
+        u8::try_from((lost_interval << 8) / expected_interval).unwrap_or(u8::MAX)
+    }
+
+    /// Starts a new reporting interval for `fraction_lost`.
+    pub fn close_interval(&mut self) {
+        self.expected_prior = self.expected();
+        self.received_prior = self.received;
+    }
+
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter / f64::from(self.clock_rate))
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn snapshot(&self) -> ReceptionStats {
+        ReceptionStats {
+            ssrc: self.ssrc,
+            clock_rate: self.clock_rate,
+            packets_received: self.received,
+            packets_expected: self.expected(),
+            cumulative_lost: self.cumulative_lost(),
+            fraction_lost: self.fraction_lost(),
+            extended_highest_seq: self.extended_max(),
+            sequence_cycles: self.cycles / RTP_SEQ_MOD,
+            duplicates: self.duplicates,
+            out_of_order: self.out_of_order,
+            jitter: self.jitter as u32,
+            rejected_payload: 0,
+            srtp_auth_failures: 0,
+            srtp_replayed: 0,
+            comfort_noise_packets: 0,
+            dtx_periods: 0,
+            dtx_silence_ms: 0,
+            packets_recovered: 0,
+            packets_unrecovered: 0,
+        }
+    }
+
+    fn init_seq(&mut self, seq: u16) {
+        self.base_seq = u32::from(seq);
+        self.max_seq = seq;
+        self.bad_seq = RTP_SEQ_MOD + 1;
+        self.cycles = 0;
+        self.received = 0;
+        self.received_prior = 0;
+        self.expected_prior = 0;
+        self.recent = 0;
+    }
+
+    fn update_seq(&mut self, seq: u16) -> SequenceOutcome {
+        let udelta = seq.wrapping_sub(self.max_seq);
+
+        if self.received == 0 && seq == self.max_seq {
+            self.received = 1;
+            self.recent = 1;
+            return SequenceOutcome::Accepted(self.extended_max());
+        }
+
+        if udelta == 0 {
+            self.received += 1;
+            self.duplicates += 1;
+            return SequenceOutcome::Duplicate;
+        }
+
+        if udelta < MAX_DROPOUT {
+            // In order, with a permissible gap.
+            if seq < self.max_seq {
+                self.cycles = self.cycles.wrapping_add(RTP_SEQ_MOD);
+            }
+            self.max_seq = seq;
+            self.recent = if u32::from(udelta) < DUPLICATE_WINDOW {
+                (self.recent << udelta) | 1
+            } else {
+                1
+            };
+            self.received += 1;
+            return SequenceOutcome::Accepted(self.extended_max());
+        }
+
+        if u32::from(udelta) <= RTP_SEQ_MOD - u32::from(MAX_MISORDER) {
+            // The sequence number made a very large jump. Two sequential
+            // packets at the new position mean the sender restarted.
+            if u32::from(seq) == self.bad_seq {
+                self.init_seq(seq);
+                self.received = 1;
+                self.recent = 1;
+                return SequenceOutcome::Restarted(self.extended_max());
+            }
+            self.bad_seq = (u32::from(seq) + 1) & (RTP_SEQ_MOD - 1);
+            return SequenceOutcome::Invalid;
+        }
+
+        // Duplicate or reordered packet from behind the highest seen.
+        let behind = u32::from(self.max_seq.wrapping_sub(seq));
+        let Some(extended) = self
+            .extended_max()
+            .checked_sub(behind)
+            .filter(|&extended| extended >= self.base_seq)
+        else {
+            return SequenceOutcome::Invalid;
+        };
+
+        self.received += 1;
+        if behind < DUPLICATE_WINDOW {
+            let bit = 1u128 << behind;
+            if self.recent & bit != 0 {
+                self.duplicates += 1;
+                return SequenceOutcome::Duplicate;
+            }
+            self.recent |= bit;
+        }
+        self.out_of_order += 1;
+        SequenceOutcome::Accepted(extended)
+    }
+
+    /// A.8 interarrival jitter, with arrival time converted to RTP units.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn update_jitter(&mut self, rtp_timestamp: u32, arrival: Instant) {
+        let arrival_units = (arrival.duration_since(self.epoch).as_secs_f64()
+            * f64::from(self.clock_rate)) as u64 as u32;
+        let transit = arrival_units.wrapping_sub(rtp_timestamp);
+
+        if let Some(last_transit) = self.transit {
+            #[allow(clippy::cast_possible_wrap)]
+            let d = f64::from((transit.wrapping_sub(last_transit) as i32).unsigned_abs());
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use SequenceOutcome::{Accepted, Duplicate, Invalid, Restarted};
+
+    fn source(seq: u16) -> SourceState {
+        SourceState::new(0x1234_5678, 8000, seq)
+    }
+
+    fn feed(state: &mut SourceState, seqs: &[u16]) -> Vec<SequenceOutcome> {
+        seqs.iter().map(|&seq| state.update_sequence(seq)).collect()
+    }
+
+    #[test]
+    fn extends_sequence_numbers_across_wrap() {
+        let mut state = source(65534);
+        assert_eq!(
+            feed(&mut state, &[65534, 65535, 0, 1]),
+            [
+                Accepted(65534),
+                Accepted(65535),
+                Accepted(65536),
+                Accepted(65537)
+            ]
+        );
+        // Reordered from before the wrap.
+        assert_eq!(state.missing(65535), None);
+        assert_eq!(state.update_sequence(65535), Duplicate);
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.extended_highest_seq, 65537);
+        assert_eq!(snapshot.sequence_cycles, 1);
+        assert_eq!(snapshot.packets_expected, 4);
+        assert_eq!(snapshot.duplicates, 1);
+    }
+
+    #[test]
+    fn counts_loss_until_late_packets_arrive() {
+        let mut state = source(0);
+        feed(&mut state, &[0, 1, 3, 4]);
+        assert_eq!(state.expected(), 5);
+        assert_eq!(state.cumulative_lost(), 1);
+        assert_eq!(state.fraction_lost(), 51);
+        assert_eq!(state.missing(2), Some(2));
+        assert_eq!(state.missing(3), None);
+        assert_eq!(state.missing(4), None);
+
+        state.close_interval();
+        assert_eq!(state.update_sequence(5), Accepted(5));
+        assert_eq!(state.fraction_lost(), 0);
+
+        assert_eq!(state.update_sequence(2), Accepted(2));
+        assert_eq!(state.cumulative_lost(), 0);
+        assert_eq!(state.missing(2), None);
+        assert_eq!(state.snapshot().out_of_order, 1);
+    }
+
+    #[test]
+    fn counts_duplicates_in_and_out_of_order() {
+        let mut state = source(10);
+        assert_eq!(
+            feed(&mut state, &[10, 11, 11, 12, 10]),
+            [
+                Accepted(10),
+                Accepted(11),
+                Duplicate,
+                Accepted(12),
+                Duplicate
+            ]
+        );
+        // A.1 counts duplicates as received, so loss can go negative.
+        assert_eq!(state.cumulative_lost(), -2);
+        assert_eq!(state.snapshot().duplicates, 2);
+    }
+
+    #[test]
+    fn follows_a_restart_once_it_is_confirmed() {
+        let mut state = source(100);
+        feed(&mut state, &[100, 101]);
+        assert!(state.follows(150));
+        assert!(state.follows(50));
+        assert!(!state.follows(101 + MAX_DROPOUT));
+        assert!(!state.follows(101u16.wrapping_sub(MAX_MISORDER + 1)));
+
+        // A single stray packet far ahead is ignored...
+        assert_eq!(state.update_sequence(20000), Invalid);
+        assert_eq!(state.update_sequence(102), Accepted(102));
+        // ...but two in a row mean the sender started again.
+        assert_eq!(state.update_sequence(30000), Invalid);
+        assert_eq!(state.update_sequence(30001), Restarted(30001));
+        assert_eq!(state.update_sequence(30002), Accepted(30002));
+        assert_eq!(state.expected(), 2);
+        assert_eq!(state.cumulative_lost(), 0);
+    }
+
+    #[test]
+    fn ignores_packets_from_before_the_start() {
+        let mut state = source(1000);
+        feed(&mut state, &[1000, 1001]);
+        assert_eq!(state.update_sequence(990), Invalid);
+        assert_eq!(state.missing(999), None);
+        assert_eq!(state.expected(), 2);
+    }
+
+    #[test]
+    fn estimates_interarrival_jitter() {
+        let mut state = source(0);
+        let start = Instant::now();
+        state.epoch = start;
+        // Half an RTP unit in, so arrival never rounds down a unit.
+        let arrive = |millis: u64| start + Duration::from_micros(millis * 1000 + 62);
+
+        // Steady 20 ms packets carry no jitter.
+        for seq in 0..5u16 {
+            let arrival = arrive(u64::from(seq) * 20);
+            state.update(seq, u32::from(seq) * 160, arrival);
+        }
+        assert_eq!(state.jitter(), Duration::ZERO);
+
+        // One packet 10 ms (80 units) late changes transit twice.
+        state.update(5, 5 * 160, arrive(110));
+        assert!((state.jitter - 5.0).abs() < f64::EPSILON);
+        state.update(6, 6 * 160, arrive(120));
+        assert!((state.jitter - 9.6875).abs() < f64::EPSILON);
+        assert_eq!(state.snapshot().jitter, 9);
+
+        // Duplicates leave it alone.
+        state.update(6, 6 * 160, arrive(500));
+        assert!((state.jitter - 9.6875).abs() < f64::EPSILON);
+    }
+}
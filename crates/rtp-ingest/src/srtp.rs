@@ -0,0 +1,945 @@
+use aes::Aes128;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, KeyInit};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use shared_types::sdp::CryptoAttribute;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+const MASTER_KEY_LEN: usize = 16;
+const GCM_TAG_LEN: usize = 16;
+/// Size of the E flag and SRTCP index trailer.
+const SRTCP_INDEX_LEN: usize = 4;
+const RTCP_HEADER_LEN: usize = 8;
+const REPLAY_WINDOW: u64 = 64;
+
+/// RFC 3711 section 4.3.2 key derivation labels.
+const LABEL_RTP_ENCRYPTION: u8 = 0;
+const LABEL_RTP_AUTH: u8 = 1;
+const LABEL_RTP_SALT: u8 = 2;
+const LABEL_RTCP_ENCRYPTION: u8 = 3;
+const LABEL_RTCP_AUTH: u8 = 4;
+const LABEL_RTCP_SALT: u8 = 5;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SrtpError {
+    #[error("unsupported SRTP crypto suite `{0}`")]
+    UnsupportedSuite(String),
+    #[error("invalid SRTP key: {0}")]
+    InvalidKey(&'static str),
+    #[error("packet too short for SRTP")]
+    TooShort,
+    #[error("SRTP authentication failed")]
+    AuthenticationFailed,
+    #[error("SRTP packet replayed")]
+    Replayed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrtpProfile {
+    /// `AES_CM_128_HMAC_SHA1_80` (RFC 4568).
+    AesCm128HmacSha1Tag80,
+    /// `AES_CM_128_HMAC_SHA1_32`, with the short tag on SRTP only.
+    AesCm128HmacSha1Tag32,
+    /// `AEAD_AES_128_GCM` (RFC 7714).
+    AeadAes128Gcm,
+}
+
+impl SrtpProfile {
+    pub fn from_suite(suite: &str) -> Option<Self> {
+        match suite {
+            "AES_CM_128_HMAC_SHA1_80" => Some(Self::AesCm128HmacSha1Tag80),
+            "AES_CM_128_HMAC_SHA1_32" => Some(Self::AesCm128HmacSha1Tag32),
+            "AEAD_AES_128_GCM" => Some(Self::AeadAes128Gcm),
+            _ => None,
+        }
+    }
+
+    pub const fn suite(self) -> &'static str {
+        match self {
+            Self::AesCm128HmacSha1Tag80 => "AES_CM_128_HMAC_SHA1_80",
+            Self::AesCm128HmacSha1Tag32 => "AES_CM_128_HMAC_SHA1_32",
+            Self::AeadAes128Gcm => "AEAD_AES_128_GCM",
+        }
+    }
+
+    const fn salt_len(self) -> usize {
+        match self {
+            Self::AeadAes128Gcm => 12,
+            _ => 14,
+        }
+    }
+
+    const fn rtp_tag_len(self) -> usize {
+        match self {
+            Self::AesCm128HmacSha1Tag80 => 10,
+            Self::AesCm128HmacSha1Tag32 => 4,
+            Self::AeadAes128Gcm => GCM_TAG_LEN,
+        }
+    }
+
+    const fn rtcp_tag_len(self) -> usize {
+        match self {
+            Self::AeadAes128Gcm => GCM_TAG_LEN,
+            _ => 10,
+        }
+    }
+
+    const fn is_aead(self) -> bool {
+        matches!(self, Self::AeadAes128Gcm)
+    }
+
+    /// Reads a DTLS-SRTP protection profile name (RFC 5764 section 4.1.2,
+    /// RFC 7714 section 14.2).
+    pub fn from_dtls_profile(name: &str) -> Option<Self> {
+        match name {
+            "SRTP_AES128_CM_SHA1_80" => Some(Self::AesCm128HmacSha1Tag80),
+            "SRTP_AES128_CM_SHA1_32" => Some(Self::AesCm128HmacSha1Tag32),
+            "SRTP_AEAD_AES_128_GCM" => Some(Self::AeadAes128Gcm),
+            _ => None,
+        }
+    }
+
+    /// Keying material a DTLS-SRTP handshake exports for this profile: a
+    /// master key and salt for each side.
+    pub const fn dtls_keying_material_len(self) -> usize {
+        2 * (MASTER_KEY_LEN + self.salt_len())
+    }
+}
+
+/// A master key and salt for one direction of an SRTP session.
+#[derive(Clone)]
+pub struct SrtpKey {
+    pub profile: SrtpProfile,
+    master_key: [u8; MASTER_KEY_LEN],
+    master_salt: Vec<u8>,
+    /// Length of the MKI field the sender inserts before the tag.
+    mki_len: usize,
+}
+
+impl std::fmt::Debug for SrtpKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print key material.
+        f.debug_struct("SrtpKey")
+            .field("profile", &self.profile)
+            .field("mki_len", &self.mki_len)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SrtpKey {
+    /// Reads an SDES `a=crypto` attribute (RFC 4568 section 6.1):
+    /// `inline:<base64 key||salt>[|lifetime][|MKI:length]`.
+    pub fn from_crypto(crypto: &CryptoAttribute) -> Result<Self, SrtpError> {
+        let profile = SrtpProfile::from_suite(&crypto.suite)
+            .ok_or_else(|| SrtpError::UnsupportedSuite(crypto.suite.clone()))?;
+        // Several keys may be listed; the first is the one in use.
+        let key_param = crypto.key_params.split(';').next().unwrap_or_default();
+        let inline = key_param
+            .strip_prefix("inline:")
+            .ok_or(SrtpError::InvalidKey("only inline keys are supported"))?;
+
+        let mut fields = inline.split('|');
+        let material = BASE64
+            .decode(fields.next().unwrap_or_default())
+            .map_err(|_| SrtpError::InvalidKey("key is not base64"))?;
+        if material.len() != MASTER_KEY_LEN + profile.salt_len() {
+            return Err(SrtpError::InvalidKey("wrong key and salt length"));
+        }
+
+        let mut mki_len = 0;
+        for field in fields {
+            if let Some((_, length)) = field.split_once(':') {
+                mki_len = length
+                    .parse()
+                    .map_err(|_| SrtpError::InvalidKey("bad MKI length"))?;
+            }
+        }
+
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        master_key.copy_from_slice(&material[..MASTER_KEY_LEN]);
+        Ok(Self {
+            profile,
+            master_key,
+            master_salt: material[MASTER_KEY_LEN..].to_vec(),
+            mki_len,
+        })
+    }
+
+    /// A fresh random key for the traffic we send.
+    pub fn generate(profile: SrtpProfile) -> Self {
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut master_key[..]);
+        let mut master_salt = vec![0u8; profile.salt_len()];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut master_salt[..]);
+        Self {
+            profile,
+            master_key,
+            master_salt,
+            mki_len: 0,
+        }
+    }
+
+    pub fn to_crypto(&self, tag: u32) -> CryptoAttribute {
+        let mut material = self.master_key.to_vec();
+        material.extend_from_slice(&self.master_salt);
+        CryptoAttribute {
+            tag,
+            suite: self.profile.suite().to_string(),
+            key_params: format!("inline:{}", BASE64.encode(material)),
+            session_params: None,
+        }
+    }
+
+    /// The AES-CM PRF of RFC 3711 section 4.3.3 with a key derivation rate
+    /// of zero. GCM's 96-bit salt is zero-padded to 112 bits (RFC 7714
+    /// section 11).
+    fn derive(&self, label: u8, len: usize) -> Vec<u8> {
+        let mut iv = [0u8; 16];
+        iv[..self.master_salt.len()].copy_from_slice(&self.master_salt);
+        iv[7] ^= label;
+
+        let mut out = vec![0u8; len];
+        Aes128Ctr::new(&self.master_key.into(), &iv.into()).apply_keystream(&mut out);
+        out
+    }
+}
+
+enum Cipher {
+    Ctr([u8; MASTER_KEY_LEN]),
+    Gcm(Box<Aes128Gcm>),
+}
+
+/// Session keys for one of SRTP or SRTCP in one direction.
+struct SessionKeys {
+    cipher: Cipher,
+    auth: Option<HmacSha1>,
+    salt: [u8; 14],
+}
+
+impl SessionKeys {
+    fn derive(key: &SrtpKey, labels: [u8; 3]) -> Self {
+        let [encryption, auth, salt_label] = labels;
+        let mut encryption_key = [0u8; MASTER_KEY_LEN];
+        encryption_key.copy_from_slice(&key.derive(encryption, MASTER_KEY_LEN));
+        let mut salt = [0u8; 14];
+        let salt_len = key.profile.salt_len();
+        salt[..salt_len].copy_from_slice(&key.derive(salt_label, salt_len));
+
+        if key.profile.is_aead() {
+            Self {
+                cipher: Cipher::Gcm(Box::new(Aes128Gcm::new(&encryption_key.into()))),
+                auth: None,
+                salt,
+            }
+        } else {
+            let auth_key = key.derive(auth, 20);
+            Self {
+                cipher: Cipher::Ctr(encryption_key),
+                auth: Some(
+                    <HmacSha1 as Mac>::new_from_slice(&auth_key)
+                        .expect("HMAC takes any key length"),
+                ),
+                salt,
+            }
+        }
+    }
+
+    /// RFC 3711 section 4.1.1 counter: salt XOR SSRC XOR packet index.
+    fn ctr_iv(&self, ssrc: u32, index: u64) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv[..14].copy_from_slice(&self.salt);
+        for (byte, value) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+            *byte ^= value;
+        }
+        for (byte, value) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+            *byte ^= value;
+        }
+        iv
+    }
+
+    /// RFC 7714 section 8.1 and 9.1 nonce: salt XOR (0 || SSRC || index).
+    fn gcm_iv(&self, ssrc: u32, index: [u8; 6]) -> [u8; 12] {
+        let mut iv = [0u8; 12];
+        iv[2..6].copy_from_slice(&ssrc.to_be_bytes());
+        iv[6..].copy_from_slice(&index);
+        for (byte, salt) in iv.iter_mut().zip(&self.salt[..12]) {
+            *byte ^= salt;
+        }
+        iv
+    }
+
+    fn tag(&self, authenticated: &[&[u8]], len: usize) -> Vec<u8> {
+        let mut mac = self
+            .auth
+            .clone()
+            .expect("AES-CM profiles carry an auth key");
+        for part in authenticated {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes()[..len].to_vec()
+    }
+}
+
+/// Sliding replay window over packet indices (RFC 3711 section 3.3.2).
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, index: u64) -> Result<(), SrtpError> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+        if index > highest {
+            return Ok(());
+        }
+        let age = highest - index;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return Err(SrtpError::Replayed);
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, index: u64) {
+        match self.highest {
+            Some(highest) if index <= highest => self.seen |= 1 << (highest - index),
+            Some(highest) => {
+                let shift = index - highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                } | 1;
+                self.highest = Some(index);
+            }
+            None => {
+                self.highest = Some(index);
+                self.seen = 1;
+            }
+        }
+    }
+}
+
+/// Per-SSRC SRTP receive state: the rollover counter and replay window.
+#[derive(Debug, Default)]
+struct RtpContext {
+    roc: u32,
+    highest_seq: Option<u16>,
+    replay: ReplayWindow,
+}
+
+impl RtpContext {
+    /// RFC 3711 Appendix A index estimation. Returns the rollover counter
+    /// the packet most likely belongs to.
+    fn estimate_roc(&self, seq: u16) -> u32 {
+        let Some(s_l) = self.highest_seq else {
+            return self.roc;
+        };
+        if s_l < 0x8000 {
+            if i32::from(seq) - i32::from(s_l) > 0x8000 {
+                return self.roc.wrapping_sub(1);
+            }
+        } else if i32::from(s_l) - 0x8000 > i32::from(seq) {
+            return self.roc.wrapping_add(1);
+        }
+        self.roc
+    }
+
+    fn accept(&mut self, roc: u32, seq: u16, index: u64) {
+        if self.replay.highest.is_none_or(|highest| index > highest) {
+            self.roc = roc;
+            self.highest_seq = Some(seq);
+        }
+        self.replay.accept(index);
+    }
+}
+
+/// The keys each side of a session protects its traffic with, from SDES or
+/// a DTLS-SRTP handshake.
+#[derive(Debug, Clone)]
+pub struct SrtpKeys {
+    /// Protects the media and RTCP we receive.
+    pub remote: SrtpKey,
+    /// Protects the RTCP we send back.
+    pub local: SrtpKey,
+}
+
+impl SrtpKeys {
+    /// Splits keying material exported from a DTLS-SRTP handshake, laid out
+    /// as client key, server key, client salt, server salt (RFC 5764
+    /// section 4.2). `server` says which side of the handshake we were.
+    pub fn from_dtls(
+        profile: SrtpProfile,
+        material: &[u8],
+        server: bool,
+    ) -> Result<Self, SrtpError> {
+        if material.len() != profile.dtls_keying_material_len() {
+            return Err(SrtpError::InvalidKey(
+                "wrong DTLS-SRTP keying material length",
+            ));
+        }
+        let salt_len = profile.salt_len();
+        let (keys, salts) = material.split_at(2 * MASTER_KEY_LEN);
+        let key = |index: usize| {
+            let mut master_key = [0u8; MASTER_KEY_LEN];
+            master_key.copy_from_slice(&keys[index * MASTER_KEY_LEN..][..MASTER_KEY_LEN]);
+            SrtpKey {
+                profile,
+                master_key,
+                master_salt: salts[index * salt_len..][..salt_len].to_vec(),
+                mki_len: 0,
+            }
+        };
+        let (client, server_key) = (key(0), key(1));
+        Ok(if server {
+            Self {
+                remote: client,
+                local: server_key,
+            }
+        } else {
+            Self {
+                remote: server_key,
+                local: client,
+            }
+        })
+    }
+}
+
+/// An SRTP session: the remote key protecting what we receive and our own
+/// key protecting the RTCP we send back, with crypto contexts per SSRC.
+pub struct SrtpSession {
+    remote_profile: SrtpProfile,
+    remote_mki_len: usize,
+    remote_rtp: SessionKeys,
+    remote_rtcp: SessionKeys,
+    local_profile: SrtpProfile,
+    local_rtcp: SessionKeys,
+    rtp_contexts: HashMap<u32, RtpContext>,
+    rtcp_replay: HashMap<u32, ReplayWindow>,
+    rtcp_index: HashMap<u32, u32>,
+}
+
+impl SrtpSession {
+    pub fn new(keys: &SrtpKeys) -> Self {
+        let SrtpKeys { remote, local } = keys;
+        Self {
+            remote_profile: remote.profile,
+            remote_mki_len: remote.mki_len,
+            remote_rtp: SessionKeys::derive(
+                remote,
+                [LABEL_RTP_ENCRYPTION, LABEL_RTP_AUTH, LABEL_RTP_SALT],
+            ),
+            remote_rtcp: SessionKeys::derive(
+                remote,
+                [LABEL_RTCP_ENCRYPTION, LABEL_RTCP_AUTH, LABEL_RTCP_SALT],
+            ),
+            local_profile: local.profile,
+            local_rtcp: SessionKeys::derive(
+                local,
+                [LABEL_RTCP_ENCRYPTION, LABEL_RTCP_AUTH, LABEL_RTCP_SALT],
+            ),
+            rtp_contexts: HashMap::new(),
+            rtcp_replay: HashMap::new(),
+            rtcp_index: HashMap::new(),
+        }
+    }
+
+    /// Authenticates and decrypts an SRTP packet into plain RTP. Crypto
+    /// state only advances for packets that authenticate.
+    pub fn unprotect_rtp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        let header_len = rtp_header_len(packet).ok_or(SrtpError::TooShort)?;
+        let tag_len = self.remote_profile.rtp_tag_len();
+        // GCM's tag ends the ciphertext rather than following the MKI, but
+        // the packet has to be long enough for it all the same.
+        let trailer = self.remote_mki_len + tag_len;
+        if packet.len() < header_len + trailer {
+            return Err(SrtpError::TooShort);
+        }
+
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let context = self.rtp_contexts.get(&ssrc);
+        let roc = context.map_or(0, |context| context.estimate_roc(seq));
+        let index = (u64::from(roc) << 16) | u64::from(seq);
+        if let Some(context) = context {
+            context.replay.check(index)?;
+        }
+
+        let keys = &self.remote_rtp;
+        let header = &packet[..header_len];
+        let mut plain = header.to_vec();
+        match &keys.cipher {
+            Cipher::Gcm(gcm) => {
+                let body = &packet[header_len..packet.len() - self.remote_mki_len];
+                let mut nonce_index = [0u8; 6];
+                nonce_index[..4].copy_from_slice(&roc.to_be_bytes());
+                nonce_index[4..].copy_from_slice(&seq.to_be_bytes());
+                let nonce = keys.gcm_iv(ssrc, nonce_index);
+                let payload = gcm
+                    .decrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: body,
+                            aad: header,
+                        },
+                    )
+                    .map_err(|_| SrtpError::AuthenticationFailed)?;
+                plain.extend_from_slice(&payload);
+            }
+            Cipher::Ctr(key) => {
+                let authenticated = &packet[..packet.len() - trailer];
+                let tag = &packet[packet.len() - tag_len..];
+                let expected = keys.tag(&[authenticated, &roc.to_be_bytes()], tag_len);
+                if !bool::from(expected.ct_eq(tag)) {
+                    return Err(SrtpError::AuthenticationFailed);
+                }
+                let mut payload = authenticated[header_len..].to_vec();
+                Aes128Ctr::new(key.into(), &keys.ctr_iv(ssrc, index).into())
+                    .apply_keystream(&mut payload);
+                plain.extend_from_slice(&payload);
+            }
+        }
+
+        self.rtp_contexts
+            .entry(ssrc)
+            .or_default()
+            .accept(roc, seq, index);
+        Ok(plain)
+    }
+
+    /// Authenticates and decrypts an SRTCP compound packet.
+    pub fn unprotect_rtcp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        let is_aead = self.remote_profile.is_aead();
+        let tag_len = self.remote_profile.rtcp_tag_len();
+        // AES-CM puts the tag after the index; GCM folds it into the ciphertext.
+        let after_index = self.remote_mki_len + if is_aead { 0 } else { tag_len };
+        let min_len =
+            RTCP_HEADER_LEN + SRTCP_INDEX_LEN + after_index + if is_aead { tag_len } else { 0 };
+        if packet.len() < min_len {
+            return Err(SrtpError::TooShort);
+        }
+
+        let index_at = packet.len() - after_index - SRTCP_INDEX_LEN;
+        let e_and_index = &packet[index_at..index_at + SRTCP_INDEX_LEN];
+        let encrypted = e_and_index[0] & 0x80 != 0;
+        let index = u32::from_be_bytes([
+            e_and_index[0] & 0x7f,
+            e_and_index[1],
+            e_and_index[2],
+            e_and_index[3],
+        ]);
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        if let Some(replay) = self.rtcp_replay.get(&ssrc) {
+            replay.check(u64::from(index))?;
+        }
+
+        let keys = &self.remote_rtcp;
+        let header = &packet[..RTCP_HEADER_LEN];
+        let body = &packet[RTCP_HEADER_LEN..index_at];
+        let mut plain = header.to_vec();
+        match &keys.cipher {
+            Cipher::Gcm(gcm) => {
+                let mut nonce_index = [0u8; 6];
+                nonce_index[2..].copy_from_slice(&index.to_be_bytes());
+                let nonce = keys.gcm_iv(ssrc, nonce_index);
+                // Unencrypted SRTCP authenticates the whole body as AAD.
+                let (msg, aad) = if encrypted {
+                    (body, [header, e_and_index].concat())
+                } else {
+                    let (clear, tag) = body.split_at(body.len() - tag_len);
+                    (tag, [header, clear, e_and_index].concat())
+                };
+                let payload = gcm
+                    .decrypt(&nonce.into(), Payload { msg, aad: &aad })
+                    .map_err(|_| SrtpError::AuthenticationFailed)?;
+                if encrypted {
+                    plain.extend_from_slice(&payload);
+                } else {
+                    plain.extend_from_slice(&body[..body.len() - tag_len]);
+                }
+            }
+            Cipher::Ctr(key) => {
+                let authenticated = &packet[..index_at + SRTCP_INDEX_LEN];
+                let tag = &packet[packet.len() - tag_len..];
+                let expected = keys.tag(&[authenticated], tag_len);
+                if !bool::from(expected.ct_eq(tag)) {
+                    return Err(SrtpError::AuthenticationFailed);
+                }
+                let mut payload = body.to_vec();
+                if encrypted {
+                    Aes128Ctr::new(key.into(), &keys.ctr_iv(ssrc, u64::from(index)).into())
+                        .apply_keystream(&mut payload);
+                }
+                plain.extend_from_slice(&payload);
+            }
+        }
+
+        self.rtcp_replay
+            .entry(ssrc)
+            .or_default()
+            .accept(u64::from(index));
+        Ok(plain)
+    }
+
+    /// Encrypts and authenticates an outgoing RTCP compound packet with our key.
+    pub fn protect_rtcp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        if packet.len() < RTCP_HEADER_LEN {
+            return Err(SrtpError::TooShort);
+        }
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let counter = self.rtcp_index.entry(ssrc).or_insert(0);
+        let index = *counter;
+        *counter = (*counter + 1) & 0x7fff_ffff;
+        let e_and_index = (index | 0x8000_0000).to_be_bytes();
+
+        let keys = &self.local_rtcp;
+        let header = &packet[..RTCP_HEADER_LEN];
+        let mut out = header.to_vec();
+        match &keys.cipher {
+            Cipher::Gcm(gcm) => {
+                let mut nonce_index = [0u8; 6];
+                nonce_index[2..].copy_from_slice(&index.to_be_bytes());
+                let nonce = keys.gcm_iv(ssrc, nonce_index);
+                let aad = [header, &e_and_index].concat();
+                let sealed = gcm
+                    .encrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: &packet[RTCP_HEADER_LEN..],
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|_| SrtpError::AuthenticationFailed)?;
+                out.extend_from_slice(&sealed);
+                out.extend_from_slice(&e_and_index);
+            }
+            Cipher::Ctr(key) => {
+                let mut payload = packet[RTCP_HEADER_LEN..].to_vec();
+                Aes128Ctr::new(key.into(), &keys.ctr_iv(ssrc, u64::from(index)).into())
+                    .apply_keystream(&mut payload);
+                out.extend_from_slice(&payload);
+                out.extend_from_slice(&e_and_index);
+                let tag = keys.tag(&[&out], self.local_profile.rtcp_tag_len());
+                out.extend_from_slice(&tag);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Fixed header, CSRCs and any header extension; everything SRTP leaves
+/// in the clear.
+fn rtp_header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 12 {
+        return None;
+    }
+    let mut len = 12 + 4 * usize::from(packet[0] & 0x0f);
+    if packet[0] & 0x10 != 0 {
+        let words = packet.get(len + 2..len + 4)?;
+        len += 4 + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+    }
+    (len <= packet.len()).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&text[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn crypto(suite: &str, key_params: &str) -> CryptoAttribute {
+        CryptoAttribute {
+            tag: 1,
+            suite: suite.to_string(),
+            key_params: key_params.to_string(),
+            session_params: None,
+        }
+    }
+
+    fn rtp(seq: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 0];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&(u32::from(seq) * 160).to_be_bytes());
+        packet.extend_from_slice(&0xCAFE_F00Du32.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// What a sender holding `key` puts on the wire for `packet`, which
+    /// the session itself never needs to do for RTP.
+    fn protect_rtp(key: &SrtpKey, packet: &[u8], roc: u32) -> Vec<u8> {
+        let keys = SessionKeys::derive(key, [LABEL_RTP_ENCRYPTION, LABEL_RTP_AUTH, LABEL_RTP_SALT]);
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let (header, payload) = packet.split_at(12);
+        let mut out = header.to_vec();
+        match &keys.cipher {
+            Cipher::Gcm(gcm) => {
+                let mut index = [0u8; 6];
+                index[..4].copy_from_slice(&roc.to_be_bytes());
+                index[4..].copy_from_slice(&seq.to_be_bytes());
+                let sealed = gcm
+                    .encrypt(
+                        &keys.gcm_iv(ssrc, index).into(),
+                        Payload {
+                            msg: payload,
+                            aad: header,
+                        },
+                    )
+                    .unwrap();
+                out.extend_from_slice(&sealed);
+            }
+            Cipher::Ctr(cipher_key) => {
+                let index = (u64::from(roc) << 16) | u64::from(seq);
+                let mut encrypted = payload.to_vec();
+                Aes128Ctr::new(cipher_key.into(), &keys.ctr_iv(ssrc, index).into())
+                    .apply_keystream(&mut encrypted);
+                out.extend_from_slice(&encrypted);
+                out.resize(out.len() + key.mki_len, 0);
+                let tag = keys.tag(
+                    &[&out[..out.len() - key.mki_len], &roc.to_be_bytes()],
+                    key.profile.rtp_tag_len(),
+                );
+                out.extend_from_slice(&tag);
+            }
+        }
+        out
+    }
+
+    fn session(profile: SrtpProfile) -> (SrtpKey, SrtpSession) {
+        let remote = SrtpKey::generate(profile);
+        let keys = SrtpKeys {
+            remote: remote.clone(),
+            local: SrtpKey::generate(profile),
+        };
+        (remote, SrtpSession::new(&keys))
+    }
+
+    #[test]
+    fn aes_cm_keystream_matches_rfc_3711_b2() {
+        let keys = SessionKeys {
+            cipher: Cipher::Ctr(hex("2B7E151628AED2A6ABF7158809CF4F3C").try_into().unwrap()),
+            auth: None,
+            salt: hex("F0F1F2F3F4F5F6F7F8F9FAFBFCFD").try_into().unwrap(),
+        };
+        let Cipher::Ctr(key) = &keys.cipher else {
+            unreachable!()
+        };
+        let mut keystream = vec![0u8; 0xFF02 * 16];
+        Aes128Ctr::new(key.into(), &keys.ctr_iv(0, 0).into()).apply_keystream(&mut keystream);
+
+        for (block, expected) in [
+            (0x0000, "E03EAD0935C95E80E166B16DD92B4EB4"),
+            (0x0001, "D23513162B02D0F72A43A2FE4A5F97AB"),
+            (0x0002, "41E95B3BB0A2E8DD477901E4FCA894C0"),
+            (0xFEFF, "EC8CDF7398607CB0F2D21675EA9EA1E4"),
+            (0xFF00, "362B7C3C6773516318A077D7FC5073AE"),
+            (0xFF01, "6A2CC3787889374FBEB4C81B17BA6C44"),
+        ] {
+            assert_eq!(
+                keystream[block * 16..][..16],
+                hex(expected),
+                "block {block:#06x}"
+            );
+        }
+    }
+
+    #[test]
+    fn derives_session_keys_as_rfc_3711_b3() {
+        let key = SrtpKey {
+            profile: SrtpProfile::AesCm128HmacSha1Tag80,
+            master_key: hex("E1F97A0D3E018BE0D64FA32C06DE4139").try_into().unwrap(),
+            master_salt: hex("0EC675AD498AFEEBB6960B3AABE6"),
+            mki_len: 0,
+        };
+        assert_eq!(
+            key.derive(LABEL_RTP_ENCRYPTION, 16),
+            hex("C61E7A93744F39EE10734AFE3FF7A087")
+        );
+        assert_eq!(
+            key.derive(LABEL_RTP_SALT, 14),
+            hex("30CBBC08863D8C85D49DB34A9AE1")
+        );
+        assert_eq!(
+            key.derive(LABEL_RTP_AUTH, 20),
+            hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4")
+        );
+    }
+
+    #[test]
+    fn reads_sdes_keys() {
+        // RFC 4568 section 6.1.
+        let key = SrtpKey::from_crypto(&crypto(
+            "AES_CM_128_HMAC_SHA1_80",
+            "inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR|2^20|1:4",
+        ))
+        .unwrap();
+        assert_eq!(key.profile, SrtpProfile::AesCm128HmacSha1Tag80);
+        assert_eq!(key.mki_len, 4);
+        assert_eq!(key.master_salt.len(), 14);
+
+        let round_trip = SrtpKey::from_crypto(&key.to_crypto(1)).unwrap();
+        assert_eq!(round_trip.master_key, key.master_key);
+        assert_eq!(round_trip.master_salt, key.master_salt);
+
+        for (suite, key_params) in [
+            (
+                "F8_128_HMAC_SHA1_80",
+                "inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR",
+            ),
+            ("AES_CM_128_HMAC_SHA1_80", "uri:https://example.com/key"),
+            ("AES_CM_128_HMAC_SHA1_80", "inline:not base64!"),
+            (
+                "AEAD_AES_128_GCM",
+                "inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR",
+            ),
+            (
+                "AES_CM_128_HMAC_SHA1_80",
+                "inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR|1:x",
+            ),
+        ] {
+            assert!(
+                SrtpKey::from_crypto(&crypto(suite, key_params)).is_err(),
+                "{suite} {key_params}"
+            );
+        }
+    }
+
+    #[test]
+    fn unprotects_rtp_for_each_profile() {
+        for profile in [
+            SrtpProfile::AesCm128HmacSha1Tag80,
+            SrtpProfile::AesCm128HmacSha1Tag32,
+            SrtpProfile::AeadAes128Gcm,
+        ] {
+            let (remote, mut session) = session(profile);
+            let packet = rtp(1, b"twenty bytes of audio");
+            let protected = protect_rtp(&remote, &packet, 0);
+            assert_ne!(protected[12..packet.len()], packet[12..]);
+            assert_eq!(session.unprotect_rtp(&protected), Ok(packet), "{profile:?}");
+            assert_eq!(
+                session.unprotect_rtp(&protected),
+                Err(SrtpError::Replayed),
+                "{profile:?}"
+            );
+
+            let mut tampered = protect_rtp(&remote, &rtp(2, b"audio"), 0);
+            tampered[13] ^= 1;
+            assert_eq!(
+                session.unprotect_rtp(&tampered),
+                Err(SrtpError::AuthenticationFailed),
+                "{profile:?}"
+            );
+            assert_eq!(
+                session.unprotect_rtp(&protected[..12]),
+                Err(SrtpError::TooShort),
+                "{profile:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn skips_the_mki_before_the_tag() {
+        let mut remote = SrtpKey::generate(SrtpProfile::AesCm128HmacSha1Tag80);
+        remote.mki_len = 4;
+        let mut session = SrtpSession::new(&SrtpKeys {
+            remote: remote.clone(),
+            local: SrtpKey::generate(SrtpProfile::AesCm128HmacSha1Tag80),
+        });
+        let packet = rtp(7, b"audio");
+        assert_eq!(
+            session.unprotect_rtp(&protect_rtp(&remote, &packet, 0)),
+            Ok(packet)
+        );
+    }
+
+    #[test]
+    fn follows_the_rollover_counter() {
+        let (remote, mut session) = session(SrtpProfile::AesCm128HmacSha1Tag80);
+        for (seq, roc) in [(65534, 0), (65535, 0), (0, 1), (65533, 0), (1, 1)] {
+            let packet = rtp(seq, b"audio");
+            assert_eq!(
+                session.unprotect_rtp(&protect_rtp(&remote, &packet, roc)),
+                Ok(packet),
+                "seq {seq}"
+            );
+        }
+        // Too old for the replay window.
+        let stale = protect_rtp(&remote, &rtp(65400, b"audio"), 0);
+        assert_eq!(session.unprotect_rtp(&stale), Err(SrtpError::Replayed));
+    }
+
+    #[test]
+    fn protects_rtcp_for_the_other_side() {
+        let receiver_report = [0x80, 201, 0x00, 0x01, 0xCA, 0xFE, 0xF0, 0x0D];
+        let sender_report = [
+            0x80, 200, 0x00, 0x06, 0xCA, 0xFE, 0xF0, 0x0D, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+            13, 14, 15, 16, 17, 18, 19, 20,
+        ];
+        for profile in [
+            SrtpProfile::AesCm128HmacSha1Tag80,
+            SrtpProfile::AesCm128HmacSha1Tag32,
+            SrtpProfile::AeadAes128Gcm,
+        ] {
+            let ours = SrtpKey::generate(profile);
+            let theirs = SrtpKey::generate(profile);
+            let mut us = SrtpSession::new(&SrtpKeys {
+                remote: theirs.clone(),
+                local: ours.clone(),
+            });
+            let mut them = SrtpSession::new(&SrtpKeys {
+                remote: ours,
+                local: theirs,
+            });
+
+            for packet in [&receiver_report[..], &sender_report] {
+                let protected = us.protect_rtcp(packet).unwrap();
+                assert_eq!(them.unprotect_rtcp(&protected).as_deref(), Ok(packet));
+                assert_eq!(them.unprotect_rtcp(&protected), Err(SrtpError::Replayed));
+            }
+            let mut tampered = us.protect_rtcp(&sender_report).unwrap();
+            tampered[10] ^= 1;
+            assert_eq!(
+                them.unprotect_rtcp(&tampered),
+                Err(SrtpError::AuthenticationFailed),
+                "{profile:?}"
+            );
+            assert_eq!(
+                them.unprotect_rtcp(&tampered[..12]),
+                Err(SrtpError::TooShort)
+            );
+        }
+    }
+
+    #[test]
+    fn splits_dtls_keying_material() {
+        let profile = SrtpProfile::AesCm128HmacSha1Tag80;
+        let material: Vec<u8> = (0..60).collect();
+        let keys = SrtpKeys::from_dtls(profile, &material, true).unwrap();
+        assert_eq!(keys.remote.master_key[..], material[..16]);
+        assert_eq!(keys.local.master_key[..], material[16..32]);
+        assert_eq!(keys.remote.master_salt, material[32..46]);
+        assert_eq!(keys.local.master_salt, material[46..]);
+
+        let client = SrtpKeys::from_dtls(profile, &material, false).unwrap();
+        assert_eq!(client.remote.master_key, keys.local.master_key);
+        assert!(SrtpKeys::from_dtls(profile, &material[1..], true).is_err());
+    }
+}
@@ -1,90 +1,1290 @@
-use std::collections::HashMap;
+use bytes::Bytes;
+use chrono::Utc;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::net::SocketAddr;
-use tracing::{debug, info};
-use uuid::Uuid;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
 
-use shared_types::{AudioChunk, StreamId, StreamMetadata, StreamState};
+use crate::comfort_noise::{DtxTracker, NoiseFiller};
+use crate::dtmf::{DtmfDetector, TelephoneEvent};
+use crate::jitter_buffer::{JitterBuffer, JitterBufferConfig};
+use crate::probation::{ProbationConfig, ProbationTable};
+use crate::rtcp_parser::{RtcpPacket, SenderClock, sdes_cname};
+use crate::rtcp_sender::{self, RtcpReportConfig, RtcpTimer};
+use crate::source_state::{SequenceOutcome, SourceState};
+use crate::srtp::{SrtpError, SrtpKeys, SrtpSession};
+use crate::ulpfec::UlpfecReceiver;
+use shared_types::{
+    AudioChunk, AudioFormat, ChunkKind, DtmfEvent, EndReason, HeaderExtensionMap, HeaderExtensions,
+    LatencyMetadata, NegotiatedMedia, PayloadTypeMap, ReceptionStats, RecordingLeg, StreamEvent,
+    StreamId, StreamMetadata, StreamState,
+};
+
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+/// How many recovered packets to remember in case their originals arrive.
+const RECENT_RECOVERIES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct StreamManagerConfig {
+    pub jitter_buffer: JitterBufferConfig,
+    pub rtcp: RtcpReportConfig,
+    pub lifecycle: LifecycleConfig,
+    pub ssrc_change: SsrcChangePolicy,
+    pub relatch: RelatchPolicy,
+    pub probation: ProbationConfig,
+    /// Payload types every stream understands: the RFC 3551 static ones
+    /// plus any dynamic mappings from configuration.
+    pub payload_types: PayloadTypeMap,
+    /// RFC 8285 header extension IDs read on streams outside a call; a
+    /// call reads the ones its SDP negotiated instead.
+    pub header_extensions: HeaderExtensionMap,
+    /// Set when this manager serves a single signalled call.
+    pub call: Option<CallContext>,
+    /// Keys for SRTP and SRTCP; plain RTP is expected when unset.
+    pub srtp: Option<SrtpKeys>,
+    /// Fill DTX silence with generated comfort noise rather than a
+    /// [`ChunkKind::Silence`] marker, for codecs we can encode.
+    pub synthesize_comfort_noise: bool,
+}
+
+/// What signalling told us about the media a manager will receive.
+#[derive(Debug, Clone)]
+pub struct CallContext {
+    pub call_id: String,
+    pub media: NegotiatedMedia,
+    pub recording: Option<RecordingLeg>,
+    /// The RTP port allocated to this media stream of the call.
+    pub media_port: u16,
+}
+
+impl Default for StreamManagerConfig {
+    fn default() -> Self {
+        Self {
+            jitter_buffer: JitterBufferConfig::default(),
+            rtcp: RtcpReportConfig::default(),
+            lifecycle: LifecycleConfig::default(),
+            ssrc_change: SsrcChangePolicy::default(),
+            relatch: RelatchPolicy::default(),
+            probation: ProbationConfig::default(),
+            payload_types: PayloadTypeMap::rfc3551(),
+            header_extensions: HeaderExtensionMap::default(),
+            call: None,
+            srtp: None,
+            synthesize_comfort_noise: false,
+        }
+    }
+}
+
+/// What to do when a new SSRC shows up from an address that already has a stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SsrcChangePolicy {
+    /// Treat the new SSRC as an unrelated stream.
+    #[default]
+    NewStream,
+    /// Carry the existing logical stream over to the new SSRC, as happens
+    /// when an SBC re-INVITE swaps the media source mid-call.
+    LinkToExisting,
+}
+
+/// What to do when a stream's SSRC turns up from a new address, carrying on
+/// its sequence. Streams latch onto the address their first valid packets
+/// came from, which behind NAT is rarely the one in SDP. Only one receive
+/// worker can follow a sender around, so `Allow` and `Reject` need a
+/// single worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RelatchPolicy {
+    /// Treat it as an unrelated stream, as on a port many senders share.
+    #[default]
+    NewStream,
+    /// Follow the sender to the new address, as when a NAT rebinds mid-call.
+    Allow,
+    /// Stay on the first address and drop packets from any other.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LifecycleConfig {
+    /// Silence after which an active stream is marked `Paused`.
+    pub pause_after: Duration,
+    /// Silence after which a stream is marked `Disconnected` and freed.
+    pub disconnect_after: Duration,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            pause_after: Duration::from_secs(5),
+            disconnect_after: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Streams are identified by where they come from as well as their SSRC, so
+/// two callers that pick the same SSRC never get merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamKey {
+    pub source_addr: SocketAddr,
+    pub ssrc: u32,
+}
 
 pub struct StreamManager {
     streams: HashMap<StreamId, StreamInfo>,
-    ssrc_to_stream: HashMap<u32, StreamId>,
+    stream_keys: HashMap<StreamKey, StreamId>,
+    /// Every stream using a given SSRC, for RTCP which arrives from a
+    /// different port than the media it describes.
+    ssrc_index: HashMap<u32, Vec<StreamId>>,
+    probation: ProbationTable,
+    config: StreamManagerConfig,
+    reporter_ssrc: u32,
+    pending_rtcp: Vec<OutgoingRtcp>,
+    /// Chunks out of the jitter buffer, waiting to be forwarded.
+    released: Vec<AudioChunk>,
+    events: broadcast::Sender<StreamEvent>,
+    srtp: Option<SrtpSession>,
+    /// SRTP failures from sources that have no stream to charge them to.
+    unattributed_srtp_failures: u64,
 }
 
 struct StreamInfo {
-    #[allow(dead_code)]
     metadata: StreamMetadata,
     packet_count: u64,
-    last_sequence: u16,
-    out_of_order_count: u64,
+    last_packet_at: Instant,
+    source: Option<SourceState>,
+    sender_clock: Option<SenderClock>,
+    jitter_buffer: JitterBuffer,
+    /// Where our reports go. None if the sender's RTP port leaves no
+    /// room above it and its RTCP has not turned up yet.
+    rtcp_addr: Option<SocketAddr>,
+    rtcp_mux: bool,
+    rtcp_timer: RtcpTimer,
+    /// Set when the sender's sequence space changed, so the next chunk out is
+    /// preceded by a discontinuity marker.
+    discontinuity_pending: bool,
+    payload_types: PayloadTypeMap,
+    header_extensions: HeaderExtensionMap,
+    rejected_payload: u64,
+    rejected_payload_types: BTreeSet<u8>,
+    srtp_auth_failures: u64,
+    srtp_replayed: u64,
+    dtmf: DtmfDetector,
+    /// Clock of the stream's telephone-event payload, once one has been seen.
+    dtmf_clock_rate: Option<u32>,
+    /// Format of the stream's audio, which its comfort noise stands in for.
+    audio_format: Option<AudioFormat>,
+    dtx: DtxTracker,
+    noise_filler: NoiseFiller,
+    fec: UlpfecReceiver,
+    recovered: u64,
+    /// Recently recovered packets, so one whose original turns up late is
+    /// not counted as recovered after all.
+    recent_recoveries: VecDeque<u32>,
+    relatches: u32,
+    /// Addresses that tried to take the stream over and were refused.
+    rejected_sources: Vec<SocketAddr>,
+    relatch_rejected: u64,
+}
+
+impl StreamInfo {
+    fn stats(&self) -> Option<ReceptionStats> {
+        self.source.as_ref().map(|source| ReceptionStats {
+            rejected_payload: self.rejected_payload,
+            srtp_auth_failures: self.srtp_auth_failures,
+            srtp_replayed: self.srtp_replayed,
+            comfort_noise_packets: self.dtx.comfort_noise_packets,
+            dtx_periods: self.dtx.periods,
+            dtx_silence_ms: self.dtx.silence_ms(source.clock_rate()),
+            packets_recovered: self.recovered,
+            packets_unrecovered: u64::try_from(source.cumulative_lost())
+                .unwrap_or_default()
+                .saturating_sub(self.recovered),
+            ..source.snapshot()
+        })
+    }
+}
+
+/// What a packet's payload type says it carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    Audio(AudioFormat),
+    /// RFC 4733 events such as DTMF, on the given RTP clock.
+    TelephoneEvent {
+        clock_rate: u32,
+    },
+    /// RFC 3389 comfort noise standing in for audio in this format.
+    ComfortNoise(AudioFormat),
+    /// RFC 2198 redundant audio wrapping blocks of other payload types.
+    Red,
+    /// RFC 5109 forward error correction for the stream's other packets.
+    Ulpfec,
+}
+
+/// A compound RTCP packet ready to go back to a sender.
+pub struct OutgoingRtcp {
+    pub destination: SocketAddr,
+    /// Send from the RTP socket because the peer multiplexes RTCP onto it.
+    pub rtcp_mux: bool,
+    pub packet: Bytes,
 }
 
 impl StreamManager {
-    pub fn new() -> Self {
+    /// A fresh event channel for [`StreamManager::with_events`].
+    pub fn event_channel() -> broadcast::Sender<StreamEvent> {
+        broadcast::channel(EVENT_CHANNEL_CAPACITY).0
+    }
+
+    /// Creates a manager that publishes onto an existing event channel, so
+    /// several managers can share one set of subscribers.
+    pub fn with_events(
+        config: StreamManagerConfig,
+        events: broadcast::Sender<StreamEvent>,
+    ) -> Self {
+        let reporter_ssrc = rand::random();
+        info!(
+            "RTCP reporter SSRC={}, CNAME={}",
+            reporter_ssrc, config.rtcp.cname
+        );
+
+        let srtp = config.srtp.as_ref().map(|keys| {
+            info!(
+                "SRTP enabled: receiving {}, sending {}",
+                keys.remote.profile.suite(),
+                keys.local.profile.suite()
+            );
+            SrtpSession::new(keys)
+        });
+
         Self {
             streams: HashMap::new(),
-            ssrc_to_stream: HashMap::new(),
+            stream_keys: HashMap::new(),
+            ssrc_index: HashMap::new(),
+            probation: ProbationTable::new(config.probation),
+            config,
+            reporter_ssrc,
+            pending_rtcp: Vec::new(),
+            released: Vec::new(),
+            events,
+            srtp,
+            unattributed_srtp_failures: 0,
         }
     }
 
-    pub fn get_or_create_stream(&mut self, source_addr: SocketAddr, ssrc: u32) -> StreamId {
-        if let Some(&stream_id) = self.ssrc_to_stream.get(&ssrc) {
-            return stream_id;
+    /// Starts decrypting with keys agreed after the manager was created, as
+    /// a DTLS-SRTP handshake on the media port does.
+    pub fn set_srtp(&mut self, keys: &SrtpKeys) {
+        info!(
+            "SRTP keyed: receiving {}, sending {}",
+            keys.remote.profile.suite(),
+            keys.local.profile.suite()
+        );
+        self.srtp = Some(SrtpSession::new(keys));
+    }
+
+    /// Decrypts an SRTP packet, or passes plain RTP through when SRTP is off.
+    /// `None` means the packet failed authentication or was a replay and has
+    /// been counted against its stream.
+    pub fn unprotect_rtp<'a>(
+        &mut self,
+        source_addr: SocketAddr,
+        data: &'a [u8],
+    ) -> Option<Cow<'a, [u8]>> {
+        let Some(session) = self.srtp.as_mut() else {
+            return Some(Cow::Borrowed(data));
+        };
+        match session.unprotect_rtp(data) {
+            Ok(plain) => Some(Cow::Owned(plain)),
+            Err(e) => {
+                let stream_id = data.get(8..12).and_then(|ssrc| {
+                    let ssrc = u32::from_be_bytes([ssrc[0], ssrc[1], ssrc[2], ssrc[3]]);
+                    self.stream_keys
+                        .get(&StreamKey { source_addr, ssrc })
+                        .copied()
+                });
+                self.count_srtp_failure(stream_id, source_addr, &e);
+                None
+            }
+        }
+    }
+
+    /// As [`Self::unprotect_rtp`], for SRTCP.
+    pub fn unprotect_rtcp<'a>(
+        &mut self,
+        source_addr: SocketAddr,
+        data: &'a [u8],
+    ) -> Option<Cow<'a, [u8]>> {
+        let Some(session) = self.srtp.as_mut() else {
+            return Some(Cow::Borrowed(data));
+        };
+        match session.unprotect_rtcp(data) {
+            Ok(plain) => Some(Cow::Owned(plain)),
+            Err(e) => {
+                let stream_id = data.get(4..8).and_then(|ssrc| {
+                    let ssrc = u32::from_be_bytes([ssrc[0], ssrc[1], ssrc[2], ssrc[3]]);
+                    self.find_by_ssrc(ssrc, source_addr)
+                });
+                self.count_srtp_failure(stream_id, source_addr, &e);
+                None
+            }
+        }
+    }
+
+    /// Charges an SRTP failure to its stream, warning only the first time
+    /// so a misconfigured key doesn't flood the log.
+    fn count_srtp_failure(
+        &mut self,
+        stream_id: Option<StreamId>,
+        source_addr: SocketAddr,
+        error: &SrtpError,
+    ) {
+        let Some(stream_info) = stream_id.and_then(|id| self.streams.get_mut(&id)) else {
+            self.unattributed_srtp_failures += 1;
+            debug!(
+                "Dropped SRTP from {} ({} unattributed so far): {}",
+                source_addr, self.unattributed_srtp_failures, error
+            );
+            return;
+        };
+
+        let first = stream_info.srtp_auth_failures + stream_info.srtp_replayed == 0;
+        match error {
+            SrtpError::Replayed => stream_info.srtp_replayed += 1,
+            _ => stream_info.srtp_auth_failures += 1,
+        }
+        if first {
+            warn!(
+                "Stream {} dropping SRTP from {}: {}",
+                stream_info.metadata.id, source_addr, error
+            );
+        } else {
+            debug!(
+                "Stream {} dropping SRTP from {}: {}",
+                stream_info.metadata.id, source_addr, error
+            );
+        }
+    }
+
+    /// Returns the stream a packet belongs to. Unknown sources must first
+    /// pass probation, and `None` is returned for packets sent meanwhile.
+    pub fn get_or_create_stream(
+        &mut self,
+        source_addr: SocketAddr,
+        ssrc: u32,
+        seq: u16,
+    ) -> Option<StreamId> {
+        let key = StreamKey { source_addr, ssrc };
+        if let Some(&stream_id) = self.stream_keys.get(&key) {
+            return Some(stream_id);
+        }
+
+        if self.config.relatch != RelatchPolicy::NewStream
+            && let Some(stream_id) = self.latched_elsewhere(ssrc, source_addr, seq)
+        {
+            return self.relatch(stream_id, key);
+        }
+
+        if !self.probation.admit(key, seq, Instant::now()) {
+            return None;
+        }
+
+        if ssrc == self.reporter_ssrc {
+            self.resolve_own_collision(source_addr);
+        }
+
+        if let Some(existing) = self.ssrc_index.get(&ssrc)
+            && !existing.is_empty()
+        {
+            warn!(
+                "SSRC collision: SSRC={} from {} is already in use by {} other stream(s)",
+                ssrc,
+                source_addr,
+                existing.len()
+            );
+        }
+
+        if self.config.ssrc_change == SsrcChangePolicy::LinkToExisting
+            && let Some(stream_id) = self.sole_stream_from(source_addr)
+        {
+            self.relink_stream(stream_id, key);
+            return Some(stream_id);
         }
 
-        let stream_id = Uuid::new_v4();
         let mut metadata = StreamMetadata::new(source_addr);
+        let stream_id = metadata.id;
         metadata.ssrc = Some(ssrc);
         metadata.state = StreamState::Active;
+        if let Some(call) = &self.config.call {
+            metadata.call_id = Some(call.call_id.clone());
+            metadata = metadata.with_negotiated_media(&call.media);
+            metadata.recording.clone_from(&call.recording);
+            metadata.media_port = Some(call.media_port);
+        }
 
         info!(
             "New RTP stream detected: ID={}, SSRC={}, Source={}",
             stream_id, ssrc, source_addr
         );
+        if let Some(signalled) = self.config.call.as_ref().and_then(|c| c.media.remote_addr)
+            && signalled != source_addr
+        {
+            info!(
+                "Stream {} latched to {} rather than {} from SDP",
+                stream_id, source_addr, signalled
+            );
+        }
 
+        // Until the sender's RTCP shows up, assume it shares the RTP port if
+        // signalling said so, or else the conventional odd port.
+        let rtcp_mux = self
+            .config
+            .call
+            .as_ref()
+            .is_some_and(|call| call.media.rtcp_mux);
+        let rtcp_addr = if rtcp_mux {
+            Some(source_addr)
+        } else {
+            rtcp_above(source_addr)
+        };
+
+        let (payload_types, header_extensions) = self.stream_mappings();
+        let now = Instant::now();
         let stream_info = StreamInfo {
-            metadata,
+            metadata: metadata.clone(),
             packet_count: 0,
-            last_sequence: 0,
-            out_of_order_count: 0,
+            last_packet_at: now,
+            source: None,
+            sender_clock: None,
+            jitter_buffer: JitterBuffer::new(stream_id, self.config.jitter_buffer),
+            rtcp_addr,
+            rtcp_mux,
+            rtcp_timer: RtcpTimer::new(now, self.config.rtcp.session_bandwidth),
+            discontinuity_pending: false,
+            payload_types,
+            header_extensions,
+            rejected_payload: 0,
+            rejected_payload_types: BTreeSet::new(),
+            srtp_auth_failures: 0,
+            srtp_replayed: 0,
+            dtmf: DtmfDetector::default(),
+            dtmf_clock_rate: None,
+            audio_format: metadata.negotiated_format,
+            dtx: DtxTracker::default(),
+            noise_filler: NoiseFiller::new(self.config.synthesize_comfort_noise),
+            fec: UlpfecReceiver::default(),
+            recovered: 0,
+            recent_recoveries: VecDeque::new(),
+            relatches: 0,
+            rejected_sources: Vec::new(),
+            relatch_rejected: 0,
         };
 
         self.streams.insert(stream_id, stream_info);
-        self.ssrc_to_stream.insert(ssrc, stream_id);
+        self.stream_keys.insert(key, stream_id);
+        self.ssrc_index.entry(ssrc).or_default().push(stream_id);
+        self.publish(StreamEvent::Created { metadata });
 
-        stream_id
+        Some(stream_id)
     }
 
-    pub fn process_audio_chunk(&mut self, stream_id: StreamId, chunk: &AudioChunk) {
+    /// Notes that the stream's sender is still sending, whatever the payload
+    /// type turns out to be, so a stream whose packets are all rejected
+    /// does not time out under a sender that is still there.
+    pub fn note_arrival(&mut self, stream_id: StreamId, arrival: Instant) {
         if let Some(stream_info) = self.streams.get_mut(&stream_id) {
-            stream_info.packet_count += 1;
+            stream_info.last_packet_at = arrival;
+        }
+    }
+
+    /// Looks up what a payload type means for this stream. Unknown or
+    /// undecodable payload types are counted and rejected rather than guessed at.
+    pub fn resolve_payload(
+        &mut self,
+        stream_id: StreamId,
+        payload_type: u8,
+    ) -> Option<PayloadKind> {
+        let stream_info = self.streams.get_mut(&stream_id)?;
+        let Some(mapping) = stream_info.payload_types.get(payload_type) else {
+            reject_payload(stream_info, payload_type, "unknown payload type");
+            return None;
+        };
+        if mapping.is("telephone-event") {
+            return Some(PayloadKind::TelephoneEvent {
+                clock_rate: mapping.clock_rate,
+            });
+        }
+        if mapping.is("red") {
+            return Some(PayloadKind::Red);
+        }
+        if mapping.is("ulpfec") {
+            return Some(PayloadKind::Ulpfec);
+        }
+        if mapping.is("CN") {
+            let Some(format) = stream_info.audio_format else {
+                reject_payload(stream_info, payload_type, "comfort noise before any audio");
+                return None;
+            };
+            return Some(PayloadKind::ComfortNoise(format));
+        }
+        let Some(format) = mapping.audio_format() else {
+            let reason = format!("unsupported encoding {mapping}");
+            reject_payload(stream_info, payload_type, &reason);
+            return None;
+        };
+
+        let codec = mapping.to_string();
+        if stream_info.metadata.codec != codec {
+            info!(
+                "Stream {} payload type {} is {}",
+                stream_id, payload_type, codec
+            );
+            stream_info.metadata.codec = codec;
+        }
+        stream_info.audio_format = Some(format);
+        Some(PayloadKind::Audio(format))
+    }
+
+    /// Decodes a telephone-event packet and publishes any key press it
+    /// completes. The packet still counts towards the stream's sequence
+    /// statistics, but not its jitter, as every packet of an event carries
+    /// the timestamp it started at.
+    pub fn process_telephone_event(
+        &mut self,
+        stream_id: StreamId,
+        seq: u16,
+        rtp_timestamp: u32,
+        payload: &[u8],
+        clock_rate: u32,
+        arrival: Instant,
+    ) {
+        let Some(stream_info) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        mark_received(stream_info, arrival, &self.events);
+
+        let ssrc = stream_info.metadata.ssrc.unwrap_or_default();
+        let source = stream_info
+            .source
+            .get_or_insert_with(|| SourceState::new(ssrc, clock_rate, seq));
+        if !matches!(
+            source.update_sequence(seq),
+            SequenceOutcome::Accepted(_) | SequenceOutcome::Restarted(_)
+        ) {
+            return;
+        }
+
+        let Some(packet) = TelephoneEvent::parse(payload) else {
+            debug!("Short telephone-event payload on stream {}", stream_id);
+            return;
+        };
+        stream_info.dtmf_clock_rate = Some(clock_rate);
+        for event in stream_info.dtmf.push(rtp_timestamp, packet, clock_rate) {
+            publish_dtmf(stream_id, event, &self.events);
+        }
+    }
+
+    /// Takes an audio or comfort noise chunk into the stream's jitter
+    /// buffer. `marker` is the RTP marker bit, which starts a talkspurt.
+    pub fn process_audio_chunk(
+        &mut self,
+        stream_id: StreamId,
+        mut chunk: AudioChunk,
+        marker: bool,
+        arrival: Instant,
+    ) {
+        if let Some(stream_info) = self.streams.get_mut(&stream_id) {
+            mark_received(stream_info, arrival, &self.events);
 
             #[allow(clippy::cast_possible_truncation)]
             let seq = chunk.sequence_number as u16;
-            if stream_info.packet_count > 1 {
-                let expected = stream_info.last_sequence.wrapping_add(1);
-                if seq != expected {
-                    stream_info.out_of_order_count += 1;
+            let ssrc = stream_info.metadata.ssrc.unwrap_or_default();
+            let source = stream_info
+                .source
+                .get_or_insert_with(|| SourceState::new(ssrc, chunk.format.rtp_clock_rate(), seq));
+
+            match source.update(seq, chunk.timestamp, arrival) {
+                SequenceOutcome::Accepted(extended) => {
+                    if let Some(index) = stream_info
+                        .recent_recoveries
+                        .iter()
+                        .position(|&recovered| recovered == extended)
+                    {
+                        debug!(
+                            "Recovered packet arrived late: seq={}, stream={}",
+                            seq, stream_id
+                        );
+                        stream_info.recent_recoveries.remove(index);
+                        stream_info.recovered -= 1;
+                        return;
+                    }
+                    chunk.sequence_number = extended;
+                }
+                SequenceOutcome::Restarted(extended) => {
+                    info!("Sequence restart: stream={}, seq={}", stream_id, extended);
+                    let flushed = stream_info.jitter_buffer.reset();
+                    release(
+                        stream_id,
+                        &mut stream_info.noise_filler,
+                        flushed,
+                        &mut self.released,
+                    );
+                    stream_info.discontinuity_pending = true;
+                    chunk.sequence_number = extended;
+                }
+                SequenceOutcome::Duplicate => {
+                    debug!("Duplicate packet: seq={}, stream={}", seq, stream_id);
+                    return;
+                }
+                SequenceOutcome::Invalid => {
                     debug!(
-                        "Out-of-order packet: expected {}, got {} (stream: {})",
-                        expected, seq, stream_id
+                        "Discarding packet outside sequence window: seq={}, stream={}",
+                        seq, stream_id
                     );
+                    return;
                 }
             }
-            stream_info.last_sequence = seq;
 
-            if stream_info.packet_count % 1000 == 0 {
+            stream_info.dtx.on_packet(
+                chunk.sequence_number,
+                chunk.timestamp,
+                marker,
+                matches!(chunk.kind, ChunkKind::ComfortNoise { .. }),
+                source.clock_rate(),
+            );
+
+            chunk.capture_time = stream_info
+                .sender_clock
+                .and_then(|clock| clock.wallclock(chunk.timestamp, source.clock_rate()));
+
+            if stream_info.discontinuity_pending {
+                stream_info.discontinuity_pending = false;
+                let marker = discontinuity_marker(stream_id, &chunk);
+                release(
+                    stream_id,
+                    &mut stream_info.noise_filler,
+                    vec![marker],
+                    &mut self.released,
+                );
+            }
+
+            let jitter = source.jitter();
+            stream_info.jitter_buffer.push(chunk, arrival, jitter);
+
+            if stream_info.packet_count.is_multiple_of(1000)
+                && let Some(stats) = stream_info.stats()
+            {
                 info!(
-                    "Stream {} stats: {} packets, {} out-of-order",
-                    stream_id, stream_info.packet_count, stream_info.out_of_order_count
+                    "Stream {} stats: {} received, {} lost ({:.2}%), {} duplicate, {} out-of-order, {} rejected, jitter {:.2}ms, {} late, playout delay {:?}",
+                    stream_id,
+                    stats.packets_received,
+                    stats.cumulative_lost,
+                    stats.loss_percent(),
+                    stats.duplicates,
+                    stats.out_of_order,
+                    stats.rejected_payload,
+                    stats.jitter_ms(),
+                    stream_info.jitter_buffer.late_packets(),
+                    stream_info.jitter_buffer.playout_delay()
                 );
             }
 
-            // TODO: Forward to audio-router service
+            let ready = stream_info.jitter_buffer.pop_ready(arrival);
+            release(
+                stream_id,
+                &mut stream_info.noise_filler,
+                ready,
+                &mut self.released,
+            );
+        }
+    }
+
+    /// Takes a chunk rebuilt from redundancy or FEC in place of packet `seq`,
+    /// provided that packet is still missing and not yet due for playout.
+    pub fn recover_audio_chunk(
+        &mut self,
+        stream_id: StreamId,
+        seq: u16,
+        mut chunk: AudioChunk,
+        arrival: Instant,
+    ) {
+        let Some(stream_info) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let Some(source) = &stream_info.source else {
+            return;
+        };
+        let Some(extended) = source
+            .missing(seq)
+            .filter(|&extended| stream_info.jitter_buffer.accepts(extended))
+        else {
+            return;
+        };
+        if stream_info.recent_recoveries.contains(&extended) {
+            return;
+        }
+
+        debug!("Recovered packet: seq={}, stream={}", seq, stream_id);
+        chunk.sequence_number = extended;
+        chunk.capture_time = stream_info
+            .sender_clock
+            .and_then(|clock| clock.wallclock(chunk.timestamp, source.clock_rate()));
+        let jitter = source.jitter();
+        stream_info.jitter_buffer.push(chunk, arrival, jitter);
+
+        stream_info.recovered += 1;
+        if stream_info.recent_recoveries.len() == RECENT_RECOVERIES {
+            stream_info.recent_recoveries.pop_front();
+        }
+        stream_info.recent_recoveries.push_back(extended);
+    }
+
+    /// Whether a stream from this SSRC is being received here.
+    pub fn has_source(&self, ssrc: u32) -> bool {
+        self.ssrc_index
+            .get(&ssrc)
+            .is_some_and(|ids| !ids.is_empty())
+    }
+
+    /// Sends the stream's RTCP back the way its RTP came, for transports
+    /// such as RFC 4571 TCP where RTP and RTCP always share a connection.
+    pub fn mux_rtcp(&mut self, stream_id: StreamId) {
+        if let Some(stream_info) = self.streams.get_mut(&stream_id) {
+            stream_info.rtcp_addr = Some(stream_info.metadata.source_addr);
+            stream_info.rtcp_mux = true;
+        }
+    }
+
+    /// Payload types and header extension IDs a new stream reads. A call's
+    /// negotiated payload types go on top of the configured ones, but its
+    /// extension IDs replace them: an ID SDP did not map means nothing.
+    fn stream_mappings(&self) -> (PayloadTypeMap, HeaderExtensionMap) {
+        let mut payload_types = self.config.payload_types.clone();
+        let mut header_extensions = self.config.header_extensions.clone();
+        if let Some(call) = &self.config.call {
+            payload_types.extend(&call.media.payload_types);
+            header_extensions.clone_from(&call.media.header_extensions);
+        }
+        (payload_types, header_extensions)
+    }
+
+    /// The header extension IDs a stream reads.
+    pub fn header_extensions(&self, stream_id: StreamId) -> Option<&HeaderExtensionMap> {
+        self.streams
+            .get(&stream_id)
+            .map(|stream_info| &stream_info.header_extensions)
+    }
+
+    /// Keeps a packet as it arrived so later FEC can rebuild its neighbours.
+    pub fn remember_for_fec(&mut self, stream_id: StreamId, seq: u16, packet: Bytes) {
+        if let Some(stream_info) = self.streams.get_mut(&stream_id) {
+            stream_info.fec.remember(seq, packet);
+        }
+    }
+
+    /// Applies an RFC 5109 FEC packet, returning the RTP packets it rebuilt.
+    /// The FEC packet's own sequence number is skipped in the jitter buffer.
+    pub fn process_fec(
+        &mut self,
+        stream_id: StreamId,
+        seq: u16,
+        payload: &Bytes,
+        arrival: Instant,
+    ) -> Vec<Bytes> {
+        let Some(stream_info) = self.streams.get_mut(&stream_id) else {
+            return Vec::new();
+        };
+        mark_received(stream_info, arrival, &self.events);
+        let Some(source) = &mut stream_info.source else {
+            debug!("FEC before any media on stream {}", stream_id);
+            return Vec::new();
+        };
+        match source.update_sequence(seq) {
+            SequenceOutcome::Accepted(extended) | SequenceOutcome::Restarted(extended) => {
+                stream_info.jitter_buffer.skip(extended);
+            }
+            SequenceOutcome::Duplicate | SequenceOutcome::Invalid => return Vec::new(),
+        }
+
+        let ssrc = stream_info.metadata.ssrc.unwrap_or_default();
+        let jitter_buffer = &stream_info.jitter_buffer;
+        stream_info.fec.apply(ssrc, payload, |seq| {
+            source
+                .missing(seq)
+                .is_some_and(|extended| jitter_buffer.accepts(extended))
+        })
+    }
+
+    /// Applies a compound RTCP packet of `size` bytes from `source_addr`.
+    /// `rtcp_mux` says whether it arrived on the RTP port.
+    pub fn process_rtcp(
+        &mut self,
+        source_addr: SocketAddr,
+        packets: Vec<RtcpPacket>,
+        size: usize,
+        rtcp_mux: bool,
+    ) {
+        // A compound packet leads with the SR or RR of whoever sent it, which
+        // tells us where to send our own reports.
+        let reporter = packets.first().and_then(|packet| match packet {
+            RtcpPacket::SenderReport(sr) => Some(sr.ssrc),
+            RtcpPacket::ReceiverReport(rr) => Some(rr.ssrc),
+            _ => None,
+        });
+        if let Some(ssrc) = reporter
+            && let Some(stream_info) = self.stream_for_ssrc(ssrc, source_addr)
+        {
+            stream_info.rtcp_addr = Some(source_addr);
+            stream_info.rtcp_mux = rtcp_mux;
+            stream_info.rtcp_timer.on_packet(size);
+        }
+
+        let loops_back = packets.iter().any(|packet| match packet {
+            RtcpPacket::SourceDescription(sdes) => sdes.chunks.iter().any(|chunk| {
+                sdes_cname(sdes, chunk.source).as_deref() == Some(self.config.rtcp.cname.as_str())
+            }),
+            _ => false,
+        });
+        if loops_back {
+            warn!(
+                "RTCP loop detected: {} is sending us our own CNAME, ignoring",
+                source_addr
+            );
+            return;
+        }
+
+        for packet in packets {
+            match packet {
+                RtcpPacket::SenderReport(sr) => {
+                    let Some(stream_info) = self.stream_for_ssrc(sr.ssrc, source_addr) else {
+                        continue;
+                    };
+                    stream_info.sender_clock = Some(SenderClock {
+                        ntp_time: sr.ntp_time,
+                        rtp_time: sr.rtp_time,
+                        received_at: Instant::now(),
+                    });
+                    debug!(
+                        "Sender report: SSRC={}, packets={}, octets={}",
+                        sr.ssrc, sr.packet_count, sr.octet_count
+                    );
+                }
+                RtcpPacket::ReceiverReport(rr) => {
+                    debug!(
+                        "Receiver report from SSRC={} with {} block(s)",
+                        rr.ssrc,
+                        rr.reports.len()
+                    );
+                }
+                RtcpPacket::SourceDescription(sdes) => {
+                    for chunk in &sdes.chunks {
+                        if let Some(cname) = sdes_cname(&sdes, chunk.source)
+                            && let Some(stream_info) =
+                                self.stream_for_ssrc(chunk.source, source_addr)
+                            && stream_info.metadata.cname.as_deref() != Some(cname.as_str())
+                        {
+                            info!("Stream {} CNAME: {}", stream_info.metadata.id, cname);
+                            stream_info.metadata.cname = Some(cname);
+                        }
+                    }
+                }
+                RtcpPacket::Goodbye(bye) => {
+                    let reason = String::from_utf8_lossy(&bye.reason).into_owned();
+                    for ssrc in bye.sources {
+                        if let Some(stream_id) = self.find_by_ssrc(ssrc, source_addr) {
+                            info!("RTCP BYE for stream {} ({})", stream_id, reason);
+                            self.end_stream(stream_id, EndReason::Bye);
+                        }
+                    }
+                }
+                RtcpPacket::App(app) => {
+                    debug!(
+                        "APP packet: SSRC={}, name={}, subtype={}, {} bytes",
+                        app.ssrc,
+                        String::from_utf8_lossy(&app.name),
+                        app.subtype,
+                        app.data.len()
+                    );
+                }
+                RtcpPacket::Other(packet_type) => {
+                    debug!("Ignoring RTCP {} from {}", packet_type, source_addr);
+                }
+            }
+        }
+    }
+
+    /// Builds receiver reports for every stream whose RTCP timer has expired,
+    /// publishing the statistics they carry.
+    pub fn due_receiver_reports(&mut self, now: Instant) -> Vec<OutgoingRtcp> {
+        let mut outgoing = std::mem::take(&mut self.pending_rtcp);
+
+        for stream_info in self.streams.values_mut() {
+            if !stream_info.rtcp_timer.is_due(now) {
+                continue;
+            }
+            stream_info
+                .rtcp_timer
+                .schedule(now, self.config.rtcp.session_bandwidth);
+
+            let Some(stats) = stream_info.stats() else {
+                continue;
+            };
+            let _ = self.events.send(StreamEvent::Stats {
+                stream_id: stream_info.metadata.id,
+                stats,
+                at: Utc::now(),
+            });
+            let Some(destination) = stream_info.rtcp_addr else {
+                continue;
+            };
+            let (lsr, dlsr) = stream_info.sender_clock.map_or((0, 0), |clock| {
+                (
+                    rtcp_sender::compact_ntp(clock.ntp_time),
+                    rtcp_sender::delay_since(clock.received_at, now),
+                )
+            });
+            let report = rtcp_sender::reception_report(&stats, lsr, dlsr);
+            if let Some(source) = stream_info.source.as_mut() {
+                source.close_interval();
+            }
+
+            match rtcp_sender::build_receiver_report(
+                self.reporter_ssrc,
+                &self.config.rtcp.cname,
+                vec![report],
+            ) {
+                Ok(packet) => {
+                    stream_info.rtcp_timer.on_packet(packet.len());
+                    outgoing.push(OutgoingRtcp {
+                        destination,
+                        rtcp_mux: stream_info.rtcp_mux,
+                        packet,
+                    });
+                }
+                Err(e) => warn!(
+                    "Failed to build receiver report for stream {}: {}",
+                    stream_info.metadata.id, e
+                ),
+            }
+        }
+
+        if let Some(session) = self.srtp.as_mut() {
+            outgoing.retain_mut(|rtcp| match session.protect_rtcp(&rtcp.packet) {
+                Ok(protected) => {
+                    rtcp.packet = Bytes::from(protected);
+                    true
+                }
+                Err(e) => {
+                    warn!("Failed to protect RTCP to {}: {}", rtcp.destination, e);
+                    false
+                }
+            });
+        }
+
+        outgoing
+    }
+
+    /// Pauses streams that have gone quiet and ends those that have been
+    /// quiet for longer than the disconnect timeout.
+    pub fn expire_idle_streams(&mut self, now: Instant) {
+        let lifecycle = self.config.lifecycle;
+        let mut expired = Vec::new();
+
+        for (&stream_id, stream_info) in &mut self.streams {
+            let idle = now.saturating_duration_since(stream_info.last_packet_at);
+            if idle >= lifecycle.disconnect_after {
+                expired.push(stream_id);
+            } else if idle >= lifecycle.pause_after
+                && stream_info.metadata.state == StreamState::Active
+            {
+                info!("Stream {} paused after {:?} of silence", stream_id, idle);
+                let event = transition(stream_info, StreamState::Paused);
+                let _ = self.events.send(event);
+            }
+        }
+
+        for stream_id in expired {
+            info!("Stream {} timed out", stream_id);
+            self.end_stream(stream_id, EndReason::Timeout);
+        }
+
+        self.probation.expire(now);
+        if self.probation.len() > 0 {
             debug!(
-                "Processed audio chunk: stream={}, seq={}, size={}",
+                "{} source(s) on probation, {} packet(s) discarded, {} source(s) evicted",
+                self.probation.len(),
+                self.probation.discarded(),
+                self.probation.evicted()
+            );
+        }
+    }
+
+    /// Flushes whatever the stream still has buffered, marks it
+    /// `Disconnected` and forgets it.
+    pub fn end_stream(&mut self, stream_id: StreamId, reason: EndReason) {
+        let Some(mut stream_info) = self.streams.remove(&stream_id) else {
+            return;
+        };
+        if let Some(clock_rate) = stream_info.dtmf_clock_rate
+            && let Some(event) = stream_info.dtmf.flush(clock_rate)
+        {
+            publish_dtmf(stream_id, event, &self.events);
+        }
+        let event = transition(&mut stream_info, StreamState::Disconnected);
+        self.publish(event);
+        if let Some(ssrc) = stream_info.metadata.ssrc {
+            self.unindex(stream_info.metadata.source_addr, ssrc, stream_id);
+        }
+        let flushed = stream_info.jitter_buffer.reset();
+        release(
+            stream_id,
+            &mut stream_info.noise_filler,
+            flushed,
+            &mut self.released,
+        );
+
+        let stats = stream_info.stats();
+        if let Some(stats) = &stats {
+            info!(
+                "Stream {} ended: {} received, {} lost ({:.2}%) of which {} recovered, jitter {:.2}ms, {} DTX period(s) totalling {}ms",
                 stream_id,
-                chunk.sequence_number,
-                chunk.data.len()
+                stats.packets_received,
+                stats.cumulative_lost,
+                stats.loss_percent(),
+                stats.packets_recovered,
+                stats.jitter_ms(),
+                stats.dtx_periods,
+                stats.dtx_silence_ms
+            );
+        } else {
+            info!("Stream {} ended before any media", stream_id);
+        }
+        if stream_info.relatches > 0 || stream_info.relatch_rejected > 0 {
+            info!(
+                "Stream {} re-latched {} time(s), ending on {}; rejected {} packet(s) from {} other address(es)",
+                stream_id,
+                stream_info.relatches,
+                stream_info.metadata.source_addr,
+                stream_info.relatch_rejected,
+                stream_info.rejected_sources.len()
+            );
+        }
+
+        self.publish(StreamEvent::Ended {
+            metadata: stream_info.metadata,
+            reason,
+            stats,
+            at: Utc::now(),
+        });
+    }
+
+    /// Ends every stream that arrived from `source_addr`, such as all the
+    /// SSRCs of a TCP connection that has closed.
+    pub fn end_streams_from(&mut self, source_addr: SocketAddr, reason: EndReason) {
+        let stream_ids: Vec<_> = self
+            .stream_keys
+            .iter()
+            .filter(|(key, _)| key.source_addr == source_addr)
+            .map(|(_, &id)| id)
+            .collect();
+        for stream_id in stream_ids {
+            self.end_stream(stream_id, reason);
+        }
+    }
+
+    pub fn end_all_streams(&mut self, reason: EndReason) {
+        let stream_ids: Vec<_> = self.streams.keys().copied().collect();
+        for stream_id in stream_ids {
+            self.end_stream(stream_id, reason);
+        }
+    }
+
+    fn publish(&self, event: StreamEvent) {
+        // Nobody listening is fine; events are advisory.
+        let _ = self.events.send(event);
+    }
+
+    fn stream_for_ssrc(&mut self, ssrc: u32, source_addr: SocketAddr) -> Option<&mut StreamInfo> {
+        let stream_id = self.find_by_ssrc(ssrc, source_addr);
+        if stream_id.is_none() {
+            debug!("RTCP for unknown SSRC={} from {}", ssrc, source_addr);
+        }
+        self.streams.get_mut(&stream_id?)
+    }
+
+    /// Matches RTCP to media by SSRC, preferring the stream whose RTCP
+    /// already comes from this address, then one whose media comes from the
+    /// same host.
+    fn find_by_ssrc(&self, ssrc: u32, rtcp_source: SocketAddr) -> Option<StreamId> {
+        let candidates = self.ssrc_index.get(&ssrc)?;
+        let find = |matches: &dyn Fn(&StreamInfo) -> bool| {
+            candidates
+                .iter()
+                .copied()
+                .find(|id| self.streams.get(id).is_some_and(matches))
+        };
+
+        find(&|info| info.rtcp_addr == Some(rtcp_source))
+            .or_else(|| find(&|info| info.metadata.source_addr.ip() == rtcp_source.ip()))
+    }
+
+    /// The only stream currently receiving from `source_addr`, if there is
+    /// exactly one.
+    fn sole_stream_from(&self, source_addr: SocketAddr) -> Option<StreamId> {
+        let mut from_addr = self
+            .stream_keys
+            .iter()
+            .filter(|(key, _)| key.source_addr == source_addr)
+            .map(|(_, &id)| id);
+        let first = from_addr.next()?;
+        from_addr.next().is_none().then_some(first)
+    }
+
+    /// The one stream already receiving `ssrc` from another address that
+    /// `seq` carries on from.
+    fn latched_elsewhere(&self, ssrc: u32, source_addr: SocketAddr, seq: u16) -> Option<StreamId> {
+        let mut moved = self.ssrc_index.get(&ssrc)?.iter().copied().filter(|id| {
+            self.streams.get(id).is_some_and(|info| {
+                info.metadata.source_addr != source_addr
+                    && info
+                        .source
+                        .as_ref()
+                        .is_some_and(|source| source.follows(seq))
+            })
+        });
+        let first = moved.next()?;
+        moved.next().is_none().then_some(first)
+    }
+
+    /// Applies the re-latch policy to a stream whose packets arrived from
+    /// `key.source_addr`, returning it if it now receives from there.
+    fn relatch(&mut self, stream_id: StreamId, key: StreamKey) -> Option<StreamId> {
+        let stream_info = self.streams.get_mut(&stream_id)?;
+        let previous_addr = stream_info.metadata.source_addr;
+
+        if self.config.relatch == RelatchPolicy::Reject {
+            stream_info.relatch_rejected += 1;
+            if !stream_info.rejected_sources.contains(&key.source_addr) {
+                stream_info.rejected_sources.push(key.source_addr);
+                warn!(
+                    "Stream {} (SSRC={}) rejected re-latch {} -> {}",
+                    stream_id, key.ssrc, previous_addr, key.source_addr
+                );
+            }
+            return None;
+        }
+
+        info!(
+            "Stream {} (SSRC={}) re-latched {} -> {}",
+            stream_id, key.ssrc, previous_addr, key.source_addr
+        );
+        stream_info.metadata.source_addr = key.source_addr;
+        stream_info.relatches += 1;
+        stream_info.rtcp_addr = if stream_info.rtcp_mux {
+            Some(key.source_addr)
+        } else {
+            // The sender's RTCP will correct this if the NAT mapped it elsewhere.
+            rtcp_above(key.source_addr)
+        };
+
+        self.stream_keys.remove(&StreamKey {
+            source_addr: previous_addr,
+            ssrc: key.ssrc,
+        });
+        self.stream_keys.insert(key, stream_id);
+        self.publish(StreamEvent::SourceChanged {
+            stream_id,
+            previous_addr,
+            source_addr: key.source_addr,
+            at: Utc::now(),
+        });
+        Some(stream_id)
+    }
+
+    /// Moves an existing stream onto a new SSRC from the same address.
+    fn relink_stream(&mut self, stream_id: StreamId, key: StreamKey) {
+        let Some(stream_info) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let previous_ssrc = stream_info.metadata.ssrc.unwrap_or_default();
+        info!(
+            "Stream {} changed SSRC {} -> {} from {}",
+            stream_id, previous_ssrc, key.ssrc, key.source_addr
+        );
+
+        let flushed = stream_info.jitter_buffer.reset();
+        release(
+            stream_id,
+            &mut stream_info.noise_filler,
+            flushed,
+            &mut self.released,
+        );
+        stream_info.metadata.ssrc = Some(key.ssrc);
+        stream_info.source = None;
+        stream_info.sender_clock = None;
+        stream_info.discontinuity_pending = true;
+
+        self.unindex(key.source_addr, previous_ssrc, stream_id);
+        self.stream_keys.insert(key, stream_id);
+        self.ssrc_index.entry(key.ssrc).or_default().push(stream_id);
+
+        self.publish(StreamEvent::SsrcChanged {
+            stream_id,
+            previous_ssrc,
+            ssrc: key.ssrc,
+            at: Utc::now(),
+        });
+    }
+
+    fn unindex(&mut self, source_addr: SocketAddr, ssrc: u32, stream_id: StreamId) {
+        self.stream_keys.remove(&StreamKey { source_addr, ssrc });
+        if let Some(ids) = self.ssrc_index.get_mut(&ssrc) {
+            ids.retain(|&id| id != stream_id);
+            if ids.is_empty() {
+                self.ssrc_index.remove(&ssrc);
+            }
+        }
+    }
+
+    /// RFC 3550 section 8.2: a remote source picked the SSRC we report
+    /// under. Retire ours with a BYE and choose another.
+    fn resolve_own_collision(&mut self, source_addr: SocketAddr) {
+        let old_ssrc = self.reporter_ssrc;
+        while self.reporter_ssrc == old_ssrc || self.ssrc_index.contains_key(&self.reporter_ssrc) {
+            self.reporter_ssrc = rand::random();
+        }
+        warn!(
+            "SSRC collision with our own SSRC={} from {}, now reporting as SSRC={}",
+            old_ssrc, source_addr, self.reporter_ssrc
+        );
+
+        let Some(rtcp_addr) = rtcp_above(source_addr) else {
+            return;
+        };
+        match rtcp_sender::build_bye(old_ssrc, "SSRC collision") {
+            Ok(packet) => self.pending_rtcp.push(OutgoingRtcp {
+                destination: rtcp_addr,
+                rtcp_mux: false,
+                packet,
+            }),
+            Err(e) => warn!("Failed to build RTCP BYE: {}", e),
+        }
+    }
+
+    /// Takes the chunks released since the last call, in playout order per
+    /// stream, for the receiver to forward.
+    pub fn take_released(&mut self) -> Vec<AudioChunk> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Releases buffered chunks whose playout time has passed. Called on a
+    /// timer so streams that go quiet still drain.
+    pub fn drain_jitter_buffers(&mut self, now: Instant) {
+        for (&stream_id, stream_info) in &mut self.streams {
+            let ready = stream_info.jitter_buffer.pop_ready(now);
+            release(
+                stream_id,
+                &mut stream_info.noise_filler,
+                ready,
+                &mut self.released,
             );
         }
     }
@@ -94,3 +1294,473 @@ impl StreamManager {
         self.streams.get(stream_id).map(|info| &info.metadata)
     }
 }
+
+/// The conventional RTCP address for RTP from `source_addr`, the port
+/// above it, which a sender on port 65535 does not have.
+fn rtcp_above(source_addr: SocketAddr) -> Option<SocketAddr> {
+    let port = source_addr.port().checked_add(1)?;
+    Some(SocketAddr::new(source_addr.ip(), port))
+}
+
+fn reject_payload(stream_info: &mut StreamInfo, payload_type: u8, reason: &str) {
+    stream_info.rejected_payload += 1;
+    if stream_info.rejected_payload_types.insert(payload_type) {
+        warn!(
+            "Rejecting payload type {} on stream {}: {}",
+            payload_type, stream_info.metadata.id, reason
+        );
+    } else {
+        debug!(
+            "Rejecting payload type {} on stream {}: {}",
+            payload_type, stream_info.metadata.id, reason
+        );
+    }
+}
+
+/// Notes media arriving, waking the stream if it had been paused.
+fn mark_received(
+    stream_info: &mut StreamInfo,
+    arrival: Instant,
+    events: &broadcast::Sender<StreamEvent>,
+) {
+    stream_info.packet_count += 1;
+    stream_info.last_packet_at = arrival;
+    if stream_info.metadata.state == StreamState::Paused {
+        let event = transition(stream_info, StreamState::Active);
+        let _ = events.send(event);
+    }
+}
+
+fn publish_dtmf(stream_id: StreamId, event: DtmfEvent, events: &broadcast::Sender<StreamEvent>) {
+    info!(
+        "Stream {} DTMF {} for {:?} at RTP timestamp {}",
+        stream_id, event.digit, event.duration, event.rtp_timestamp
+    );
+    let _ = events.send(StreamEvent::Dtmf {
+        stream_id,
+        event,
+        at: Utc::now(),
+    });
+}
+
+fn transition(stream_info: &mut StreamInfo, state: StreamState) -> StreamEvent {
+    let previous = stream_info.metadata.state;
+    stream_info.metadata.state = state;
+    StreamEvent::StateChanged {
+        stream_id: stream_info.metadata.id,
+        previous,
+        current: state,
+        at: Utc::now(),
+    }
+}
+
+fn discontinuity_marker(stream_id: StreamId, next: &AudioChunk) -> AudioChunk {
+    AudioChunk {
+        data: Bytes::new(),
+        format: next.format,
+        sequence_number: next.sequence_number,
+        timestamp: next.timestamp,
+        kind: ChunkKind::Discontinuity,
+        capture_time: None,
+        extensions: HeaderExtensions::default(),
+        metadata: LatencyMetadata::new(stream_id),
+    }
+}
+
+/// Queues chunks released by the jitter buffer for forwarding, with DTX
+/// silence filled in.
+fn release(
+    stream_id: StreamId,
+    noise_filler: &mut NoiseFiller,
+    chunks: Vec<AudioChunk>,
+    released: &mut Vec<AudioChunk>,
+) {
+    for chunk in chunks {
+        for filler in noise_filler.fill(stream_id, &chunk) {
+            log_chunk(stream_id, &filler);
+            released.push(filler);
+        }
+        log_chunk(stream_id, &chunk);
+        released.push(chunk);
+    }
+}
+
+fn log_chunk(stream_id: StreamId, chunk: &AudioChunk) {
+    match chunk.kind {
+        ChunkKind::Audio => debug!(
+            "Processed audio chunk: stream={}, seq={}, size={}",
+            stream_id,
+            chunk.sequence_number,
+            chunk.data.len()
+        ),
+        ChunkKind::Gap { packets } => debug!(
+            "Gap in audio: stream={}, seq={}, lost={}",
+            stream_id, chunk.sequence_number, packets
+        ),
+        ChunkKind::Discontinuity => debug!(
+            "Discontinuity: stream={}, seq={}",
+            stream_id, chunk.sequence_number
+        ),
+        ChunkKind::ComfortNoise { noise_level } => debug!(
+            "Comfort noise: stream={}, seq={}, level=-{}dBov",
+            stream_id, chunk.sequence_number, noise_level
+        ),
+        ChunkKind::Silence { samples } => debug!(
+            "DTX silence: stream={}, seq={}, samples={}",
+            stream_id, chunk.sequence_number, samples
+        ),
+        ChunkKind::GeneratedNoise => debug!(
+            "Generated noise: stream={}, ts={}, size={}",
+            stream_id,
+            chunk.timestamp,
+            chunk.data.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), port)
+    }
+
+    /// A manager that creates streams on their first packet, and a
+    /// subscription to its events.
+    fn manager(config: StreamManagerConfig) -> (StreamManager, broadcast::Receiver<StreamEvent>) {
+        let events = StreamManager::event_channel();
+        let subscription = events.subscribe();
+        let config = StreamManagerConfig {
+            probation: ProbationConfig {
+                min_sequential: 1,
+                ..ProbationConfig::default()
+            },
+            ..config
+        };
+        (StreamManager::with_events(config, events), subscription)
+    }
+
+    fn chunk(stream_id: StreamId, seq: u16) -> AudioChunk {
+        AudioChunk {
+            data: Bytes::from_static(&[0xff; 160]),
+            format: AudioFormat::g711_ulaw_mono(),
+            sequence_number: u32::from(seq),
+            timestamp: u32::from(seq) * 160,
+            kind: ChunkKind::Audio,
+            capture_time: None,
+            extensions: HeaderExtensions::default(),
+            metadata: LatencyMetadata::new(stream_id),
+        }
+    }
+
+    /// The events published since last asked, in brief.
+    fn seen(subscription: &mut broadcast::Receiver<StreamEvent>) -> Vec<String> {
+        let mut seen = Vec::new();
+        loop {
+            let event = match subscription.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => return seen,
+                Err(e) => panic!("event subscription failed: {e}"),
+            };
+            seen.push(match event {
+                StreamEvent::Created { metadata } => {
+                    format!("created {:?}", metadata.state)
+                }
+                StreamEvent::StateChanged {
+                    previous, current, ..
+                } => format!("{previous:?} -> {current:?}"),
+                StreamEvent::SsrcChanged {
+                    previous_ssrc,
+                    ssrc,
+                    ..
+                } => format!("SSRC {previous_ssrc} -> {ssrc}"),
+                StreamEvent::SourceChanged {
+                    previous_addr,
+                    source_addr,
+                    ..
+                } => format!("source {previous_addr} -> {source_addr}"),
+                StreamEvent::Stats { stats, .. } => {
+                    format!("stats, {} received", stats.packets_received)
+                }
+                StreamEvent::Dtmf { event, .. } => format!("DTMF {}", event.digit),
+                StreamEvent::Ended { reason, stats, .. } => format!(
+                    "ended {reason:?}, {} received",
+                    stats.map_or(0, |stats| stats.packets_received)
+                ),
+            });
+        }
+    }
+
+    #[test]
+    fn pauses_resumes_and_times_out_idle_streams() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let stream_id = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        let start = Instant::now();
+        manager.process_audio_chunk(stream_id, chunk(stream_id, 0), true, start);
+        assert_eq!(seen(&mut events), ["created Active"]);
+
+        manager.expire_idle_streams(start + Duration::from_millis(4999));
+        assert_eq!(seen(&mut events), Vec::<String>::new());
+        manager.expire_idle_streams(start + Duration::from_secs(5));
+        manager.expire_idle_streams(start + Duration::from_secs(6));
+        assert_eq!(seen(&mut events), ["Active -> Paused"]);
+
+        let resumed = start + Duration::from_secs(10);
+        manager.process_audio_chunk(stream_id, chunk(stream_id, 1), false, resumed);
+        assert_eq!(seen(&mut events), ["Paused -> Active"]);
+
+        manager.expire_idle_streams(resumed + Duration::from_secs(29));
+        assert_eq!(seen(&mut events), ["Active -> Paused"]);
+        manager.expire_idle_streams(resumed + Duration::from_secs(30));
+        assert_eq!(
+            seen(&mut events),
+            ["Paused -> Disconnected", "ended Timeout, 2 received"]
+        );
+        assert!(!manager.has_source(1));
+
+        // The sender coming back is a new stream.
+        let again = manager.get_or_create_stream(addr(5004), 1, 2).unwrap();
+        assert_ne!(again, stream_id);
+        assert_eq!(seen(&mut events), ["created Active"]);
+    }
+
+    #[test]
+    fn rejected_packets_keep_a_stream_alive() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let stream_id = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        let start = Instant::now();
+        manager.note_arrival(stream_id, start + Duration::from_secs(29));
+        manager.expire_idle_streams(start + Duration::from_secs(30));
+        assert_eq!(seen(&mut events), ["created Active"]);
+    }
+
+    #[test]
+    fn ends_streams_on_rtcp_bye() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let stream_id = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        manager.process_audio_chunk(stream_id, chunk(stream_id, 0), true, Instant::now());
+
+        let bye = rtcp_sender::build_bye(1, "done").unwrap();
+        let packets = crate::rtcp_parser::parse_compound(&bye).unwrap();
+        manager.process_rtcp(addr(5005), packets, bye.len(), false);
+        assert_eq!(
+            seen(&mut events),
+            [
+                "created Active",
+                "Active -> Disconnected",
+                "ended Bye, 1 received"
+            ]
+        );
+        assert!(!manager.has_source(1));
+    }
+
+    #[test]
+    fn ends_streams_for_signalling_and_closed_connections() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        for ssrc in [1, 2] {
+            manager.get_or_create_stream(addr(5004), ssrc, 0).unwrap();
+        }
+        let other = manager.get_or_create_stream(addr(6004), 3, 0).unwrap();
+        seen(&mut events);
+
+        manager.end_streams_from(addr(5004), EndReason::ConnectionClosed);
+        assert_eq!(
+            seen(&mut events),
+            [
+                "Active -> Disconnected",
+                "ended ConnectionClosed, 0 received"
+            ]
+            .repeat(2)
+        );
+        manager.end_stream(other, EndReason::Hangup);
+        manager.end_all_streams(EndReason::Hangup);
+        assert_eq!(
+            seen(&mut events),
+            ["Active -> Disconnected", "ended Hangup, 0 received"]
+        );
+    }
+
+    #[test]
+    fn keeps_senders_that_share_an_ssrc_apart() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let first = manager.get_or_create_stream(addr(5004), 7, 0).unwrap();
+        let second = manager.get_or_create_stream(addr(6004), 7, 100).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(manager.get_or_create_stream(addr(5004), 7, 1), Some(first));
+        assert_eq!(manager.ssrc_index[&7], [first, second]);
+        assert_eq!(seen(&mut events), ["created Active", "created Active"]);
+
+        // RTCP goes to the stream whose media shares its host.
+        manager
+            .streams
+            .get_mut(&second)
+            .unwrap()
+            .metadata
+            .source_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 6004);
+        assert_eq!(manager.find_by_ssrc(7, addr(5005)), Some(first));
+    }
+
+    #[test]
+    fn picks_a_new_ssrc_when_a_source_takes_ours() {
+        let (mut manager, _events) = manager(StreamManagerConfig::default());
+        let ours = manager.reporter_ssrc;
+        manager.get_or_create_stream(addr(5004), ours, 0).unwrap();
+        assert_ne!(manager.reporter_ssrc, ours);
+        assert!(!manager.has_source(manager.reporter_ssrc));
+
+        let outgoing = manager.due_receiver_reports(Instant::now());
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].destination, addr(5005));
+        let packets = crate::rtcp_parser::parse_compound(&outgoing[0].packet).unwrap();
+        assert!(matches!(&packets[1], RtcpPacket::Goodbye(bye) if bye.sources == [ours]));
+
+        // From the top port there is nowhere to send the BYE.
+        let ours = manager.reporter_ssrc;
+        manager.get_or_create_stream(addr(65535), ours, 0).unwrap();
+        assert_ne!(manager.reporter_ssrc, ours);
+        assert_eq!(manager.due_receiver_reports(Instant::now()).len(), 0);
+    }
+
+    #[test]
+    fn links_a_new_ssrc_to_the_stream_from_the_same_address() {
+        let (mut manager, mut events) = manager(StreamManagerConfig {
+            ssrc_change: SsrcChangePolicy::LinkToExisting,
+            ..StreamManagerConfig::default()
+        });
+        let stream_id = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        let start = Instant::now();
+        manager.process_audio_chunk(stream_id, chunk(stream_id, 0), true, start);
+
+        assert_eq!(
+            manager.get_or_create_stream(addr(5004), 2, 5000),
+            Some(stream_id)
+        );
+        assert_eq!(seen(&mut events), ["created Active", "SSRC 1 -> 2"]);
+        assert!(!manager.has_source(1));
+        assert!(manager.has_source(2));
+
+        // Whatever was buffered goes out, then a discontinuity before the
+        // new sender's audio.
+        manager.process_audio_chunk(stream_id, chunk(stream_id, 5000), true, start);
+        let kinds: Vec<_> = manager
+            .take_released()
+            .iter()
+            .map(|chunk| (chunk.sequence_number, chunk.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [(0, ChunkKind::Audio), (5000, ChunkKind::Discontinuity)]
+        );
+    }
+
+    #[test]
+    fn makes_a_new_stream_for_a_new_ssrc_by_default() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let first = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        let second = manager.get_or_create_stream(addr(5004), 2, 0).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(seen(&mut events), ["created Active", "created Active"]);
+    }
+
+    #[test]
+    fn publishes_statistics_with_each_receiver_report() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let start = Instant::now();
+        for port in [5004, 65535] {
+            let stream_id = manager.get_or_create_stream(addr(port), 1, 0).unwrap();
+            for seq in [0, 1, 3] {
+                manager.process_audio_chunk(stream_id, chunk(stream_id, seq), false, start);
+            }
+        }
+        assert_eq!(seen(&mut events).len(), 2);
+
+        // Nothing is due until the first, shortened, interval is up.
+        assert_eq!(manager.due_receiver_reports(start).len(), 0);
+        assert_eq!(seen(&mut events), Vec::<String>::new());
+
+        // A sender on the top port has nowhere to take a report.
+        let later = start + Duration::from_secs(10);
+        let outgoing = manager.due_receiver_reports(later);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].destination, addr(5005));
+        assert_eq!(seen(&mut events), ["stats, 3 received"].repeat(2));
+
+        let packets = crate::rtcp_parser::parse_compound(&outgoing[0].packet).unwrap();
+        let RtcpPacket::ReceiverReport(rr) = &packets[0] else {
+            panic!("unexpected packets {packets:?}");
+        };
+        assert_eq!(rr.reports[0].total_lost, 1);
+        assert_eq!(rr.reports[0].last_sequence_number, 3);
+    }
+
+    /// A stream from port 5004 that has received sequence numbers 0 to 2.
+    fn latched(manager: &mut StreamManager) -> StreamId {
+        let stream_id = manager.get_or_create_stream(addr(5004), 1, 0).unwrap();
+        for seq in 0..3 {
+            manager.process_audio_chunk(stream_id, chunk(stream_id, seq), false, Instant::now());
+        }
+        stream_id
+    }
+
+    #[test]
+    fn follows_a_sender_to_a_new_address_when_allowed() {
+        let (mut manager, mut events) = manager(StreamManagerConfig {
+            relatch: RelatchPolicy::Allow,
+            ..StreamManagerConfig::default()
+        });
+        let stream_id = latched(&mut manager);
+        assert_eq!(
+            manager.get_or_create_stream(addr(6000), 1, 3),
+            Some(stream_id)
+        );
+        assert_eq!(
+            seen(&mut events),
+            [
+                "created Active",
+                "source 192.0.2.10:5004 -> 192.0.2.10:6000"
+            ]
+        );
+        let stream_info = &manager.streams[&stream_id];
+        assert_eq!(stream_info.metadata.source_addr, addr(6000));
+        assert_eq!(stream_info.rtcp_addr, Some(addr(6001)));
+        assert_eq!(stream_info.relatches, 1);
+
+        // Packets that do not carry on the sequence are someone else's.
+        let other = manager.get_or_create_stream(addr(7000), 1, 40000);
+        assert!(other.is_some_and(|other| other != stream_id));
+    }
+
+    #[test]
+    fn keeps_a_sender_to_its_first_address_when_rejecting() {
+        let (mut manager, mut events) = manager(StreamManagerConfig {
+            relatch: RelatchPolicy::Reject,
+            ..StreamManagerConfig::default()
+        });
+        let stream_id = latched(&mut manager);
+        for seq in 3..5 {
+            assert_eq!(manager.get_or_create_stream(addr(6000), 1, seq), None);
+        }
+        assert_eq!(seen(&mut events), ["created Active"]);
+        let stream_info = &manager.streams[&stream_id];
+        assert_eq!(stream_info.metadata.source_addr, addr(5004));
+        assert_eq!(stream_info.rtcp_addr, Some(addr(5005)));
+        assert_eq!(stream_info.relatch_rejected, 2);
+        assert_eq!(stream_info.rejected_sources, [addr(6000)]);
+        assert_eq!(
+            manager.get_or_create_stream(addr(5004), 1, 3),
+            Some(stream_id)
+        );
+    }
+
+    #[test]
+    fn makes_a_new_stream_for_a_new_address_by_default() {
+        let (mut manager, mut events) = manager(StreamManagerConfig::default());
+        let stream_id = latched(&mut manager);
+        let other = manager.get_or_create_stream(addr(6000), 1, 3);
+        assert!(other.is_some_and(|other| other != stream_id));
+        assert_eq!(seen(&mut events), ["created Active", "created Active"]);
+    }
+}
@@ -0,0 +1,378 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, SocketAddr};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const HEADER_LEN: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+/// RFC 5389 section 18.2 and RFC 8445 section 20.1 attribute types.
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_USE_CANDIDATE: u16 = 0x0025;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+
+const MESSAGE_INTEGRITY_LEN: usize = 20;
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StunError {
+    #[error("not a STUN binding request")]
+    NotBindingRequest,
+    #[error("truncated STUN message")]
+    Truncated,
+    #[error("STUN request has no USERNAME")]
+    MissingUsername,
+    #[error("STUN request has no MESSAGE-INTEGRITY")]
+    MissingIntegrity,
+    #[error("STUN MESSAGE-INTEGRITY does not match")]
+    IntegrityFailed,
+    #[error("STUN FINGERPRINT does not match")]
+    FingerprintFailed,
+}
+
+/// A connectivity check from an ICE agent, already authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindingRequest {
+    pub transaction_id: [u8; 12],
+    /// `<our ufrag>:<their ufrag>`.
+    pub username: String,
+    /// The controlling agent nominated the pair this check arrived on.
+    pub use_candidate: bool,
+}
+
+/// Whether a datagram on a port shared with DTLS and RTP is STUN
+/// (RFC 7983 section 7): a first byte of 0 to 3 and the magic cookie.
+pub fn is_stun(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN
+        && data[0] < 4
+        && u32::from_be_bytes([data[4], data[5], data[6], data[7]]) == MAGIC_COOKIE
+}
+
+/// Parses a Binding request and checks its MESSAGE-INTEGRITY against our
+/// ICE password (RFC 8445 section 7.2.2) and its FINGERPRINT when present.
+pub fn parse_binding_request(data: &[u8], password: &[u8]) -> Result<BindingRequest, StunError> {
+    if !is_stun(data) {
+        return Err(StunError::NotBindingRequest);
+    }
+    if u16::from_be_bytes([data[0], data[1]]) != BINDING_REQUEST {
+        return Err(StunError::NotBindingRequest);
+    }
+    let length = usize::from(u16::from_be_bytes([data[2], data[3]]));
+    let message = data
+        .get(..HEADER_LEN + length)
+        .ok_or(StunError::Truncated)?;
+
+    let mut transaction_id = [0u8; 12];
+    transaction_id.copy_from_slice(&message[8..HEADER_LEN]);
+    let mut username = None;
+    let mut use_candidate = false;
+    let mut integrity = None;
+
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= message.len() {
+        let kind = u16::from_be_bytes([message[offset], message[offset + 1]]);
+        let len = usize::from(u16::from_be_bytes([
+            message[offset + 2],
+            message[offset + 3],
+        ]));
+        let value = message
+            .get(offset + 4..offset + 4 + len)
+            .ok_or(StunError::Truncated)?;
+        match kind {
+            ATTR_USERNAME => username = Some(String::from_utf8_lossy(value).into_owned()),
+            ATTR_USE_CANDIDATE => use_candidate = true,
+            ATTR_MESSAGE_INTEGRITY if integrity.is_none() => {
+                if len != MESSAGE_INTEGRITY_LEN {
+                    return Err(StunError::Truncated);
+                }
+                integrity = Some(offset);
+            }
+            ATTR_FINGERPRINT => {
+                if len != 4 {
+                    return Err(StunError::Truncated);
+                }
+                let expected = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                if fingerprint(&message[..offset]) != expected {
+                    return Err(StunError::FingerprintFailed);
+                }
+                break;
+            }
+            // Attributes after MESSAGE-INTEGRITY other than FINGERPRINT
+            // are to be ignored.
+            _ => {}
+        }
+        offset += 4 + len.next_multiple_of(4);
+    }
+
+    let integrity = integrity.ok_or(StunError::MissingIntegrity)?;
+    let expected = &message[integrity + 4..integrity + 4 + MESSAGE_INTEGRITY_LEN];
+    let computed = message_integrity(&message[..integrity], password);
+    if !bool::from(computed.ct_eq(expected)) {
+        return Err(StunError::IntegrityFailed);
+    }
+
+    Ok(BindingRequest {
+        transaction_id,
+        username: username.ok_or(StunError::MissingUsername)?,
+        use_candidate,
+    })
+}
+
+/// A Binding success response telling the sender the address its check
+/// came from, signed with our ICE password.
+pub fn binding_success(transaction_id: &[u8; 12], mapped: SocketAddr, password: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(80);
+    message.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction_id);
+
+    let port = mapped.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut address = Vec::with_capacity(20);
+    match mapped.ip() {
+        IpAddr::V4(ip) => {
+            address.extend_from_slice(&[0, 1]);
+            address.extend_from_slice(&port.to_be_bytes());
+            let ip = u32::from(ip) ^ MAGIC_COOKIE;
+            address.extend_from_slice(&ip.to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            address.extend_from_slice(&[0, 2]);
+            address.extend_from_slice(&port.to_be_bytes());
+            let mut mask = [0u8; 16];
+            mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            mask[4..].copy_from_slice(transaction_id);
+            address.extend(ip.octets().iter().zip(mask).map(|(byte, mask)| byte ^ mask));
+        }
+    }
+    push_attribute(&mut message, ATTR_XOR_MAPPED_ADDRESS, &address);
+
+    // Each of these covers the message up to itself, with the length
+    // field already counting it.
+    set_length(&mut message, 4 + MESSAGE_INTEGRITY_LEN);
+    let integrity = message_integrity(&message, password);
+    push_attribute(&mut message, ATTR_MESSAGE_INTEGRITY, &integrity);
+    set_length(&mut message, 8);
+    let crc = fingerprint(&message);
+    push_attribute(&mut message, ATTR_FINGERPRINT, &crc.to_be_bytes());
+    message
+}
+
+fn push_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&u16::try_from(value.len()).unwrap_or(u16::MAX).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len().next_multiple_of(4), 0);
+}
+
+/// Sets the header length to cover the attributes so far plus `extra`.
+fn set_length(message: &mut [u8], extra: usize) {
+    let length = u16::try_from(message.len() - HEADER_LEN + extra).unwrap_or(u16::MAX);
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// HMAC-SHA1 over `prefix` with its length field adjusted to end just
+/// after a MESSAGE-INTEGRITY attribute appended to it.
+fn message_integrity(prefix: &[u8], password: &[u8]) -> [u8; MESSAGE_INTEGRITY_LEN] {
+    let length =
+        u16::try_from(prefix.len() - HEADER_LEN + 4 + MESSAGE_INTEGRITY_LEN).unwrap_or(u16::MAX);
+    let mut mac = HmacSha1::new_from_slice(password).expect("HMAC accepts any key length");
+    mac.update(&prefix[..2]);
+    mac.update(&length.to_be_bytes());
+    mac.update(&prefix[4..]);
+    mac.finalize().into_bytes().into()
+}
+
+/// CRC-32 over `prefix` with its length field adjusted to end just after
+/// a FINGERPRINT attribute appended to it.
+fn fingerprint(prefix: &[u8]) -> u32 {
+    let length = u16::try_from(prefix.len() - HEADER_LEN + 8).unwrap_or(u16::MAX);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&prefix[..2]);
+    crc.update(&length.to_be_bytes());
+    crc.update(&prefix[4..]);
+    crc.finalize() ^ FINGERPRINT_XOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+    const TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// RFC 5769 section 2.1.
+    const SAMPLE_REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74,
+        0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e,
+        0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20,
+        0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+        0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5,
+        0x7a, 0x3b, 0xcf,
+    ];
+
+    /// RFC 5769 section 2.2.
+    const SAMPLE_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    /// RFC 5769 section 2.3.
+    const SAMPLE_IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    /// Where the XOR-MAPPED-ADDRESS value of a sample response starts.
+    const SAMPLE_ADDRESS_OFFSET: usize = 40;
+
+    /// A Binding request with `attributes`, signed with `password`.
+    fn signed_request(attributes: &[(u16, &[u8])], password: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+        message.extend_from_slice(&0u16.to_be_bytes());
+        message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        message.extend_from_slice(&TRANSACTION_ID);
+        for &(kind, value) in attributes {
+            push_attribute(&mut message, kind, value);
+        }
+        let integrity = message_integrity(&message, password);
+        push_attribute(&mut message, ATTR_MESSAGE_INTEGRITY, &integrity);
+        set_length(&mut message, 0);
+        message
+    }
+
+    /// Checks the MESSAGE-INTEGRITY and FINGERPRINT ending a message.
+    fn assert_signed(message: &[u8], password: &[u8]) {
+        let fingerprint_at = message.len() - 8;
+        let integrity_at = fingerprint_at - 4 - MESSAGE_INTEGRITY_LEN;
+        assert_eq!(
+            message_integrity(&message[..integrity_at], password),
+            message[integrity_at + 4..fingerprint_at]
+        );
+        assert_eq!(
+            fingerprint(&message[..fingerprint_at]).to_be_bytes(),
+            message[fingerprint_at + 4..]
+        );
+    }
+
+    #[test]
+    fn parses_the_rfc_5769_sample_request() {
+        assert!(is_stun(&SAMPLE_REQUEST));
+        assert_eq!(
+            parse_binding_request(&SAMPLE_REQUEST, PASSWORD),
+            Ok(BindingRequest {
+                transaction_id: TRANSACTION_ID,
+                username: "evtj:h6vY".to_string(),
+                use_candidate: false,
+            })
+        );
+    }
+
+    #[test]
+    fn signs_like_the_rfc_5769_sample_responses() {
+        assert_signed(&SAMPLE_IPV4_RESPONSE, PASSWORD);
+        assert_signed(&SAMPLE_IPV6_RESPONSE, PASSWORD);
+    }
+
+    #[test]
+    fn maps_addresses_like_the_rfc_5769_sample_responses() {
+        for (sample, mapped) in [
+            (&SAMPLE_IPV4_RESPONSE[..], "192.0.2.1:32853"),
+            (
+                &SAMPLE_IPV6_RESPONSE[..],
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853",
+            ),
+        ] {
+            let response = binding_success(&TRANSACTION_ID, mapped.parse().unwrap(), PASSWORD);
+            let address_len = usize::from(sample[SAMPLE_ADDRESS_OFFSET - 1]);
+            let expected = &sample[SAMPLE_ADDRESS_OFFSET - 4..SAMPLE_ADDRESS_OFFSET + address_len];
+            // Ours has no SOFTWARE attribute, so XOR-MAPPED-ADDRESS comes first.
+            assert_eq!(&response[HEADER_LEN..HEADER_LEN + expected.len()], expected);
+            assert_eq!(&response[..2], &BINDING_SUCCESS.to_be_bytes());
+            assert_eq!(
+                usize::from(u16::from_be_bytes([response[2], response[3]])),
+                response.len() - HEADER_LEN
+            );
+            assert_signed(&response, PASSWORD);
+        }
+    }
+
+    #[test]
+    fn reads_use_candidate_without_a_fingerprint() {
+        let request = signed_request(
+            &[(ATTR_USERNAME, b"ours:theirs"), (ATTR_USE_CANDIDATE, b"")],
+            PASSWORD,
+        );
+        let parsed = parse_binding_request(&request, PASSWORD).unwrap();
+        assert_eq!(parsed.username, "ours:theirs");
+        assert!(parsed.use_candidate);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut tampered = SAMPLE_REQUEST;
+        tampered[30] ^= 1;
+        let mut bad_fingerprint = SAMPLE_REQUEST;
+        bad_fingerprint[107] ^= 1;
+        let no_username = signed_request(&[], PASSWORD);
+        let mut unsigned = no_username[..HEADER_LEN].to_vec();
+        push_attribute(&mut unsigned, ATTR_USERNAME, b"ours:theirs");
+        set_length(&mut unsigned, 0);
+
+        for (message, password, error) in [
+            (
+                &SAMPLE_REQUEST[..],
+                &b"wrong"[..],
+                StunError::IntegrityFailed,
+            ),
+            (&tampered, PASSWORD, StunError::FingerprintFailed),
+            (&bad_fingerprint, PASSWORD, StunError::FingerprintFailed),
+            (&SAMPLE_REQUEST[..100], PASSWORD, StunError::Truncated),
+            (
+                &SAMPLE_IPV4_RESPONSE,
+                PASSWORD,
+                StunError::NotBindingRequest,
+            ),
+            (
+                &SAMPLE_REQUEST[..19],
+                PASSWORD,
+                StunError::NotBindingRequest,
+            ),
+            (&no_username, PASSWORD, StunError::MissingUsername),
+            (&unsigned, PASSWORD, StunError::MissingIntegrity),
+        ] {
+            assert_eq!(parse_binding_request(message, password), Err(error));
+        }
+    }
+
+    #[test]
+    fn tells_stun_from_rtp_and_dtls() {
+        let mut rtp = SAMPLE_REQUEST;
+        rtp[0] = 0x80;
+        let mut dtls = SAMPLE_REQUEST;
+        dtls[0] = 22;
+        assert!(!is_stun(&rtp));
+        assert!(!is_stun(&dtls));
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+/// Largest datagram we expect; RTP is kept under the path MTU.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+/// Room for a GRO buffer, which coalesces up to 64 KiB of one flow's datagrams.
+#[cfg(target_os = "linux")]
+const MAX_GRO_SIZE: usize = 65_535;
+
+/// Receives datagrams several at a time. On Linux one `recvmmsg` call fills
+/// up to `batch_size` buffers, and with UDP GRO the kernel may coalesce a
+/// flow's back-to-back datagrams into a single buffer that is split again
+/// here. A batch size of one falls back to a plain `recv_from` per packet.
+pub struct BatchReceiver {
+    buffers: Vec<Vec<u8>>,
+    /// Index, length, sender and GRO segment size of each filled buffer.
+    filled: Vec<(usize, usize, SocketAddr, usize)>,
+    #[cfg(target_os = "linux")]
+    gro: bool,
+}
+
+impl BatchReceiver {
+    pub fn new(socket: &UdpSocket, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        #[cfg(target_os = "linux")]
+        let gro = batch_size > 1 && enable_gro(socket);
+        #[cfg(not(target_os = "linux"))]
+        let _ = socket;
+
+        #[cfg(target_os = "linux")]
+        let buffer_size = if gro { MAX_GRO_SIZE } else { MAX_DATAGRAM_SIZE };
+        #[cfg(not(target_os = "linux"))]
+        let buffer_size = MAX_DATAGRAM_SIZE;
+
+        Self {
+            buffers: vec![vec![0u8; buffer_size]; batch_size],
+            filled: Vec::with_capacity(batch_size),
+            #[cfg(target_os = "linux")]
+            gro,
+        }
+    }
+
+    /// Waits for at least one datagram and returns every one that was ready,
+    /// with its sender.
+    pub async fn recv(
+        &mut self,
+        socket: &UdpSocket,
+    ) -> io::Result<impl Iterator<Item = (&[u8], SocketAddr)>> {
+        self.filled.clear();
+        if self.buffers.len() == 1 {
+            let (len, source_addr) = socket.recv_from(&mut self.buffers[0]).await?;
+            self.filled.push((0, len, source_addr, len));
+        } else {
+            self.recv_batch(socket).await?;
+        }
+
+        let buffers = &self.buffers;
+        Ok(self
+            .filled
+            .iter()
+            .flat_map(move |&(index, len, source_addr, segment)| {
+                buffers[index][..len]
+                    .chunks(segment.max(1))
+                    .map(move |datagram| (datagram, source_addr))
+            }))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_batch(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        use std::io::IoSliceMut;
+        use std::os::fd::AsRawFd;
+
+        use nix::sys::socket::{
+            ControlMessageOwned, MsgFlags, MultiHeaders, SockaddrStorage, recvmmsg,
+        };
+        use tokio::io::Interest;
+
+        loop {
+            socket.readable().await?;
+            let result = socket.try_io(Interest::READABLE, || {
+                let cmsg = self.gro.then(|| nix::cmsg_space!(i32));
+                let mut headers =
+                    MultiHeaders::<SockaddrStorage>::preallocate(self.buffers.len(), cmsg);
+                let mut slices: Vec<[IoSliceMut<'_>; 1]> = self
+                    .buffers
+                    .iter_mut()
+                    .map(|buffer| [IoSliceMut::new(buffer)])
+                    .collect();
+                let received = recvmmsg(
+                    socket.as_raw_fd(),
+                    &mut headers,
+                    slices.iter_mut(),
+                    MsgFlags::MSG_DONTWAIT,
+                    None,
+                )
+                .map_err(io::Error::from)?;
+
+                for (index, message) in received.enumerate() {
+                    let Some(source_addr) = message.address.as_ref().and_then(socket_addr) else {
+                        continue;
+                    };
+                    let segment = message
+                        .cmsgs()
+                        .ok()
+                        .into_iter()
+                        .flatten()
+                        .find_map(|cmsg| match cmsg {
+                            ControlMessageOwned::UdpGroSegments(size) => usize::try_from(size).ok(),
+                            _ => None,
+                        })
+                        .unwrap_or(message.bytes);
+                    self.filled
+                        .push((index, message.bytes, source_addr, segment));
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_batch(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let (len, source_addr) = socket.recv_from(&mut self.buffers[0]).await?;
+        self.filled.push((0, len, source_addr, len));
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn enable_gro(socket: &UdpSocket) -> bool {
+    use nix::sys::socket::{setsockopt, sockopt::UdpGroSegment};
+
+    match setsockopt(socket, UdpGroSegment, &true) {
+        Ok(()) => {
+            info!("UDP GRO enabled");
+            true
+        }
+        Err(e) => {
+            debug!("UDP GRO unavailable: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(address: &nix::sys::socket::SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(v4) = address.as_sockaddr_in() {
+        return Some(SocketAddr::V4((*v4).into()));
+    }
+    address
+        .as_sockaddr_in6()
+        .map(|v6| SocketAddr::V6((*v6).into()))
+}
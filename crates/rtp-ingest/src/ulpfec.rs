@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
+
+const RTP_HEADER_LEN: usize = 12;
+const FEC_HEADER_LEN: usize = 10;
+/// Longest span a level 0 mask can protect, with the L bit set.
+const MAX_PROTECTED: usize = 48;
+/// FEC packets that could not be used yet, kept in case another recovery
+/// leaves them a single packet short.
+const MAX_PENDING: usize = 8;
+
+/// The level 0 protection from one RFC 5109 FEC packet. Further ULP levels
+/// are not used; level 0 covers the whole of each packet in practice.
+#[derive(Debug, Clone)]
+struct FecPacket {
+    ssrc: u32,
+    sequence_base: u16,
+    /// Bit `n` from the top protects `sequence_base + n`.
+    mask: u64,
+    mask_bits: u32,
+    /// XOR of P, X, CC, M and PT over the protected packets.
+    header_recovery: [u8; 2],
+    timestamp_recovery: u32,
+    length_recovery: u16,
+    payload: Bytes,
+}
+
+impl FecPacket {
+    fn parse(ssrc: u32, payload: &Bytes) -> Option<Self> {
+        let header = payload.get(..FEC_HEADER_LEN)?;
+        let long_mask = header[0] & 0x40 != 0;
+        let level_header_len = if long_mask { 8 } else { 4 };
+        let level = payload.get(FEC_HEADER_LEN..FEC_HEADER_LEN + level_header_len)?;
+
+        let protection_length = usize::from(u16::from_be_bytes([level[0], level[1]]));
+        let mask_bits = if long_mask { 48 } else { 16 };
+        let mask = level[2..]
+            .iter()
+            .fold(0u64, |mask, &byte| (mask << 8) | u64::from(byte));
+        let start = FEC_HEADER_LEN + level_header_len;
+        payload.get(start..start + protection_length)?;
+
+        Some(Self {
+            ssrc,
+            sequence_base: u16::from_be_bytes([header[2], header[3]]),
+            mask,
+            mask_bits,
+            header_recovery: [header[0], header[1]],
+            timestamp_recovery: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            length_recovery: u16::from_be_bytes([header[8], header[9]]),
+            payload: payload.slice(start..start + protection_length),
+        })
+    }
+
+    fn protected(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.mask_bits)
+            .filter(|bit| self.mask & (1 << (self.mask_bits - 1 - bit)) != 0)
+            .map(|bit| {
+                #[allow(clippy::cast_possible_truncation)]
+                self.sequence_base.wrapping_add(bit as u16)
+            })
+    }
+}
+
+/// Recovers lost packets from RFC 5109 ULPFEC, keeping the recent packets
+/// of one stream to XOR against. Packets are kept exactly as they came off
+/// the wire (after SRTP), since that is what the sender protected.
+#[derive(Debug, Default)]
+pub struct UlpfecReceiver {
+    history: VecDeque<(u16, Bytes)>,
+    pending: VecDeque<FecPacket>,
+}
+
+impl UlpfecReceiver {
+    /// Keeps a media packet for later recovery.
+    pub fn remember(&mut self, seq: u16, packet: Bytes) {
+        if self.history.iter().any(|&(kept, _)| kept == seq) {
+            return;
+        }
+        if self.history.len() == MAX_PROTECTED {
+            self.history.pop_front();
+        }
+        self.history.push_back((seq, packet));
+    }
+
+    /// Applies an FEC packet, returning the protected packets it rebuilt.
+    /// `is_missing` says whether a sequence number is still needed, as
+    /// opposed to received or too old to matter.
+    pub fn apply(
+        &mut self,
+        ssrc: u32,
+        payload: &Bytes,
+        is_missing: impl Fn(u16) -> bool,
+    ) -> Vec<Bytes> {
+        let Some(fec) = FecPacket::parse(ssrc, payload) else {
+            debug!("Malformed ULPFEC packet ({} bytes)", payload.len());
+            return Vec::new();
+        };
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(fec);
+
+        // Each recovery may leave another pending packet one short, so go
+        // round until nothing changes.
+        let mut recovered = Vec::new();
+        loop {
+            let before = recovered.len();
+            let mut index = 0;
+            while index < self.pending.len() {
+                let fec = &self.pending[index];
+                let missing: Vec<u16> = fec.protected().filter(|&seq| !self.has(seq)).collect();
+                if missing.len() > 1 {
+                    index += 1;
+                    continue;
+                }
+                // Done with it: either nothing is missing, or the one packet
+                // that is gets rebuilt now or is no longer needed.
+                let Some(fec) = self.pending.remove(index) else {
+                    break;
+                };
+                if let [seq] = missing[..]
+                    && is_missing(seq)
+                    && let Some(packet) = self.recover(&fec, seq)
+                {
+                    self.remember(seq, packet.clone());
+                    recovered.push(packet);
+                }
+            }
+            if recovered.len() == before {
+                break;
+            }
+        }
+        recovered
+    }
+
+    fn has(&self, seq: u16) -> bool {
+        self.history.iter().any(|&(kept, _)| kept == seq)
+    }
+
+    /// XORs the FEC packet with every other packet it protects to rebuild
+    /// the one that is missing.
+    fn recover(&self, fec: &FecPacket, missing: u16) -> Option<Bytes> {
+        let mut header = fec.header_recovery;
+        let mut timestamp = fec.timestamp_recovery;
+        let mut length = fec.length_recovery;
+        let mut payload = fec.payload.to_vec();
+
+        for seq in fec.protected().filter(|&seq| seq != missing) {
+            let (_, packet) = self.history.iter().find(|&&(kept, _)| kept == seq)?;
+            let body = packet.get(RTP_HEADER_LEN..)?;
+            header[0] ^= packet[0];
+            header[1] ^= packet[1];
+            timestamp ^= u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            length ^= u16::try_from(body.len()).ok()?;
+            for (byte, protected) in payload.iter_mut().zip(body) {
+                *byte ^= protected;
+            }
+        }
+
+        let length = usize::from(length);
+        if length > payload.len() {
+            debug!(
+                "ULPFEC cannot rebuild seq {}: {} bytes needed, {} protected",
+                missing,
+                length,
+                payload.len()
+            );
+            return None;
+        }
+
+        let mut packet = BytesMut::with_capacity(RTP_HEADER_LEN + length);
+        packet.put_u8(0x80 | (header[0] & 0x3f));
+        packet.put_u8(header[1]);
+        packet.put_u16(missing);
+        packet.put_u32(timestamp);
+        packet.put_u32(fec.ssrc);
+        packet.put_slice(&payload[..length]);
+        debug!("ULPFEC rebuilt seq {} ({} bytes)", missing, packet.len());
+        Some(packet.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSRC: u32 = 0x1122_3344;
+
+    fn media(seq: u16, payload: &[u8]) -> Bytes {
+        let mut packet = BytesMut::new();
+        packet.put_u8(0x80);
+        packet.put_u8(if seq.is_multiple_of(2) { 0x80 } else { 0 });
+        packet.put_u16(seq);
+        packet.put_u32(u32::from(seq) * 160);
+        packet.put_u32(SSRC);
+        packet.put_slice(payload);
+        packet.freeze()
+    }
+
+    /// Builds the RFC 5109 FEC payload protecting `packets`, with a long
+    /// mask when asked for.
+    fn fec(base: u16, packets: &[(u16, Bytes)], long_mask: bool) -> Bytes {
+        let mut header = [0u8; 2];
+        let mut timestamp = 0u32;
+        let mut length = 0u16;
+        let mut protected = Vec::new();
+        let mut mask = 0u64;
+        let mask_bits = if long_mask { 48 } else { 16 };
+        for (seq, packet) in packets {
+            let body = &packet[RTP_HEADER_LEN..];
+            header[0] ^= packet[0];
+            header[1] ^= packet[1];
+            timestamp ^= u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            length ^= u16::try_from(body.len()).unwrap();
+            if protected.len() < body.len() {
+                protected.resize(body.len(), 0);
+            }
+            for (byte, value) in protected.iter_mut().zip(body) {
+                *byte ^= value;
+            }
+            mask |= 1 << (mask_bits - 1 - u32::from(seq.wrapping_sub(base)));
+        }
+
+        let mut payload = BytesMut::new();
+        payload.put_u8((header[0] & 0x3f) | if long_mask { 0x40 } else { 0 });
+        payload.put_u8(header[1]);
+        payload.put_u16(base);
+        payload.put_u32(timestamp);
+        payload.put_u16(length);
+        payload.put_u16(u16::try_from(protected.len()).unwrap());
+        if long_mask {
+            payload.put_slice(&mask.to_be_bytes()[2..]);
+        } else {
+            payload.put_slice(&mask.to_be_bytes()[6..]);
+        }
+        payload.put_slice(&protected);
+        payload.freeze()
+    }
+
+    #[test]
+    fn rebuilds_a_lost_packet() {
+        let packets: Vec<_> = (10..13)
+            .map(|seq| {
+                (
+                    seq,
+                    media(
+                        seq,
+                        &vec![u8::try_from(seq).unwrap(); 20 + usize::from(seq)],
+                    ),
+                )
+            })
+            .collect();
+        let mut receiver = UlpfecReceiver::default();
+        receiver.remember(10, packets[0].1.clone());
+        receiver.remember(12, packets[2].1.clone());
+
+        let recovered = receiver.apply(SSRC, &fec(10, &packets, false), |seq| seq == 11);
+        assert_eq!(recovered, [packets[1].1.clone()]);
+    }
+
+    #[test]
+    fn rebuilds_with_a_long_mask_across_wrap() {
+        let packets: Vec<_> = [65530, 65535, 4]
+            .into_iter()
+            .map(|seq: u16| (seq, media(seq, b"audio")))
+            .collect();
+        let mut receiver = UlpfecReceiver::default();
+        receiver.remember(65530, packets[0].1.clone());
+        receiver.remember(4, packets[2].1.clone());
+
+        let recovered = receiver.apply(SSRC, &fec(65530, &packets, true), |_| true);
+        assert_eq!(recovered, [packets[1].1.clone()]);
+    }
+
+    #[test]
+    fn keeps_an_fec_packet_until_it_is_one_short() {
+        let packets: Vec<_> = (0..4).map(|seq| (seq, media(seq, b"audio"))).collect();
+        let mut receiver = UlpfecReceiver::default();
+        receiver.remember(0, packets[0].1.clone());
+        receiver.remember(3, packets[3].1.clone());
+
+        // 1 and 2 are both lost, so the first FEC packet has to wait.
+        let all = fec(0, &packets, false);
+        assert_eq!(receiver.apply(SSRC, &all, |_| true), Vec::<Bytes>::new());
+
+        // Rebuilding 2 from the second lets the first rebuild 1.
+        let tail = fec(2, &packets[2..], false);
+        let recovered = receiver.apply(SSRC, &tail, |_| true);
+        assert_eq!(recovered, [packets[2].1.clone(), packets[1].1.clone()]);
+    }
+
+    #[test]
+    fn leaves_packets_that_are_no_longer_needed() {
+        let packets: Vec<_> = (0..2).map(|seq| (seq, media(seq, b"audio"))).collect();
+        let mut receiver = UlpfecReceiver::default();
+        receiver.remember(0, packets[0].1.clone());
+
+        let recovered = receiver.apply(SSRC, &fec(0, &packets, false), |_| false);
+        assert_eq!(recovered, Vec::<Bytes>::new());
+    }
+
+    #[test]
+    fn rejects_malformed_fec_packets() {
+        let packets = [(0, media(0, b"audio"))];
+        let whole = fec(0, &packets, false);
+        // Short of the FEC header, of the level header, and of the
+        // protected bytes it claims.
+        for length in [5, FEC_HEADER_LEN + 2, whole.len() - 1] {
+            assert!(FecPacket::parse(SSRC, &whole.slice(..length)).is_none());
+        }
+        let fec = FecPacket::parse(SSRC, &whole).unwrap();
+        assert_eq!(fec.protected().collect::<Vec<_>>(), [0]);
+    }
+}
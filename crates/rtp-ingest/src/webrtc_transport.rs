@@ -0,0 +1,183 @@
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::dtls::{self, DtlsEvent, DtlsServer};
+use crate::stun;
+
+/// Without a fresh connectivity check for this long, the peer has withdrawn
+/// consent to receive and is treated as gone (RFC 7675 section 5.1).
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+const UFRAG_LEN: usize = 8;
+const PASSWORD_LEN: usize = 24;
+
+/// Our ICE username fragment and password for one session.
+#[derive(Debug, Clone)]
+pub struct IceCredentials {
+    pub ufrag: String,
+    pub pwd: String,
+}
+
+impl IceCredentials {
+    pub fn generate() -> Self {
+        let random = |len| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect()
+        };
+        Self {
+            ufrag: random(UFRAG_LEN),
+            pwd: random(PASSWORD_LEN),
+        }
+    }
+}
+
+/// What to do with a datagram that arrived on a WebRTC peer's media port.
+#[derive(Debug)]
+pub enum Inbound {
+    /// SRTP or SRTCP from a checked address, to go on to the streams.
+    Media,
+    /// An ICE check or DTLS record, handled here. `replies` go back to the
+    /// sender.
+    Handled {
+        replies: Vec<Vec<u8>>,
+        event: Option<DtlsEvent>,
+    },
+    Dropped,
+}
+
+/// The ICE-lite (RFC 8445 section 2.5) and DTLS-SRTP layer of one WebRTC
+/// peer's media port. As a lite agent we only answer the peer's
+/// connectivity checks, and only accept DTLS and media from addresses that
+/// passed one.
+pub struct WebRtcTransport {
+    local: IceCredentials,
+    /// USERNAME a check from the peer carries: `<our ufrag>:<theirs>`.
+    username: String,
+    dtls: DtlsServer,
+    connected: bool,
+    /// When each address last passed a check.
+    checked: HashMap<SocketAddr, Instant>,
+    nominated: Option<SocketAddr>,
+    created_at: Instant,
+    rejected_checks: u64,
+}
+
+impl WebRtcTransport {
+    pub fn new(local: IceCredentials, remote_ufrag: &str, dtls: DtlsServer) -> Self {
+        Self {
+            username: format!("{}:{}", local.ufrag, remote_ufrag),
+            local,
+            dtls,
+            connected: false,
+            checked: HashMap::new(),
+            nominated: None,
+            created_at: Instant::now(),
+            rejected_checks: 0,
+        }
+    }
+
+    pub fn receive(&mut self, data: &[u8], source_addr: SocketAddr) -> Inbound {
+        if stun::is_stun(data) {
+            return self.receive_check(data, source_addr);
+        }
+        if !self.checked.contains_key(&source_addr) {
+            debug!("Dropping datagram from unchecked address {}", source_addr);
+            return Inbound::Dropped;
+        }
+        if dtls::is_dtls(data) {
+            return self.receive_dtls(data, source_addr);
+        }
+        // Until DTLS has agreed keys there is nothing to decrypt media with.
+        if self.connected {
+            Inbound::Media
+        } else {
+            Inbound::Dropped
+        }
+    }
+
+    /// Whether the peer has stopped checking, or never did.
+    pub fn consent_expired(&self, now: Instant) -> bool {
+        let last = self
+            .checked
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(self.created_at);
+        now.duration_since(last) > CONSENT_TIMEOUT
+    }
+
+    fn receive_check(&mut self, data: &[u8], source_addr: SocketAddr) -> Inbound {
+        let request = match stun::parse_binding_request(data, self.local.pwd.as_bytes()) {
+            Ok(request) if request.username == self.username => request,
+            Ok(request) => {
+                self.reject_check(source_addr, &format!("username {}", request.username));
+                return Inbound::Dropped;
+            }
+            Err(e) => {
+                self.reject_check(source_addr, &e.to_string());
+                return Inbound::Dropped;
+            }
+        };
+
+        if self.checked.insert(source_addr, Instant::now()).is_none() {
+            info!("ICE connectivity check from {} succeeded", source_addr);
+        }
+        if request.use_candidate && self.nominated != Some(source_addr) {
+            info!("ICE peer nominated {}", source_addr);
+            self.nominated = Some(source_addr);
+        }
+        let response = stun::binding_success(
+            &request.transaction_id,
+            source_addr,
+            self.local.pwd.as_bytes(),
+        );
+        Inbound::Handled {
+            replies: vec![response],
+            event: None,
+        }
+    }
+
+    fn reject_check(&mut self, source_addr: SocketAddr, reason: &str) {
+        self.rejected_checks += 1;
+        if self.rejected_checks == 1 {
+            warn!("Rejecting ICE check from {}: {}", source_addr, reason);
+        } else {
+            debug!("Rejecting ICE check from {}: {}", source_addr, reason);
+        }
+    }
+
+    fn receive_dtls(&mut self, data: &[u8], source_addr: SocketAddr) -> Inbound {
+        let event = match self.dtls.handle(data) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("DTLS with {} failed: {}", source_addr, e);
+                None
+            }
+        };
+        match &event {
+            Some(DtlsEvent::Connected(keys)) => {
+                info!(
+                    "DTLS-SRTP established with {} using {}",
+                    source_addr,
+                    keys.remote.profile.suite()
+                );
+                self.connected = true;
+            }
+            Some(DtlsEvent::Closed) => {
+                info!("DTLS closed by {}", source_addr);
+                self.connected = false;
+            }
+            None => {}
+        }
+        Inbound::Handled {
+            replies: self.dtls.take_outgoing(),
+            event,
+        }
+    }
+}
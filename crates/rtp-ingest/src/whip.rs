@@ -0,0 +1,442 @@
+use anyhow::Result;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use openssl::memcmp;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::capture::PacketCapture;
+use crate::dtls::DtlsCertificate;
+use crate::media_ports::{MediaPortPool, PortPair};
+use crate::publisher::ChunkPublisher;
+use crate::rtp_receiver::{ReceiveConfig, RtpReceiver, SharedWebRtc};
+use crate::stream_manager::{CallContext, RelatchPolicy, SsrcChangePolicy, StreamManagerConfig};
+use crate::webrtc_transport::{IceCredentials, WebRtcTransport};
+use shared_types::sdp::{AcceptedMedia, Attribute, MediaDescription, Origin};
+use shared_types::{EndReason, NegotiatedMedia, SessionDescription, StreamEvent};
+
+const WHIP_PATH: &str = "/whip";
+const SDP: &str = "application/sdp";
+/// Priority of our single host candidate (RFC 8445 section 5.1.2.1, type
+/// preference 126, local preference 65535, component 1).
+const HOST_PRIORITY: u32 = 2_130_706_431;
+/// Ports in the pool that may be taken by something else; try a few.
+const MEDIA_BIND_ATTEMPTS: usize = 4;
+const CONSENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct WhipConfig {
+    /// Where to serve WHIP over HTTP.
+    pub bind: SocketAddr,
+    /// Address of the ICE candidate offered to clients.
+    pub media_ip: IpAddr,
+    pub media_ports: RangeInclusive<u16>,
+    /// Bearer token clients must present; anyone may publish when unset.
+    pub token: Option<String>,
+}
+
+/// One client's ingest session with the receiver running on its ports.
+struct Session {
+    ports: PortPair,
+    webrtc: SharedWebRtc,
+    receiver: Arc<RtpReceiver>,
+    task: JoinHandle<()>,
+}
+
+/// A WHIP endpoint (RFC 9725) for browsers and softphones. Each offer sent by POST
+/// gets a session with its own media port, where ICE-lite and DTLS-SRTP are
+/// completed and the Opus it carries goes through the same stream handling
+/// as SIP media. DELETE on the session's URL ends it.
+pub struct WhipServer {
+    listener: TcpListener,
+    state: Arc<WhipState>,
+}
+
+struct WhipState {
+    config: WhipConfig,
+    stream_config: StreamManagerConfig,
+    events: broadcast::Sender<StreamEvent>,
+    publisher: Option<ChunkPublisher>,
+    capture: Option<PacketCapture>,
+    certificate: DtlsCertificate,
+    sessions: Mutex<HashMap<String, Session>>,
+    ports: Mutex<MediaPortPool>,
+}
+
+/// Why an offer was turned down, as an HTTP response.
+struct Rejection(StatusCode, &'static str);
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl WhipServer {
+    pub async fn bind(
+        config: WhipConfig,
+        stream_config: StreamManagerConfig,
+        events: broadcast::Sender<StreamEvent>,
+        publisher: Option<ChunkPublisher>,
+        capture: Option<PacketCapture>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(config.bind).await?;
+        let certificate = DtlsCertificate::generate()?;
+        info!(
+            "WHIP listening on http://{}{}, DTLS fingerprint {}",
+            config.bind,
+            WHIP_PATH,
+            certificate.fingerprint()
+        );
+        if config.token.is_none() {
+            warn!("No WHIP token configured, accepting media from anyone");
+        }
+
+        Ok(Self {
+            listener,
+            state: Arc::new(WhipState {
+                ports: Mutex::new(MediaPortPool::new(config.media_ports.clone())),
+                config,
+                stream_config,
+                events,
+                publisher,
+                capture,
+                certificate,
+                sessions: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let router = Router::new()
+            .route(WHIP_PATH, post(create_session))
+            .route(&format!("{WHIP_PATH}/{{id}}"), delete(delete_session))
+            .with_state(Arc::clone(&self.state));
+        tokio::select! {
+            result = axum::serve(self.listener, router) => result?,
+            () = self.state.expire_sessions() => {}
+        }
+        Ok(())
+    }
+}
+
+impl WhipState {
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.config.token else {
+            return true;
+        };
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|presented| {
+                // memcmp::eq panics on a length mismatch, and the length is
+                // no secret.
+                let presented = presented.trim().as_bytes();
+                presented.len() == token.len() && memcmp::eq(presented, token.as_bytes())
+            })
+    }
+
+    /// Negotiates the offer's first audio m-line, starts a receiver for it
+    /// and returns the session ID and our answer.
+    async fn start(&self, offer: &SessionDescription) -> Result<(String, String), Rejection> {
+        let Some((index, mut negotiated)) = offer.negotiate_audio() else {
+            return Err(Rejection(StatusCode::BAD_REQUEST, "no acceptable audio"));
+        };
+        let media = &offer.media[index];
+        let attribute = |name| {
+            media
+                .attribute(name)
+                .or_else(|| offer.attribute(name))
+                .and_then(|attribute| attribute.value.as_deref())
+        };
+        let (Some(remote_ufrag), Some(fingerprint)) =
+            (attribute("ice-ufrag"), attribute("fingerprint"))
+        else {
+            return Err(Rejection(
+                StatusCode::BAD_REQUEST,
+                "offer has no ICE credentials or DTLS fingerprint",
+            ));
+        };
+        // We answer as the DTLS server, so the client has to be able to
+        // take the client role.
+        if attribute("setup") == Some("passive") {
+            return Err(Rejection(
+                StatusCode::BAD_REQUEST,
+                "offer insists on a=setup:passive",
+            ));
+        }
+        let dtls = self.certificate.accept(fingerprint).map_err(|e| {
+            warn!("Unusable WHIP offer fingerprint: {}", e);
+            Rejection(StatusCode::BAD_REQUEST, "unusable DTLS fingerprint")
+        })?;
+
+        // WebRTC always multiplexes RTCP, and the c= line of an offer from
+        // an ICE agent is a placeholder.
+        negotiated.rtcp_mux = true;
+        negotiated.remote_addr = None;
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let credentials = IceCredentials::generate();
+        let webrtc = Arc::new(std::sync::Mutex::new(WebRtcTransport::new(
+            credentials.clone(),
+            remote_ufrag,
+            dtls,
+        )));
+        let session = self
+            .start_media(&id, &negotiated, webrtc)
+            .await
+            .ok_or(Rejection(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no media port available",
+            ))?;
+
+        let answer = self.answer_sdp(offer, index, &negotiated, session.ports, &credentials);
+        info!(
+            "WHIP session {} started: {:?} on ports {}/{} ({} session(s))",
+            id,
+            negotiated.format.codec,
+            session.ports.rtp,
+            session.ports.rtcp,
+            self.sessions.lock().await.len() + 1
+        );
+        self.sessions.lock().await.insert(id.clone(), session);
+        Ok((id, answer.to_string()))
+    }
+
+    /// Allocates a port pair and starts the session's receiver on it.
+    async fn start_media(
+        &self,
+        id: &str,
+        negotiated: &NegotiatedMedia,
+        webrtc: SharedWebRtc,
+    ) -> Option<Session> {
+        let mut config = self.stream_config.clone();
+        let mut call = CallContext {
+            call_id: id.to_string(),
+            media: negotiated.clone(),
+            recording: None,
+            media_port: 0,
+        };
+        // The port only ever carries this client, which may change SSRC or
+        // move between the candidate pairs ICE found.
+        config.ssrc_change = SsrcChangePolicy::LinkToExisting;
+        if config.relatch == RelatchPolicy::NewStream {
+            config.relatch = RelatchPolicy::Allow;
+        }
+        // Keyed by the DTLS handshake once it completes.
+        config.srtp = None;
+
+        for _ in 0..MEDIA_BIND_ATTEMPTS {
+            let Some(ports) = self.ports.lock().await.allocate(id) else {
+                warn!("WHIP media port pool exhausted, rejecting session {}", id);
+                return None;
+            };
+            call.media_port = ports.rtp;
+            config.call = Some(call.clone());
+            let bind_addr = SocketAddr::new(self.config.bind.ip(), ports.rtp);
+            let receiver = RtpReceiver::bind(
+                bind_addr,
+                config.clone(),
+                self.events.clone(),
+                ReceiveConfig::default(),
+            );
+            match receiver.await {
+                Ok(receiver) => {
+                    let receiver = Arc::new(
+                        receiver
+                            .with_publisher(self.publisher.as_ref())
+                            .with_capture(self.capture.as_ref())
+                            .with_webrtc(&webrtc),
+                    );
+                    let media_receiver = Arc::clone(&receiver);
+                    let task = tokio::spawn(async move {
+                        if let Err(e) = media_receiver.run().await {
+                            error!("WHIP media receiver stopped: {}", e);
+                        }
+                    });
+                    return Some(Session {
+                        ports,
+                        webrtc,
+                        receiver,
+                        task,
+                    });
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not bind media ports {}/{}: {}",
+                        ports.rtp, ports.rtcp, e
+                    );
+                    self.ports.lock().await.release(ports.rtp);
+                }
+            }
+        }
+        None
+    }
+
+    /// Our answer: receive-only on the negotiated m-line, as an ICE-lite
+    /// agent with a single host candidate and the DTLS server role.
+    fn answer_sdp(
+        &self,
+        offer: &SessionDescription,
+        index: usize,
+        negotiated: &NegotiatedMedia,
+        ports: PortPair,
+        credentials: &IceCredentials,
+    ) -> SessionDescription {
+        let origin = Origin {
+            username: "navitel".to_string(),
+            session_id: u64::from(rand::random::<u32>()),
+            session_version: 1,
+            address: self.config.media_ip,
+        };
+        let accepted = AcceptedMedia {
+            index,
+            port: ports.rtp,
+            negotiated,
+            crypto: None,
+        };
+        let mut answer = offer.answer(origin, self.config.media_ip, &[accepted]);
+        answer.attributes.push(Attribute::new("ice-lite", None));
+
+        let mid = offer.media[index]
+            .attribute("mid")
+            .and_then(|mid| mid.value.clone());
+        if let Some(mid) = &mid
+            && offer.attribute("group").is_some()
+        {
+            answer
+                .attributes
+                .push(Attribute::new("group", Some(format!("BUNDLE {mid}"))));
+        }
+
+        let media: &mut MediaDescription = &mut answer.media[index];
+        if let Some(mid) = mid {
+            media.attributes.push(Attribute::new("mid", Some(mid)));
+        }
+        for (name, value) in [
+            ("ice-ufrag", credentials.ufrag.clone()),
+            ("ice-pwd", credentials.pwd.clone()),
+            ("fingerprint", self.certificate.fingerprint().to_string()),
+            ("setup", "passive".to_string()),
+            (
+                "candidate",
+                format!(
+                    "1 1 udp {} {} {} typ host",
+                    HOST_PRIORITY, self.config.media_ip, ports.rtp
+                ),
+            ),
+        ] {
+            media.attributes.push(Attribute::new(name, Some(value)));
+        }
+        media
+            .attributes
+            .push(Attribute::new("end-of-candidates", None));
+        answer
+    }
+
+    async fn stop(&self, id: &str, session: Session, reason: EndReason) {
+        session.receiver.end_streams(reason).await;
+        session.task.abort();
+        self.ports.lock().await.release(session.ports.rtp);
+        info!(
+            "WHIP session {} ended, released media ports {}/{}",
+            id, session.ports.rtp, session.ports.rtcp
+        );
+    }
+
+    /// Ends sessions whose client has stopped sending ICE checks, or never
+    /// started, so abandoned ones do not hold ports forever.
+    async fn expire_sessions(&self) {
+        let mut interval = time::interval(CONSENT_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let expired: Vec<_> = {
+                let mut sessions = self.sessions.lock().await;
+                let ids: Vec<_> = sessions
+                    .iter()
+                    .filter(|(_, session)| {
+                        session
+                            .webrtc
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .consent_expired(now)
+                    })
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                ids.into_iter()
+                    .filter_map(|id| sessions.remove(&id).map(|session| (id, session)))
+                    .collect()
+            };
+            for (id, session) in expired {
+                warn!("WHIP session {} lost ICE consent", id);
+                self.stop(&id, session, EndReason::Timeout).await;
+            }
+        }
+    }
+}
+
+async fn create_session(
+    State(whip): State<Arc<WhipState>>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if !whip.authorized(&headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !content_type.eq_ignore_ascii_case(SDP) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, [(header::ACCEPT, SDP)]).into_response();
+    }
+    let Ok(offer) = SessionDescription::parse(&body) else {
+        return Rejection(StatusCode::BAD_REQUEST, "malformed SDP offer").into_response();
+    };
+
+    match whip.start(&offer).await {
+        Ok((id, answer)) => Response::builder()
+            .status(StatusCode::CREATED)
+            .header(header::CONTENT_TYPE, SDP)
+            .header(header::LOCATION, format!("{WHIP_PATH}/{id}"))
+            .body(Body::from(answer))
+            .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        Err(rejection) => {
+            warn!("Rejecting WHIP offer: {}", rejection.1);
+            rejection.into_response()
+        }
+    }
+}
+
+async fn delete_session(
+    State(whip): State<Arc<WhipState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if !whip.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let Some(session) = whip.sessions.lock().await.remove(&id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    info!("WHIP session {} deleted by client", id);
+    whip.stop(&id, session, EndReason::Hangup).await;
+    StatusCode::OK
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+use crate::test_audio::AudioGenerator;
+
+const PCMU_FRAME_SAMPLES: u32 = 160;
+
+/// One simulated sender: its own socket, so the receiver sees a distinct
+/// source address, and its own SSRC and sequence space.
+struct LoadStream {
+    socket: UdpSocket,
+    packet: Vec<u8>,
+    sequence_number: u16,
+    timestamp: u32,
+}
+
+impl LoadStream {
+    async fn new(frame: &[u8], rng: &mut impl Rng) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let mut packet = vec![0x80, 0];
+        packet.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&rng.r#gen::<u32>().to_be_bytes());
+        packet.extend_from_slice(frame);
+        Ok(Self {
+            socket,
+            packet,
+            sequence_number: rng.r#gen(),
+            timestamp: rng.r#gen(),
+        })
+    }
+
+    /// Sends the next PCMU frame, or returns false if the socket buffer is full.
+    fn try_send(&mut self, target: SocketAddr) -> Result<bool> {
+        self.packet[2..4].copy_from_slice(&self.sequence_number.to_be_bytes());
+        self.packet[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        match self.socket.try_send_to(&self.packet, target) {
+            Ok(_) => {
+                self.sequence_number = self.sequence_number.wrapping_add(1);
+                self.timestamp = self.timestamp.wrapping_add(PCMU_FRAME_SAMPLES);
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Sends PCMU from `streams` sources round-robin as fast as the sockets
+/// allow, for measuring how many packets per second the receiver sustains.
+#[allow(clippy::cast_precision_loss)]
+pub async fn run(target: SocketAddr, streams: usize, duration: Duration) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    let frame = AudioGenerator::new(8000, 1).generate_pcmu_samples(PCMU_FRAME_SAMPLES as usize);
+    let mut senders = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        senders.push(LoadStream::new(&frame, &mut rng).await?);
+    }
+    info!(
+        "Sending load from {} streams to {} for {:?}",
+        streams, target, duration
+    );
+
+    let start = Instant::now();
+    let mut sent = 0u64;
+    while start.elapsed() < duration {
+        let mut progressed = false;
+        for sender in &mut senders {
+            if sender.try_send(target)? {
+                sent += 1;
+                progressed = true;
+            }
+        }
+        if !progressed {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    info!(
+        "Load complete: {} packets in {:.2}s ({:.0} pps)",
+        sent,
+        elapsed,
+        sent as f64 / elapsed
+    );
+    Ok(())
+}
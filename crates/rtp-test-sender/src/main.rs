@@ -1,9 +1,14 @@
+mod load;
+mod replay;
 mod rtp_sender;
+mod sip_client;
 mod test_audio;
 
 use anyhow::Result;
 use clap::Parser;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{Level, info};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
@@ -45,6 +50,25 @@ struct Args {
     /// Simulate channel swap after N seconds (0 = disabled)
     #[arg(long, default_value = "0")]
     swap_channels_after: u64,
+
+    /// Instead of paced media, send as fast as possible from this many
+    /// streams for the duration, to benchmark the receiver (0 = disabled)
+    #[arg(long, default_value = "0")]
+    load_streams: usize,
+
+    /// Place a SIP call to this address and send media where it answers
+    #[arg(long)]
+    sip: Option<SocketAddr>,
+
+    /// Stamp each packet with its send time and chunk number in the navitel
+    /// origin header extension, so ingest can measure end-to-end latency
+    #[arg(long)]
+    origin_stamp: bool,
+
+    /// Resend the RTP and RTCP in this pcap or pcapng file to the target
+    /// as captured, instead of generating media
+    #[arg(long, conflicts_with_all = ["load_streams", "sip"])]
+    replay: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -55,6 +79,11 @@ async fn main() -> Result<()> {
 
     info!("Starting RTP Test Sender");
     info!("Target: {}", args.target);
+
+    if let Some(path) = &args.replay {
+        return replay::run(path, args.target).await;
+    }
+
     info!("Payload Type: {}", args.payload_type);
     info!("Duration: {}s", args.duration);
     info!("Packet Interval: {}ms", args.interval);
@@ -72,8 +101,39 @@ async fn main() -> Result<()> {
         );
     }
 
+    if args.load_streams > 0 {
+        return load::run(
+            args.target,
+            args.load_streams,
+            Duration::from_secs(args.duration),
+        )
+        .await;
+    }
+
+    let sip = args.sip;
+    let payload_type = args.payload_type;
+    let origin_stamp = args.origin_stamp;
     let mut sender = rtp_sender::RtpSender::new(args).await?;
+
+    let Some(sip_server) = sip else {
+        sender.run().await?;
+        info!("RTP Test Sender finished");
+        return Ok(());
+    };
+
+    let mut call = sip_client::SipClient::new(sip_server).await?;
+    let rtpmap = if payload_type == 111 {
+        "opus/48000/2"
+    } else {
+        "PCMU/8000"
+    };
+    let media_target = call
+        .invite(sender.local_port()?, payload_type, rtpmap, origin_stamp)
+        .await?;
+    info!("Call answered, sending media to {}", media_target);
+    sender.set_target(media_target);
     sender.run().await?;
+    call.bye().await?;
 
     info!("RTP Test Sender finished");
     Ok(())
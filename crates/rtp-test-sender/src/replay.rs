@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::info;
+
+use shared_types::pcap::{self, UdpDatagram};
+
+/// Resends the RTP and RTCP in a pcap or pcapng capture to `target` byte
+/// for byte, so SSRCs, sequence numbers and timestamps are the captured
+/// ones, with the captured gaps between datagrams. Each original sender
+/// gets a socket of its own. RTCP that went to the port above its RTP
+/// goes to the port above `target`.
+pub async fn run(path: &Path, target: SocketAddr) -> Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let datagrams: Vec<UdpDatagram> = pcap::read_udp(&data)?
+        .into_iter()
+        .filter(|datagram| datagram.rtp_ssrc().is_some() || datagram.is_rtcp())
+        .collect();
+    let flows = pcap::rtp_flows(&datagrams);
+    for flow in &flows {
+        info!(
+            "Replaying SSRC {} from {} to {}: {} packet(s) from seq {}",
+            flow.ssrc, flow.source, flow.destination, flow.packets, flow.first_sequence
+        );
+    }
+    let rtp_destinations: HashSet<_> = flows.iter().map(|flow| flow.destination).collect();
+    // With no port above the target, RTCP is multiplexed with the RTP.
+    let mut rtcp_target = target;
+    rtcp_target.set_port(target.port().checked_add(1).unwrap_or(target.port()));
+
+    let bind = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut sockets = HashMap::new();
+    for datagram in &datagrams {
+        if let Entry::Vacant(entry) = sockets.entry(datagram.source) {
+            entry.insert(UdpSocket::bind(bind).await?);
+        }
+    }
+
+    let start = Instant::now();
+    let first = datagrams.first().map(|datagram| datagram.time);
+    for datagram in &datagrams {
+        let offset = first
+            .and_then(|first| (datagram.time - first).to_std().ok())
+            .unwrap_or_default();
+        time::sleep_until(start + offset).await;
+
+        let above_rtp = datagram
+            .destination
+            .port()
+            .checked_sub(1)
+            .is_some_and(|port| {
+                let mut rtp_port = datagram.destination;
+                rtp_port.set_port(port);
+                rtp_destinations.contains(&rtp_port)
+            });
+        let destination =
+            if datagram.is_rtcp() && !rtp_destinations.contains(&datagram.destination) && above_rtp
+            {
+                rtcp_target
+            } else {
+                target
+            };
+        sockets[&datagram.source]
+            .send_to(&datagram.payload, destination)
+            .await?;
+    }
+
+    info!(
+        "Replayed {} datagram(s) from {} sender(s) in {:?}",
+        datagrams.len(),
+        sockets.len(),
+        start.elapsed()
+    );
+    Ok(())
+}
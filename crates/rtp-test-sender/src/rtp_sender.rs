@@ -1,8 +1,11 @@
 use crate::Args;
 use crate::test_audio::AudioGenerator;
 use anyhow::Result;
+use chrono::Utc;
 use rand::Rng;
+use rtp::header::{EXTENSION_PROFILE_ONE_BYTE, Extension};
 use rtp::packet::Packet;
+use shared_types::OriginStamp;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
@@ -10,6 +13,10 @@ use tokio::time;
 use tracing::{debug, info};
 use webrtc_util::marshal::Marshal;
 
+/// ID the origin stamp goes out under, which is what ingest reads on its
+/// fixed port by default.
+pub const ORIGIN_EXTENSION_ID: u8 = 4;
+
 pub struct RtpSender {
     socket: UdpSocket,
     target: SocketAddr,
@@ -18,6 +25,8 @@ pub struct RtpSender {
     sequence_number: u16,
     timestamp: u32,
     audio_generator: AudioGenerator,
+    /// Chunk number for the next origin stamp.
+    chunk_id: u32,
 }
 
 impl RtpSender {
@@ -47,9 +56,34 @@ impl RtpSender {
             sequence_number: rng.r#gen(),
             timestamp: rng.r#gen(),
             audio_generator,
+            chunk_id: 0,
         })
     }
 
+    pub fn local_port(&self) -> Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    pub fn set_target(&mut self, target: SocketAddr) {
+        self.target = target;
+    }
+
+    /// Header extensions for the next packet: the origin stamp, if asked for.
+    fn next_extensions(&mut self) -> Vec<Extension> {
+        if !self.args.origin_stamp {
+            return Vec::new();
+        }
+        let origin = OriginStamp {
+            time: Utc::now(),
+            chunk_id: self.chunk_id,
+        };
+        self.chunk_id = self.chunk_id.wrapping_add(1);
+        vec![Extension {
+            id: ORIGIN_EXTENSION_ID,
+            payload: origin.encode().to_vec().into(),
+        }]
+    }
+
     #[allow(clippy::cast_possible_truncation)]
     pub async fn run(&mut self) -> Result<()> {
         let start_time = Instant::now();
@@ -86,20 +120,26 @@ impl RtpSender {
                 self.audio_generator.generate_samples(samples_per_packet)
             };
 
+            let extensions = self.next_extensions();
+
             // Create RTP packet
             let packet = Packet {
                 header: rtp::header::Header {
                     version: 2,
                     padding: false,
-                    extension: false,
+                    extension: !extensions.is_empty(),
                     marker: false,
                     payload_type: self.args.payload_type,
                     sequence_number: self.sequence_number,
                     timestamp: self.timestamp,
                     ssrc: self.ssrc,
                     csrc: vec![],
-                    extension_profile: 0,
-                    extensions: vec![],
+                    extension_profile: if extensions.is_empty() {
+                        0
+                    } else {
+                        EXTENSION_PROFILE_ONE_BYTE
+                    },
+                    extensions,
                     extensions_padding: 0,
                 },
                 payload: audio_data.into(),
@@ -141,7 +181,7 @@ impl RtpSender {
                 .timestamp
                 .wrapping_add((samples_per_packet * self.args.interval as usize / 20) as u32);
 
-            if packets_sent % 50 == 0 && packets_sent > 0 {
+            if packets_sent.is_multiple_of(50) && packets_sent > 0 {
                 debug!(
                     "Sent {} packets, dropped {}, reordered {}",
                     packets_sent, packets_dropped, packets_reordered
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result, bail};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, info};
+
+use shared_types::SessionDescription;
+use shared_types::header_extension::ORIGIN_URI;
+
+use crate::rtp_sender::ORIGIN_EXTENSION_ID;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A scripted SIP caller: INVITE with an SDP offer, ACK the answer, and
+/// BYE once the media is done. Just enough to exercise rtp-ingest locally.
+pub struct SipClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    call_id: String,
+    from_tag: String,
+    to: Option<String>,
+    cseq: u32,
+}
+
+impl SipClient {
+    pub async fn new(server: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        Ok(Self {
+            socket,
+            server,
+            call_id: format!("{:016x}@rtp-test-sender", rand::random::<u64>()),
+            from_tag: format!("{:08x}", rand::random::<u32>()),
+            to: None,
+            cseq: 0,
+        })
+    }
+
+    /// Places the call offering one payload type and returns where to send media.
+    pub async fn invite(
+        &mut self,
+        media_port: u16,
+        payload_type: u8,
+        rtpmap: &str,
+        origin_stamp: bool,
+    ) -> Result<SocketAddr> {
+        let mut offer = format!(
+            "v=0\r\no=rtp-test-sender 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+             m=audio {media_port} RTP/AVP {payload_type}\r\na=rtpmap:{payload_type} {rtpmap}\r\na=ptime:20\r\na=sendonly\r\n"
+        );
+        if origin_stamp {
+            write!(offer, "a=extmap:{ORIGIN_EXTENSION_ID} {ORIGIN_URI}\r\n")?;
+        }
+
+        self.cseq += 1;
+        self.send(&self.request("INVITE", self.cseq, "application/sdp", &offer))
+            .await?;
+
+        let (status, headers, body) = loop {
+            let (status, headers, body) = self.receive().await?;
+            info!("INVITE got {}", status);
+            if status >= 200 {
+                break (status, headers, body);
+            }
+        };
+        if status != 200 {
+            bail!("call rejected with {status}");
+        }
+        self.to = header(&headers, "To").map(str::to_string);
+        self.send(&self.request("ACK", self.cseq, "", "")).await?;
+
+        let answer = SessionDescription::parse(&body)?;
+        let media = answer.media.first().context("answer has no media")?;
+        let ip = media
+            .connection
+            .or(answer.connection)
+            .context("answer has no connection address")?;
+        Ok(SocketAddr::new(ip, media.port))
+    }
+
+    pub async fn bye(&mut self) -> Result<()> {
+        self.cseq += 1;
+        self.send(&self.request("BYE", self.cseq, "", "")).await?;
+        // Skip any INVITE 2xx retransmission still in flight.
+        loop {
+            let (status, headers, _) = self.receive().await?;
+            if header(&headers, "CSeq").is_some_and(|cseq| cseq.ends_with("BYE")) {
+                info!("BYE got {}", status);
+                return Ok(());
+            }
+        }
+    }
+
+    fn request(&self, method: &str, cseq: u32, content_type: &str, body: &str) -> String {
+        let local = self
+            .socket
+            .local_addr()
+            .map_or_else(|_| "127.0.0.1:0".to_string(), |addr| addr.to_string());
+        let to = self
+            .to
+            .clone()
+            .unwrap_or_else(|| format!("<sip:navitel@{}>", self.server));
+
+        let mut message = format!(
+            "{method} sip:navitel@{server} SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {local};branch=z9hG4bK{branch:08x};rport\r\n\
+             Max-Forwards: 70\r\n\
+             From: <sip:tester@{local}>;tag={from_tag}\r\n\
+             To: {to}\r\n\
+             Call-ID: {call_id}\r\n\
+             CSeq: {cseq} {method}\r\n\
+             Contact: <sip:tester@{local}>\r\n",
+            server = self.server,
+            branch = rand::random::<u32>(),
+            from_tag = self.from_tag,
+            call_id = self.call_id,
+        );
+        if !content_type.is_empty() {
+            let _ = write!(message, "Content-Type: {content_type}\r\n");
+        }
+        let _ = write!(message, "Content-Length: {}\r\n\r\n{body}", body.len());
+        message
+    }
+
+    async fn send(&self, message: &str) -> Result<()> {
+        debug!("Sending SIP:\n{}", message);
+        self.socket.send_to(message.as_bytes(), self.server).await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> Result<(u16, Vec<(String, String)>, String)> {
+        let mut buf = vec![0u8; 65_535];
+        let (len, _) = time::timeout(RESPONSE_TIMEOUT, self.socket.recv_from(&mut buf))
+            .await
+            .context("timed out waiting for SIP response")??;
+        let text = String::from_utf8_lossy(&buf[..len]).into_owned();
+        debug!("Received SIP:\n{}", text);
+
+        let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|status| status.parse().ok())
+            .context("bad SIP status line")?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Ok((status, headers, body.to_string()))
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
@@ -1,6 +1,8 @@
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::header_extension::HeaderExtensions;
 use crate::latency::LatencyMetadata;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,9 +11,43 @@ pub struct AudioChunk {
     pub format: AudioFormat,
     pub sequence_number: u32,
     pub timestamp: u32,
+    pub kind: ChunkKind,
+    /// Sender wall-clock time of the first sample, once an RTCP sender
+    /// report has tied the RTP clock to NTP time.
+    pub capture_time: Option<DateTime<Utc>>,
+    /// RFC 8285 header extensions of the packet the chunk came from.
+    pub extensions: HeaderExtensions,
     pub metadata: LatencyMetadata,
 }
 
+/// What an [`AudioChunk`] carries. Gap chunks have no payload and stand in
+/// for packets that never arrived, so consumers can keep their timelines
+/// aligned instead of silently splicing audio together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkKind {
+    Audio,
+    Gap {
+        packets: u32,
+    },
+    /// The sender's sequence and timestamp space changed, for example after
+    /// an SSRC change or restart. Timing does not carry over from earlier chunks.
+    Discontinuity,
+    /// An RFC 3389 comfort noise packet: the sender has gone quiet and
+    /// describes the background noise instead. `data` holds the CN payload.
+    ComfortNoise {
+        /// Noise level in -dBov.
+        noise_level: u8,
+    },
+    /// Silence the sender chose not to send (DTX), as opposed to loss. Has no
+    /// payload and spans `samples` RTP timestamp units.
+    Silence {
+        samples: u32,
+    },
+    /// Comfort noise generated locally to fill DTX silence, encoded like the
+    /// stream's audio so consumers see a continuous signal.
+    GeneratedNoise,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AudioFormat {
     pub codec: AudioCodec,
@@ -39,6 +75,14 @@ impl AudioFormat {
         }
     }
 
+    /// The RTP timestamp clock rate, which for G.722 is not its sample rate.
+    pub const fn rtp_clock_rate(&self) -> u32 {
+        match self.codec {
+            AudioCodec::G722 => 8000,
+            _ => self.sample_rate,
+        }
+    }
+
     pub const fn g711_ulaw_mono() -> Self {
         Self {
             codec: AudioCodec::G711Ulaw,
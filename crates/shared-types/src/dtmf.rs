@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A key the caller pressed, decoded from RFC 4733 telephone-events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DtmfEvent {
+    /// `0`-`9`, `*`, `#` or `A`-`D`.
+    pub digit: char,
+    /// How long the key was held.
+    pub duration: Duration,
+    /// RTP timestamp at which the key was first pressed, on the same clock
+    /// as the stream's audio.
+    pub rtp_timestamp: u32,
+}
+
+impl DtmfEvent {
+    /// The digit for an RFC 4733 section 3.2 DTMF event code.
+    pub fn digit_for_code(code: u8) -> Option<char> {
+        match code {
+            0..=9 => Some(char::from(b'0' + code)),
+            10 => Some('*'),
+            11 => Some('#'),
+            12..=15 => Some(char::from(b'A' + code - 12)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// RFC 6464 client-to-mixer audio level.
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+/// Sender's transmit time, as WebRTC congestion control uses it.
+pub const ABS_SEND_TIME_URI: &str = "http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time";
+/// Sequence number shared by every stream a sender transmits.
+pub const TRANSPORT_SEQUENCE_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+/// Navitel's origin stamp; see [`OriginStamp`].
+pub const ORIGIN_URI: &str = "urn:navitel:rtp-hdrext:origin";
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HeaderExtensionError {
+    #[error("invalid header extension `{0}`, expected ID=URI")]
+    InvalidEntry(String),
+    #[error("invalid header extension ID `{0}`, expected 1-255")]
+    InvalidId(String),
+    #[error("unsupported header extension `{0}`")]
+    Unsupported(String),
+}
+
+/// The RFC 8285 header extensions the pipeline reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HeaderExtensionKind {
+    AudioLevel,
+    AbsSendTime,
+    TransportSequence,
+    Origin,
+}
+
+impl HeaderExtensionKind {
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        match uri {
+            AUDIO_LEVEL_URI => Some(Self::AudioLevel),
+            ABS_SEND_TIME_URI => Some(Self::AbsSendTime),
+            TRANSPORT_SEQUENCE_URI => Some(Self::TransportSequence),
+            ORIGIN_URI => Some(Self::Origin),
+            _ => None,
+        }
+    }
+
+    pub const fn uri(self) -> &'static str {
+        match self {
+            Self::AudioLevel => AUDIO_LEVEL_URI,
+            Self::AbsSendTime => ABS_SEND_TIME_URI,
+            Self::TransportSequence => TRANSPORT_SEQUENCE_URI,
+            Self::Origin => ORIGIN_URI,
+        }
+    }
+}
+
+impl fmt::Display for HeaderExtensionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.uri())
+    }
+}
+
+/// Extension ID to meaning for one RTP session, as `a=extmap` lines
+/// negotiate it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderExtensionMap {
+    ids: BTreeMap<u8, HeaderExtensionKind>,
+}
+
+impl HeaderExtensionMap {
+    pub fn insert(&mut self, id: u8, kind: HeaderExtensionKind) {
+        self.ids.insert(id, kind);
+    }
+
+    pub fn get(&self, id: u8) -> Option<HeaderExtensionKind> {
+        self.ids.get(&id).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, HeaderExtensionKind)> {
+        self.ids.iter().map(|(&id, &kind)| (id, kind))
+    }
+
+    /// Parses an `ID=URI` configuration entry.
+    pub fn parse_entry(entry: &str) -> Result<(u8, HeaderExtensionKind), HeaderExtensionError> {
+        let (id, uri) = entry
+            .split_once('=')
+            .ok_or_else(|| HeaderExtensionError::InvalidEntry(entry.to_string()))?;
+        let id = id
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|&id| id > 0)
+            .ok_or_else(|| HeaderExtensionError::InvalidId(id.to_string()))?;
+        let kind = HeaderExtensionKind::from_uri(uri.trim())
+            .ok_or_else(|| HeaderExtensionError::Unsupported(uri.to_string()))?;
+        Ok((id, kind))
+    }
+}
+
+/// What the header extensions of the packet a chunk came from said.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderExtensions {
+    pub audio_level: Option<AudioLevel>,
+    /// Sender's transmit time as 6.18 fixed-point seconds, wrapping every
+    /// 64 seconds.
+    pub abs_send_time: Option<u32>,
+    pub transport_sequence: Option<u16>,
+}
+
+/// RFC 6464 audio level of one packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioLevel {
+    /// Level in -dBov, 0 to 127.
+    pub level: u8,
+    /// The sender's voice activity detector heard speech.
+    pub voice_activity: bool,
+}
+
+impl HeaderExtensions {
+    /// Records one extension element. Returns false if its payload is not
+    /// the shape `kind` has, which leaves the value unset.
+    pub fn record(&mut self, kind: HeaderExtensionKind, payload: &[u8]) -> bool {
+        match (kind, payload) {
+            (HeaderExtensionKind::AudioLevel, &[byte]) => {
+                self.audio_level = Some(AudioLevel {
+                    level: byte & 0x7F,
+                    voice_activity: byte & 0x80 != 0,
+                });
+            }
+            (HeaderExtensionKind::AbsSendTime, &[a, b, c]) => {
+                self.abs_send_time = Some(u32::from_be_bytes([0, a, b, c]));
+            }
+            (HeaderExtensionKind::TransportSequence, &[a, b]) => {
+                self.transport_sequence = Some(u16::from_be_bytes([a, b]));
+            }
+            // The origin stamp is latency metadata rather than a transport
+            // detail; see `OriginStamp::decode`.
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// When and as which chunk a piece of audio left the original sender, so
+/// latency can be measured end to end rather than from ingestion. Carried
+/// in the [`ORIGIN_URI`] header extension as 10 bytes: NTP seconds (32
+/// bits), the top 16 bits of the NTP fraction (about 15 µs) and the
+/// sender's chunk counter (32 bits), all big-endian. It fits the one-byte
+/// extension form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OriginStamp {
+    pub time: DateTime<Utc>,
+    pub chunk_id: u32,
+}
+
+impl OriginStamp {
+    pub const ENCODED_LEN: usize = 10;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let seconds = self.time.timestamp() + NTP_UNIX_OFFSET;
+        let fraction = (u64::from(self.time.timestamp_subsec_nanos()) << 16) / 1_000_000_000;
+        let mut encoded = [0u8; Self::ENCODED_LEN];
+        // NTP era 0 ends in 2036; the truncation is the wire format's.
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        encoded[..4].copy_from_slice(&(seconds as u32).to_be_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        encoded[4..6].copy_from_slice(&(fraction as u16).to_be_bytes());
+        encoded[6..].copy_from_slice(&self.chunk_id.to_be_bytes());
+        encoded
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let payload: &[u8; Self::ENCODED_LEN] = payload.try_into().ok()?;
+        let seconds = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        let fraction = u16::from_be_bytes([payload[4], payload[5]]);
+        let nanos = (u64::from(fraction) * 1_000_000_000) >> 16;
+        let time = DateTime::from_timestamp(
+            i64::from(seconds) - NTP_UNIX_OFFSET,
+            u32::try_from(nanos).ok()?,
+        )?;
+        Some(Self {
+            time,
+            chunk_id: u32::from_be_bytes([payload[6], payload[7], payload[8], payload[9]]),
+        })
+    }
+}
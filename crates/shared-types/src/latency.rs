@@ -3,11 +3,15 @@ use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use uuid::Uuid;
 
+use crate::header_extension::OriginStamp;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LatencyMetadata {
     pub stream_id: Uuid,
     pub chunk_id: Uuid,
     pub ingestion_time: DateTime<Utc>,
+    /// Set when the sender stamped the packet with its own send time.
+    pub origin: Option<OriginStamp>,
     pub stages: Vec<ProcessingStage>,
 }
 
@@ -34,6 +38,7 @@ impl LatencyMetadata {
             stream_id,
             chunk_id: Uuid::new_v4(),
             ingestion_time: Utc::now(),
+            origin: None,
             stages: Vec::new(),
         }
     }
@@ -62,6 +67,16 @@ impl LatencyMetadata {
         Duration::from_millis(elapsed.num_milliseconds().unsigned_abs())
     }
 
+    /// Time since the original sender stamped the audio, when it did. Only
+    /// as accurate as the two clocks are in step.
+    pub fn end_to_end_latency(&self) -> Option<Duration> {
+        let origin = self.origin?;
+        let elapsed = Utc::now() - origin.time;
+        Some(Duration::from_millis(
+            elapsed.num_milliseconds().unsigned_abs(),
+        ))
+    }
+
     pub fn stage_latency(&self, stage_name: &str) -> Option<Duration> {
         self.stages
             .iter()
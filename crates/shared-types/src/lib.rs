@@ -1,7 +1,22 @@
 pub mod audio;
+pub mod dtmf;
+pub mod header_extension;
 pub mod latency;
+pub mod payload;
+pub mod pcap;
+pub mod sdp;
+pub mod stats;
 pub mod stream;
 
-pub use audio::{AudioChunk, AudioCodec, AudioFormat};
+pub use audio::{AudioChunk, AudioCodec, AudioFormat, ChunkKind};
+pub use dtmf::DtmfEvent;
+pub use header_extension::{
+    AudioLevel, HeaderExtensionError, HeaderExtensionKind, HeaderExtensionMap, HeaderExtensions,
+    OriginStamp,
+};
 pub use latency::{LatencyMetadata, ProcessingStage, StageMetrics};
-pub use stream::{StreamId, StreamMetadata, StreamState};
+pub use payload::{PayloadError, PayloadMapping, PayloadTypeMap};
+pub use pcap::{PcapError, RtpFlow, UdpDatagram};
+pub use sdp::{NegotiatedMedia, SdpError, SessionDescription};
+pub use stats::ReceptionStats;
+pub use stream::{EndReason, RecordingLeg, StreamEvent, StreamId, StreamMetadata, StreamState};
@@ -0,0 +1,313 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+use crate::audio::{AudioCodec, AudioFormat};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PayloadError {
+    #[error("invalid rtpmap encoding `{0}`, expected NAME/CLOCK[/CHANNELS]")]
+    InvalidRtpmap(String),
+    #[error("invalid payload type `{0}`, expected 0-127")]
+    InvalidPayloadType(String),
+}
+
+/// What a payload type number means on the wire, as an SDP `a=rtpmap`
+/// line (plus optional `a=fmtp` parameters) describes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadMapping {
+    pub encoding_name: String,
+    pub clock_rate: u32,
+    pub channels: u8,
+    pub fmtp: Option<String>,
+}
+
+impl PayloadMapping {
+    pub fn new(encoding_name: impl Into<String>, clock_rate: u32, channels: u8) -> Self {
+        Self {
+            encoding_name: encoding_name.into(),
+            clock_rate,
+            channels,
+            fmtp: None,
+        }
+    }
+
+    /// Parses the encoding part of an rtpmap, e.g. `opus/48000/2`.
+    pub fn parse_rtpmap(encoding: &str) -> Result<Self, PayloadError> {
+        let invalid = || PayloadError::InvalidRtpmap(encoding.to_string());
+        let mut parts = encoding.trim().split('/');
+
+        let name = parts
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(invalid)?;
+        let clock_rate = parts
+            .next()
+            .and_then(|rate| rate.parse().ok())
+            .ok_or_else(invalid)?;
+        let channels = match parts.next() {
+            Some(channels) => channels.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self::new(name, clock_rate, channels))
+    }
+
+    /// Whether the encoding name matches, ignoring case as SDP requires.
+    pub fn is(&self, encoding_name: &str) -> bool {
+        self.encoding_name.eq_ignore_ascii_case(encoding_name)
+    }
+
+    /// The audio format carried, for encodings the pipeline can decode.
+    pub fn audio_format(&self) -> Option<AudioFormat> {
+        let (codec, sample_rate, bits_per_sample) =
+            match self.encoding_name.to_ascii_uppercase().as_str() {
+                "PCMU" => (AudioCodec::G711Ulaw, self.clock_rate, 8),
+                "PCMA" => (AudioCodec::G711Alaw, self.clock_rate, 8),
+                // G.722 samples at 16 kHz but keeps an 8 kHz RTP clock for
+                // historical reasons (RFC 3551 section 4.5.2).
+                "G722" => (AudioCodec::G722, 16000, 8),
+                "L16" => (AudioCodec::Pcm, self.clock_rate, 16),
+                "OPUS" => (AudioCodec::Opus, self.clock_rate, 16),
+                _ => return None,
+            };
+
+        Some(AudioFormat {
+            codec,
+            sample_rate,
+            // Opus always advertises 2 channels in rtpmap; the stream itself
+            // may well be mono, which is what telephony sends.
+            channels: if codec == AudioCodec::Opus {
+                1
+            } else {
+                self.channels
+            },
+            bits_per_sample,
+        })
+    }
+}
+
+impl fmt::Display for PayloadMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.encoding_name, self.clock_rate)?;
+        if self.channels != 1 {
+            write!(f, "/{}", self.channels)?;
+        }
+        Ok(())
+    }
+}
+
+/// Payload type number to mapping registry for one RTP session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadTypeMap {
+    mappings: BTreeMap<u8, PayloadMapping>,
+}
+
+impl PayloadTypeMap {
+    /// The static payload types assigned by RFC 3551 tables 4 and 5.
+    pub fn rfc3551() -> Self {
+        let mut map = Self::default();
+        for (payload_type, name, clock_rate, channels) in [
+            (0, "PCMU", 8000, 1),
+            (3, "GSM", 8000, 1),
+            (4, "G723", 8000, 1),
+            (5, "DVI4", 8000, 1),
+            (6, "DVI4", 16000, 1),
+            (7, "LPC", 8000, 1),
+            (8, "PCMA", 8000, 1),
+            (9, "G722", 8000, 1),
+            (10, "L16", 44100, 2),
+            (11, "L16", 44100, 1),
+            (12, "QCELP", 8000, 1),
+            (13, "CN", 8000, 1),
+            (14, "MPA", 90000, 0),
+            (15, "G728", 8000, 1),
+            (16, "DVI4", 11025, 1),
+            (17, "DVI4", 22050, 1),
+            (18, "G729", 8000, 1),
+            (25, "CelB", 90000, 0),
+            (26, "JPEG", 90000, 0),
+            (28, "nv", 90000, 0),
+            (31, "H261", 90000, 0),
+            (32, "MPV", 90000, 0),
+            (33, "MP2T", 90000, 0),
+            (34, "H263", 90000, 0),
+        ] {
+            map.insert(
+                payload_type,
+                PayloadMapping::new(name, clock_rate, channels),
+            );
+        }
+        map
+    }
+
+    pub fn insert(&mut self, payload_type: u8, mapping: PayloadMapping) {
+        self.mappings.insert(payload_type, mapping);
+    }
+
+    pub fn get(&self, payload_type: u8) -> Option<&PayloadMapping> {
+        self.mappings.get(&payload_type)
+    }
+
+    /// Attaches `a=fmtp` parameters to an existing mapping.
+    pub fn set_fmtp(&mut self, payload_type: u8, fmtp: impl Into<String>) {
+        if let Some(mapping) = self.mappings.get_mut(&payload_type) {
+            mapping.fmtp = Some(fmtp.into());
+        }
+    }
+
+    /// Overlays `other` on top of this map; its entries win.
+    pub fn extend(&mut self, other: &Self) {
+        self.mappings.extend(
+            other
+                .mappings
+                .iter()
+                .map(|(&pt, mapping)| (pt, mapping.clone())),
+        );
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &PayloadMapping)> {
+        self.mappings.iter().map(|(&pt, mapping)| (pt, mapping))
+    }
+
+    /// Parses a `PT=NAME/CLOCK[/CHANNELS]` configuration entry.
+    pub fn parse_entry(entry: &str) -> Result<(u8, PayloadMapping), PayloadError> {
+        let (payload_type, encoding) = entry
+            .split_once('=')
+            .ok_or_else(|| PayloadError::InvalidRtpmap(entry.to_string()))?;
+        let payload_type = payload_type
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|&pt| pt < 128)
+            .ok_or_else(|| PayloadError::InvalidPayloadType(payload_type.to_string()))?;
+        Ok((payload_type, PayloadMapping::parse_rtpmap(encoding)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_configured_mappings() {
+        assert_eq!(
+            PayloadTypeMap::parse_entry("96=opus/48000/2"),
+            Ok((96, PayloadMapping::new("opus", 48000, 2)))
+        );
+        assert_eq!(
+            PayloadTypeMap::parse_entry(" 101 = telephone-event/8000 "),
+            Ok((101, PayloadMapping::new("telephone-event", 8000, 1)))
+        );
+        assert_eq!(
+            PayloadTypeMap::parse_entry("0=PCMU/8000"),
+            Ok((0, PayloadMapping::new("PCMU", 8000, 1)))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        for (entry, error) in [
+            ("96", PayloadError::InvalidRtpmap("96".into())),
+            (
+                "128=opus/48000",
+                PayloadError::InvalidPayloadType("128".into()),
+            ),
+            (
+                "-1=opus/48000",
+                PayloadError::InvalidPayloadType("-1".into()),
+            ),
+            ("x=opus/48000", PayloadError::InvalidPayloadType("x".into())),
+            (
+                "=opus/48000",
+                PayloadError::InvalidPayloadType(String::new()),
+            ),
+            ("96=", PayloadError::InvalidRtpmap(String::new())),
+            ("96=opus", PayloadError::InvalidRtpmap("opus".into())),
+            ("96=/48000", PayloadError::InvalidRtpmap("/48000".into())),
+            (
+                "96=opus/fast",
+                PayloadError::InvalidRtpmap("opus/fast".into()),
+            ),
+            (
+                "96=opus/48000/",
+                PayloadError::InvalidRtpmap("opus/48000/".into()),
+            ),
+            (
+                "96=opus/48000/300",
+                PayloadError::InvalidRtpmap("opus/48000/300".into()),
+            ),
+            (
+                "96=opus/48000/2/1",
+                PayloadError::InvalidRtpmap("opus/48000/2/1".into()),
+            ),
+        ] {
+            assert_eq!(PayloadTypeMap::parse_entry(entry), Err(error), "{entry}");
+        }
+    }
+
+    #[test]
+    fn maps_encodings_to_audio_formats() {
+        let format = |encoding: &str| {
+            PayloadMapping::parse_rtpmap(encoding)
+                .unwrap()
+                .audio_format()
+                .map(|format| {
+                    (
+                        format.codec,
+                        format.sample_rate,
+                        format.channels,
+                        format.bits_per_sample,
+                    )
+                })
+        };
+        assert_eq!(
+            format("PCMU/8000"),
+            Some((AudioCodec::G711Ulaw, 8000, 1, 8))
+        );
+        assert_eq!(
+            format("pcma/8000"),
+            Some((AudioCodec::G711Alaw, 8000, 1, 8))
+        );
+        assert_eq!(format("G722/8000"), Some((AudioCodec::G722, 16000, 1, 8)));
+        assert_eq!(format("L16/44100/2"), Some((AudioCodec::Pcm, 44100, 2, 16)));
+        assert_eq!(
+            format("opus/48000/2"),
+            Some((AudioCodec::Opus, 48000, 1, 16))
+        );
+        assert_eq!(format("GSM/8000"), None);
+        assert_eq!(format("telephone-event/8000"), None);
+    }
+
+    #[test]
+    fn knows_the_static_payload_types() {
+        let map = PayloadTypeMap::rfc3551();
+        assert_eq!(map.get(0).unwrap().to_string(), "PCMU/8000");
+        assert_eq!(map.get(10).unwrap().to_string(), "L16/44100/2");
+        assert!(map.get(9).unwrap().is("g722"));
+        // Reserved and dynamic numbers have no static meaning.
+        for payload_type in [1, 2, 19, 72, 96, 127] {
+            assert_eq!(map.get(payload_type), None, "{payload_type}");
+        }
+    }
+
+    #[test]
+    fn overlays_dynamic_mappings() {
+        let mut map = PayloadTypeMap::rfc3551();
+        let mut call = PayloadTypeMap::default();
+        call.insert(96, PayloadMapping::new("opus", 48000, 2));
+        call.insert(0, PayloadMapping::new("PCMA", 8000, 1));
+        call.set_fmtp(96, "useinbandfec=1");
+        call.set_fmtp(97, "ignored");
+        map.extend(&call);
+
+        assert_eq!(map.get(96).unwrap().fmtp.as_deref(), Some("useinbandfec=1"));
+        assert_eq!(map.get(97), None);
+        assert!(map.get(0).unwrap().is("PCMA"));
+        assert!(map.get(8).unwrap().is("PCMA"));
+    }
+}
@@ -0,0 +1,661 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LOOP: u16 = 108;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+const PROTOCOL_UDP: u8 = 17;
+/// IPv6 extension headers walked past to reach UDP: hop-by-hop, routing
+/// and destination options.
+const IPV6_SKIPPABLE_HEADERS: [u8; 3] = [0, 43, 60];
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PcapError {
+    #[error("not a pcap or pcapng file")]
+    UnknownFormat,
+    #[error("malformed pcapng block at byte {0}")]
+    MalformedBlock(usize),
+}
+
+/// A UDP datagram as a capture recorded it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpDatagram {
+    pub time: DateTime<Utc>,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+impl UdpDatagram {
+    /// The SSRC, if the payload has the shape of an RTP packet: version 2,
+    /// a whole fixed header, and a second octet RTCP does not use (RFC
+    /// 5761 section 4).
+    pub fn rtp_ssrc(&self) -> Option<u32> {
+        let header = self.payload.get(..12)?;
+        if header[0] >> 6 != 2 || (192..=223).contains(&header[1]) {
+            return None;
+        }
+        Some(u32::from_be_bytes([
+            header[8], header[9], header[10], header[11],
+        ]))
+    }
+
+    /// Whether the payload has the shape of an RTCP packet.
+    pub fn is_rtcp(&self) -> bool {
+        matches!(self.payload.as_slice(), [first, second, ..]
+            if first >> 6 == 2 && (192..=223).contains(second))
+    }
+}
+
+/// One sender's RTP to one address under one SSRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpFlow {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub ssrc: u32,
+    pub packets: usize,
+    pub first_sequence: u16,
+}
+
+/// Reads every UDP datagram over IPv4 or IPv6 from a pcap or pcapng
+/// capture, in file order. Ethernet (with VLAN tags), raw IP, loopback and
+/// Linux cooked captures are understood; other packets, fragments and
+/// pcapng interfaces with other link types are skipped. A record cut
+/// short at the end of the file, as a capture stopped abruptly leaves, is
+/// taken as the end.
+pub fn read_udp(data: &[u8]) -> Result<Vec<UdpDatagram>, PcapError> {
+    let magic = read_u32(data, 0, Endian::Little).ok_or(PcapError::UnknownFormat)?;
+    match magic {
+        PCAPNG_SECTION_HEADER => read_pcapng(data),
+        PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS => Ok(read_pcap(data, Endian::Little, magic)),
+        _ => match magic.swap_bytes() {
+            magic @ (PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS) => {
+                Ok(read_pcap(data, Endian::Big, magic))
+            }
+            _ => Err(PcapError::UnknownFormat),
+        },
+    }
+}
+
+/// The RTP flows among `datagrams`, in the order they first appear.
+pub fn rtp_flows(datagrams: &[UdpDatagram]) -> Vec<RtpFlow> {
+    let mut flows: Vec<RtpFlow> = Vec::new();
+    let mut index = HashMap::new();
+    for datagram in datagrams {
+        let Some(ssrc) = datagram.rtp_ssrc() else {
+            continue;
+        };
+        let key = (datagram.source, datagram.destination, ssrc);
+        let position = *index.entry(key).or_insert_with(|| {
+            flows.push(RtpFlow {
+                source: datagram.source,
+                destination: datagram.destination,
+                ssrc,
+                packets: 0,
+                first_sequence: u16::from_be_bytes([datagram.payload[2], datagram.payload[3]]),
+            });
+            flows.len() - 1
+        });
+        flows[position].packets += 1;
+    }
+    flows
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+fn read_u16(data: &[u8], at: usize, endian: Endian) -> Option<u16> {
+    let bytes = data.get(at..at + 2)?.try_into().ok()?;
+    Some(match endian {
+        Endian::Little => u16::from_le_bytes(bytes),
+        Endian::Big => u16::from_be_bytes(bytes),
+    })
+}
+
+fn read_u32(data: &[u8], at: usize, endian: Endian) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?.try_into().ok()?;
+    Some(match endian {
+        Endian::Little => u32::from_le_bytes(bytes),
+        Endian::Big => u32::from_be_bytes(bytes),
+    })
+}
+
+fn read_pcap(data: &[u8], endian: Endian, magic: u32) -> Vec<UdpDatagram> {
+    let units_per_second = if magic == PCAP_MAGIC_NANOS {
+        1_000_000_000
+    } else {
+        1_000_000
+    };
+    // The upper bits of the link type field carry FCS information.
+    let Some(link_type) = read_u32(data, 20, endian) else {
+        return Vec::new();
+    };
+    #[allow(clippy::cast_possible_truncation)]
+    let link_type = link_type as u16;
+
+    let mut datagrams = Vec::new();
+    let mut offset = PCAP_HEADER_LEN;
+    while let (Some(seconds), Some(fraction), Some(captured)) = (
+        read_u32(data, offset, endian),
+        read_u32(data, offset + 4, endian),
+        read_u32(data, offset + 8, endian),
+    ) {
+        let start = offset + PCAP_RECORD_HEADER_LEN;
+        let Some(frame) = data.get(start..start + captured as usize) else {
+            break;
+        };
+        let time = timestamp(
+            u128::from(seconds) * units_per_second + u128::from(fraction),
+            units_per_second,
+        );
+        datagrams.extend(time.and_then(|time| decode_frame(link_type, frame, time)));
+        offset = start + captured as usize;
+    }
+    datagrams
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<UdpDatagram>, PcapError> {
+    let mut datagrams = Vec::new();
+    let mut endian = Endian::Little;
+    // Link type and timestamp units of each interface in the section.
+    let mut interfaces: Vec<(u16, u128)> = Vec::new();
+    let mut offset = 0;
+    while offset + 12 <= data.len() {
+        if read_u32(data, offset, Endian::Little) == Some(PCAPNG_SECTION_HEADER) {
+            endian = match read_u32(data, offset + 8, Endian::Little) {
+                Some(PCAPNG_BYTE_ORDER_MAGIC) => Endian::Little,
+                Some(magic) if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => Endian::Big,
+                _ => return Err(PcapError::MalformedBlock(offset)),
+            };
+            interfaces.clear();
+        }
+        let (Some(block_type), Some(length)) = (
+            read_u32(data, offset, endian),
+            read_u32(data, offset + 4, endian),
+        ) else {
+            break;
+        };
+        let length = length as usize;
+        if length < 12 || !length.is_multiple_of(4) {
+            return Err(PcapError::MalformedBlock(offset));
+        }
+        let Some(body) = data.get(offset + 8..offset + length - 4) else {
+            break;
+        };
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type =
+                    read_u16(body, 0, endian).ok_or(PcapError::MalformedBlock(offset))?;
+                let units = body
+                    .get(8..)
+                    .and_then(|options| interface_resolution(options, endian))
+                    .unwrap_or(1_000_000);
+                interfaces.push((link_type, units));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                datagrams.extend(enhanced_packet(body, endian, &interfaces));
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(datagrams)
+}
+
+fn enhanced_packet(body: &[u8], endian: Endian, interfaces: &[(u16, u128)]) -> Option<UdpDatagram> {
+    let interface = read_u32(body, 0, endian)?;
+    let &(link_type, units) = interfaces.get(interface as usize)?;
+    let high = read_u32(body, 4, endian)?;
+    let low = read_u32(body, 8, endian)?;
+    let captured = read_u32(body, 12, endian)? as usize;
+    let frame = body.get(20..20 + captured)?;
+    let time = timestamp((u128::from(high) << 32) | u128::from(low), units)?;
+    decode_frame(link_type, frame, time)
+}
+
+/// Timestamp units per second from an interface's `if_tsresol` option:
+/// a negative power of ten, or of two if the top bit is set.
+fn interface_resolution(mut options: &[u8], endian: Endian) -> Option<u128> {
+    while let (Some(code), Some(length)) =
+        (read_u16(options, 0, endian), read_u16(options, 2, endian))
+    {
+        if code == PCAPNG_OPTION_END {
+            break;
+        }
+        let length = usize::from(length);
+        if code == PCAPNG_OPTION_TSRESOL {
+            let resolution = *options.get(4)?;
+            let exponent = u32::from(resolution & 0x7F);
+            return if resolution & 0x80 == 0 {
+                10u128.checked_pow(exponent)
+            } else {
+                1u128.checked_shl(exponent)
+            };
+        }
+        options = options.get(4 + length.next_multiple_of(4)..)?;
+    }
+    None
+}
+
+fn timestamp(ticks: u128, units_per_second: u128) -> Option<DateTime<Utc>> {
+    let seconds = i64::try_from(ticks / units_per_second).ok()?;
+    let nanos = u32::try_from(ticks % units_per_second * 1_000_000_000 / units_per_second).ok()?;
+    DateTime::from_timestamp(seconds, nanos)
+}
+
+/// The UDP datagram in one captured frame, if it holds one.
+fn decode_frame(link_type: u16, frame: &[u8], time: DateTime<Utc>) -> Option<UdpDatagram> {
+    let packet = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        // A four-byte address family, which the IP version says again.
+        LINKTYPE_NULL | LINKTYPE_LOOP => frame.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = read_u16(frame, offset, Endian::Big)?;
+            while matches!(ethertype, ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+                offset += 4;
+                ethertype = read_u16(frame, offset, Endian::Big)?;
+            }
+            ip_payload(ethertype, frame.get(offset + 2..)?)?
+        }
+        LINKTYPE_LINUX_SLL => ip_payload(read_u16(frame, 14, Endian::Big)?, frame.get(16..)?)?,
+        LINKTYPE_LINUX_SLL2 => ip_payload(read_u16(frame, 0, Endian::Big)?, frame.get(20..)?)?,
+        _ => return None,
+    };
+    let (source, destination, udp) = match packet.first()? >> 4 {
+        4 => ipv4_udp(packet)?,
+        6 => ipv6_udp(packet)?,
+        _ => return None,
+    };
+
+    let source_port = read_u16(udp, 0, Endian::Big)?;
+    let destination_port = read_u16(udp, 2, Endian::Big)?;
+    let length = usize::from(read_u16(udp, 4, Endian::Big)?);
+    let payload = udp.get(8..length.min(udp.len()))?;
+    Some(UdpDatagram {
+        time,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        payload: payload.to_vec(),
+    })
+}
+
+fn ip_payload(ethertype: u16, payload: &[u8]) -> Option<&[u8]> {
+    matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6).then_some(payload)
+}
+
+fn ipv4_udp(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let header_len = usize::from(packet[0] & 0x0F) * 4;
+    let total_len = usize::from(read_u16(packet, 2, Endian::Big)?);
+    let fragment = read_u16(packet, 6, Endian::Big)?;
+    // More fragments, or an offset: not reassembled.
+    if fragment & 0x3FFF != 0 || *packet.get(9)? != PROTOCOL_UDP {
+        return None;
+    }
+    let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+    // Ethernet pads short frames past the end of the IP packet.
+    let udp = packet.get(header_len..total_len.min(packet.len()))?;
+    Some((
+        Ipv4Addr::from(source).into(),
+        Ipv4Addr::from(destination).into(),
+        udp,
+    ))
+}
+
+fn ipv6_udp(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let payload_len = usize::from(read_u16(packet, 4, Endian::Big)?);
+    let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+    let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+    let mut next_header = *packet.get(6)?;
+    let mut rest = packet.get(40..(40 + payload_len).min(packet.len()))?;
+    while IPV6_SKIPPABLE_HEADERS.contains(&next_header) {
+        next_header = *rest.first()?;
+        let length = (usize::from(*rest.get(1)?) + 1) * 8;
+        rest = rest.get(length..)?;
+    }
+    if next_header != PROTOCOL_UDP {
+        return None;
+    }
+    Some((
+        Ipv6Addr::from(source).into(),
+        Ipv6Addr::from(destination).into(),
+        rest,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTP: [u8; 16] = [
+        0x80, 0x00, 0x12, 0x34, 0, 0, 0x01, 0x40, 0xDE, 0xAD, 0xBE, 0xEF, 0xFF, 0xFF, 0xFF, 0xFF,
+    ];
+    const RTCP_BYE: [u8; 8] = [0x81, 203, 0x00, 0x01, 0xDE, 0xAD, 0xBE, 0xEF];
+
+    fn udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut udp = Vec::new();
+        udp.extend_from_slice(&source_port.to_be_bytes());
+        udp.extend_from_slice(&destination_port.to_be_bytes());
+        udp.extend_from_slice(&u16::try_from(8 + payload.len()).unwrap().to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(payload);
+        udp
+    }
+
+    fn ipv4(fragment: u16, udp: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&u16::try_from(20 + udp.len()).unwrap().to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&fragment.to_be_bytes());
+        packet.extend_from_slice(&[64, PROTOCOL_UDP, 0, 0]);
+        packet.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7]);
+        packet.extend_from_slice(udp);
+        packet
+    }
+
+    fn ethernet(ethertypes: &[u16], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for (i, ethertype) in ethertypes.iter().enumerate() {
+            frame.extend_from_slice(&ethertype.to_be_bytes());
+            if i + 1 < ethertypes.len() {
+                frame.extend_from_slice(&[0, 42]);
+            }
+        }
+        frame.extend_from_slice(payload);
+        // Padded to the Ethernet minimum.
+        frame.resize(frame.len().max(60), 0);
+        frame
+    }
+
+    fn pcap(endian: Endian, magic: u32, link_type: u32, records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let u16_bytes = |value: u16| match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        };
+        let u32_bytes = |value: u32| match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        };
+        let mut file = Vec::new();
+        file.extend_from_slice(&u32_bytes(magic));
+        file.extend_from_slice(&u16_bytes(2));
+        file.extend_from_slice(&u16_bytes(4));
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32_bytes(65535));
+        file.extend_from_slice(&u32_bytes(link_type));
+        for &(seconds, fraction, frame) in records {
+            let length = u32::try_from(frame.len()).unwrap();
+            file.extend_from_slice(&u32_bytes(seconds));
+            file.extend_from_slice(&u32_bytes(fraction));
+            file.extend_from_slice(&u32_bytes(length));
+            file.extend_from_slice(&u32_bytes(length));
+            file.extend_from_slice(frame);
+        }
+        file
+    }
+
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().next_multiple_of(4);
+        let length = u32::try_from(12 + padded).unwrap();
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.resize(8 + padded, 0);
+        block.extend_from_slice(&length.to_le_bytes());
+        block
+    }
+
+    fn pcapng(link_type: u16, tsresol: Option<u8>, packets: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        section.extend_from_slice(&u64::MAX.to_le_bytes());
+        let mut file = pcapng_block(PCAPNG_SECTION_HEADER, &section);
+
+        let mut interface = link_type.to_le_bytes().to_vec();
+        interface.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        if let Some(resolution) = tsresol {
+            interface.extend_from_slice(&PCAPNG_OPTION_TSRESOL.to_le_bytes());
+            interface.extend_from_slice(&1u16.to_le_bytes());
+            interface.extend_from_slice(&[resolution, 0, 0, 0]);
+            interface.extend_from_slice(&[0; 4]);
+        }
+        file.extend(pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+
+        for &(ticks, frame) in packets {
+            let length = u32::try_from(frame.len()).unwrap();
+            let mut packet = 0u32.to_le_bytes().to_vec();
+            #[allow(clippy::cast_possible_truncation)]
+            let (high, low) = ((ticks >> 32) as u32, ticks as u32);
+            packet.extend_from_slice(&high.to_le_bytes());
+            packet.extend_from_slice(&low.to_le_bytes());
+            packet.extend_from_slice(&length.to_le_bytes());
+            packet.extend_from_slice(&length.to_le_bytes());
+            packet.extend_from_slice(frame);
+            file.extend(pcapng_block(PCAPNG_ENHANCED_PACKET, &packet));
+        }
+        file
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn reads_little_endian_microsecond_pcap() {
+        let frame = ipv4(0, &udp(5000, 5004, &RTP));
+        let file = pcap(
+            Endian::Little,
+            PCAP_MAGIC_MICROS,
+            101,
+            &[(1_700_000_000, 250_000, &frame)],
+        );
+
+        let datagrams = read_udp(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        let datagram = &datagrams[0];
+        assert_eq!(datagram.source, addr("192.0.2.1:5000"));
+        assert_eq!(datagram.destination, addr("198.51.100.7:5004"));
+        assert_eq!(datagram.payload, RTP);
+        assert_eq!(datagram.time.timestamp(), 1_700_000_000);
+        assert_eq!(datagram.time.timestamp_subsec_micros(), 250_000);
+    }
+
+    #[test]
+    fn reads_big_endian_nanosecond_pcap_over_vlan_ethernet() {
+        let packet = ipv4(0, &udp(5000, 5004, &RTP));
+        let frame = ethernet(&[ETHERTYPE_VLAN, ETHERTYPE_IPV4], &packet);
+        // FCS bits above the link type are ignored.
+        let link_type = 0x1000_0000 | u32::from(LINKTYPE_ETHERNET);
+        let file = pcap(
+            Endian::Big,
+            PCAP_MAGIC_NANOS,
+            link_type,
+            &[(10, 123_456_789, &frame)],
+        );
+
+        let datagrams = read_udp(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].payload, RTP);
+        assert_eq!(datagrams[0].time.timestamp_subsec_nanos(), 123_456_789);
+    }
+
+    #[test]
+    fn reads_pcapng_with_timestamp_resolution() {
+        let frame = ethernet(&[ETHERTYPE_IPV4], &ipv4(0, &udp(5000, 5004, &RTP)));
+        let file = pcapng(LINKTYPE_ETHERNET, Some(9), &[(1_500_000_000, &frame)]);
+
+        let datagrams = read_udp(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].time.timestamp(), 1);
+        assert_eq!(datagrams[0].time.timestamp_subsec_millis(), 500);
+
+        // Microseconds when the interface does not say.
+        let file = pcapng(LINKTYPE_ETHERNET, None, &[(1_500_000, &frame)]);
+        assert_eq!(
+            read_udp(&file).unwrap()[0].time.timestamp_subsec_millis(),
+            500
+        );
+    }
+
+    #[test]
+    fn reads_ipv6_past_extension_headers() {
+        let udp = udp(5000, 5004, &RTP);
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&u16::try_from(8 + udp.len()).unwrap().to_be_bytes());
+        // Hop-by-hop options first, then UDP.
+        packet.extend_from_slice(&[0, 64]);
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(&udp);
+        let file = pcap(
+            Endian::Little,
+            PCAP_MAGIC_MICROS,
+            u32::from(LINKTYPE_IPV6),
+            &[(0, 0, &packet)],
+        );
+
+        let datagrams = read_udp(&file).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].source, addr("[::1]:5000"));
+        assert_eq!(datagrams[0].destination, addr("[2001:db8::7]:5004"));
+        assert_eq!(datagrams[0].payload, RTP);
+    }
+
+    #[test]
+    fn reads_linux_cooked_captures() {
+        let packet = ipv4(0, &udp(5000, 5004, &RTP));
+        let mut sll = vec![0; 14];
+        sll.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        sll.extend_from_slice(&packet);
+        let mut sll2 = ETHERTYPE_IPV4.to_be_bytes().to_vec();
+        sll2.extend_from_slice(&[0; 18]);
+        sll2.extend_from_slice(&packet);
+
+        for (link_type, frame) in [(LINKTYPE_LINUX_SLL, sll), (LINKTYPE_LINUX_SLL2, sll2)] {
+            let file = pcap(
+                Endian::Little,
+                PCAP_MAGIC_MICROS,
+                u32::from(link_type),
+                &[(0, 0, &frame)],
+            );
+            assert_eq!(
+                read_udp(&file).unwrap()[0].payload,
+                RTP,
+                "link type {link_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn skips_fragments_and_other_link_types() {
+        let fragment = ipv4(0x2000, &udp(5000, 5004, &RTP));
+        let whole = ipv4(0, &udp(5000, 5004, &RTP));
+        let file = pcap(
+            Endian::Little,
+            PCAP_MAGIC_MICROS,
+            101,
+            &[(0, 0, &fragment), (0, 1, &whole)],
+        );
+        assert_eq!(read_udp(&file).unwrap().len(), 1);
+
+        let file = pcap(Endian::Little, PCAP_MAGIC_MICROS, 147, &[(0, 0, &whole)]);
+        assert_eq!(read_udp(&file).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_record() {
+        let frame = ipv4(0, &udp(5000, 5004, &RTP));
+        let mut file = pcap(
+            Endian::Little,
+            PCAP_MAGIC_MICROS,
+            101,
+            &[(0, 0, &frame), (0, 1, &frame)],
+        );
+        file.truncate(file.len() - 5);
+        assert_eq!(read_udp(&file).unwrap().len(), 1);
+
+        let mut file = pcapng(LINKTYPE_RAW, None, &[(0, &frame), (1, &frame)]);
+        file.truncate(file.len() - 5);
+        assert_eq!(read_udp(&file).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed_files() {
+        assert_eq!(read_udp(b""), Err(PcapError::UnknownFormat));
+        assert_eq!(read_udp(b"GIF89a......"), Err(PcapError::UnknownFormat));
+
+        let frame = ipv4(0, &udp(5000, 5004, &RTP));
+        let mut file = pcapng(LINKTYPE_RAW, None, &[(0, &frame)]);
+        let section_len = 28;
+        // An interface block whose length is not a multiple of four.
+        file[section_len + 4] = 0x1E;
+        assert_eq!(read_udp(&file), Err(PcapError::MalformedBlock(section_len)));
+    }
+
+    #[test]
+    fn tells_rtp_from_rtcp_and_groups_flows() {
+        let mut second = RTP;
+        second[3] = 0x35;
+        let frames = [
+            ipv4(0, &udp(5000, 5004, &RTP)),
+            ipv4(0, &udp(5001, 5005, &RTCP_BYE)),
+            ipv4(0, &udp(5000, 5004, &second)),
+            ipv4(0, &udp(5000, 5004, b"not rtp")),
+        ];
+        let records: Vec<_> = frames
+            .iter()
+            .map(|frame| (0, 0, frame.as_slice()))
+            .collect();
+        let datagrams = read_udp(&pcap(Endian::Little, PCAP_MAGIC_MICROS, 101, &records)).unwrap();
+
+        assert_eq!(datagrams[0].rtp_ssrc(), Some(0xDEAD_BEEF));
+        assert!(!datagrams[0].is_rtcp());
+        assert_eq!(datagrams[1].rtp_ssrc(), None);
+        assert!(datagrams[1].is_rtcp());
+        assert_eq!(datagrams[3].rtp_ssrc(), None);
+        assert!(!datagrams[3].is_rtcp());
+
+        assert_eq!(
+            rtp_flows(&datagrams),
+            [RtpFlow {
+                source: addr("192.0.2.1:5000"),
+                destination: addr("198.51.100.7:5004"),
+                ssrc: 0xDEAD_BEEF,
+                packets: 2,
+                first_sequence: 0x1234,
+            }]
+        );
+    }
+}
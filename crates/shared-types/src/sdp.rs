@@ -0,0 +1,839 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::audio::AudioFormat;
+use crate::header_extension::{HeaderExtensionKind, HeaderExtensionMap};
+use crate::payload::{PayloadError, PayloadMapping, PayloadTypeMap};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SdpError {
+    #[error("SDP is missing its `{0}=` line")]
+    MissingLine(char),
+    #[error("invalid SDP line `{line}`: {reason}")]
+    InvalidLine { line: String, reason: &'static str },
+    #[error(transparent)]
+    Payload(#[from] PayloadError),
+}
+
+/// An RFC 8866 session description, limited to what audio ingestion needs.
+/// Attributes this module does not interpret are kept so they survive a
+/// round trip.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionDescription {
+    pub origin: Origin,
+    pub session_name: String,
+    pub connection: Option<IpAddr>,
+    pub direction: Option<Direction>,
+    pub attributes: Vec<Attribute>,
+    pub media: Vec<MediaDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: u64,
+    pub session_version: u64,
+    pub address: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaDescription {
+    pub media: String,
+    pub port: u16,
+    pub protocol: String,
+    /// Payload types in the sender's order of preference.
+    pub formats: Vec<u8>,
+    pub connection: Option<IpAddr>,
+    /// Mappings for `formats`, with static payload types filled in from
+    /// RFC 3551 when the offer leaves out their rtpmap.
+    pub payload_types: PayloadTypeMap,
+    pub ptime: Option<u32>,
+    pub rtcp_mux: bool,
+    pub direction: Option<Direction>,
+    pub crypto: Vec<CryptoAttribute>,
+    /// `a=extmap` entries for extensions we read; the rest stay in
+    /// `attributes`.
+    pub header_extensions: HeaderExtensionMap,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+/// RFC 4568 SDES key exchange. Key parameters are kept as written; the
+/// SRTP layer decodes them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CryptoAttribute {
+    pub tag: u32,
+    pub suite: String,
+    pub key_params: String,
+    pub session_params: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// The outcome of negotiating one audio m-line: what we expect to receive
+/// and how to read it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NegotiatedMedia {
+    /// Where the remote side receives media, if it gave an address.
+    pub remote_addr: Option<SocketAddr>,
+    pub payload_type: u8,
+    pub format: AudioFormat,
+    /// The chosen codec plus any accepted auxiliary payloads such as
+    /// telephone-event and comfort noise.
+    pub payload_types: PayloadTypeMap,
+    pub ptime: Option<u32>,
+    pub rtcp_mux: bool,
+    pub crypto: Option<CryptoAttribute>,
+    /// Offered header extensions we read, under the offer's IDs.
+    pub header_extensions: HeaderExtensionMap,
+}
+
+/// An offered m-line we are taking, as passed to [`SessionDescription::answer`].
+#[derive(Debug, Clone)]
+pub struct AcceptedMedia<'a> {
+    /// Position of the m-line in the offer.
+    pub index: usize,
+    /// Local port the media will be received on.
+    pub port: u16,
+    pub negotiated: &'a NegotiatedMedia,
+    /// Our own SDES key for the stream, when the offer is SRTP.
+    pub crypto: Option<CryptoAttribute>,
+}
+
+/// Payloads accepted alongside the main codec because they share its stream.
+const AUXILIARY_ENCODINGS: [&str; 4] = ["telephone-event", "CN", "red", "ulpfec"];
+
+impl Direction {
+    fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Self::SendRecv),
+            "sendonly" => Some(Self::SendOnly),
+            "recvonly" => Some(Self::RecvOnly),
+            "inactive" => Some(Self::Inactive),
+            _ => None,
+        }
+    }
+
+    /// The direction to answer with, given that we only ever receive.
+    #[must_use]
+    pub const fn answer(self) -> Self {
+        match self {
+            Self::SendRecv | Self::SendOnly => Self::RecvOnly,
+            Self::RecvOnly | Self::Inactive => Self::Inactive,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SendRecv => "sendrecv",
+            Self::SendOnly => "sendonly",
+            Self::RecvOnly => "recvonly",
+            Self::Inactive => "inactive",
+        })
+    }
+}
+
+impl FromStr for CryptoAttribute {
+    type Err = SdpError;
+
+    /// Parses the value of `a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:...`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || SdpError::InvalidLine {
+            line: format!("a=crypto:{value}"),
+            reason: "expected TAG SUITE KEY-PARAMS [SESSION-PARAMS]",
+        };
+        let mut parts = value.splitn(4, ' ');
+        let tag = parts
+            .next()
+            .and_then(|tag| tag.parse().ok())
+            .ok_or_else(invalid)?;
+        let suite = parts.next().ok_or_else(invalid)?.to_string();
+        let key_params = parts.next().ok_or_else(invalid)?.to_string();
+        Ok(Self {
+            tag,
+            suite,
+            key_params,
+            session_params: parts.next().map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for CryptoAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.tag, self.suite, self.key_params)?;
+        if let Some(session_params) = &self.session_params {
+            write!(f, " {session_params}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Attribute {
+    pub fn new(name: impl Into<String>, value: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            value,
+        }
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "a={}:{}", self.name, value),
+            None => write!(f, "a={}", self.name),
+        }
+    }
+}
+
+impl MediaDescription {
+    pub fn new(media: impl Into<String>, port: u16, protocol: impl Into<String>) -> Self {
+        Self {
+            media: media.into(),
+            port,
+            protocol: protocol.into(),
+            formats: Vec::new(),
+            connection: None,
+            payload_types: PayloadTypeMap::default(),
+            ptime: None,
+            rtcp_mux: false,
+            direction: None,
+            crypto: Vec::new(),
+            header_extensions: HeaderExtensionMap::default(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds a payload type to the m-line along with its mapping.
+    pub fn add_format(&mut self, payload_type: u8, mapping: PayloadMapping) {
+        self.formats.push(payload_type);
+        self.payload_types.insert(payload_type, mapping);
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name == name)
+    }
+
+    /// Whether the m-line uses an SRTP profile such as `RTP/SAVP`.
+    pub fn is_secure(&self) -> bool {
+        self.protocol
+            .split('/')
+            .any(|part| part.starts_with("SAVP"))
+    }
+
+    /// Picks the first offered codec we can decode, in the offerer's order
+    /// of preference. A port of zero means the stream was rejected.
+    pub fn negotiate(&self, session: &SessionDescription) -> Option<NegotiatedMedia> {
+        if self.media != "audio" || self.port == 0 {
+            return None;
+        }
+
+        let (payload_type, format) = self.formats.iter().find_map(|&pt| {
+            self.payload_types
+                .get(pt)
+                .and_then(PayloadMapping::audio_format)
+                .map(|format| (pt, format))
+        })?;
+
+        let mut payload_types = PayloadTypeMap::default();
+        for &pt in &self.formats {
+            if let Some(mapping) = self.payload_types.get(pt)
+                && (pt == payload_type
+                    || AUXILIARY_ENCODINGS.iter().any(|&name| {
+                        mapping.is(name) && mapping.clock_rate == format.rtp_clock_rate()
+                    }))
+            {
+                payload_types.insert(pt, mapping.clone());
+            }
+        }
+
+        let remote_addr = self
+            .connection
+            .or(session.connection)
+            .map(|ip| SocketAddr::new(ip, self.port));
+
+        Some(NegotiatedMedia {
+            remote_addr,
+            payload_type,
+            format,
+            payload_types,
+            ptime: self.ptime,
+            rtcp_mux: self.rtcp_mux,
+            crypto: self
+                .is_secure()
+                .then(|| self.crypto.first().cloned())
+                .flatten(),
+            header_extensions: self.header_extensions.clone(),
+        })
+    }
+}
+
+impl SessionDescription {
+    pub fn new(origin: Origin, session_name: impl Into<String>) -> Self {
+        Self {
+            origin,
+            session_name: session_name.into(),
+            connection: None,
+            direction: None,
+            attributes: Vec::new(),
+            media: Vec::new(),
+        }
+    }
+
+    pub fn parse(sdp: &str) -> Result<Self, SdpError> {
+        let mut origin = None;
+        let mut session_name = None;
+        let mut connection = None;
+        let mut direction = None;
+        let mut attributes = Vec::new();
+        let mut media: Vec<MediaDescription> = Vec::new();
+        // fmtp may precede the rtpmap of its payload type, so parameters
+        // are attached once each section is complete.
+        let mut fmtp = Vec::new();
+
+        for line in sdp
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+        {
+            let invalid = |reason| SdpError::InvalidLine {
+                line: line.to_string(),
+                reason,
+            };
+            let (kind, value) = line
+                .split_once('=')
+                .filter(|(kind, _)| kind.len() == 1)
+                .ok_or_else(|| invalid("expected <type>=<value>"))?;
+
+            match (kind, media.last_mut()) {
+                ("o", None) => {
+                    origin = Some(parse_origin(value).ok_or_else(|| invalid("bad origin"))?);
+                }
+                ("s", None) => session_name = Some(value.to_string()),
+                ("c", None) => {
+                    connection =
+                        Some(parse_connection(value).ok_or_else(|| invalid("bad connection"))?);
+                }
+                ("c", Some(current)) => {
+                    current.connection =
+                        Some(parse_connection(value).ok_or_else(|| invalid("bad connection"))?);
+                }
+                ("m", _) => {
+                    media.push(parse_media(value).ok_or_else(|| invalid("bad media line"))?);
+                }
+                ("a", None) => {
+                    let attribute = parse_attribute(value);
+                    match Direction::from_attribute(&attribute.name) {
+                        Some(dir) => direction = Some(dir),
+                        None => attributes.push(attribute),
+                    }
+                }
+                ("a", Some(current)) => {
+                    let attribute = parse_attribute(value);
+                    if attribute.name == "fmtp" && attribute.value.is_some() {
+                        let (pt, params) = parse_fmtp(&attribute)?;
+                        fmtp.push((media.len() - 1, pt, params));
+                    } else {
+                        apply_media_attribute(current, attribute)?;
+                    }
+                }
+                // v=, t=, b= and the rest carry nothing we act on.
+                _ => {}
+            }
+        }
+
+        let mut session = Self {
+            origin: origin.ok_or(SdpError::MissingLine('o'))?,
+            session_name: session_name.unwrap_or_else(|| "-".to_string()),
+            connection,
+            direction,
+            attributes,
+            media,
+        };
+
+        let defaults = PayloadTypeMap::rfc3551();
+        for media in &mut session.media {
+            for &pt in &media.formats {
+                if media.payload_types.get(pt).is_none()
+                    && let Some(mapping) = defaults.get(pt)
+                {
+                    media.payload_types.insert(pt, mapping.clone());
+                }
+            }
+        }
+        for (index, pt, params) in fmtp {
+            let media = &mut session.media[index];
+            // A static type may have fmtp without an rtpmap.
+            if media.payload_types.get(pt).is_none()
+                && let Some(mapping) = defaults.get(pt)
+            {
+                media.payload_types.insert(pt, mapping.clone());
+            }
+            media.payload_types.set_fmtp(pt, params);
+        }
+        Ok(session)
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name == name)
+    }
+
+    /// The direction of a media section, falling back to the session-level one.
+    pub fn media_direction(&self, media: &MediaDescription) -> Direction {
+        media
+            .direction
+            .or(self.direction)
+            .unwrap_or(Direction::SendRecv)
+    }
+
+    /// Negotiates the first audio m-line we can receive.
+    pub fn negotiate_audio(&self) -> Option<(usize, NegotiatedMedia)> {
+        self.media
+            .iter()
+            .enumerate()
+            .find_map(|(index, media)| media.negotiate(self).map(|negotiated| (index, negotiated)))
+    }
+
+    /// Negotiates every audio m-line we can receive, as a recording session
+    /// with one m-line per participant needs.
+    pub fn negotiate_all_audio(&self) -> Vec<(usize, NegotiatedMedia)> {
+        self.media
+            .iter()
+            .enumerate()
+            .filter_map(|(index, media)| {
+                media.negotiate(self).map(|negotiated| (index, negotiated))
+            })
+            .collect()
+    }
+
+    /// Builds a receive-only answer to this offer. Every offered m-line gets
+    /// an m-line back as RFC 3264 requires; those not listed in `accepted`
+    /// are rejected with port zero. Accepted m-lines keep their RFC 4574
+    /// `label`.
+    #[must_use]
+    pub fn answer(&self, origin: Origin, address: IpAddr, accepted: &[AcceptedMedia]) -> Self {
+        let mut answer = Self::new(origin, "navitel");
+        answer.connection = Some(address);
+
+        for (index, offered) in self.media.iter().enumerate() {
+            let mut media =
+                MediaDescription::new(offered.media.clone(), 0, offered.protocol.clone());
+            if let Some(accepted) = accepted.iter().find(|accepted| accepted.index == index) {
+                let negotiated = accepted.negotiated;
+                media.port = accepted.port;
+                // The chosen codec goes first so it reads as our preference.
+                let mut formats: Vec<_> = negotiated.payload_types.iter().collect();
+                formats.sort_by_key(|&(pt, _)| pt != negotiated.payload_type);
+                for (pt, mapping) in formats {
+                    media.add_format(pt, mapping.clone());
+                }
+                media.ptime = negotiated.ptime;
+                media.rtcp_mux = negotiated.rtcp_mux;
+                media.crypto.extend(accepted.crypto.clone());
+                media
+                    .header_extensions
+                    .clone_from(&negotiated.header_extensions);
+                media.direction = Some(self.media_direction(offered).answer());
+                if let Some(label) = offered.attribute("label") {
+                    media.attributes.push(label.clone());
+                }
+            } else {
+                media.formats.clone_from(&offered.formats);
+            }
+            answer.media.push(media);
+        }
+        answer
+    }
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v=0\r\n")?;
+        write!(
+            f,
+            "o={} {} {} IN {} {}\r\n",
+            self.origin.username,
+            self.origin.session_id,
+            self.origin.session_version,
+            address_type(self.origin.address),
+            self.origin.address
+        )?;
+        write!(f, "s={}\r\n", self.session_name)?;
+        if let Some(connection) = self.connection {
+            write!(f, "c=IN {} {}\r\n", address_type(connection), connection)?;
+        }
+        write!(f, "t=0 0\r\n")?;
+        for attribute in &self.attributes {
+            write!(f, "{attribute}\r\n")?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={direction}\r\n")?;
+        }
+        for media in &self.media {
+            write!(f, "{media}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {} {}", self.media, self.port, self.protocol)?;
+        for pt in &self.formats {
+            write!(f, " {pt}")?;
+        }
+        write!(f, "\r\n")?;
+        if let Some(connection) = self.connection {
+            write!(f, "c=IN {} {}\r\n", address_type(connection), connection)?;
+        }
+        for &pt in &self.formats {
+            if let Some(mapping) = self.payload_types.get(pt) {
+                write!(f, "a=rtpmap:{pt} {mapping}\r\n")?;
+                if let Some(fmtp) = &mapping.fmtp {
+                    write!(f, "a=fmtp:{pt} {fmtp}\r\n")?;
+                }
+            }
+        }
+        if let Some(ptime) = self.ptime {
+            write!(f, "a=ptime:{ptime}\r\n")?;
+        }
+        if self.rtcp_mux {
+            write!(f, "a=rtcp-mux\r\n")?;
+        }
+        for crypto in &self.crypto {
+            write!(f, "a=crypto:{crypto}\r\n")?;
+        }
+        for (id, kind) in self.header_extensions.iter() {
+            write!(f, "a=extmap:{id} {kind}\r\n")?;
+        }
+        for attribute in &self.attributes {
+            write!(f, "{attribute}\r\n")?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={direction}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+const fn address_type(address: IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(_) => "IP4",
+        IpAddr::V6(_) => "IP6",
+    }
+}
+
+fn parse_origin(value: &str) -> Option<Origin> {
+    let mut parts = value.split_whitespace();
+    let username = parts.next()?.to_string();
+    let session_id = parts.next()?.parse().ok()?;
+    let session_version = parts.next()?.parse().ok()?;
+    let (_net_type, _addr_type) = (parts.next()?, parts.next()?);
+    let address = parts.next()?.parse().ok()?;
+    Some(Origin {
+        username,
+        session_id,
+        session_version,
+        address,
+    })
+}
+
+/// `IN IP4 192.0.2.1`, ignoring any multicast TTL suffix.
+fn parse_connection(value: &str) -> Option<IpAddr> {
+    let mut parts = value.split_whitespace();
+    if parts.next()? != "IN" {
+        return None;
+    }
+    let _addr_type = parts.next()?;
+    parts.next()?.split('/').next()?.parse().ok()
+}
+
+/// `audio 49170 RTP/AVP 0 8 101`. Port counts (`49170/2`) are not supported.
+fn parse_media(value: &str) -> Option<MediaDescription> {
+    let mut parts = value.split_whitespace();
+    let media = parts.next()?;
+    let port = parts.next()?.parse().ok()?;
+    let protocol = parts.next()?;
+    let mut description = MediaDescription::new(media, port, protocol);
+    // Non-RTP protocols carry format names rather than payload types.
+    description.formats = parts.filter_map(|pt| pt.parse().ok()).collect();
+    Some(description)
+}
+
+fn parse_attribute(value: &str) -> Attribute {
+    match value.split_once(':') {
+        Some((name, value)) => Attribute::new(name, Some(value.to_string())),
+        None => Attribute::new(value, None),
+    }
+}
+
+fn apply_media_attribute(
+    media: &mut MediaDescription,
+    attribute: Attribute,
+) -> Result<(), SdpError> {
+    let invalid = |reason| SdpError::InvalidLine {
+        line: attribute.to_string(),
+        reason,
+    };
+
+    match (attribute.name.as_str(), attribute.value.as_deref()) {
+        ("rtpmap", Some(value)) => {
+            let (pt, encoding) = value
+                .split_once(' ')
+                .ok_or_else(|| invalid("expected PT ENCODING"))?;
+            let (pt, mapping) = PayloadTypeMap::parse_entry(&format!("{pt}={encoding}"))?;
+            media.payload_types.insert(pt, mapping);
+        }
+        ("ptime", Some(value)) => {
+            media.ptime = Some(value.trim().parse().map_err(|_| invalid("bad ptime"))?);
+        }
+        ("rtcp-mux", None) => media.rtcp_mux = true,
+        ("crypto", Some(value)) => media.crypto.push(value.parse()?),
+        ("extmap", Some(value)) => match parse_extmap(value) {
+            Some((id, kind)) => media.header_extensions.insert(id, kind),
+            None => media.attributes.push(attribute),
+        },
+        (name, None) if Direction::from_attribute(name).is_some() => {
+            media.direction = Direction::from_attribute(name);
+        }
+        _ => media.attributes.push(attribute),
+    }
+    Ok(())
+}
+
+/// Reads `a=fmtp:PT PARAMS`.
+fn parse_fmtp(attribute: &Attribute) -> Result<(u8, String), SdpError> {
+    let invalid = |reason| SdpError::InvalidLine {
+        line: attribute.to_string(),
+        reason,
+    };
+    let (pt, params) = attribute
+        .value
+        .as_deref()
+        .and_then(|value| value.split_once(' '))
+        .ok_or_else(|| invalid("expected PT PARAMS"))?;
+    let pt = pt.parse().map_err(|_| invalid("bad payload type"))?;
+    Ok((pt, params.to_string()))
+}
+
+/// Reads `ID[/DIRECTION] URI [ATTRIBUTES]`, for extensions we understand.
+fn parse_extmap(value: &str) -> Option<(u8, HeaderExtensionKind)> {
+    let mut parts = value.split_whitespace();
+    let id = parts.next()?.split('/').next()?.parse().ok()?;
+    let kind = HeaderExtensionKind::from_uri(parts.next()?)?;
+    Some((id, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioCodec;
+
+    /// The example session of RFC 8866 section 5.
+    const RFC_8866_EXAMPLE: &str = "v=0\r\n\
+        o=jdoe 3724394400 3724394405 IN IP4 198.51.100.1\r\n\
+        s=Call to John Smith\r\n\
+        i=SDP Offer #1\r\n\
+        u=http://www.jdoe.example.com/home.html\r\n\
+        e=Jane Doe <jane@jdoe.example.com>\r\n\
+        p=+1 617 555-6011\r\n\
+        c=IN IP4 198.51.100.1\r\n\
+        t=0 0\r\n\
+        m=audio 49170 RTP/AVP 0\r\n\
+        m=audio 49180 RTP/AVP 0\r\n\
+        m=video 51372 RTP/AVP 99\r\n\
+        c=IN IP6 2001:db8::2\r\n\
+        a=rtpmap:99 h263-1998/90000\r\n";
+
+    /// A WebRTC-style offer: Opus with in-band FEC, DTMF at two clock
+    /// rates, SDES and header extensions.
+    const OPUS_OFFER: &str = "v=0\r\n\
+        o=- 42 2 IN IP4 192.0.2.10\r\n\
+        s=-\r\n\
+        c=IN IP4 192.0.2.10\r\n\
+        t=0 0\r\n\
+        a=sendonly\r\n\
+        m=audio 5004 RTP/SAVP 111 0 101 126\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+        a=rtpmap:101 telephone-event/48000\r\n\
+        a=rtpmap:126 telephone-event/8000\r\n\
+        a=ptime:20\r\n\
+        a=rtcp-mux\r\n\
+        a=crypto:1 AES_CM_128_HMAC_SHA1_80 inline:PS1uQCVeeCFCanVmcjkpPywjNWhcYD0mXXtxaVBR|2^20|1:4\r\n\
+        a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+        a=extmap:5 urn:example:unknown\r\n\
+        a=label:caller\r\n\
+        m=audio 0 RTP/AVP 0\r\n";
+
+    #[test]
+    fn parses_the_rfc_8866_example() {
+        let session = SessionDescription::parse(RFC_8866_EXAMPLE).unwrap();
+        assert_eq!(session.origin.username, "jdoe");
+        assert_eq!(session.origin.session_version, 3_724_394_405);
+        assert_eq!(session.session_name, "Call to John Smith");
+        assert_eq!(session.connection, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(session.media.len(), 3);
+        assert_eq!(
+            session.media[2].connection,
+            Some("2001:db8::2".parse().unwrap())
+        );
+
+        // PCMU comes from the RFC 3551 table, as the offer has no rtpmap for it.
+        let pcmu = session.media[0].payload_types.get(0).unwrap();
+        assert_eq!(pcmu.to_string(), "PCMU/8000");
+        assert_eq!(session.negotiate_all_audio().len(), 2);
+        let (index, negotiated) = session.negotiate_audio().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(negotiated.format, AudioFormat::g711_ulaw_mono());
+        assert_eq!(
+            negotiated.remote_addr,
+            Some("198.51.100.1:49170".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn negotiates_the_first_decodable_codec_and_its_companions() {
+        let session = SessionDescription::parse(OPUS_OFFER).unwrap();
+        let media = &session.media[0];
+        assert!(media.is_secure());
+        assert!(media.rtcp_mux);
+        assert_eq!(media.ptime, Some(20));
+        assert_eq!(session.media_direction(media), Direction::SendOnly);
+        assert_eq!(
+            media.header_extensions.get(1),
+            Some(HeaderExtensionKind::AudioLevel)
+        );
+        assert_eq!(media.header_extensions.get(5), None);
+        assert!(media.attribute("extmap").is_some());
+
+        let (index, negotiated) = session.negotiate_audio().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(negotiated.payload_type, 111);
+        assert_eq!(negotiated.format.codec, AudioCodec::Opus);
+        // Only the telephone-event at Opus's clock rate goes along with it.
+        let accepted: Vec<_> = negotiated.payload_types.iter().map(|(pt, _)| pt).collect();
+        assert_eq!(accepted, [101, 111]);
+        let crypto = negotiated.crypto.unwrap();
+        assert_eq!(crypto.suite, "AES_CM_128_HMAC_SHA1_80");
+        assert_eq!(
+            crypto.to_string().parse::<CryptoAttribute>().unwrap(),
+            crypto
+        );
+
+        // The second m-line was offered with port zero.
+        assert_eq!(session.negotiate_all_audio().len(), 1);
+    }
+
+    #[test]
+    fn answers_every_offered_m_line() {
+        let offer = SessionDescription::parse(OPUS_OFFER).unwrap();
+        let (index, negotiated) = offer.negotiate_audio().unwrap();
+        let origin = Origin {
+            username: "-".to_string(),
+            session_id: 7,
+            session_version: 7,
+            address: "203.0.113.5".parse().unwrap(),
+        };
+        let answer = offer.answer(
+            origin,
+            "203.0.113.5".parse().unwrap(),
+            &[AcceptedMedia {
+                index,
+                port: 20000,
+                negotiated: &negotiated,
+                crypto: None,
+            }],
+        );
+
+        let reparsed = SessionDescription::parse(&answer.to_string()).unwrap();
+        assert_eq!(reparsed.media.len(), 2);
+        let accepted = &reparsed.media[0];
+        assert_eq!(accepted.port, 20000);
+        assert_eq!(accepted.formats, [111, 101]);
+        assert_eq!(
+            accepted.payload_types.get(111).unwrap().fmtp.as_deref(),
+            Some("minptime=10;useinbandfec=1")
+        );
+        assert_eq!(accepted.direction, Some(Direction::RecvOnly));
+        assert_eq!(
+            accepted
+                .attribute("label")
+                .and_then(|label| label.value.as_deref()),
+            Some("caller")
+        );
+        assert_eq!(reparsed.media[1].port, 0);
+        assert_eq!(reparsed.media[1].formats, [0]);
+    }
+
+    #[test]
+    fn rejects_malformed_descriptions() {
+        let missing_origin = "v=0\r\ns=-\r\nm=audio 5004 RTP/AVP 0\r\n";
+        assert_eq!(
+            SessionDescription::parse(missing_origin),
+            Err(SdpError::MissingLine('o'))
+        );
+        for sdp in [
+            "v=0\r\no=- 1 1 IN IP4 nowhere\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nc=IN IP4\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nm=audio many RTP/AVP 0\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nbogus\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nm=audio 5004 RTP/AVP 96\r\na=rtpmap:96\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nm=audio 5004 RTP/AVP 96\r\na=fmtp:x y\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nm=audio 5004 RTP/AVP 0\r\na=ptime:soon\r\n",
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\nm=audio 5004 RTP/SAVP 0\r\na=crypto:1 AES_CM_128_HMAC_SHA1_80\r\n",
+        ] {
+            assert!(
+                matches!(
+                    SessionDescription::parse(sdp),
+                    Err(SdpError::InvalidLine { .. } | SdpError::Payload(_))
+                ),
+                "{sdp:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn fmtp_before_rtpmap_is_kept() {
+        let sdp = "v=0\r\n\
+                   o=- 1 1 IN IP4 192.0.2.1\r\n\
+                   s=-\r\n\
+                   c=IN IP4 192.0.2.1\r\n\
+                   t=0 0\r\n\
+                   m=audio 5004 RTP/AVP 111 101\r\n\
+                   a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+                   a=fmtp:101 0-15\r\n\
+                   a=rtpmap:111 opus/48000/2\r\n\
+                   a=rtpmap:101 telephone-event/8000\r\n";
+        let session = SessionDescription::parse(sdp).unwrap();
+        let payload_types = &session.media[0].payload_types;
+        assert_eq!(
+            payload_types.get(111).unwrap().fmtp.as_deref(),
+            Some("minptime=10;useinbandfec=1")
+        );
+        assert_eq!(
+            payload_types.get(101).unwrap().fmtp.as_deref(),
+            Some("0-15")
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// Snapshot of RFC 3550 reception statistics for a single RTP source.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReceptionStats {
+    pub ssrc: u32,
+    /// RTP timestamp clock rate that `jitter` is expressed in.
+    pub clock_rate: u32,
+    /// Packets received, including duplicates, as RFC 3550 counts them.
+    pub packets_received: u64,
+    pub packets_expected: u64,
+    /// Expected minus received; negative when duplicates outnumber losses.
+    pub cumulative_lost: i64,
+    /// Loss over the current reporting interval, as a fixed point fraction of 256.
+    pub fraction_lost: u8,
+    pub extended_highest_seq: u32,
+    pub sequence_cycles: u32,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    /// Interarrival jitter in RTP timestamp units.
+    pub jitter: u32,
+    /// Packets dropped because their payload type had no usable mapping.
+    pub rejected_payload: u64,
+    /// SRTP or SRTCP packets that failed authentication.
+    pub srtp_auth_failures: u64,
+    /// SRTP or SRTCP packets dropped as replays.
+    pub srtp_replayed: u64,
+    /// RFC 3389 comfort noise packets received.
+    pub comfort_noise_packets: u64,
+    /// Times the sender went silent under DTX; not counted as loss.
+    pub dtx_periods: u64,
+    /// Total DTX silence in milliseconds.
+    pub dtx_silence_ms: u64,
+    /// Lost packets rebuilt from RFC 2198 redundancy or RFC 5109 FEC.
+    pub packets_recovered: u64,
+    /// Lost packets that nothing could rebuild.
+    pub packets_unrecovered: u64,
+}
+
+impl ReceptionStats {
+    #[allow(clippy::cast_precision_loss)]
+    pub fn loss_percent(&self) -> f64 {
+        if self.packets_expected == 0 {
+            return 0.0;
+        }
+        self.cumulative_lost.max(0) as f64 * 100.0 / self.packets_expected as f64
+    }
+
+    pub fn jitter_ms(&self) -> f64 {
+        if self.clock_rate == 0 {
+            return 0.0;
+        }
+        f64::from(self.jitter) * 1000.0 / f64::from(self.clock_rate)
+    }
+}
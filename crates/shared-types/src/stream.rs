@@ -3,6 +3,12 @@ use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use uuid::Uuid;
 
+use crate::audio::AudioFormat;
+use crate::dtmf::DtmfEvent;
+use crate::payload::PayloadTypeMap;
+use crate::sdp::NegotiatedMedia;
+use crate::stats::ReceptionStats;
+
 pub type StreamId = Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,7 +19,32 @@ pub struct StreamMetadata {
     pub state: StreamState,
     pub codec: String,
     pub ssrc: Option<u32>,
+    pub cname: Option<String>,
     pub call_id: Option<String>,
+    /// The format agreed in signalling, when the stream was set up with SDP.
+    pub negotiated_format: Option<AudioFormat>,
+    pub payload_types: Option<PayloadTypeMap>,
+    /// Whose audio this is, when the stream is one leg of a SIPREC recording.
+    pub recording: Option<RecordingLeg>,
+    /// Local RTP port allocated to the stream by signalling, if any.
+    pub media_port: Option<u16>,
+}
+
+/// One participant's stream within a recorded call, from the SIPREC
+/// recording metadata (RFC 7865).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingLeg {
+    /// The recording session (`session_id`) the stream belongs to.
+    pub session_id: Option<String>,
+    pub participant_id: String,
+    /// Address of record, e.g. `sip:alice@example.com`.
+    pub aor: Option<String>,
+    pub name: Option<String>,
+    /// Participants the metadata says receive this stream, i.e. the other
+    /// side of the call. RFC 7865 has no notion of which party placed it.
+    pub heard_by: Vec<String>,
+    /// SDP `a=label` tying the metadata stream to its m-line.
+    pub label: String,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -25,6 +56,82 @@ pub enum StreamState {
     Error,
 }
 
+/// Lifecycle notifications so downstream services know when calls start and end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StreamEvent {
+    Created {
+        metadata: StreamMetadata,
+    },
+    StateChanged {
+        stream_id: StreamId,
+        previous: StreamState,
+        current: StreamState,
+        at: DateTime<Utc>,
+    },
+    /// The sender switched SSRC and the new one was linked to this stream.
+    SsrcChanged {
+        stream_id: StreamId,
+        previous_ssrc: u32,
+        ssrc: u32,
+        at: DateTime<Utc>,
+    },
+    /// The sender's packets started arriving from a new address, typically
+    /// after a NAT rebinding, and the stream followed them there.
+    SourceChanged {
+        stream_id: StreamId,
+        previous_addr: SocketAddr,
+        source_addr: SocketAddr,
+        at: DateTime<Utc>,
+    },
+    /// The stream's reception statistics so far, sent each time we
+    /// report on it in RTCP.
+    Stats {
+        stream_id: StreamId,
+        stats: ReceptionStats,
+        at: DateTime<Utc>,
+    },
+    /// The caller pressed a key.
+    Dtmf {
+        stream_id: StreamId,
+        event: DtmfEvent,
+        at: DateTime<Utc>,
+    },
+    Ended {
+        metadata: StreamMetadata,
+        reason: EndReason,
+        stats: Option<ReceptionStats>,
+        at: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndReason {
+    /// The sender said goodbye with RTCP BYE.
+    Bye,
+    /// No media arrived within the inactivity timeout.
+    Timeout,
+    /// The call carrying the stream was hung up in signalling.
+    Hangup,
+    /// The connection carrying the stream was closed: its RFC 4571 TCP
+    /// connection, or a WebRTC peer's DTLS session.
+    ConnectionClosed,
+    /// The capture being replayed into the pipeline ran out.
+    ReplayEnded,
+}
+
+impl StreamEvent {
+    pub fn stream_id(&self) -> StreamId {
+        match self {
+            Self::Created { metadata } | Self::Ended { metadata, .. } => metadata.id,
+            Self::StateChanged { stream_id, .. }
+            | Self::SsrcChanged { stream_id, .. }
+            | Self::SourceChanged { stream_id, .. }
+            | Self::Stats { stream_id, .. }
+            | Self::Dtmf { stream_id, .. } => *stream_id,
+        }
+    }
+}
+
 impl StreamMetadata {
     pub fn new(source_addr: SocketAddr) -> Self {
         Self {
@@ -34,7 +141,12 @@ impl StreamMetadata {
             state: StreamState::Connecting,
             codec: String::new(),
             ssrc: None,
+            cname: None,
             call_id: None,
+            negotiated_format: None,
+            payload_types: None,
+            recording: None,
+            media_port: None,
         }
     }
 
@@ -44,4 +156,14 @@ impl StreamMetadata {
         self.codec = codec;
         self
     }
+
+    #[must_use]
+    pub fn with_negotiated_media(mut self, media: &NegotiatedMedia) -> Self {
+        if let Some(mapping) = media.payload_types.get(media.payload_type) {
+            self.codec = mapping.to_string();
+        }
+        self.negotiated_format = Some(media.format);
+        self.payload_types = Some(media.payload_types.clone());
+        self
+    }
 }